@@ -0,0 +1,297 @@
+//! Attribute macros of `blynk_io`, enabled by its `macros` feature
+//!
+//! `#[handler]` goes on an impl block of the handler struct. Its methods
+//! marked with `#[on_write(pin = N)]` or `#[on_read(pin = N)]` are called
+//! by the generated `Event::handle_vpin_write` and `handle_vpin_read`, the
+//! same way `BLYNK_WRITE(VN)` and `BLYNK_READ(VN)` work in the C++ library.
+//!
+//! The impl block is either a plain one, then the whole `Event`
+//! implementation is generated, or `impl Event for ...` with other hooks.
+//! A `handle_vpin_write` (`handle_vpin_read`) written there still gets the
+//! pins no method is marked for.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Attribute, Error, Ident, ImplItem, ImplItemFn, ItemImpl, LitInt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Write,
+    Read,
+}
+
+impl Kind {
+    fn of(attr: &Attribute) -> Option<Kind> {
+        match attr.path().segments.last()?.ident.to_string().as_str() {
+            "on_write" => Some(Kind::Write),
+            "on_read" => Some(Kind::Read),
+            _ => None,
+        }
+    }
+
+    /// `Event` method dispatching the pins of this kind
+    fn hook(self) -> &'static str {
+        match self {
+            Kind::Write => "handle_vpin_write",
+            Kind::Read => "handle_vpin_read",
+        }
+    }
+}
+
+struct Route {
+    kind: Kind,
+    pin: u8,
+    method: Ident,
+}
+
+/// Generates the `Event` implementation dispatching virtual pins to the
+/// marked methods
+///
+/// # Example
+/// ```ignore
+/// use blynk_io::{self as blynk, BlynkError, Client, ParamList, Protocol};
+///
+/// struct Relay {
+///     on: bool,
+/// }
+///
+/// #[blynk::handler]
+/// impl Relay {
+///     #[blynk::on_write(pin = 5)]
+///     fn switch(&mut self, _client: &mut Client, params: ParamList<'_>) -> Result<(), BlynkError> {
+///         self.on = params.as_str() == "1";
+///         Ok(())
+///     }
+///
+///     #[blynk::on_read(pin = 4)]
+///     fn state(&mut self, client: &mut Client) -> Result<(), BlynkError> {
+///         client.virtual_write(4, if self.on { "1" } else { "0" })
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn handler(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        let args = TokenStream2::from(args);
+        return Error::new_spanned(args, "`handler` takes no arguments")
+            .into_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(input as ItemImpl);
+    expand(item)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Marks a method of a `#[handler]` impl block as the handler of writes
+/// to the virtual pin, takes the client and the written value and returns
+/// the hook result
+#[proc_macro_attribute]
+pub fn on_write(_args: TokenStream, _input: TokenStream) -> TokenStream {
+    outside_handler("on_write")
+}
+
+/// Marks a method of a `#[handler]` impl block as the handler of reads
+/// of the virtual pin, takes the client and returns the hook result
+#[proc_macro_attribute]
+pub fn on_read(_args: TokenStream, _input: TokenStream) -> TokenStream {
+    outside_handler("on_read")
+}
+
+/// `on_write` and `on_read` are removed by `handler`, they only expand on
+/// their own when used outside of it
+fn outside_handler(name: &str) -> TokenStream {
+    let msg = format!(
+        "`{}` only works on methods of a `#[handler]` impl block",
+        name
+    );
+    Error::new(proc_macro2::Span::call_site(), msg)
+        .into_compile_error()
+        .into()
+}
+
+/// Parses the `pin = N` argument
+fn parse_pin(attr: &Attribute) -> syn::Result<u8> {
+    let mut pin = None;
+    attr.parse_nested_meta(|meta| {
+        if !meta.path.is_ident("pin") {
+            return Err(meta.error("expected `pin = N`"));
+        }
+        let lit: LitInt = meta.value()?.parse()?;
+        pin = Some(lit.base10_parse()?);
+        Ok(())
+    })?;
+    pin.ok_or_else(|| Error::new_spanned(attr, "expected `pin = N`"))
+}
+
+/// Takes the route attributes off the method, returns the routes
+fn take_routes(method: &mut ImplItemFn, routes: &[Route]) -> syn::Result<Vec<Route>> {
+    let mut taken: Vec<Route> = Vec::new();
+    let mut attrs = Vec::new();
+    for attr in std::mem::take(&mut method.attrs) {
+        let Some(kind) = Kind::of(&attr) else {
+            attrs.push(attr);
+            continue;
+        };
+        let pin = parse_pin(&attr)?;
+        if let Some(other) = routes
+            .iter()
+            .chain(&taken)
+            .find(|route| route.kind == kind && route.pin == pin)
+        {
+            let msg = format!("pin {} is already handled by `{}`", pin, other.method);
+            return Err(Error::new_spanned(attr, msg));
+        }
+        taken.push(Route {
+            kind,
+            pin,
+            method: method.sig.ident.clone(),
+        });
+    }
+    method.attrs = attrs;
+    if !taken.is_empty() && method.sig.asyncness.is_some() != cfg!(feature = "async") {
+        let msg = if cfg!(feature = "async") {
+            "pin handlers have to be `async` in the async mode"
+        } else {
+            "pin handlers can't be `async` in the blocking mode"
+        };
+        return Err(Error::new_spanned(&method.sig, msg));
+    }
+    Ok(taken)
+}
+
+/// `handle_vpin_write` or `handle_vpin_read` calling the marked methods,
+/// other pins go to `fallback` if the impl block had one
+fn dispatch(kind: Kind, routes: &[Route], fallback: Option<&Ident>) -> TokenStream2 {
+    let (asyncness, aw) = if cfg!(feature = "async") {
+        (quote!(async), quote!(.await))
+    } else {
+        (quote!(), quote!())
+    };
+    let hook = format_ident!("{}", kind.hook());
+    let routes = routes.iter().filter(|route| route.kind == kind);
+    let pins = routes.clone().map(|route| route.pin);
+    let methods = routes.map(|route| &route.method);
+    match kind {
+        Kind::Write => {
+            let fallback = match fallback {
+                Some(fallback) => quote!(self.#fallback(client, pin_num, params)#aw),
+                None => quote!(::core::result::Result::Ok(())),
+            };
+            quote! {
+                #asyncness fn #hook(
+                    &mut self,
+                    client: &mut ::blynk_io::Client,
+                    pin_num: ::blynk_io::VirtualPin,
+                    params: ::blynk_io::ParamList<'_>,
+                ) -> ::core::result::Result<(), ::blynk_io::BlynkError> {
+                    match pin_num.number() {
+                        #(#pins => self.#methods(client, params)#aw,)*
+                        _ => #fallback,
+                    }
+                }
+            }
+        }
+        Kind::Read => {
+            let fallback = match fallback {
+                Some(fallback) => quote!(self.#fallback(client, pin_num)#aw),
+                None => quote!(::core::result::Result::Ok(())),
+            };
+            quote! {
+                #asyncness fn #hook(
+                    &mut self,
+                    client: &mut ::blynk_io::Client,
+                    pin_num: ::blynk_io::VirtualPin,
+                ) -> ::core::result::Result<(), ::blynk_io::BlynkError> {
+                    match pin_num.number() {
+                        #(#pins => self.#methods(client)#aw,)*
+                        _ => #fallback,
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn expand(mut item: ItemImpl) -> syn::Result<TokenStream2> {
+    let mut routes = Vec::new();
+    let mut routed = Vec::new();
+    let mut rest = Vec::new();
+    for impl_item in std::mem::take(&mut item.items) {
+        let ImplItem::Fn(mut method) = impl_item else {
+            rest.push(impl_item);
+            continue;
+        };
+        let taken = take_routes(&mut method, &routes)?;
+        if taken.is_empty() {
+            rest.push(ImplItem::Fn(method));
+        } else {
+            routes.extend(taken);
+            routed.push(method);
+        }
+    }
+
+    // hooks written in the trait impl are kept for the unrouted pins
+    let mut dispatchers = Vec::new();
+    for kind in [Kind::Write, Kind::Read] {
+        if !routes.iter().any(|route| route.kind == kind) {
+            continue;
+        }
+        let mut fallback = None;
+        if item.trait_.is_some() {
+            if let Some(pos) = rest.iter().position(
+                |impl_item| matches!(impl_item, ImplItem::Fn(f) if f.sig.ident == kind.hook()),
+            ) {
+                let ImplItem::Fn(mut method) = rest.remove(pos) else {
+                    unreachable!()
+                };
+                method.sig.ident = format_ident!("__blynk_{}", kind.hook());
+                fallback = Some(method.sig.ident.clone());
+                routed.push(method);
+            }
+        }
+        dispatchers.push(dispatch(kind, &routes, fallback.as_ref()));
+    }
+
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    let async_trait = if cfg!(feature = "async")
+        && !item.attrs.iter().any(|attr| {
+            attr.path()
+                .segments
+                .last()
+                .is_some_and(|seg| seg.ident == "async_trait")
+        }) {
+        quote!(#[::blynk_io::__private::async_trait])
+    } else {
+        quote!()
+    };
+
+    if item.trait_.is_some() {
+        item.items = rest;
+        item.items
+            .extend(dispatchers.into_iter().map(ImplItem::Verbatim));
+        let attrs = std::mem::take(&mut item.attrs);
+        Ok(quote! {
+            #(#attrs)*
+            #async_trait
+            #item
+
+            impl #impl_generics #self_ty #where_clause {
+                #(#routed)*
+            }
+        })
+    } else {
+        item.items = rest;
+        item.items.extend(routed.into_iter().map(ImplItem::Fn));
+        Ok(quote! {
+            #item
+
+            #async_trait
+            impl #impl_generics ::blynk_io::Event for #self_ty #where_clause {
+                #(#dispatchers)*
+            }
+        })
+    }
+}
@@ -1,290 +1,398 @@
+//! IO of the async mode and the parts of its API taking closures, the rest
+//! is generated from the sources shared with the blocking mode
 use log::*;
 
-pub use self::client::{Client, Protocol};
+pub(crate) use self::stream::SplitStream;
+pub use self::stream::Stream;
 
-pub mod client;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod stream;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
-use crate::message::Message;
-use crate::{BlynkError, Config, ConnectionState, DefaultHandler, Result};
-use async_trait::async_trait;
-
-use crate::conf;
-use crate::message::{MessageType, ProtocolStatus};
+use crate::message::ProtocolHeader;
+use crate::sender::Commands;
+use crate::{conf, proto, BlynkError, BlynkEvent, BlynkSender, ConfigSource, EventQueue, Result};
+use crate::{Blynk, Connection, Event, FnEvent, ParamList, PinHandle, Protocol, VirtualPin};
 
+use smol::channel::Receiver;
 use smol::future::FutureExt;
-use smol::{Async, Timer};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::time::{Duration, Instant};
-
-#[allow(unused_variables)]
-#[async_trait]
-pub trait Event: Send {
-    async fn handle_connect(&mut self, client: &mut Client) {}
-    async fn handle_disconnect(&mut self) {}
-    async fn handle_internal(&mut self, client: &mut Client, data: &[String]) {}
-    async fn handle_vpin_read(&mut self, client: &mut Client, pin_num: u8) {}
-    async fn handle_vpin_write(&mut self, client: &mut Client, pin_num: u8, data: &str) {}
+use smol::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use smol::lock::{Semaphore, SemaphoreGuardArc};
+use smol::stream::{Stream as EventStream, StreamExt};
+use smol::{Task, Timer};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+pub(crate) mod prelude {
+    pub(crate) use smol::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    pub(crate) use smol::io::{BufReader, Chain, Cursor};
 }
 
-#[async_trait]
-impl Event for DefaultHandler {}
+/// Socket a proxy tunnel or a WebSocket upgrade is done over
+pub(crate) trait Socket: smol::io::AsyncRead + smol::io::AsyncWrite + Unpin {}
+
+impl<T: smol::io::AsyncRead + smol::io::AsyncWrite + Unpin> Socket for T {}
+
+/// Future returned by the pin routes, it may hold on to the client
+pub type RouteFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+/// Handler of writes to a single virtual pin, see `Blynk::on_virtual_write`
+pub(crate) type WriteRoute<C> =
+    Box<dyn for<'a> FnMut(&'a mut C, ParamList<'a>) -> RouteFuture<'a> + Send>;
+/// Handler of reads of a single virtual pin, see `Blynk::on_virtual_read`
+pub(crate) type ReadRoute<C> = Box<dyn for<'a> FnMut(&'a mut C) -> RouteFuture<'a> + Send>;
+/// Handler of writes run as a task, see `Blynk::spawn_virtual_write`
+type TaskWriteRoute =
+    Box<dyn for<'a> FnMut(BlynkSender, ParamList<'a>) -> RouteFuture<'static> + Send>;
+/// Handler of reads run as a task, see `Blynk::spawn_virtual_read`
+type TaskReadRoute = Box<dyn FnMut(BlynkSender) -> RouteFuture<'static> + Send>;
+
+pub(crate) type ConnectFn<P> = Box<dyn for<'a> FnMut(&'a mut P) -> RouteFuture<'a> + Send>;
+pub(crate) type DisconnectFn = Box<dyn FnMut() -> RouteFuture<'static> + Send>;
+pub(crate) type PinReadFn<P> =
+    Box<dyn for<'a> FnMut(&'a mut P, VirtualPin) -> RouteFuture<'a> + Send>;
+pub(crate) type PinWriteFn<P> =
+    Box<dyn for<'a> FnMut(&'a mut P, VirtualPin, ParamList<'a>) -> RouteFuture<'a> + Send>;
+pub(crate) type InternalFn<P> =
+    Box<dyn for<'a> FnMut(&'a mut P, ParamList<'a>) -> RouteFuture<'a> + Send>;
+
+pub(crate) async fn sleep(duration: Duration) {
+    Timer::after(duration).await;
+}
 
-pub struct Blynk<E: Event> {
-    conn_state: ConnectionState,
-    config: Config,
+pub(crate) async fn resolve(host: &str, port: u64) -> io::Result<Vec<SocketAddr>> {
+    let host_port = [host.to_string(), ":".to_string(), port.to_string()].join("");
+    smol::unblock(move || Ok(host_port.to_socket_addrs()?.collect())).await
+}
 
-    client: Client,
+/// Reads one whole message into `frame`, waits until one arrives
+pub(crate) async fn read_frame<T: AsyncRead + Unpin>(
+    reader: &mut BufReader<T>,
+    frame: &mut Vec<u8>,
+) -> Result<()> {
+    read_frame_within(reader, frame, None).await
+}
 
-    pub handler: Option<E>,
+/// Reads one whole message, waiting at most `timeout` for it to start.
+/// Once it started the rest is read without a timeout, cancelling the
+/// read half way would lose the position in the stream
+pub(crate) async fn read_frame_within<T: AsyncRead + Unpin>(
+    reader: &mut BufReader<T>,
+    frame: &mut Vec<u8>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let start = async { Ok(reader.fill_buf().await?.is_empty()) };
+    let empty = match timeout {
+        Some(timeout) => {
+            start
+                .or(async {
+                    Timer::after(timeout).await;
+                    Err(io::Error::from(ErrorKind::TimedOut))
+                })
+                .await?
+        }
+        None => start.await?,
+    };
+    if empty {
+        return Err(BlynkError::EmptyBuffer);
+    }
 
-    last_rcv_time: Instant,
-    last_ping_time: Instant,
-    last_send_time: Instant,
+    frame.resize(ProtocolHeader::SIZE, 0);
+    reader.read_exact(frame).await?;
+    frame.resize(ProtocolHeader::SIZE + proto::body_len(frame), 0);
+    reader
+        .read_exact(&mut frame[ProtocolHeader::SIZE..])
+        .await?;
+    Ok(())
 }
 
-impl<E: Event> Blynk<E> {
-    /// Returns the Blynk client initalized with API token
-    ///
-    /// # Arguments
-    /// * `auth_token` - A string that holds the Blynk API token
-    pub fn new(auth_token: String) -> Blynk<E> {
-        Self {
-            conn_state: ConnectionState::Disconnected,
-            config: Config {
-                token: auth_token,
-                ..Default::default()
-            },
-
-            client: Client::default(),
-            handler: None,
-
-            last_rcv_time: Instant::now(),
-            last_ping_time: Instant::now(),
-            last_send_time: Instant::now(),
+/// Pin handlers run as tasks of their own, see `Blynk::spawn_virtual_write`
+pub(crate) struct Tasks {
+    write_routes: BTreeMap<u8, TaskWriteRoute>,
+    read_routes: BTreeMap<u8, TaskReadRoute>,
+    /// Permits of the spawned handlers running at once
+    permits: Arc<Semaphore>,
+}
+
+impl Default for Tasks {
+    fn default() -> Tasks {
+        Tasks {
+            write_routes: BTreeMap::new(),
+            read_routes: BTreeMap::new(),
+            permits: Arc::new(Semaphore::new(conf::MAX_HANDLER_TASKS)),
         }
     }
+}
 
-    pub fn set_config(&mut self, config: Config) {
-        self.config = config;
+impl Tasks {
+    /// Spawns the write handler of the pin, false if it has none
+    pub(crate) fn spawn_write(&mut self, pin: u8, commands: &Commands, values: &[&str]) -> bool {
+        let Some(route) = self.write_routes.get_mut(&pin) else {
+            return false;
+        };
+        let Some(permit) = self.permits.try_acquire_arc() else {
+            warn!(
+                "Too many spawned handlers running, dropping write of pin {}",
+                pin
+            );
+            return true;
+        };
+        let task = route(commands.sender(), ParamList::new(values));
+        Tasks::spawn(pin, permit, task);
+        true
     }
 
-    /// Returns the low level Client abstraction that is implements
-    /// the protocol and is responsible for the communication
-    pub fn client(&mut self) -> &mut Client {
-        self.last_send_time = Instant::now();
-        &mut self.client
+    /// Spawns the read handler of the pin, false if it has none
+    pub(crate) fn spawn_read(&mut self, pin: u8, commands: &Commands) -> bool {
+        let Some(route) = self.read_routes.get_mut(&pin) else {
+            return false;
+        };
+        let Some(permit) = self.permits.try_acquire_arc() else {
+            warn!(
+                "Too many spawned handlers running, dropping read of pin {}",
+                pin
+            );
+            return true;
+        };
+        let task = route(commands.sender());
+        Tasks::spawn(pin, permit, task);
+        true
     }
 
-    /// Performs event loop run that is reposnible for:
-    /// - checking the connection state
-    /// - reconnecting if connection failed
-    /// - reading any pending responses from blynk servers
-    /// - executing events hooks if those are provided
-    pub async fn run(&mut self) {
-        if !matches!(self.conn_state, ConnectionState::Authenticated) {
-            error!("Not connected, trying reconnect");
-            if let Err(err) = self.connect().await {
-                error!("Problem while connecting: {}", err);
-                self.disconnect("Problem while connecting").await;
-                return;
+    /// Runs the task holding the permit, which is released once it ends
+    fn spawn(pin: u8, permit: SemaphoreGuardArc, task: RouteFuture<'static>) {
+        smol::spawn(async move {
+            if let Err(err) = task.await {
+                error!("Spawned handler of pin {} failed: {}", pin, err);
             }
-        }
-
-        if !self.is_server_alive().await {
-            info!("Blynk is offline for some reson :(");
-            self.disconnect("Blynk server is offline").await;
-            return;
-        }
-
-        // otherwise wait for response
-        self.read_response()
-            .or(async {
-                Timer::after(Duration::from_millis(5)).await;
-            })
-            .await;
+            drop(permit);
+        })
+        .detach();
     }
+}
 
-    /// Sets the events handler for incoming events from the Blynk platform
+impl<E: Event<C>, S: ConfigSource + Sync, C: Connection> Blynk<E, S, C> {
+    /// Calls `route` with the client and the written values on every write
+    /// of the virtual pin instead of the handler, so simple projects don't
+    /// need an `Event` implementation at all
+    ///
+    /// # Example
+    /// ```
+    /// use blynk_io::*;
     ///
-    /// See `Event` trait documentation for example implementation
-    pub fn set_handler(&mut self, hook: E) {
-        self.handler = Some(hook);
+    /// let mut blynk = Blynk::<DefaultHandler>::new("BLYNK TOKEN".to_string());
+    /// blynk.on_virtual_write(5, |client, params| {
+    ///     Box::pin(async move { client.virtual_write(6, params.as_str()).await })
+    /// });
+    /// ```
+    pub fn on_virtual_write<P, F>(&mut self, pin: P, route: F)
+    where
+        P: Into<VirtualPin>,
+        F: for<'a> FnMut(&'a mut C, ParamList<'a>) -> RouteFuture<'a> + Send + 'static,
+    {
+        self.write_routes
+            .insert(pin.into().number(), Box::new(route));
     }
 
-    /// Gets a mutable referance to handler if it's defined
-    pub fn handler(&mut self) -> Option<&mut E> {
-        match &self.handler {
-            Some(_) => self.handler.as_mut(),
-            None => None,
-        }
+    /// Calls `route` on every read request of the virtual pin instead of
+    /// the handler
+    pub fn on_virtual_read<P, F>(&mut self, pin: P, route: F)
+    where
+        P: Into<VirtualPin>,
+        F: for<'a> FnMut(&'a mut C) -> RouteFuture<'a> + Send + 'static,
+    {
+        self.read_routes
+            .insert(pin.into().number(), Box::new(route));
     }
 
-    /// Connects to Blynk servers
+    /// Runs `route` as a task of its own on every write of the virtual
+    /// pin, so a slow one doesn't hold back pings and the other messages.
+    /// It gets a `BlynkSender` instead of the client and the written
+    /// values, the task has to own whatever it takes from them. Its writes
+    /// are sent by the next `run`. Failures are only logged,
+    /// `HandlerErrorPolicy` doesn't apply to the tasks. Writes arriving
+    /// while `set_task_limit` handlers run are dropped
     ///
-    /// Performs authentication and sets up heart beat with the servers
+    /// # Example
+    /// ```
+    /// use blynk_io::*;
+    /// use std::time::Duration;
     ///
-    /// Calls hook in event of succseful handshake
-    async fn connect(&mut self) -> Result<()> {
-        self.conn_state = ConnectionState::Connecting;
-
-        let host_port = vec![
-            self.config.server.clone(),
-            ":".to_string(),
-            self.config.port.to_string(),
-        ]
-        .join("");
-
-        let addrs = smol::unblock(move || host_port.to_socket_addrs())
-            .await?
-            .collect::<Vec<_>>();
-        let addr = *addrs.first().ok_or(BlynkError::Dns)?;
-
-        info!("stream open start tp {:?}", addr);
-
-        // opening async TcpStream connection does not work yet with esp-rs
-        let blocking_stream =
-            smol::unblock(move || TcpStream::connect_timeout(&addr, Duration::from_secs(3)))
-                .await?;
-        let stream = Async::new(blocking_stream)?;
-
-        // once it works ;-)
-        // let stream = Async::<TcpStream>::connect(addr).or(async {
-        //     Timer::after(Duration::from_secs(10)).await;
-        //     Err(io::ErrorKind::TimedOut.into())
-        // })
-        // .await.unwrap();
-
-        self.client.set_stream(stream);
-
-        info!("Successfully connected to blynk server");
-
-        self.authenticate(&self.config.token.clone()).await?;
-        self.set_heartbeat().await?;
-
-        self.last_rcv_time = Instant::now();
-
-        if let Some(hook) = &mut self.handler {
-            hook.handle_connect(&mut self.client).await;
-        }
-        Ok(())
+    /// let mut blynk = Blynk::<DefaultHandler>::new("BLYNK TOKEN".to_string());
+    /// blynk.spawn_virtual_write(5, |sender, params| {
+    ///     let value = params.as_str().to_string();
+    ///     Box::pin(async move {
+    ///         smol::Timer::after(Duration::from_secs(10)).await;
+    ///         sender.virtual_write(6, &value)
+    ///     })
+    /// });
+    /// ```
+    pub fn spawn_virtual_write<P, F>(&mut self, pin: P, route: F)
+    where
+        P: Into<VirtualPin>,
+        F: for<'a> FnMut(BlynkSender, ParamList<'a>) -> RouteFuture<'static> + Send + 'static,
+    {
+        self.tasks
+            .write_routes
+            .insert(pin.into().number(), Box::new(route));
     }
 
-    /// Disconnects from the Blynk servers
-    ///
-    /// Calls disconnect hook
-    async fn disconnect(&mut self, msg: &str) {
-        if let Some(hook) = &mut self.handler {
-            hook.handle_disconnect().await;
-        }
-
-        self.client.disconnect();
-        self.conn_state = ConnectionState::Disconnected;
-        error!("{}", msg);
-
-        // thread::sleep(conf::RECONNECT_SLEEP);
-        info!("1s sleep start");
-        smol::Timer::after(conf::RECONNECT_SLEEP).await;
+    /// Runs `route` as a task of its own on every read request of the
+    /// virtual pin, see `spawn_virtual_write`
+    pub fn spawn_virtual_read<P, F>(&mut self, pin: P, route: F)
+    where
+        P: Into<VirtualPin>,
+        F: FnMut(BlynkSender) -> RouteFuture<'static> + Send + 'static,
+    {
+        self.tasks
+            .read_routes
+            .insert(pin.into().number(), Box::new(route));
     }
 
-    async fn authenticate(&mut self, token: &str) -> Result<()> {
-        info!("Authenticating device...");
-        self.conn_state = ConnectionState::Authentiacting;
-        self.client().login(token).await?;
+    /// Number of spawned handlers running at once, writes and reads of the
+    /// spawned pins arriving while that many run are dropped. Applies to
+    /// the handlers spawned from now on
+    pub fn set_task_limit(&mut self, limit: usize) {
+        self.tasks.permits = Arc::new(Semaphore::new(limit));
+    }
+}
 
-        let msg = self.client.read().await.unwrap();
-        if !matches!(msg.status, Some(ProtocolStatus::StatusOk)) {
-            match (msg.status.unwrap(), msg.mtype) {
-                (ProtocolStatus::StatusInvalidToken, _) => {
-                    return Err(BlynkError::InvalidAuthToken.into());
-                }
-                (_, MessageType::Redirect) => {
-                    return Err(BlynkError::Redirection.into());
+impl<S, C> Blynk<EventQueue, S, C>
+where
+    S: ConfigSource + Send + Sync + 'static,
+    C: Connection + 'static,
+{
+    /// Moves the connection to a background task that keeps running it,
+    /// the events it receives are returned as a stream
+    ///
+    /// # Example
+    /// ```no_run
+    /// use blynk_io::*;
+    /// use smol::stream::StreamExt;
+    ///
+    /// # smol::block_on(async {
+    /// let mut events = Blynk::<EventQueue>::new("BLYNK TOKEN".to_string()).events();
+    /// while let Some(event) = events.next().await {
+    ///     println!("{:?}", event);
+    /// }
+    /// # })
+    /// ```
+    pub fn events(mut self) -> Events {
+        let (tx, rx) = smol::channel::unbounded();
+        self.handler.get_or_insert_with(EventQueue::default);
+        let task = smol::spawn(async move {
+            loop {
+                self.run().await;
+                let Some(queue) = self.handler.as_mut() else {
+                    return;
+                };
+                while let Some(event) = queue.pop() {
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
                 }
-                (_, _) => panic!("Critical error"),
             }
-        }
-
-        self.conn_state = ConnectionState::Authenticated;
-        info!("Access granted");
-        Ok(())
+        });
+        Events { rx, _task: task }
     }
+}
 
-    async fn set_heartbeat(&mut self) -> Result<()> {
-        info!("Setting heartbeat");
-        self.client()
-            .heartbeat(conf::HEARTBEAT_PERIOD, 1024)
-            .await?;
+/// Stream of the events of a connection run by a background task, see
+/// `Blynk::events`. Dropping it stops the task and closes the connection
+pub struct Events {
+    rx: Receiver<BlynkEvent>,
+    _task: Task<()>,
+}
 
-        self.client.set_read_timeout(conf::SOCK_MAX_TIMEOUT);
-        let msg = self.client.read().await?;
+impl EventStream for Events {
+    type Item = BlynkEvent;
 
-        if !matches!(msg.status, Some(ProtocolStatus::StatusOk)) {
-            return Err(BlynkError::HeartbeatSet(msg.status.unwrap()).into());
-        }
-        Ok(())
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<BlynkEvent>> {
+        self.rx.poll_next(cx)
     }
+}
 
-    async fn is_server_alive(&mut self) -> bool {
-        let hbeat_ms = conf::HEARTBEAT_PERIOD.as_millis();
-        let rcv_delta = self.last_rcv_time.elapsed().as_millis();
-        let ping_delta = self.last_ping_time.elapsed().as_millis();
-        let send_delta = self.last_send_time.elapsed().as_millis();
-
-        if rcv_delta > hbeat_ms + (hbeat_ms / 2) {
-            warn!("Server not alive, will initiate disconnect");
-            return false;
-        }
-
-        if (ping_delta > hbeat_ms / 10) && (send_delta > hbeat_ms || rcv_delta > hbeat_ms) {
-            if self.client().ping().await.is_err() {
-                error!("Unable to ping");
-                return false;
-            }
-
-            self.last_ping_time = Instant::now();
-            info!("Heartbeat delta: {}ms", ping_delta);
-        }
+impl<E: Event<C>, S: ConfigSource + Sync, C: Connection> PinHandle<'_, E, S, C> {
+    /// Calls `route` on every write of the pin, see `Blynk::on_virtual_write`
+    ///
+    /// # Example
+    /// ```
+    /// use blynk_io::*;
+    ///
+    /// let mut blynk = Blynk::<DefaultHandler>::new("BLYNK TOKEN".to_string());
+    /// let mut slider = blynk.virtual_pin(pins::V5);
+    /// slider.on_write(|client, params| {
+    ///     Box::pin(async move { client.virtual_write(pins::V6, params.as_str()).await })
+    /// });
+    /// ```
+    pub fn on_write<F>(&mut self, route: F) -> &mut Self
+    where
+        F: for<'a> FnMut(&'a mut C, ParamList<'a>) -> RouteFuture<'a> + Send + 'static,
+    {
+        self.blynk.on_virtual_write(self.pin, route);
+        self
+    }
+}
 
-        true
+impl<P: Protocol> FnEvent<P> {
+    pub fn on_connect<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: for<'a> FnMut(&'a mut P) -> RouteFuture<'a> + Send + 'static,
+    {
+        self.connect = Some(Box::new(f));
+        self
     }
 
-    async fn read_response(&mut self) {
-        self.last_rcv_time = Instant::now();
-        self.client.set_read_timeout(Duration::from_millis(5));
+    pub fn on_disconnect<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: FnMut() -> RouteFuture<'static> + Send + 'static,
+    {
+        self.disconnect = Some(Box::new(f));
+        self
+    }
 
-        if let Ok(msg) = self.client.read().await {
-            if let Err(err) = self.process(msg).await {
-                error!("Problem handling req from API: {}", err);
-            }
-        }
+    /// Called with the pin number, see `Blynk::on_virtual_read` for a
+    /// single pin
+    pub fn on_vpin_read<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: for<'a> FnMut(&'a mut P, VirtualPin) -> RouteFuture<'a> + Send + 'static,
+    {
+        self.vpin_read = Some(Box::new(f));
+        self
     }
 
-    async fn process(&mut self, msg: Message) -> Result<()> {
-        info!("Message processing ASD {:?}", msg);
-        if let MessageType::Ping = msg.mtype {
-            self.client
-                .response(ProtocolStatus::StatusOk as u16, msg.id)
-                .await?;
-        }
+    /// Called with the pin number and the written values, see
+    /// `Blynk::on_virtual_write` for a single pin
+    ///
+    /// # Example
+    /// ```
+    /// use blynk_io::*;
+    ///
+    /// let mut blynk = Blynk::new("BLYNK TOKEN".to_string());
+    /// blynk.set_handler(FnEvent::new().on_vpin_write(|client, pin, params| {
+    ///     Box::pin(async move { client.virtual_write(pin.number() + 1, params.as_str()).await })
+    /// }));
+    /// ```
+    pub fn on_vpin_write<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: for<'a> FnMut(&'a mut P, VirtualPin, ParamList<'a>) -> RouteFuture<'a> + Send + 'static,
+    {
+        self.vpin_write = Some(Box::new(f));
+        self
+    }
 
-        if let Some(hook) = &mut self.handler {
-            match msg.mtype {
-                MessageType::Internal => {
-                    hook.handle_internal(&mut self.client, &msg.body[1..]).await;
-                }
-                MessageType::Hw | MessageType::Bridge => {
-                    if msg.body.len() >= 3 && msg.body.get(0).unwrap() == "vw" {
-                        let pin_num = msg.body[1].parse::<u8>().unwrap();
-                        hook.handle_vpin_write(&mut self.client, pin_num, &msg.body[2])
-                            .await;
-                    } else if msg.body.len() == 2 && msg.body.get(0).unwrap() == "vr" {
-                        let pin_num = msg.body[1].parse::<u8>().unwrap();
-                        hook.handle_vpin_read(&mut self.client, pin_num).await;
-                    }
-                }
-                _ => (),
-            }
-        }
-        Ok(())
+    pub fn on_internal<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: for<'a> FnMut(&'a mut P, ParamList<'a>) -> RouteFuture<'a> + Send + 'static,
+    {
+        self.internal = Some(Box::new(f));
+        self
     }
 }
@@ -0,0 +1,93 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use smol::io::{AsyncRead, AsyncWrite};
+
+use crate::mqtt::Bridge;
+
+/// Async MQTT connection carrying Blynk messages
+pub struct Mqtt<S> {
+    inner: S,
+    bridge: Bridge,
+    /// Packets waiting to be written to the inner stream
+    outgoing: Vec<u8>,
+    /// Size of the message being written, reported once its packets are sent
+    in_flight: Option<usize>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Mqtt<S> {
+    pub fn new(inner: S, bridge: Bridge) -> Mqtt<S> {
+        Mqtt {
+            inner,
+            bridge,
+            outgoing: Vec::new(),
+            in_flight: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.outgoing.is_empty() {
+            let size = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing))?;
+            if size == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing.drain(..size);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Mqtt<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut raw = [0; 512];
+        while !this.bridge.has_incoming() {
+            let size = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut raw))?;
+            if size == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            this.bridge.decode(&raw[..size])?;
+            // subscription is sent along with the next write
+            this.outgoing.extend(this.bridge.take_outgoing());
+        }
+        Poll::Ready(Ok(this.bridge.read_incoming(buf)))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Mqtt<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.in_flight.is_none() {
+            this.bridge.write(buf);
+            this.outgoing.extend(this.bridge.take_outgoing());
+            this.in_flight = Some(buf.len());
+        }
+        ready!(this.poll_drain(cx))?;
+        Poll::Ready(Ok(this.in_flight.take().unwrap_or_default()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,272 @@
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use smol::future::FutureExt;
+use smol::io::AsyncWriteExt;
+use smol::prelude::{AsyncRead, AsyncWrite};
+use smol::{Async, Timer};
+
+use crate::{conf, net, ConfigSource, Result};
+
+/// Connection to Blynk servers, either plain TCP or TLS wrapped
+pub enum Stream {
+    Tcp(Async<TcpStream>),
+    #[cfg(feature = "tls")]
+    Tls(Box<futures_rustls::client::TlsStream<Async<TcpStream>>>),
+    #[cfg(feature = "native-tls")]
+    NativeTls(Box<async_native_tls::TlsStream<Async<TcpStream>>>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<super::websocket::WebSocket<Stream>>),
+    #[cfg(feature = "mqtt")]
+    Mqtt(Box<super::mqtt::Mqtt<Stream>>),
+}
+
+impl Stream {
+    /// Wraps connected socket according to the `Config`, performing
+    /// TLS handshake if `tls` is enabled, WebSocket upgrade if
+    /// `websocket` path is set and speaking MQTT if `mqtt` is enabled
+    pub async fn new(sock: Async<TcpStream>, config: &impl ConfigSource) -> Result<Stream> {
+        let stream = Stream::secure(sock, config).await?;
+        let stream = Stream::upgrade(stream, config).await?;
+        if !config.mqtt() {
+            return Ok(stream);
+        }
+
+        #[cfg(feature = "mqtt")]
+        {
+            let bridge = crate::mqtt::Bridge::for_config(config);
+            Ok(Stream::Mqtt(Box::new(super::mqtt::Mqtt::new(
+                stream, bridge,
+            ))))
+        }
+
+        #[cfg(not(feature = "mqtt"))]
+        Err(io::Error::new(io::ErrorKind::Unsupported, "built without `mqtt` feature").into())
+    }
+
+    async fn upgrade(stream: Stream, config: &impl ConfigSource) -> Result<Stream> {
+        match config.websocket() {
+            None => Ok(stream),
+            #[cfg(feature = "websocket")]
+            Some(path) => {
+                let ws =
+                    super::websocket::WebSocket::connect(stream, config.server(), path).await?;
+                Ok(Stream::WebSocket(Box::new(ws)))
+            }
+            #[cfg(not(feature = "websocket"))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "built without `websocket` feature",
+            )
+            .into()),
+        }
+    }
+
+    /// rustls is preferred over `native-tls` when both features are
+    /// turned on
+    async fn secure(sock: Async<TcpStream>, config: &impl ConfigSource) -> Result<Stream> {
+        if !config.tls() {
+            return Ok(Stream::Tcp(sock));
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            let connector = futures_rustls::TlsConnector::from(crate::tls::client_config(config)?);
+            let stream = connector
+                .connect(crate::tls::server_name(config)?, sock)
+                .await
+                .map_err(crate::tls::handshake_error)?;
+            Ok(Stream::Tls(Box::new(stream)))
+        }
+
+        #[cfg(all(feature = "native-tls", not(feature = "tls")))]
+        {
+            let connector = crate::system_tls::builder(config)?;
+            let stream = async_native_tls::TlsConnector::from(connector)
+                .connect(config.server(), sock)
+                .await
+                .map_err(|err| crate::BlynkError::Tls(err.to_string()))?;
+            crate::system_tls::verify_peer(config, stream.peer_certificate().ok().flatten())?;
+            Ok(Stream::NativeTls(Box::new(stream)))
+        }
+
+        // embedded-tls backend is blocking only
+        #[cfg(not(any(feature = "tls", feature = "native-tls")))]
+        Err(crate::BlynkError::Tls("built without `tls` feature".into()))
+    }
+}
+
+impl Stream {
+    /// Returns the underlying socket
+    pub fn socket(&self) -> &Async<TcpStream> {
+        match self {
+            Stream::Tcp(sock) => sock,
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.get_ref().0,
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => tls.get_ref(),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => ws.get_ref().socket(),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => mqtt.get_ref().socket(),
+        }
+    }
+
+    /// Shuts the socket down without waiting for the TLS close notify,
+    /// so it can be used from synchronous teardown
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket().get_ref().shutdown(how)
+    }
+}
+
+impl Stream {
+    /// Connects to one of `addrs` and opens the tunnel to the server if
+    /// they are the proxy's
+    pub(crate) async fn connect<S: ConfigSource + Sync>(
+        addrs: Vec<SocketAddr>,
+        config: &S,
+    ) -> Result<Stream> {
+        let sock = net::race(addrs, conf::CONNECT_ATTEMPT_DELAY, Duration::from_secs(3)).await?;
+        let mut sock = Async::new(sock)?;
+        if let Some(proxy) = config.proxy() {
+            crate::proxy::tunnel(&mut sock, proxy, config.server(), config.port()).await?;
+        }
+        Stream::new(sock, config).await
+    }
+
+    /// Writes a part of `buf`, `None` if the socket is not ready for it
+    /// within `SOCK_TIMEOUT`
+    pub(crate) async fn try_write(&mut self, buf: &[u8]) -> Option<io::Result<usize>> {
+        async { Some(self.write(buf).await) }
+            .or(async {
+                Timer::after(conf::SOCK_TIMEOUT).await;
+                None
+            })
+            .await
+    }
+}
+
+impl From<Async<TcpStream>> for Stream {
+    fn from(sock: Async<TcpStream>) -> Stream {
+        Stream::Tcp(sock)
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(sock) => Pin::new(sock).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => Pin::new(tls).poll_read(cx, buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => Pin::new(ws).poll_read(cx, buf),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => Pin::new(mqtt).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(sock) => Pin::new(sock).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => Pin::new(tls).poll_write(cx, buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => Pin::new(ws).poll_write(cx, buf),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => Pin::new(mqtt).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(sock) => Pin::new(sock).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => Pin::new(tls).poll_flush(cx),
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => Pin::new(tls).poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => Pin::new(ws).poll_flush(cx),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => Pin::new(mqtt).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(sock) => Pin::new(sock).poll_close(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => Pin::new(tls).poll_close(cx),
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => Pin::new(tls).poll_close(cx),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => Pin::new(ws).poll_close(cx),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => Pin::new(mqtt).poll_close(cx),
+        }
+    }
+}
+
+/// Stream of a `ClientReader` or a `ClientWriter`. The lock is held only
+/// while the stream is polled, so the halves never wait for each other
+pub struct SplitStream(Arc<Mutex<Stream>>);
+
+impl SplitStream {
+    pub(crate) fn pair(stream: Stream) -> io::Result<(SplitStream, SplitStream)> {
+        let stream = Arc::new(Mutex::new(stream));
+        Ok((SplitStream(stream.clone()), SplitStream(stream)))
+    }
+
+    pub(crate) fn shutdown(&mut self) -> io::Result<()> {
+        self.lock().shutdown(Shutdown::Both)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Stream> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl AsyncRead for SplitStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.lock()).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SplitStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.lock()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.lock()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.lock()).poll_close(cx)
+    }
+}
@@ -0,0 +1,96 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use smol::io::{AsyncRead, AsyncWrite};
+
+use crate::websocket::{handshake, Framer};
+
+/// Async WebSocket connection carrying Blynk messages
+pub struct WebSocket<S> {
+    inner: S,
+    framer: Framer,
+    /// Frames waiting to be written to the inner stream
+    outgoing: Vec<u8>,
+    /// Size of the payload being written, reported once its frame is sent
+    in_flight: Option<usize>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    /// Upgrades the connection performing the HTTP handshake
+    pub async fn connect(mut inner: S, host: &str, path: &str) -> io::Result<WebSocket<S>> {
+        let framer = handshake(&mut inner, host, path).await?;
+        Ok(WebSocket {
+            inner,
+            framer,
+            outgoing: Vec::new(),
+            in_flight: None,
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.outgoing.is_empty() {
+            let size = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing))?;
+            if size == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing.drain(..size);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut raw = [0; 512];
+        while !this.framer.has_payload() && !this.framer.is_closed() {
+            let size = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut raw))?;
+            if size == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            this.framer.decode(&raw[..size])?;
+            // pongs are sent along with the next write
+            this.outgoing.extend(this.framer.take_control());
+        }
+        Poll::Ready(Ok(this.framer.read_payload(buf)))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    /// Sends the whole buffer as one binary frame
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.in_flight.is_none() {
+            let frame = this.framer.encode(buf);
+            this.outgoing.extend(frame);
+            this.in_flight = Some(buf.len());
+        }
+        ready!(this.poll_drain(cx))?;
+        Poll::Ready(Ok(this.in_flight.take().unwrap_or_default()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
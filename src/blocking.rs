@@ -1,348 +1,281 @@
+//! IO of the blocking mode and the parts of its API taking closures, the
+//! rest is generated from the sources shared with the async mode
 use log::*;
-use std::net::{TcpStream, ToSocketAddrs};
-use std::thread;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
-#[path = "./client.rs"]
-mod client;
+#[path = "./stream.rs"]
+mod stream;
 
-use super::config::Config;
-use super::message::{Message, MessageType, ProtocolStatus};
-use super::{conf, BlynkError, ConnectionState, DefaultHandler, Result};
-pub use client::{Client, Protocol};
+pub(crate) use stream::SplitStream;
+pub use stream::Stream;
 
-/// Used in order to implement handler logic for requests coming
-/// from Blynk.io servers and various transitions between connection states.
-///
-/// # Example
-/// ```
-/// use blynk_io::*;
-///
-/// struct EventsHandler;
-/// impl Event for EventsHandler {
-///     fn handle_vpin_write(&mut self, _client: &mut Client, pin_num: u8, data: &str) {
-///         println!("pin {:?} write {:?}", pin_num, data);
-///     }
-/// }
-/// ```
-#[allow(unused_variables)]
-pub trait Event: Send {
-    fn handle_connect(&mut self, client: &mut Client) {}
-    fn handle_disconnect(&mut self) {}
-    fn handle_internal(&mut self, client: &mut Client, data: &[String]) {}
-    fn handle_vpin_read(&mut self, client: &mut Client, pin_num: u8) {}
-    fn handle_vpin_write(&mut self, client: &mut Client, pin_num: u8, data: &str) {}
-}
+use crate::message::ProtocolHeader;
+use crate::{conf, proto, BlynkError, BlynkEvent, BlynkSender, ConfigSource, EventQueue, Result};
+use crate::{Blynk, Connection, Event, FnEvent, ParamList, PinHandle, Protocol, VirtualPin};
 
-impl Event for DefaultHandler {}
+pub(crate) mod prelude {
+    pub(crate) use std::io::{BufReader, Chain, Cursor, Read, Write};
+}
 
-/// Main API for interacting with Blynk.io platform. Use it in order to
-/// keep connectivity with the Blynk servers and handle the protocol activity.
-///
-/// # Example
-/// ```
-/// use blynk_io::Blynk;
-///
-/// let mut blynk = <Blynk>::new("BYNK TOKEN".to_string());
-/// loop {
-///     blynk.run();
-///     break; // remove this in your actual program
-/// }
-/// ```
+/// Socket a proxy tunnel or a WebSocket upgrade is done over
+pub(crate) trait Socket: Read + Write {}
 
-pub struct Blynk<E: Event = DefaultHandler> {
-    conn_state: ConnectionState,
-    config: Config,
+impl<T: Read + Write> Socket for T {}
 
-    client: Client,
+/// Handler of writes to a single virtual pin, see `Blynk::on_virtual_write`
+pub(crate) type WriteRoute<C> = Box<dyn FnMut(&mut C, ParamList<'_>) -> Result<()> + Send>;
+/// Handler of reads of a single virtual pin, see `Blynk::on_virtual_read`
+pub(crate) type ReadRoute<C> = Box<dyn FnMut(&mut C) -> Result<()> + Send>;
 
-    pub handler: Option<E>,
+pub(crate) type ConnectFn<P> = Box<dyn FnMut(&mut P) -> Result<()> + Send>;
+pub(crate) type DisconnectFn = Box<dyn FnMut() -> Result<()> + Send>;
+pub(crate) type PinReadFn<P> = Box<dyn FnMut(&mut P, VirtualPin) -> Result<()> + Send>;
+pub(crate) type PinWriteFn<P> =
+    Box<dyn FnMut(&mut P, VirtualPin, ParamList<'_>) -> Result<()> + Send>;
+pub(crate) type InternalFn<P> = Box<dyn FnMut(&mut P, ParamList<'_>) -> Result<()> + Send>;
 
-    last_rcv_time: Instant,
-    last_ping_time: Instant,
-    last_send_time: Instant,
+pub(crate) fn sleep(duration: Duration) {
+    thread::sleep(duration);
 }
 
-impl<E: Event> Blynk<E> {
-    /// Returns the Blynk client initalized with API token
-    ///
-    /// # Arguments
-    /// * `auth_token` - A string that holds the Blynk API token
-    pub fn new(auth_token: String) -> Blynk<E> {
-        Self {
-            conn_state: ConnectionState::Disconnected,
-            config: Config {
-                token: auth_token,
-                ..Default::default()
-            },
-
-            client: Client::default(),
-            handler: None,
-
-            last_rcv_time: Instant::now(),
-            last_ping_time: Instant::now(),
-            last_send_time: Instant::now(),
-        }
-    }
-
-    pub fn set_config(&mut self, config: Config) {
-        self.config = config;
-    }
+pub(crate) fn resolve(host: &str, port: u64) -> io::Result<Vec<SocketAddr>> {
+    let host_port = [host.to_string(), ":".to_string(), port.to_string()].join("");
+    Ok(host_port.to_socket_addrs()?.collect())
+}
 
-    /// Returns the low level Client abstraction that is implements
-    /// the protocol and is responsible for the communication
-    fn client(&mut self) -> &mut Client {
-        self.last_send_time = Instant::now();
-        &mut self.client
+/// Reads one whole message into `frame`
+pub(crate) fn read_frame<R: BufRead>(reader: &mut R, frame: &mut Vec<u8>) -> Result<()> {
+    // only waiting for the message to start may time out, nothing
+    // is consumed until then
+    if reader.fill_buf()?.is_empty() {
+        return Err(BlynkError::EmptyBuffer);
     }
+    frame.resize(ProtocolHeader::SIZE, 0);
+    read_full(reader, frame)?;
+    frame.resize(ProtocolHeader::SIZE + proto::body_len(frame), 0);
+    read_full(reader, &mut frame[ProtocolHeader::SIZE..])
+}
 
-    /// Performs event loop run that is reposnible for:
-    /// - checking the connection state
-    /// - reconnecting if connection failed
-    /// - reading any pending responses from blynk servers
-    /// - executing events hooks if those are provided
-    pub fn run(&mut self) {
-        if !matches!(self.conn_state, ConnectionState::Authenticated) {
-            error!("Not connected, trying reconnect");
-            if let Err(err) = self.connect() {
-                error!("Problem while connecting: {}", err);
-                self.disconnect("Problem while connecting");
-                return;
-            }
-        }
-
-        self.read_response();
-        if !self.is_server_alive() {
-            info!("Blynk is offline for some reson :(");
-            self.disconnect("Blynk server is offline");
+/// Reads exactly `buf.len()` bytes, waiting up to `conf::SOCK_MAX_TIMEOUT`
+/// for the rest of a message split across several TCP segments
+fn read_full<R: Read>(reader: &mut R, mut buf: &mut [u8]) -> Result<()> {
+    let deadline = Instant::now() + conf::SOCK_MAX_TIMEOUT;
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(size) => buf = &mut std::mem::take(&mut buf)[size..],
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err)
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                    && Instant::now() < deadline => {}
+            Err(err) => return Err(err.into()),
         }
     }
+    Ok(())
+}
 
-    /// Sets the events handler for incoming events from the Blynk platform
+impl<E: Event<C>, S: ConfigSource + Sync, C: Connection> Blynk<E, S, C> {
+    /// Calls `route` with the client and the written values on every write
+    /// of the virtual pin instead of the handler, so simple projects don't
+    /// need an `Event` implementation at all
+    ///
+    /// # Example
+    /// ```
+    /// use blynk_io::*;
     ///
-    /// See `Event` trait documentation for example implementation
-    pub fn set_handler(&mut self, hook: E) {
-        self.handler = Some(hook);
+    /// let mut blynk = Blynk::<DefaultHandler>::new("BLYNK TOKEN".to_string());
+    /// blynk.on_virtual_write(5, |client, params| client.virtual_write(6, params.as_str()));
+    /// ```
+    pub fn on_virtual_write<P, F>(&mut self, pin: P, route: F)
+    where
+        P: Into<VirtualPin>,
+        F: FnMut(&mut C, ParamList<'_>) -> Result<()> + Send + 'static,
+    {
+        self.write_routes
+            .insert(pin.into().number(), Box::new(route));
     }
 
-    /// Gets a mutable referance to handler if it's defined
-    pub fn handler(&mut self) -> Option<&mut E> {
-        match &self.handler {
-            Some(_) => self.handler.as_mut(),
-            None => None,
-        }
+    /// Calls `route` on every read request of the virtual pin instead of
+    /// the handler
+    pub fn on_virtual_read<P, F>(&mut self, pin: P, route: F)
+    where
+        P: Into<VirtualPin>,
+        F: FnMut(&mut C) -> Result<()> + Send + 'static,
+    {
+        self.read_routes
+            .insert(pin.into().number(), Box::new(route));
     }
+}
 
-    /// Connects to Blynk servers
+impl<S, C> Blynk<EventQueue, S, C>
+where
+    S: ConfigSource + Send + Sync + 'static,
+    C: Connection + 'static,
+{
+    /// Moves the connection to a thread of its own that keeps it alive,
+    /// answering pings and sending heartbeats, while the application
+    /// takes the events with `BlynkThread::poll` and writes through its
+    /// `sender`. Code blocking the application thread for a while no
+    /// longer gets the device disconnected
     ///
-    /// Performs authentication and sets up heart beat with the servers
+    /// # Example
+    /// ```no_run
+    /// use blynk_io::*;
     ///
-    /// Calls hook in event of succseful handshake
-    fn connect(&mut self) -> Result<()> {
-        self.conn_state = ConnectionState::Connecting;
-
-        let host_port = vec![
-            self.config.server.clone(),
-            ":".to_string(),
-            self.config.port.to_string(),
-        ]
-        .join("");
-        let addrs = host_port.to_socket_addrs()?.collect::<Vec<_>>();
-        let addr = addrs.first().ok_or(BlynkError::Dns)?;
-
-        let stream = TcpStream::connect_timeout(addr, conf::SOCK_TIMEOUT)?;
-        self.client.set_stream(stream);
-
-        info!("Successfully connected to blynk server");
-
-        self.authenticate(&self.config.token.clone())?;
-        self.set_heartbeat()?;
-
-        self.last_rcv_time = Instant::now();
-
-        if let Some(hook) = &mut self.handler {
-            hook.handle_connect(&mut self.client);
-        }
-        Ok(())
-    }
-
-    /// Disconnects from the Blynk servers
-    ///
-    /// Calls disconnect hook
-    fn disconnect(&mut self, msg: &str) {
-        if let Some(hook) = &mut self.handler {
-            hook.handle_disconnect();
-        }
-
-        self.client.disconnect();
-        self.conn_state = ConnectionState::Disconnected;
-        error!("{}", msg);
-
-        thread::sleep(conf::RECONNECT_SLEEP);
-    }
-
-    fn authenticate(&mut self, token: &str) -> Result<()> {
-        info!("Authenticating device...");
-        self.conn_state = ConnectionState::Authentiacting;
-        self.client().login(token)?;
-
-        let msg = self.client.read().unwrap();
-        if !matches!(msg.status, Some(ProtocolStatus::StatusOk)) {
-            match (msg.status.unwrap(), msg.mtype) {
-                (ProtocolStatus::StatusInvalidToken, _) => {
-                    return Err(BlynkError::InvalidAuthToken);
-                }
-                (_, MessageType::Redirect) => {
-                    return Err(BlynkError::Redirection);
+    /// let blynk = Blynk::<EventQueue>::new("BLYNK TOKEN".to_string()).spawn();
+    /// loop {
+    ///     if let Some(BlynkEvent::VPinRead { pin }) = blynk.poll() {
+    ///         blynk.sender().virtual_write(pin, "1").unwrap();
+    ///     }
+    ///     // slow work doesn't hold the connection back
+    /// }
+    /// ```
+    pub fn spawn(mut self) -> BlynkThread {
+        let (tx, rx) = mpsc::channel();
+        let sender = self.sender();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        self.handler.get_or_insert_with(EventQueue::default);
+        let thread = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                self.run();
+                let Some(queue) = self.handler.as_mut() else {
+                    return;
+                };
+                while let Some(event) = queue.pop() {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
                 }
-                (_, _) => panic!("Critical error"),
             }
+        });
+        BlynkThread {
+            events: rx,
+            sender,
+            stop,
+            thread: Some(thread),
         }
-
-        self.conn_state = ConnectionState::Authenticated;
-        info!("Access granted");
-        Ok(())
     }
+}
 
-    fn set_heartbeat(&mut self) -> Result<()> {
-        info!("Setting heartbeat");
-        self.client().heartbeat(conf::HEARTBEAT_PERIOD, 1024)?;
-
-        self.client.set_read_timeout(conf::SOCK_MAX_TIMEOUT);
-        let msg = self.client.read()?;
+/// Connection run by a background thread, see `Blynk::spawn`. Dropping it
+/// stops the thread, after its current `run`, and closes the connection
+pub struct BlynkThread {
+    events: Receiver<BlynkEvent>,
+    sender: BlynkSender,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
 
-        if !matches!(msg.status, Some(ProtocolStatus::StatusOk)) {
-            return Err(BlynkError::HeartbeatSet(msg.status.unwrap()));
-        }
-        Ok(())
+impl BlynkThread {
+    /// Oldest event not taken yet, doesn't wait for one
+    pub fn poll(&self) -> Option<BlynkEvent> {
+        self.events.try_recv().ok()
     }
 
-    #[allow(clippy::wrong_self_convention)]
-    fn is_server_alive(&mut self) -> bool {
-        let hbeat_ms = conf::HEARTBEAT_PERIOD.as_millis();
-        let rcv_delta = self.last_rcv_time.elapsed().as_millis();
-        let ping_delta = self.last_ping_time.elapsed().as_millis();
-        let send_delta = self.last_send_time.elapsed().as_millis();
-
-        if rcv_delta > hbeat_ms + (hbeat_ms / 2) {
-            warn!("Server not alive, will initiate disconnect");
-            return false;
-        }
-
-        if (ping_delta > hbeat_ms / 10) && (send_delta > hbeat_ms || rcv_delta > hbeat_ms) {
-            if self.client().ping().is_err() {
-                error!("Unable to ping");
-                return false;
-            }
-
-            self.last_ping_time = Instant::now();
-            info!("Heartbeat delta: {}ms", ping_delta);
-        }
-
-        true
+    /// Waits at most `timeout` for the next event
+    pub fn poll_timeout(&self, timeout: Duration) -> Option<BlynkEvent> {
+        self.events.recv_timeout(timeout).ok()
     }
 
-    fn read_response(&mut self) {
-        self.last_rcv_time = Instant::now();
-        self.client.set_read_timeout(Duration::from_millis(5));
-
-        if let Ok(msg) = self.client.read() {
-            if let Err(err) = self.process(msg) {
-                error!("Problem handling req from API: {}", err);
-            }
-        }
+    /// Handle queueing writes for the thread, it can be cloned and passed
+    /// on to other threads
+    pub fn sender(&self) -> &BlynkSender {
+        &self.sender
     }
+}
 
-    fn process(&mut self, msg: Message) -> Result<()> {
-        if let MessageType::Ping = msg.mtype {
-            self.client
-                .response(ProtocolStatus::StatusOk as u16, msg.id)?;
-        }
-
-        if let Some(hook) = &mut self.handler {
-            match msg.mtype {
-                MessageType::Internal => {
-                    hook.handle_internal(&mut self.client, &msg.body[1..]);
-                }
-                MessageType::Hw | MessageType::Bridge => {
-                    if msg.body.len() >= 3 && msg.body.get(0).unwrap() == "vw" {
-                        let pin_num = msg.body[1].parse::<u8>().unwrap();
-                        hook.handle_vpin_write(&mut self.client, pin_num, &msg.body[2]);
-                    } else if msg.body.len() == 2 && msg.body.get(0).unwrap() == "vr" {
-                        let pin_num = msg.body[1].parse::<u8>().unwrap();
-                        hook.handle_vpin_read(&mut self.client, pin_num);
-                    }
-                }
-                _ => (),
+impl Drop for BlynkThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Connection thread panicked");
             }
         }
-        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[derive(Default)]
-    struct EventsHandler {
-        pin_num: u8,
-        data: String,
+impl<E: Event<C>, S: ConfigSource + Sync, C: Connection> PinHandle<'_, E, S, C> {
+    /// Calls `route` on every write of the pin, see `Blynk::on_virtual_write`
+    ///
+    /// # Example
+    /// ```
+    /// use blynk_io::*;
+    ///
+    /// let mut blynk = Blynk::<DefaultHandler>::new("BLYNK TOKEN".to_string());
+    /// let mut slider = blynk.virtual_pin(pins::V5);
+    /// slider.on_write(|client, params| client.virtual_write(pins::V6, params.as_str()));
+    /// ```
+    pub fn on_write<F>(&mut self, route: F) -> &mut Self
+    where
+        F: FnMut(&mut C, ParamList<'_>) -> Result<()> + Send + 'static,
+    {
+        self.blynk.on_virtual_write(self.pin, route);
+        self
     }
+}
 
-    impl Event for EventsHandler {
-        fn handle_vpin_read(&mut self, _client: &mut Client, pin_num: u8) {
-            self.pin_num = pin_num
-        }
-
-        fn handle_vpin_write(&mut self, _client: &mut Client, pin_num: u8, data: &str) {
-            self.pin_num = pin_num;
-            self.data = data.to_string();
-        }
-
-        fn handle_internal(&mut self, _client: &mut Client, data: &[String]) {
-            self.data = data.join(" ");
-        }
+impl<P: Protocol> FnEvent<P> {
+    pub fn on_connect<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: FnMut(&mut P) -> Result<()> + Send + 'static,
+    {
+        self.connect = Some(Box::new(f));
+        self
     }
 
-    #[test]
-    fn calls_vpinread_handler_with_params() {
-        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vr", "22"]);
-        let mut blynk = Blynk::new("abc".to_string());
-
-        let handler: EventsHandler = Default::default();
-        blynk.set_handler(handler);
-        blynk.process(msg).unwrap();
-
-        assert_eq!(22, blynk.handler().unwrap().pin_num);
+    pub fn on_disconnect<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: FnMut() -> Result<()> + Send + 'static,
+    {
+        self.disconnect = Some(Box::new(f));
+        self
     }
-    #[test]
-    fn calls_vpinwrite_handler_with_params() {
-        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "42", "my-val"]);
-        let mut blynk = Blynk::new("abc".to_string());
 
-        let handler: EventsHandler = Default::default();
-        blynk.set_handler(handler);
-        blynk.process(msg).unwrap();
-
-        assert_eq!(42, blynk.handler().unwrap().pin_num);
-        assert_eq!("my-val", blynk.handler().unwrap().data);
+    /// Called with the pin number, see `Blynk::on_virtual_read` for a
+    /// single pin
+    pub fn on_vpin_read<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: FnMut(&mut P, VirtualPin) -> Result<()> + Send + 'static,
+    {
+        self.vpin_read = Some(Box::new(f));
+        self
     }
-    #[test]
-    fn calls_internal_handler_with_params() {
-        let msg = Message::new(
-            MessageType::Internal,
-            1,
-            None,
-            None,
-            vec!["_internal", "hello", "world"],
-        );
-        let mut blynk = Blynk::new("abc".to_string());
 
-        let handler: EventsHandler = Default::default();
-        blynk.set_handler(handler);
-        blynk.process(msg).unwrap();
+    /// Called with the pin number and the written values, see
+    /// `Blynk::on_virtual_write` for a single pin
+    ///
+    /// # Example
+    /// ```
+    /// use blynk_io::*;
+    ///
+    /// let mut blynk = Blynk::new("BLYNK TOKEN".to_string());
+    /// blynk.set_handler(
+    ///     FnEvent::new()
+    ///         .on_connect(|client| client.sync_all())
+    ///         .on_vpin_write(|client, pin, params| client.virtual_write(pin.number() + 1, params.as_str())),
+    /// );
+    /// ```
+    pub fn on_vpin_write<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: FnMut(&mut P, VirtualPin, ParamList<'_>) -> Result<()> + Send + 'static,
+    {
+        self.vpin_write = Some(Box::new(f));
+        self
+    }
 
-        assert_eq!("hello world", blynk.handler().unwrap().data);
+    pub fn on_internal<F>(mut self, f: F) -> FnEvent<P>
+    where
+        F: FnMut(&mut P, ParamList<'_>) -> Result<()> + Send + 'static,
+    {
+        self.internal = Some(Box::new(f));
+        self
     }
 }
@@ -0,0 +1,2011 @@
+//! Connection loop of the blocking and the async API, see `Blynk`
+
+use log::*;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::client::{Client, Connection};
+use crate::handler::{call_hook, Event};
+use crate::message::{MessageRef, ProtocolStatus};
+use crate::net;
+use crate::proto::{self, Liveness};
+use crate::rt;
+use crate::sender::Commands;
+use crate::session::{Dispatch, Session};
+use crate::sync_client::SyncClient;
+use crate::{conf, maybe_async};
+use crate::{
+    BlynkError, BlynkEvent, BlynkSender, Config, ConfigSource, DefaultHandler, EventQueue,
+    IntoBlynkValue, LogDrain, Middleware, PanicReporter, ParamList, PinHistory, Result, TimeZone,
+    VirtualPin,
+};
+
+/// Source of time for the connection liveness and reconnect logic.
+///
+/// Defaults to `SystemClock`, replace it to control time in tests.
+#[maybe_async]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    async fn sleep(&self, duration: Duration);
+}
+
+/// Wall-clock time backed by `Instant`, sleeping blocks the thread in
+/// the blocking mode
+pub struct SystemClock;
+
+#[maybe_async]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        rt::sleep(duration).await;
+    }
+}
+
+/// Turns the server (or proxy) name into addresses to connect to.
+///
+/// Defaults to `SystemResolver`, replace it where `ToSocketAddrs` is not
+/// reliable, e.g. with the esp-idf resolver or a static host table:
+///
+#[cfg_attr(not(feature = "async"), doc = "```")]
+#[cfg_attr(feature = "async", doc = "```ignore")]
+/// use blynk_io::Resolver;
+/// use std::io;
+/// use std::net::SocketAddr;
+///
+/// struct Hosts;
+/// impl Resolver for Hosts {
+///     fn resolve(&self, host: &str, port: u64) -> io::Result<Vec<SocketAddr>> {
+///         match host {
+///             "blynk.cloud" => Ok(vec![SocketAddr::from(([10, 0, 0, 2], port as u16))]),
+///             _ => Err(io::ErrorKind::NotFound.into()),
+///         }
+///     }
+/// }
+/// ```
+#[maybe_async]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, host: &str, port: u64) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves names with `ToSocketAddrs`, on the blocking thread pool in
+/// the async mode
+pub struct SystemResolver;
+
+#[maybe_async]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u64) -> io::Result<Vec<SocketAddr>> {
+        rt::resolve(host, port).await
+    }
+}
+
+/// Main API for interacting with Blynk.io platform. Use it in order to
+/// keep connectivity with the Blynk servers and handle the protocol activity.
+///
+/// The network layer is the `Client` unless another `Connection` is passed
+/// to `with_client`.
+///
+/// # Example
+#[cfg_attr(not(feature = "async"), doc = "```")]
+#[cfg_attr(feature = "async", doc = "```ignore")]
+/// use blynk_io::Blynk;
+///
+/// let mut blynk = <Blynk>::new("BYNK TOKEN".to_string());
+/// loop {
+///     blynk.run();
+///     break; // remove this in your actual program
+/// }
+/// ```
+pub struct Blynk<E = DefaultHandler, S = Config, C = Client>
+where
+    E: Event<C>,
+    S: ConfigSource,
+    C: Connection,
+{
+    session: Session,
+    config: S,
+
+    client: C,
+
+    pub handler: Option<E>,
+
+    clock: Box<dyn Clock>,
+    resolver: Box<dyn Resolver>,
+    dns_cache: net::DnsCache,
+    /// Buffer the received messages are read into and parsed in place
+    rx: Vec<u8>,
+    log_drain: Option<LogDrain>,
+    panic_reporter: Option<PanicReporter>,
+    /// Commands queued by the `BlynkSender` handles
+    commands: Commands,
+    /// Queue of the `SyncClient` handles, created by the first call
+    sync_client: Option<SyncClient>,
+    pub(crate) write_routes: BTreeMap<u8, rt::WriteRoute<C>>,
+    pub(crate) read_routes: BTreeMap<u8, rt::ReadRoute<C>>,
+    /// Pin handlers run as tasks of their own
+    #[cfg(feature = "async")]
+    pub(crate) tasks: rt::Tasks,
+}
+
+impl<E: Event> Blynk<E> {
+    /// Returns the Blynk client initalized with API token
+    ///
+    /// # Arguments
+    /// * `auth_token` - A string that holds the Blynk API token
+    pub fn new(auth_token: String) -> Blynk<E> {
+        Blynk::with_config(Config {
+            token: auth_token,
+            ..Default::default()
+        })
+    }
+}
+
+#[maybe_async]
+impl<E: Event, S: ConfigSource + Sync> Blynk<E, S> {
+    /// Returns the Blynk client using provided configuration, which can
+    /// be also fixed at compile time (see `StaticConfig`)
+    pub fn with_config(config: S) -> Blynk<E, S> {
+        Blynk::with_client(config, Client::default())
+    }
+
+    /// Fetches the value the server keeps for the virtual pin, `None` if
+    /// it doesn't answer within `conf::SOCK_MAX_TIMEOUT`. Messages received
+    /// in the meantime are handled with the next `run`
+    pub async fn sync_virtual<P: Into<VirtualPin>>(&mut self, pin: P) -> Result<Option<String>> {
+        let pin = pin.into();
+        let mut values = self
+            .client
+            .read_virtual_many([pin], conf::SOCK_MAX_TIMEOUT)
+            .await?;
+        Ok(values.remove(&pin))
+    }
+
+    /// Number of messages waiting to be sent to the Blynk servers
+    pub fn queue_depth(&self) -> usize {
+        self.client.queue_depth()
+    }
+
+    /// Registers a callback fired every time the outgoing queue grows
+    /// to `mark` messages (see `Client::set_high_water_mark`)
+    pub fn set_high_water_mark<F>(&mut self, mark: usize, callback: F)
+    where
+        F: FnMut(usize) + Send + 'static,
+    {
+        self.client.set_high_water_mark(mark, callback);
+    }
+
+    /// Passes the messages sent and received through `middleware`, see
+    /// `Client::add_middleware`
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.client.add_middleware(middleware);
+    }
+
+    /// Keeps recent values written to the pins tracked by `history`,
+    /// see `PinHistory`
+    pub fn set_pin_history(&mut self, history: PinHistory) {
+        self.client.set_history(history);
+    }
+}
+
+#[maybe_async]
+impl<E: Event<C>, S: ConfigSource + Sync, C: Connection> Blynk<E, S, C> {
+    /// Returns the Blynk client talking to the server through `client`,
+    /// e.g. a test double or a transport the crate doesn't support
+    pub fn with_client(config: S, client: C) -> Blynk<E, S, C> {
+        Self {
+            session: Session::new(Instant::now()),
+            config,
+
+            client,
+            handler: None,
+
+            clock: Box::new(SystemClock),
+            resolver: Box::new(SystemResolver),
+            dns_cache: net::DnsCache::default(),
+            rx: Vec::new(),
+            log_drain: None,
+            panic_reporter: None,
+            commands: Commands::default(),
+            sync_client: None,
+            write_routes: BTreeMap::new(),
+            read_routes: BTreeMap::new(),
+            #[cfg(feature = "async")]
+            tasks: rt::Tasks::default(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: S) {
+        self.config = config;
+        self.dns_cache.clear();
+    }
+
+    /// Replaces the source of time used for heartbeats and reconnects
+    pub fn set_clock<K: Clock + 'static>(&mut self, clock: K) {
+        self.session.restart(clock.now());
+        self.clock = Box::new(clock);
+    }
+
+    /// Replaces the resolver used to look up the server address
+    pub fn set_resolver<R: Resolver + 'static>(&mut self, resolver: R) {
+        self.resolver = Box::new(resolver);
+        self.dns_cache.clear();
+    }
+
+    /// Returns the low level Client abstraction that is implements
+    /// the protocol and is responsible for the communication
+    pub fn client(&mut self) -> &mut C {
+        self.session.sent(self.clock.now());
+        &mut self.client
+    }
+
+    /// Performs event loop run that is reposnible for:
+    /// - checking the connection state
+    /// - reconnecting if connection failed
+    /// - reading any pending responses from blynk servers
+    /// - executing events hooks if those are provided
+    pub async fn run(&mut self) {
+        if !self.session.is_authenticated() {
+            error!("Not connected, trying reconnect");
+            if let Err(err) = self.connect().await {
+                error!("Problem while connecting: {}", err);
+                self.disconnect("Problem while connecting").await;
+                return;
+            }
+        }
+
+        if let Err(err) = self.client.flush().await {
+            error!("Problem flushing outgoing queue: {}", err);
+        }
+
+        let now = self.clock.now();
+        if let Err(err) = self.client.retransmit(now, self.config.heartbeat()).await {
+            error!("Messages not acknowledged: {}", err);
+            self.disconnect("Messages not acknowledged").await;
+            return;
+        }
+        if let Err(err) = self.client.release_throttled().await {
+            error!("Problem sending held back notifications: {}", err);
+        }
+        if let Some(drain) = &self.log_drain {
+            if let Err(err) = drain.drain(&mut self.client).await {
+                warn!("Problem writing logs to terminal: {}", err);
+            }
+        }
+        if let Some(reporter) = &self.panic_reporter {
+            if let Err(err) = reporter.report(&mut self.client).await {
+                warn!("Problem reporting panic: {}", err);
+            }
+        }
+        if let Err(err) = self.commands.drain(&mut self.client).await {
+            warn!("Problem sending queued commands: {}", err);
+        }
+        if let Some(shared) = &self.sync_client {
+            if let Err(err) = shared.drain(&mut self.client).await {
+                warn!("Problem sending messages of other threads: {}", err);
+            }
+        }
+
+        self.read_response().await;
+        self.deliver_settled().await;
+        if self.session.take_reconnect() {
+            self.disconnect("Reconnecting after handler failure").await;
+            return;
+        }
+        if !self.is_server_alive().await {
+            info!("Blynk is offline for some reson :(");
+            self.disconnect("Blynk server is offline").await;
+        }
+    }
+
+    /// Passes writes of debounced pins that settled to the handler
+    async fn deliver_settled(&mut self) {
+        for (pin, values) in self.session.settled(self.clock.now()) {
+            let values: Vec<&str> = values.iter().map(String::as_str).collect();
+            let handled = self.write_pin(pin, &values).await;
+            self.handler_failed(handled).await;
+        }
+    }
+
+    /// Passes the write to the route of the pin, or to the handler
+    async fn write_pin(&mut self, pin: u8, values: &[&str]) -> Result<()> {
+        if let Some(route) = self.write_routes.get_mut(&pin) {
+            return route(&mut self.client, ParamList::new(values)).await;
+        }
+        #[cfg(feature = "async")]
+        if self.tasks.spawn_write(pin, &self.commands, values) {
+            return Ok(());
+        }
+        match &mut self.handler {
+            Some(hook) => {
+                hook.handle_vpin_write(&mut self.client, pin.into(), ParamList::new(values))
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn pin_history(&self) -> Option<&PinHistory> {
+        self.client.history()
+    }
+
+    /// Writes the logs collected by `TerminalLogger` on every `run`
+    pub fn set_log_drain(&mut self, drain: LogDrain) {
+        self.log_drain = Some(drain);
+    }
+
+    /// Sends panic reports collected by the reporter once connected, see
+    /// `PanicReporter::install`
+    pub fn set_panic_reporter(&mut self, reporter: PanicReporter) {
+        self.panic_reporter = Some(reporter);
+    }
+
+    /// Returns a handle queueing writes for the next `run`, for threads
+    /// or tasks that can't borrow the `Blynk`
+    pub fn sender(&self) -> BlynkSender {
+        self.commands.sender()
+    }
+
+    /// Returns a writer shared with other threads, its messages are sent
+    /// by `run` (see `SyncClient`). All the handles share one queue
+    pub fn sync_client(&mut self) -> SyncClient {
+        self.sync_client
+            .get_or_insert_with(|| SyncClient::new(conf::SYNC_CLIENT_CAPACITY))
+            .clone()
+    }
+
+    /// Collapses writes of the pin arriving in quick succession, e.g. while
+    /// a slider is dragged: only the last one is passed to the handler,
+    /// once no other write arrived for `quiet`
+    pub fn debounce<P: Into<VirtualPin>>(&mut self, pin: P, quiet: Duration) {
+        self.session.debounce(pin.into().number(), quiet);
+    }
+
+    /// Handle bound to the virtual pin, so code dealing with a single
+    /// widget doesn't pass its number around
+    ///
+    /// See `PinHandle::on_write` for an example
+    pub fn virtual_pin<P: Into<VirtualPin>>(&mut self, pin: P) -> PinHandle<'_, E, S, C> {
+        PinHandle {
+            blynk: self,
+            pin: pin.into(),
+        }
+    }
+
+    /// Marks the virtual pin as stateful: its value is synced after every
+    /// (re)connect and writes repeating the last value it received don't
+    /// reach the handler, so e.g. a relay isn't switched again
+    pub fn track_pin<P: Into<VirtualPin>>(&mut self, pin: P) {
+        self.session.track_pin(pin.into().number());
+    }
+
+    /// Time zone received so far, see `Protocol::request_timezone`
+    pub fn timezone(&self) -> &TimeZone {
+        self.session.timezone()
+    }
+
+    /// Sets the events handler for incoming events from the Blynk platform
+    ///
+    /// See `Event` trait documentation for example implementation
+    pub fn set_handler(&mut self, hook: E) {
+        self.handler = Some(hook);
+    }
+
+    /// Gets a mutable referance to handler if it's defined
+    pub fn handler(&mut self) -> Option<&mut E> {
+        match &self.handler {
+            Some(_) => self.handler.as_mut(),
+            None => None,
+        }
+    }
+
+    /// Connects to Blynk servers, following redirects to other servers
+    ///
+    /// Performs authentication and sets up heart beat with the servers
+    ///
+    /// Calls hook in event of succseful handshake
+    async fn connect(&mut self) -> Result<()> {
+        let mut redirects = 0;
+        loop {
+            match self.open().await {
+                Err(BlynkError::Redirection) => {
+                    if self
+                        .session
+                        .follow_redirect(redirects, &mut self.config)
+                        .is_none()
+                    {
+                        return Err(BlynkError::Redirection);
+                    }
+                    self.client.disconnect();
+                    self.dns_cache.clear();
+                    redirects += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Opens a single connection to the configured server
+    async fn open(&mut self) -> Result<()> {
+        self.session.connecting();
+
+        let (server, port) = match self.config.proxy() {
+            Some(proxy) => (proxy.server.as_str(), proxy.port),
+            None => (self.config.server(), self.config.port()),
+        };
+        let now = self.clock.now();
+        let addrs = match self.dns_cache.get(now) {
+            Some(addrs) => addrs,
+            None => {
+                let addrs = self.resolver.resolve(server, port).await?;
+                self.dns_cache.store(addrs.clone(), now);
+                addrs
+            }
+        };
+
+        self.client
+            .open(addrs, &self.config)
+            .await
+            .inspect_err(|_| self.dns_cache.failed())?;
+        self.dns_cache.connected();
+        self.client.set_handshake(true);
+
+        info!("Successfully connected to blynk server");
+
+        let token = self.config.token().to_string();
+        self.authenticate(&token).await?;
+        self.set_heartbeat().await?;
+        self.client.set_handshake(false);
+        let tracked = self.session.connected(self.clock.now());
+        if self.config.sync_on_connect() {
+            self.client.sync_all().await?;
+        }
+        if !tracked.is_empty() {
+            self.client.virtual_sync(tracked).await?;
+        }
+
+        if let Some(hook) = &mut self.handler {
+            let handled = hook.handle_connect(&mut self.client).await;
+            self.handler_failed(handled).await;
+        }
+        Ok(())
+    }
+
+    /// Disconnects from the Blynk servers
+    ///
+    /// Calls disconnect hook
+    async fn disconnect(&mut self, msg: &str) {
+        if let Some(hook) = &mut self.handler {
+            if let Err(err) = hook.handle_disconnect().await {
+                error!("Handler failed: {}", err);
+            }
+        }
+
+        self.client.disconnect();
+        self.session.disconnected(msg);
+
+        self.clock.sleep(conf::RECONNECT_SLEEP).await;
+    }
+
+    async fn authenticate(&mut self, token: &str) -> Result<()> {
+        self.session.authenticating();
+        self.client().login(token).await?;
+
+        let msg = self.client.read().await?;
+        self.client.acknowledge(msg.id);
+        self.session.logged_in(&msg, self.config.port())
+    }
+
+    async fn set_heartbeat(&mut self) -> Result<()> {
+        info!("Setting heartbeat");
+        let device_id = self.config.device_id().map(String::from);
+        let firmware = self.config.firmware().cloned();
+        let (heartbeat, rcv_buffer) = (self.config.heartbeat(), self.config.rcv_buffer());
+        self.client()
+            .heartbeat_with_info(
+                heartbeat,
+                rcv_buffer,
+                device_id.as_deref(),
+                firmware.as_ref(),
+            )
+            .await?;
+
+        self.client.set_read_timeout(conf::SOCK_MAX_TIMEOUT);
+        let msg = self.client.read().await?;
+        self.client.acknowledge(msg.id);
+        proto::check_heartbeat(&msg)
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    async fn is_server_alive(&mut self) -> bool {
+        match self
+            .session
+            .liveness(self.clock.now(), self.config.heartbeat())
+        {
+            Liveness::Dead => false,
+            Liveness::Ping => {
+                if self.client.ping().await.is_err() {
+                    error!("Unable to ping");
+                    return false;
+                }
+                self.session.pinged(self.clock.now());
+                true
+            }
+            Liveness::Alive => true,
+        }
+    }
+
+    async fn read_response(&mut self) {
+        self.client.set_read_timeout(Duration::from_millis(5));
+
+        // reused between runs, messages are dispatched without copying
+        let mut frame = std::mem::take(&mut self.rx);
+        loop {
+            // pending messages were received while waiting for responses
+            let res = match self.client.take_pending() {
+                Some(msg) => {
+                    self.session.received(self.clock.now());
+                    self.process(msg.to_ref()).await
+                }
+                None => match self.client.read_frame(&mut frame).await {
+                    Ok(()) => {
+                        self.session.received(self.clock.now());
+                        match MessageRef::parse(&frame) {
+                            Ok(msg) => self.process(msg).await,
+                            Err(err) => Err(err),
+                        }
+                    }
+                    Err(_) => break,
+                },
+            };
+            if let Err(err) = res {
+                error!("Problem handling req from API: {}", err);
+            }
+            // messages received together are all handled in this run
+            if !self.client.has_buffered_message() {
+                break;
+            }
+        }
+        self.rx = frame;
+    }
+
+    async fn process(&mut self, msg: MessageRef<'_>) -> Result<()> {
+        if self.client.has_middleware() {
+            return match self.client.intercept(msg.into_owned()) {
+                Some(msg) => self.dispatch(msg.to_ref()).await,
+                None => Ok(()),
+            };
+        }
+        self.dispatch(msg).await
+    }
+
+    /// Passes the received message to the handler
+    async fn dispatch(&mut self, msg: MessageRef<'_>) -> Result<()> {
+        let now = self.clock.now();
+        let handled = match self.session.dispatch(&msg, &mut self.client, now) {
+            Dispatch::Done => Ok(()),
+            Dispatch::Pong(id) => {
+                return self
+                    .client
+                    .response(u16::from(ProtocolStatus::StatusOk), id)
+                    .await;
+            }
+            Dispatch::Reply(reply) => {
+                return self
+                    .client
+                    .internal(reply.iter().map(String::as_str).collect())
+                    .await;
+            }
+            Dispatch::Write(pin_num, values) => self.write_pin(pin_num, values).await,
+            Dispatch::Read(pin_num) => self.read_pin(pin_num).await,
+            Dispatch::Hooks(hooks) => {
+                let mut handled = Ok(());
+                if let Some(handler) = &mut self.handler {
+                    for hook in hooks {
+                        let timezone = self.session.timezone();
+                        let res = call_hook(handler, &mut self.client, timezone, hook).await;
+                        handled = handled.and(res);
+                    }
+                }
+                handled
+            }
+        };
+        self.handler_failed(handled).await;
+        Ok(())
+    }
+
+    /// Passes the read request to the route of the pin, or to the handler
+    async fn read_pin(&mut self, pin: u8) -> Result<()> {
+        if let Some(route) = self.read_routes.get_mut(&pin) {
+            return route(&mut self.client).await;
+        }
+        #[cfg(feature = "async")]
+        if self.tasks.spawn_read(pin, &self.commands) {
+            return Ok(());
+        }
+        match &mut self.handler {
+            Some(hook) => hook.handle_vpin_read(&mut self.client, pin.into()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Applies `HandlerErrorPolicy` to the result of a handler hook
+    async fn handler_failed(&mut self, handled: Result<()>) {
+        let Err(err) = handled else {
+            return;
+        };
+        let policy = self.config.handler_error_policy();
+        if let Some(code) = self.session.handler_failed(&err, policy) {
+            if let Err(err) = self.client.log_event(&code, &err.to_string()).await {
+                warn!("Problem logging handler failure: {}", err);
+            }
+        }
+    }
+}
+
+#[maybe_async]
+impl<S: ConfigSource + Sync, C: Connection> Blynk<EventQueue, S, C> {
+    /// Returns the next event, running the event loop once if none is
+    /// waiting, for applications that would rather poll than implement
+    /// `Event`
+    ///
+    /// # Example
+    #[cfg_attr(not(feature = "async"), doc = "```no_run")]
+    #[cfg_attr(feature = "async", doc = "```ignore")]
+    /// use blynk_io::*;
+    ///
+    /// let mut blynk = Blynk::<EventQueue>::new("BLYNK TOKEN".to_string());
+    /// loop {
+    ///     match blynk.poll() {
+    ///         Some(BlynkEvent::VPinRead { pin }) => blynk.client().virtual_write(pin, "1").unwrap(),
+    ///         Some(event) => println!("{:?}", event),
+    ///         None => (),
+    ///     }
+    /// }
+    /// ```
+    pub async fn poll(&mut self) -> Option<BlynkEvent> {
+        let queue = self.handler.get_or_insert_with(EventQueue::default);
+        if queue.is_empty() {
+            self.run().await;
+        }
+        self.handler.as_mut()?.pop()
+    }
+}
+
+/// Virtual pin of a `Blynk`, see `Blynk::virtual_pin`
+pub struct PinHandle<'a, E = DefaultHandler, S = Config, C = Client>
+where
+    E: Event<C>,
+    S: ConfigSource,
+    C: Connection,
+{
+    pub(crate) blynk: &'a mut Blynk<E, S, C>,
+    pub(crate) pin: VirtualPin,
+}
+
+#[maybe_async]
+impl<E: Event<C>, S: ConfigSource + Sync, C: Connection> PinHandle<'_, E, S, C> {
+    pub fn pin(&self) -> VirtualPin {
+        self.pin
+    }
+
+    pub async fn write<V: IntoBlynkValue + Send>(&mut self, value: V) -> Result<()> {
+        self.blynk.client().virtual_write(self.pin, value).await
+    }
+
+    pub async fn set_property(&mut self, prop: &str, value: &str) -> Result<()> {
+        self.blynk
+            .client()
+            .set_property(self.pin, prop, value)
+            .await
+    }
+}
+
+#[maybe_async]
+impl<E: Event, S: ConfigSource + Sync> PinHandle<'_, E, S> {
+    /// Value the server keeps for the pin, see `Blynk::sync_virtual`
+    pub async fn sync(&mut self) -> Result<Option<String>> {
+        self.blynk.sync_virtual(self.pin).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Message, MessageType, ProtocolHeader};
+    use crate::testing::{self, wait_for, FakeClock, FakeServer};
+    use crate::{pins, Action, Protocol, Retransmit};
+    use crate::{ConnectionState, HandlerChain, HandlerErrorPolicy, Propagation};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Default)]
+    struct EventsHandler {
+        pin_num: u8,
+        data: String,
+        responses: Vec<(u16, ProtocolStatus)>,
+    }
+
+    #[maybe_async]
+    impl Event for EventsHandler {
+        async fn handle_vpin_read(
+            &mut self,
+            _client: &mut Client,
+            pin_num: VirtualPin,
+        ) -> Result<()> {
+            self.pin_num = pin_num.number();
+            Ok(())
+        }
+
+        async fn handle_vpin_write(
+            &mut self,
+            _client: &mut Client,
+            pin_num: VirtualPin,
+            params: ParamList<'_>,
+        ) -> Result<()> {
+            self.pin_num = pin_num.number();
+            self.data = params.as_str().to_string();
+            Ok(())
+        }
+
+        async fn handle_internal(
+            &mut self,
+            _client: &mut Client,
+            params: ParamList<'_>,
+        ) -> Result<()> {
+            self.data = params.as_slice().join(" ");
+            Ok(())
+        }
+
+        async fn handle_response(
+            &mut self,
+            _client: &mut Client,
+            msg_id: u16,
+            status: ProtocolStatus,
+        ) -> Result<()> {
+            self.responses.push((msg_id, status));
+            Ok(())
+        }
+    }
+
+    /// Blynk talking to the returned socket instead of a server
+    fn connected<E: Event>(listener: &TcpListener) -> (Blynk<E>, TcpStream) {
+        let mut blynk = Blynk::new("abc".to_string());
+        let sock = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        blynk.client.set_stream(testing::stream(sock));
+        let (server, _) = listener.accept().unwrap();
+        (blynk, server)
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn calls_vpinread_handler_with_params() {
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vr", "22"]);
+        let mut blynk = Blynk::new("abc".to_string());
+
+        let handler: EventsHandler = Default::default();
+        blynk.set_handler(handler);
+        blynk.process(msg.to_ref()).await.unwrap();
+
+        assert_eq!(22, blynk.handler().unwrap().pin_num);
+    }
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn calls_vpinwrite_handler_with_params() {
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "42", "my-val"]);
+        let mut blynk = Blynk::new("abc".to_string());
+
+        let handler: EventsHandler = Default::default();
+        blynk.set_handler(handler);
+        blynk.process(msg.to_ref()).await.unwrap();
+
+        assert_eq!(42, blynk.handler().unwrap().pin_num);
+        assert_eq!("my-val", blynk.handler().unwrap().data);
+    }
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn calls_internal_handler_with_params() {
+        let msg = Message::new(
+            MessageType::Internal,
+            1,
+            None,
+            None,
+            vec!["_internal", "hello", "world"],
+        );
+        let mut blynk = Blynk::new("abc".to_string());
+
+        let handler: EventsHandler = Default::default();
+        blynk.set_handler(handler);
+        blynk.process(msg.to_ref()).await.unwrap();
+
+        assert_eq!("hello world", blynk.handler().unwrap().data);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn reports_statuses_of_sent_commands() {
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(EventsHandler::default());
+        blynk
+            .client
+            .responses
+            .track(&proto::virtual_write(7, 1, "on"));
+
+        let status = Some(ProtocolStatus::StatusQuotaLimit);
+        for id in [7, 7, 8] {
+            let msg = Message::new(MessageType::Rsp, id, None, status, vec![]);
+            blynk.process(msg.to_ref()).await.unwrap();
+        }
+        // only the first response matches a command that was sent
+        assert_eq!(
+            vec![(7, ProtocolStatus::StatusQuotaLimit)],
+            blynk.handler().unwrap().responses
+        );
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn handles_all_messages_received_at_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut blynk, mut server) = connected(&listener);
+        blynk.set_handler(EventsHandler::default());
+
+        let mut data = Message::new(MessageType::Hw, 1, None, None, vec!["vr", "22"]).serialize();
+        data.extend(
+            Message::new(MessageType::Internal, 2, None, None, vec!["rtc", "1"]).serialize(),
+        );
+        server.write_all(&data).unwrap();
+
+        // a single run handles both messages
+        blynk.read_response().await;
+        assert_eq!(22, blynk.handler().unwrap().pin_num);
+        assert_eq!("1", blynk.handler().unwrap().data);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn sync_virtual_returns_stored_value() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut blynk, mut server) = connected(&listener);
+        blynk.set_handler(EventsHandler::default());
+
+        let server = thread::spawn(move || {
+            let mut request = [0; ProtocolHeader::SIZE + 4];
+            server.read_exact(&mut request).unwrap();
+            let mut data =
+                Message::new(MessageType::Hw, 1, None, None, vec!["vr", "22"]).serialize();
+            data.extend(
+                Message::new(MessageType::Hw, 2, None, None, vec!["vw", "5", "21"]).serialize(),
+            );
+            server.write_all(&data).unwrap();
+            server
+        });
+
+        let value = blynk.sync_virtual(5).await.unwrap();
+        assert_eq!(Some("21".to_string()), value);
+        let _server = server.join().unwrap();
+        // the read request received in the meantime is handled later
+        blynk.read_response().await;
+        assert_eq!(22, blynk.handler().unwrap().pin_num);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn answers_history_command() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut blynk, mut server) = connected::<DefaultHandler>(&listener);
+
+        blynk.set_pin_history(PinHistory::new(5).track(4));
+        blynk.client.virtual_write(4, "21.5").await.unwrap();
+        assert_eq!(1, blynk.pin_history().unwrap().get(4).count());
+
+        let msg = Message::new(MessageType::Internal, 1, None, None, vec!["history", "4"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+
+        // skip the pin write, then read the reply
+        let mut buf = vec![0; ProtocolHeader::SIZE + "vw\x004\x0021.5".len()];
+        server.read_exact(&mut buf).unwrap();
+        let (_, _, size) = ProtocolHeader::read_from(&mut server).unwrap();
+        let mut body = vec![0; size.into()];
+        server.read_exact(&mut body).unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with("history\x004\x00"));
+        assert!(body.ends_with(" 21.5"));
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn reconnects_after_server_goes_silent() {
+        let server = FakeServer::start();
+        let clock = FakeClock::new();
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_config(server.config());
+        blynk.set_clock(clock.clone());
+
+        blynk.run().await;
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        assert_eq!(1, server.connections());
+
+        // server stops answering, pings are sent but never acknowledged
+        server.set_silent(true);
+        clock.advance(conf::HEARTBEAT_PERIOD + Duration::from_secs(1));
+        blynk.run().await;
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        wait_for(|| server.pings() == 1);
+
+        // and after 90s of silence the connection is declared dead
+        clock.advance(Duration::from_secs(90));
+        blynk.run().await;
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Disconnected
+        ));
+
+        // server is back, next run reconnects
+        server.set_silent(false);
+        blynk.run().await;
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        assert_eq!(2, server.connections());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn keeps_connection_with_unanswered_writes() {
+        let server = FakeServer::start();
+        let clock = FakeClock::new();
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_config(Config {
+            retransmit: Some(Retransmit {
+                heartbeats: 1,
+                attempts: 1,
+            }),
+            ..server.config()
+        });
+        blynk.set_clock(clock.clone());
+        blynk.run().await;
+        assert_eq!(1, server.connections());
+
+        // the server never acknowledges writes, that is no reason to
+        // send them again or to give up on the connection
+        blynk.virtual_pin(5).write("on").await.unwrap();
+        for pings in 1..=4 {
+            clock.advance(conf::HEARTBEAT_PERIOD + Duration::from_secs(1));
+            blynk.run().await;
+            // the answer to the ping keeps the connection alive
+            wait_for(|| server.pings() == pings);
+        }
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        assert_eq!(1, server.connections());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn pings_past_writes_in_full_window() {
+        let server = FakeServer::start();
+        let clock = FakeClock::new();
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_config(Config {
+            max_in_flight: Some(2),
+            ..server.config()
+        });
+        blynk.set_clock(clock.clone());
+        blynk.run().await;
+
+        // more writes than the window holds, none of them is answered
+        for val in ["1", "2", "3", "4"] {
+            blynk.virtual_pin(5).write(val).await.unwrap();
+        }
+        for pings in 1..=3 {
+            clock.advance(conf::HEARTBEAT_PERIOD + Duration::from_secs(1));
+            blynk.run().await;
+            wait_for(|| server.pings() == pings);
+        }
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        assert_eq!(1, server.connections());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn keeps_connection_after_sync_on_connect() {
+        let server = FakeServer::start();
+        let clock = FakeClock::new();
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_config(Config {
+            retransmit: Some(Retransmit {
+                heartbeats: 1,
+                attempts: 1,
+            }),
+            sync_on_connect: true,
+            ..server.config()
+        });
+        blynk.set_clock(clock.clone());
+        blynk.run().await;
+
+        // the sync is answered with pin writes, never with a response
+        for pings in 1..=4 {
+            clock.advance(conf::HEARTBEAT_PERIOD + Duration::from_secs(1));
+            blynk.run().await;
+            wait_for(|| server.pings() == pings);
+        }
+        assert_eq!(1, server.connections());
+        assert_eq!(1, server.syncs());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn syncs_all_pins_on_connect() {
+        let server = FakeServer::start();
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_config(server.config());
+        blynk.run().await;
+        assert_eq!(0, server.syncs());
+
+        let server = FakeServer::start();
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_config(Config {
+            sync_on_connect: true,
+            ..server.config()
+        });
+        blynk.run().await;
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        wait_for(|| server.syncs() == 1);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn ignores_repeated_writes_of_tracked_pins() {
+        let write = |val| Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", val]);
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(EventsHandler::default());
+        blynk.track_pin(5);
+
+        blynk.process(write("1").to_ref()).await.unwrap();
+        assert_eq!("1", blynk.handler().unwrap().data);
+        blynk.handler().unwrap().data.clear();
+        blynk.process(write("1").to_ref()).await.unwrap();
+        assert_eq!("", blynk.handler().unwrap().data);
+        blynk.process(write("0").to_ref()).await.unwrap();
+        assert_eq!("0", blynk.handler().unwrap().data);
+
+        let server = FakeServer::start();
+        blynk.set_config(server.config());
+        blynk.run().await;
+        wait_for(|| server.syncs() == 1);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn delivers_settled_writes_of_debounced_pins() {
+        let write = |val| Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", val]);
+        let clock = FakeClock::new();
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(EventsHandler::default());
+        blynk.set_clock(clock.clone());
+        blynk.debounce(5, Duration::from_millis(200));
+
+        blynk.process(write("10").to_ref()).await.unwrap();
+        clock.advance(Duration::from_millis(150));
+        blynk.process(write("20").to_ref()).await.unwrap();
+        clock.advance(Duration::from_millis(150));
+        blynk.deliver_settled().await;
+        assert_eq!("", blynk.handler().unwrap().data);
+
+        clock.advance(Duration::from_millis(50));
+        blynk.deliver_settled().await;
+        assert_eq!("20", blynk.handler().unwrap().data);
+        assert_eq!(5, blynk.handler().unwrap().pin_num);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn swaps_boxed_handlers() {
+        struct Mode(&'static str, Arc<Mutex<Vec<String>>>);
+        #[maybe_async]
+        impl Event for Mode {
+            async fn handle_vpin_write(
+                &mut self,
+                _client: &mut Client,
+                _pin_num: VirtualPin,
+                params: ParamList<'_>,
+            ) -> Result<()> {
+                self.1
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", self.0, params.as_str()));
+                Ok(())
+            }
+        }
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let write = |val| Message::new(MessageType::Hw, 1, None, None, vec!["vw", "1", val]);
+        let mut blynk = Blynk::<Box<dyn Event>>::new("abc".to_string());
+        blynk.set_handler(Box::new(Mode("provisioning", calls.clone())));
+        blynk.process(write("a").to_ref()).await.unwrap();
+        blynk.set_handler(Box::new(Mode("normal", calls.clone())));
+        blynk.process(write("b").to_ref()).await.unwrap();
+        assert_eq!(vec!["provisioning a", "normal b"], *calls.lock().unwrap());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn chains_handlers() {
+        struct Part(&'static str, Arc<Mutex<Vec<String>>>);
+        #[maybe_async]
+        impl Event for Part {
+            async fn handle_vpin_write(
+                &mut self,
+                _client: &mut Client,
+                pin_num: VirtualPin,
+                _params: ParamList<'_>,
+            ) -> Result<()> {
+                self.1
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", self.0, pin_num));
+                Ok(())
+            }
+
+            async fn handle_app_connected(&mut self, _client: &mut Client) -> Result<()> {
+                self.1.lock().unwrap().push(format!("{} app", self.0));
+                Ok(())
+            }
+        }
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut blynk = Blynk::<HandlerChain>::new("abc".to_string());
+        blynk.set_handler(
+            HandlerChain::default()
+                .with_pins(Part("ota", calls.clone()), &[pins::V60])
+                .with(Part("app", calls.clone())),
+        );
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "60", "1"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vw", "1", "1"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        let msg = Message::new(MessageType::Internal, 3, None, None, vec!["acon"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        assert_eq!(
+            vec!["ota V60", "ota V1", "app V1", "ota app", "app app"],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn stops_propagation_of_pin_events() {
+        struct Guard(Arc<Mutex<Vec<String>>>);
+        #[maybe_async]
+        impl Event for Guard {
+            async fn handle_vpin_write(
+                &mut self,
+                _client: &mut Client,
+                pin_num: VirtualPin,
+                _params: ParamList<'_>,
+            ) -> Result<()> {
+                self.0.lock().unwrap().push(format!("guard {}", pin_num));
+                Ok(())
+            }
+
+            fn propagation(&self, pin_num: VirtualPin) -> Propagation {
+                match pin_num {
+                    pins::V5 => Propagation::Stop,
+                    _ => Propagation::Continue,
+                }
+            }
+        }
+
+        struct App(Arc<Mutex<Vec<String>>>);
+        #[maybe_async]
+        impl Event for App {
+            async fn handle_vpin_write(
+                &mut self,
+                _client: &mut Client,
+                pin_num: VirtualPin,
+                _params: ParamList<'_>,
+            ) -> Result<()> {
+                self.0.lock().unwrap().push(format!("app {}", pin_num));
+                Ok(())
+            }
+        }
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut blynk = Blynk::<HandlerChain>::new("abc".to_string());
+        blynk.set_handler(
+            HandlerChain::default()
+                .with(Guard(calls.clone()))
+                .with(App(calls.clone())),
+        );
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "1"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vw", "6", "1"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        assert_eq!(
+            vec!["guard V5", "guard V6", "app V6"],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn intercepts_received_messages() {
+        struct Filter;
+        impl Middleware for Filter {
+            fn on_incoming(&mut self, msg: &Message) -> Action {
+                match msg.virtual_write_value() {
+                    Some((9, _)) => Action::Drop,
+                    Some((pin, value)) => Action::Replace(Message::new(
+                        MessageType::Hw,
+                        msg.id,
+                        None,
+                        None,
+                        vec!["vw", &pin.to_string(), &value.repeat(2)],
+                    )),
+                    None => Action::Forward,
+                }
+            }
+        }
+
+        let write = |pin, val| Message::new(MessageType::Hw, 1, None, None, vec!["vw", pin, val]);
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(EventsHandler::default());
+        blynk.add_middleware(Filter);
+        blynk.process(write("9", "on").to_ref()).await.unwrap();
+        assert_eq!("", blynk.handler().unwrap().data);
+        blynk.process(write("1", "on").to_ref()).await.unwrap();
+        assert_eq!("onon", blynk.handler().unwrap().data);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn applies_handler_error_policy() {
+        struct Failing;
+        #[maybe_async]
+        impl Event for Failing {
+            async fn handle_vpin_write(
+                &mut self,
+                _client: &mut Client,
+                _pin_num: VirtualPin,
+                params: ParamList<'_>,
+            ) -> Result<()> {
+                params
+                    .as_str()
+                    .parse::<u8>()
+                    .map(drop)
+                    .map_err(BlynkError::handler)
+            }
+        }
+
+        let write = |val| Message::new(MessageType::Hw, 1, None, None, vec!["vw", "1", val]);
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(Failing);
+        blynk.process(write("x").to_ref()).await.unwrap();
+        assert!(!blynk.session.take_reconnect());
+
+        blynk.set_config(Config {
+            handler_error_policy: HandlerErrorPolicy::Reconnect,
+            ..Config::default()
+        });
+        blynk.process(write("1").to_ref()).await.unwrap();
+        assert!(!blynk.session.take_reconnect());
+        blynk.process(write("x").to_ref()).await.unwrap();
+        assert!(blynk.session.take_reconnect());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn tests_handlers_with_client_doubles() {
+        use crate::rt::prelude::{BufReader, Cursor};
+
+        #[derive(Default)]
+        struct Recorder {
+            msg_id: u16,
+            reader: Option<BufReader<Cursor<Vec<u8>>>>,
+        }
+
+        impl Protocol for Recorder {
+            type T = Cursor<Vec<u8>>;
+
+            fn set_reader(&mut self, reader: BufReader<Self::T>) {
+                self.reader = Some(reader);
+            }
+
+            fn reader(&mut self) -> Option<&mut BufReader<Self::T>> {
+                self.reader.as_mut()
+            }
+
+            fn msg_id(&mut self) -> u16 {
+                self.msg_id += 1;
+                self.msg_id
+            }
+
+            fn disconnect(&mut self) {}
+        }
+
+        struct Echo;
+        #[maybe_async]
+        impl<P: Protocol> Event<P> for Echo {
+            async fn handle_vpin_write(
+                &mut self,
+                client: &mut P,
+                pin_num: VirtualPin,
+                params: ParamList<'_>,
+            ) -> Result<()> {
+                client
+                    .virtual_write(pin_num.number() + 1, params.as_str())
+                    .await
+            }
+        }
+
+        let mut client = Recorder::default();
+        client.set_stream(Cursor::new(Vec::new()));
+        Echo.handle_vpin_write(&mut client, VirtualPin(3), ParamList::new(&["on"]))
+            .await
+            .unwrap();
+        let sent = client.reader.unwrap().into_inner().into_inner();
+        assert_eq!(proto::virtual_write(1, 4, "on"), sent);
+
+        // and so can a chain of them
+        let mut chain = HandlerChain::<Recorder>::default()
+            .with_pins(Echo, &[pins::V3])
+            .with(Echo);
+        let mut client = Recorder::default();
+        client.set_stream(Cursor::new(Vec::new()));
+        chain
+            .handle_vpin_write(&mut client, VirtualPin(3), ParamList::new(&["on"]))
+            .await
+            .unwrap();
+        let sent = client.reader.unwrap().into_inner().into_inner();
+        assert_eq!(proto::virtual_write(1, 4, "on"), sent);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn fans_events_out_to_tuples() {
+        struct Part(&'static str, Arc<Mutex<Vec<String>>>);
+        #[maybe_async]
+        impl Event for Part {
+            async fn handle_vpin_write(
+                &mut self,
+                _client: &mut Client,
+                pin_num: VirtualPin,
+                _params: ParamList<'_>,
+            ) -> Result<()> {
+                self.1
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", self.0, pin_num));
+                match self.0 {
+                    "ota" => Err(BlynkError::handler("busy")),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers = (
+            Part("ota", calls.clone()),
+            Part("diag", calls.clone()),
+            Part("app", calls.clone()),
+        );
+        let res = handlers
+            .handle_vpin_write(
+                &mut Client::default(),
+                VirtualPin(5),
+                ParamList::new(&["on"]),
+            )
+            .await;
+        assert!(matches!(res, Err(BlynkError::Handler(_))));
+        assert_eq!(vec!["ota V5", "diag V5", "app V5"], *calls.lock().unwrap());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn polls_events() {
+        let server = FakeServer::start();
+        let mut blynk = Blynk::<EventQueue>::with_config(server.config());
+        let event = blynk.poll().await;
+        assert_eq!(Some(BlynkEvent::Connected), event);
+
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "1", "2"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vr", "4"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        let values = vec!["1".to_string(), "2".to_string()];
+        let event = blynk.poll().await;
+        assert_eq!(
+            Some(BlynkEvent::VPinWrite {
+                pin: pins::V5,
+                values
+            }),
+            event
+        );
+        let event = blynk.poll().await;
+        assert_eq!(Some(BlynkEvent::VPinRead { pin: pins::V4 }), event);
+        let event = blynk.poll().await;
+        assert_eq!(None, event);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn connects_through_custom_resolver() {
+        struct Hosts(SocketAddr);
+        #[maybe_async]
+        impl Resolver for Hosts {
+            async fn resolve(&self, host: &str, _port: u64) -> io::Result<Vec<SocketAddr>> {
+                assert_eq!("blynk.test", host);
+                Ok(vec![self.0])
+            }
+        }
+
+        let server = FakeServer::start();
+        let config = server.config();
+        let addr = SocketAddr::new(config.server.parse().unwrap(), config.port as u16);
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_config(Config {
+            server: "blynk.test".into(),
+            ..config
+        });
+        blynk.set_resolver(Hosts(addr));
+
+        blynk.run().await;
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        assert_eq!(1, server.connections());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn follows_redirect_to_another_server() {
+        let server = FakeServer::start();
+        let target = server.config();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let redirect = Message::new(
+            MessageType::Redirect,
+            1,
+            None,
+            None,
+            vec![&target.server, &target.port.to_string()],
+        );
+        let data = redirect.serialize();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let (_, _, size) = ProtocolHeader::read_from(&mut sock).unwrap();
+            sock.read_exact(&mut vec![0; size.into()]).unwrap();
+            sock.write_all(&data).unwrap();
+        });
+
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_config(Config {
+            server: addr.ip().to_string(),
+            port: addr.port().into(),
+            ..target
+        });
+        blynk.run().await;
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        assert_eq!(1, server.connections());
+        assert_eq!(server.config().port, blynk.config.port);
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn runs_over_injected_connection() {
+        use std::io::{BufReader, Cursor};
+
+        /// Reads the scripted server messages, keeps what's written
+        struct Pipe {
+            input: Cursor<Vec<u8>>,
+            output: Vec<u8>,
+        }
+
+        impl Read for Pipe {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.input.read(buf)
+            }
+        }
+
+        impl Write for Pipe {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.output.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        struct Scripted {
+            msg_id: u16,
+            script: Vec<u8>,
+            reader: Option<BufReader<Pipe>>,
+        }
+
+        impl Protocol for Scripted {
+            type T = Pipe;
+
+            fn set_reader(&mut self, reader: BufReader<Pipe>) {
+                self.reader = Some(reader);
+            }
+
+            fn reader(&mut self) -> Option<&mut BufReader<Pipe>> {
+                self.reader.as_mut()
+            }
+
+            fn msg_id(&mut self) -> u16 {
+                self.msg_id += 1;
+                self.msg_id
+            }
+
+            fn disconnect(&mut self) {
+                self.reader = None;
+            }
+        }
+
+        impl Connection for Scripted {
+            fn open<S: ConfigSource>(
+                &mut self,
+                _addrs: Vec<SocketAddr>,
+                _config: &S,
+            ) -> Result<()> {
+                let input = Cursor::new(std::mem::take(&mut self.script));
+                self.set_stream(Pipe {
+                    input,
+                    output: Vec::new(),
+                });
+                Ok(())
+            }
+        }
+
+        struct Echo;
+        impl<P: Protocol> Event<P> for Echo {
+            fn handle_vpin_write(
+                &mut self,
+                client: &mut P,
+                pin_num: VirtualPin,
+                params: ParamList<'_>,
+            ) -> Result<()> {
+                client.virtual_write(pin_num, params.as_str())
+            }
+        }
+
+        let ok = u16::from(ProtocolStatus::StatusOk);
+        let mut script = Vec::new();
+        for id in [1, 2] {
+            ProtocolHeader::write_to((MessageType::Rsp as u8, id, ok), &mut script).unwrap();
+        }
+        script.extend(proto::virtual_write(7, 3, "on"));
+        let client = Scripted {
+            msg_id: 0,
+            script,
+            reader: None,
+        };
+        let config = Config {
+            token: "abc".into(),
+            server: "127.0.0.1".into(),
+            ..Config::default()
+        };
+        let mut blynk = Blynk::with_client(config, client);
+        blynk.set_handler(Echo);
+        blynk.run();
+        assert!(matches!(
+            blynk.session.state(),
+            ConnectionState::Authenticated
+        ));
+        let sent = &blynk.client.reader.as_ref().unwrap().get_ref().output;
+        assert!(sent.starts_with(&proto::login(1, "abc")));
+        assert!(sent.ends_with(&proto::virtual_write(3, 3, "on")));
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn runs_connection_on_background_thread() {
+        let server = FakeServer::start();
+        let blynk = Blynk::<EventQueue>::with_config(server.config()).spawn();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(Some(BlynkEvent::Connected), blynk.poll_timeout(timeout));
+        assert_eq!(None, blynk.poll());
+        blynk.sender().virtual_write(1, "on").unwrap();
+
+        let sender = blynk.sender().clone();
+        drop(blynk);
+        assert_eq!(1, server.connections());
+        assert!(sender.virtual_write(1, "off").is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[smol_potat::test]
+    async fn streams_events_from_background_task() {
+        use smol::stream::StreamExt;
+
+        let server = FakeServer::start();
+        let mut events = Blynk::<EventQueue>::with_config(server.config()).events();
+        assert_eq!(Some(BlynkEvent::Connected), events.next().await);
+        assert_eq!(1, server.connections());
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn handles_events_with_closures() {
+        use crate::FnEvent;
+
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = writes.clone();
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(FnEvent::new().on_vpin_write(move |_, pin, params| {
+            recorded
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", pin, params.as_str()));
+            Ok(())
+        }));
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "on"]);
+        blynk.process(msg.to_ref()).unwrap();
+        // hooks without a closure are skipped
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vr", "4"]);
+        blynk.process(msg.to_ref()).unwrap();
+        assert_eq!(vec!["V5 on"], *writes.lock().unwrap());
+    }
+
+    #[cfg(feature = "async")]
+    #[smol_potat::test]
+    async fn handles_events_with_closures() {
+        use crate::FnEvent;
+
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = writes.clone();
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(FnEvent::new().on_vpin_write(move |_, pin, params| {
+            let recorded = recorded.clone();
+            let value = params.as_str().to_string();
+            Box::pin(async move {
+                recorded.lock().unwrap().push(format!("{} {}", pin, value));
+                Ok(())
+            })
+        }));
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "on"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        // hooks without a closure are skipped
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vr", "4"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        assert_eq!(vec!["V5 on"], *writes.lock().unwrap());
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn pin_handle_is_bound_to_its_pin() {
+        use crate::pins;
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut blynk, mut server) = connected(&listener);
+        blynk.set_handler(EventsHandler::default());
+
+        let mut pin = blynk.virtual_pin(pins::V5);
+        let writes = calls.clone();
+        pin.on_write(move |_, params| {
+            writes.lock().unwrap().push(params.as_str().to_string());
+            Ok(())
+        });
+        pin.write(21).unwrap();
+        assert_eq!(5, pin.pin());
+
+        let sent = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "21"]).serialize();
+        let mut received = vec![0; sent.len()];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(sent, received);
+
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "on"]);
+        blynk.process(msg.to_ref()).unwrap();
+        assert_eq!(vec!["on"], *calls.lock().unwrap());
+        assert_eq!("", blynk.handler().unwrap().data);
+    }
+
+    #[cfg(feature = "async")]
+    #[smol_potat::test]
+    async fn pin_handle_is_bound_to_its_pin() {
+        use crate::pins;
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut blynk, mut server) = connected(&listener);
+        blynk.set_handler(EventsHandler::default());
+
+        let mut pin = blynk.virtual_pin(pins::V5);
+        let writes = calls.clone();
+        pin.on_write(move |_, params| {
+            let writes = writes.clone();
+            let value = params.as_str().to_string();
+            Box::pin(async move {
+                writes.lock().unwrap().push(value);
+                Ok(())
+            })
+        });
+        pin.write(21).await.unwrap();
+        assert_eq!(5, pin.pin());
+
+        let sent = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "21"]).serialize();
+        let mut received = vec![0; sent.len()];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(sent, received);
+
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "on"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        assert_eq!(vec!["on"], *calls.lock().unwrap());
+        assert_eq!("", blynk.handler().unwrap().data);
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn routes_pins_to_closures() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(EventsHandler::default());
+        let writes = calls.clone();
+        blynk.on_virtual_write(5, move |_, params| {
+            writes
+                .lock()
+                .unwrap()
+                .push(format!("write {}", params.as_str()));
+            Ok(())
+        });
+        let reads = calls.clone();
+        blynk.on_virtual_read(4, move |_| {
+            reads.lock().unwrap().push("read".into());
+            Ok(())
+        });
+
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "on"]);
+        blynk.process(msg.to_ref()).unwrap();
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vr", "4"]);
+        blynk.process(msg.to_ref()).unwrap();
+        assert_eq!(vec!["write on", "read"], *calls.lock().unwrap());
+        assert_eq!("", blynk.handler().unwrap().data);
+
+        // other pins still reach the handler
+        let msg = Message::new(MessageType::Hw, 3, None, None, vec!["vw", "6", "off"]);
+        blynk.process(msg.to_ref()).unwrap();
+        assert_eq!("off", blynk.handler().unwrap().data);
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn routes_every_value_of_a_write() {
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let mut blynk = <Blynk>::new("abc".to_string());
+        let writes = values.clone();
+        blynk.on_virtual_write(5, move |_, params| {
+            writes
+                .lock()
+                .unwrap()
+                .extend(params.iter().map(String::from));
+            Ok(())
+        });
+
+        let msg = Message::new(
+            MessageType::Hw,
+            1,
+            None,
+            None,
+            vec!["vw", "5", "1", "2", "3"],
+        );
+        blynk.process(msg.to_ref()).unwrap();
+        assert_eq!(vec!["1", "2", "3"], *values.lock().unwrap());
+    }
+
+    #[cfg(feature = "async")]
+    #[smol_potat::test]
+    async fn routes_pins_to_closures() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(EventsHandler::default());
+        let writes = calls.clone();
+        blynk.on_virtual_write(5, move |_, params| {
+            let writes = writes.clone();
+            let value = params.as_str().to_string();
+            Box::pin(async move {
+                writes.lock().unwrap().push(format!("write {}", value));
+                Ok(())
+            })
+        });
+        let reads = calls.clone();
+        blynk.on_virtual_read(4, move |_| {
+            let reads = reads.clone();
+            Box::pin(async move {
+                reads.lock().unwrap().push("read".to_string());
+                Ok(())
+            })
+        });
+
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "on"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vr", "4"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        assert_eq!(vec!["write on", "read"], *calls.lock().unwrap());
+        assert_eq!("", blynk.handler().unwrap().data);
+
+        // other pins still reach the handler
+        let msg = Message::new(MessageType::Hw, 3, None, None, vec!["vw", "6", "off"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        assert_eq!("off", blynk.handler().unwrap().data);
+    }
+
+    #[cfg(feature = "async")]
+    #[smol_potat::test]
+    async fn routes_every_value_of_a_write() {
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let mut blynk = <Blynk>::new("abc".to_string());
+        let writes = values.clone();
+        blynk.on_virtual_write(5, move |_, params| {
+            let writes = writes.clone();
+            let received: Vec<String> = params.iter().map(String::from).collect();
+            Box::pin(async move {
+                writes.lock().unwrap().extend(received);
+                Ok(())
+            })
+        });
+        let spawned = Arc::new(Mutex::new(Vec::new()));
+        let tasks = spawned.clone();
+        blynk.spawn_virtual_write(6, move |_, params| {
+            let tasks = tasks.clone();
+            let received: Vec<String> = params.iter().map(String::from).collect();
+            Box::pin(async move {
+                tasks.lock().unwrap().extend(received);
+                Ok(())
+            })
+        });
+
+        let msg = Message::new(
+            MessageType::Hw,
+            1,
+            None,
+            None,
+            vec!["vw", "5", "1", "2", "3"],
+        );
+        blynk.process(msg.to_ref()).await.unwrap();
+        assert_eq!(vec!["1", "2", "3"], *values.lock().unwrap());
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vw", "6", "4", "5"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        wait_for(|| spawned.lock().unwrap().len() == 2);
+        assert_eq!(vec!["4", "5"], *spawned.lock().unwrap());
+    }
+
+    #[cfg(feature = "async")]
+    #[smol_potat::test]
+    async fn spawns_handlers_up_to_limit() {
+        let (spawned, running) = (Arc::new(Mutex::new(0)), Arc::new(Mutex::new(0)));
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let (gate, opened) = smol::channel::unbounded::<()>();
+        let mut blynk = <Blynk>::new("abc".to_string());
+        blynk.set_task_limit(2);
+        let (routed, started, writes) = (spawned.clone(), running.clone(), values.clone());
+        blynk.spawn_virtual_write(5, move |_, params| {
+            *routed.lock().unwrap() += 1;
+            let (started, writes, opened) = (started.clone(), writes.clone(), opened.clone());
+            let value = params.as_str().to_string();
+            Box::pin(async move {
+                *started.lock().unwrap() += 1;
+                opened.recv().await.unwrap();
+                writes.lock().unwrap().push(value);
+                Ok(())
+            })
+        });
+
+        // handlers waiting on the gate don't hold back processing, the
+        // writes beyond the limit are dropped without building a task
+        for id in 1..=100 {
+            let msg = Message::new(MessageType::Hw, id, None, None, vec!["vw", "5", "on"]);
+            blynk.process(msg.to_ref()).await.unwrap();
+        }
+        wait_for(|| *running.lock().unwrap() == 2);
+        rt::sleep(Duration::from_millis(50)).await;
+        assert_eq!(2, *running.lock().unwrap());
+        assert_eq!(2, *spawned.lock().unwrap());
+        for _ in 1..=2 {
+            gate.send(()).await.unwrap();
+        }
+        wait_for(|| values.lock().unwrap().len() == 2);
+
+        // finished handlers free their slots
+        rt::sleep(Duration::from_millis(50)).await;
+        let msg = Message::new(MessageType::Hw, 101, None, None, vec!["vw", "5", "off"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        gate.send(()).await.unwrap();
+        wait_for(|| values.lock().unwrap().len() == 3);
+        assert_eq!(3, *spawned.lock().unwrap());
+    }
+
+    #[cfg(all(feature = "macros", not(feature = "async")))]
+    #[test]
+    fn dispatches_pins_to_marked_methods() {
+        #[derive(Default)]
+        struct Relay {
+            on: bool,
+            reads: usize,
+        }
+
+        #[crate::handler]
+        impl Relay {
+            #[crate::on_write(pin = 5)]
+            fn switch(&mut self, _client: &mut Client, params: ParamList<'_>) -> Result<()> {
+                self.on = params.as_str() == "1";
+                Ok(())
+            }
+
+            #[crate::on_read(pin = 4)]
+            fn state(&mut self, _client: &mut Client) -> Result<()> {
+                self.reads += 1;
+                Ok(())
+            }
+        }
+
+        let write = |pin, val| Message::new(MessageType::Hw, 1, None, None, vec!["vw", pin, val]);
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(Relay::default());
+        blynk.process(write("5", "1").to_ref()).unwrap();
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vr", "4"]);
+        blynk.process(msg.to_ref()).unwrap();
+        assert!(blynk.handler().unwrap().on);
+        assert_eq!(1, blynk.handler().unwrap().reads);
+
+        // hooks of a trait impl get the other pins
+        #[derive(Default)]
+        struct Dimmer {
+            level: String,
+            other: Vec<u8>,
+        }
+
+        #[crate::handler]
+        impl Event for Dimmer {
+            fn handle_vpin_write(
+                &mut self,
+                _client: &mut Client,
+                pin_num: VirtualPin,
+                _params: ParamList<'_>,
+            ) -> Result<()> {
+                self.other.push(pin_num.number());
+                Ok(())
+            }
+
+            #[crate::on_write(pin = 6)]
+            fn level(&mut self, _client: &mut Client, params: ParamList<'_>) -> Result<()> {
+                self.level = params.as_str().into();
+                Ok(())
+            }
+        }
+
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(Dimmer::default());
+        blynk.process(write("7", "1").to_ref()).unwrap();
+        blynk.process(write("6", "40").to_ref()).unwrap();
+        assert_eq!(vec![7], blynk.handler().unwrap().other);
+        assert_eq!("40", blynk.handler().unwrap().level);
+    }
+
+    #[cfg(all(feature = "macros", feature = "async"))]
+    #[smol_potat::test]
+    async fn dispatches_pins_to_marked_methods() {
+        #[derive(Default)]
+        struct Relay {
+            on: bool,
+        }
+
+        #[crate::handler]
+        impl Relay {
+            #[crate::on_write(pin = 5)]
+            async fn switch(&mut self, _client: &mut Client, params: ParamList<'_>) -> Result<()> {
+                self.on = params.as_str() == "1";
+                Ok(())
+            }
+        }
+
+        let mut blynk = Blynk::new("abc".to_string());
+        blynk.set_handler(Relay::default());
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "5", "1"]);
+        blynk.process(msg.to_ref()).await.unwrap();
+        assert!(blynk.handler().unwrap().on);
+    }
+}
@@ -1,32 +1,22 @@
-use std::fmt;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::net::{Shutdown, TcpStream};
-use std::thread;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{Shutdown, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use log::*;
 
-use crate::conf;
-use crate::message::{Message, MessageType, ProtocolHeader};
-use crate::{BlynkError, Result};
-
-const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
-
-#[derive(Debug)]
-struct ClientError;
-
-impl fmt::Display for ClientError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "client encountered an error")
-    }
-}
-
-// impl Error for ClientError {
-//     fn description(&self) -> &str {
-//         "client error lol"
-//     }
-// }
+use crate::config::ConfigSource;
+use crate::message::{Message, MessageType, PinMode};
+use crate::middleware::{Middleware, Middlewares};
+use crate::proto::{self, EventQuota, Outbox, Responses, Retransmits, Throttle};
+use crate::rt::{self, prelude::*, Stream};
+use crate::session;
+use crate::split::{self, ClientReader, ClientWriter};
+use crate::widgets::{Color, Location};
+use crate::WritePolicy;
+use crate::{conf, maybe_async};
+use crate::{BlynkError, FirmwareInfo, Notification, NotifyLimit, PinHistory, Result, Retransmit};
+use crate::{IntoBlynkValue, VirtualPin};
 
 #[derive(Default)]
 /// Implements state of the connection abstraction with Blynk.io servers.
@@ -34,22 +24,360 @@ impl fmt::Display for ClientError {
 /// communicate with those servers
 pub struct Client {
     msg_id: u16,
-    reader: Option<BufReader<TcpStream>>,
+    reader: Option<BufReader<Stream>>,
+    pending: VecDeque<Message>,
+    outbox: Outbox,
+    history: Option<PinHistory>,
+    #[cfg(feature = "async")]
+    read_timeout: Option<Duration>,
+    pub(crate) responses: Responses,
+    retransmits: Option<Retransmits>,
+    events: EventQuota,
+    throttle: Option<Throttle>,
+    middleware: Middlewares,
 }
 
+#[maybe_async]
 impl Client {
+    /// Reads waiting longer than `duration` fail with `ErrorKind::TimedOut`
+    /// (or `WouldBlock`, depending on the platform, in blocking mode)
     pub fn set_read_timeout(&mut self, duration: Duration) {
+        #[cfg(not(feature = "async"))]
         if let Ok(stream) = self.stream() {
             stream
                 .set_read_timeout(Some(duration))
                 .expect("read timeout problem");
         }
+        #[cfg(feature = "async")]
+        {
+            self.read_timeout = Some(duration);
+        }
+    }
+
+    /// Splits the connection into a reader and a writer, both `Send`, so
+    /// they can be moved to different threads or tasks. The halves talk
+    /// to the server directly: the outgoing queue, retransmits, limits and
+    /// middleware of the client are left behind, and answering pings and
+    /// requests of the server is up to the owner of the writer
+    ///
+    /// Plain TCP halves never wait for each other. TLS, WebSocket and MQTT
+    /// streams keep one state shared by both halves, so a write waits up
+    /// to `SPLIT_READ_SLICE` (50ms) while the reader is idle
+    pub fn split(mut self) -> Result<(ClientReader, ClientWriter)> {
+        let reader = self.reader.take().ok_or(BlynkError::StreamIsNone)?;
+        let buffered = reader.buffer().to_vec();
+        split::split(reader.into_inner(), buffered, self.msg_id)
+    }
+
+    /// Number of events that can still be logged today, see
+    /// `Protocol::log_event`
+    pub fn events_remaining(&mut self) -> usize {
+        self.events.remaining(Instant::now())
+    }
+
+    /// Number of messages waiting in the outgoing queue
+    pub fn queue_depth(&self) -> usize {
+        self.outbox.len()
+    }
+
+    /// Registers a callback fired every time the outgoing queue grows
+    /// to `mark` messages, e.g. in order to shed telemetry load
+    pub fn set_high_water_mark<F>(&mut self, mark: usize, callback: F)
+    where
+        F: FnMut(usize) + Send + 'static,
+    {
+        self.outbox.set_high_water_mark(mark, Box::new(callback));
+    }
+
+    /// Enables recording of values written to the pins tracked by `history`
+    pub fn set_history(&mut self, history: PinHistory) {
+        self.history = Some(history);
+    }
+
+    pub fn history(&self) -> Option<&PinHistory> {
+        self.history.as_ref()
+    }
+
+    /// Sets handling of writes issued while the handshake is in progress
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.outbox.set_write_policy(policy);
+    }
+
+    /// Enables re-sending of messages the server didn't acknowledge
+    pub fn set_retransmit(&mut self, policy: Option<Retransmit>) {
+        self.retransmits = policy.map(Retransmits::new);
+    }
+
+    /// Limits how often notifications and emails are sent, keeps the
+    /// times of the last ones if the limit doesn't change
+    pub fn set_notify_limit(&mut self, limit: Option<NotifyLimit>) {
+        if self.throttle.as_ref().map(Throttle::limit) != limit {
+            self.throttle = limit.map(Throttle::new);
+        }
+    }
+
+    /// Limits the number of sent messages waiting for a response
+    pub fn set_max_in_flight(&mut self, max: Option<usize>) {
+        self.outbox.set_window(max);
+    }
+
+    /// Passes every outgoing message through `middleware` before it's
+    /// queued, after the middleware added before
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.add(Box::new(middleware));
+    }
+
+    /// Virtual pins whose queued writes are replaced by newer ones
+    pub fn set_coalesced_pins<I, N>(&mut self, pins: I)
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<VirtualPin>,
+    {
+        let pins: Vec<u8> = pins.into_iter().map(|pin| pin.into().number()).collect();
+        self.outbox.set_coalesced_pins(&pins);
+    }
+
+    /// Checks and tracks the message and queues it, returns true if the
+    /// queue has to be flushed
+    async fn enqueue(&mut self, msg: Vec<u8>) -> Result<bool> {
+        let Some(msg) = self.middleware.outgoing(msg) else {
+            return Ok(false);
+        };
+        proto::check_size(&msg)?;
+        self.stream()?;
+        self.events.admit(&msg, Instant::now())?;
+        let msg = match &mut self.throttle {
+            Some(throttle) => match throttle.admit(msg, Instant::now())? {
+                Some(msg) => msg,
+                None => return Ok(false),
+            },
+            None => msg,
+        };
+        for id in self.outbox.coalesce(&msg) {
+            self.responses.resolve(id);
+            if let Some(retransmits) = &mut self.retransmits {
+                retransmits.ack(id);
+            }
+        }
+        self.responses.track(&msg);
+        if let Some(retransmits) = &mut self.retransmits {
+            retransmits.track(&msg);
+        }
+        match self.outbox.admit(msg)? {
+            // handshake goes ahead of the writes queued in the meantime
+            Some(msg) if self.outbox.handshake() => {
+                let stream = self.stream()?;
+                stream.write_all(&msg).await?;
+                stream.flush().await?;
+                Ok(false)
+            }
+            Some(msg) => {
+                self.outbox.push(msg);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Writes recorded values of `pin` into the terminal widget attached
+    /// to `terminal_pin`, one `<unix seconds> <value>` line per entry
+    pub async fn dump_history<P, T>(&mut self, pin: P, terminal_pin: T) -> Result<()>
+    where
+        P: Into<VirtualPin>,
+        T: Into<VirtualPin>,
+    {
+        let terminal_pin = terminal_pin.into();
+        let lines = match &self.history {
+            Some(history) => history.lines(pin.into().number()),
+            None => return Ok(()),
+        };
+        for line in lines {
+            self.virtual_write(terminal_pin, format!("{}\n", line))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Writes queued messages to the socket.
+    ///
+    /// Messages that can't be written because the socket is not ready
+    /// (within `SOCK_TIMEOUT`) are kept in the queue until the next flush.
+    pub async fn flush(&mut self) -> Result<()> {
+        let mut retries = conf::RETRIES_TX_MAX_NUM;
+        while let Some(msg) = self.outbox.front() {
+            let stream = self
+                .reader
+                .as_mut()
+                .ok_or(BlynkError::StreamIsNone)?
+                .get_mut();
+
+            match stream.try_write(msg).await {
+                Some(Ok(size)) if size > 0 => {
+                    if self.outbox.advance(size) {
+                        retries = conf::RETRIES_TX_MAX_NUM;
+                        debug!("Sent message, awaiting reply...!!");
+                    }
+                }
+                None => {
+                    debug!("Socket not ready, {} messages queued", self.outbox.len());
+                    return Ok(());
+                }
+                Some(res) => {
+                    error!("Problem sending!: {:?}", res);
+                    retries -= 1;
+                    if retries == 0 {
+                        self.outbox.pop();
+                        return Err(BlynkError::MessageSend);
+                    }
+                    rt::sleep(conf::RETRIES_TX_DELAY).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the next message to process, preferring the ones that were
+    /// received (but not consumed) while waiting for other responses
+    pub async fn next_message(&mut self) -> Result<Message> {
+        match self.pending.pop_front() {
+            Some(msg) => Ok(msg),
+            None => self.read().await,
+        }
+    }
+
+    /// Returns true if the next message can be returned right away, it's
+    /// either pending or it was already received as a whole
+    pub fn has_buffered_message(&self) -> bool {
+        !self.pending.is_empty()
+            || self
+                .reader
+                .as_ref()
+                .is_some_and(|reader| proto::frame_len(reader.buffer()).is_some())
+    }
+
+    /// Requests values of several virtual pins at once and waits until
+    /// all of them arrive or the `timeout` elapses.
+    ///
+    /// Returns the values received so far, keyed by the pin. Other
+    /// messages received in the meantime are kept for regular processing.
+    pub async fn read_virtual_many<I, N>(
+        &mut self,
+        pins: I,
+        timeout: Duration,
+    ) -> Result<HashMap<VirtualPin, String>>
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<VirtualPin>,
+    {
+        let pins: Vec<u8> = pins.into_iter().map(|pin| pin.into().number()).collect();
+        let msg = proto::virtual_sync(self.msg_id(), &pins);
+        self.send(msg).await?;
+
+        let deadline = Instant::now() + timeout;
+        let mut values = HashMap::new();
+        while !pins
+            .iter()
+            .all(|pin| values.contains_key(&VirtualPin::from(*pin)))
+        {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Timed out waiting for virtual pin values");
+                break;
+            }
+            self.set_read_timeout(deadline - now);
+
+            let msg = match self.read().await {
+                Ok(msg) => msg,
+                Err(BlynkError::Io(err))
+                    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(err),
+            };
+
+            match msg.virtual_write_value() {
+                Some((pin, val)) if pins.contains(&pin) => {
+                    values.insert(pin.into(), val.to_string());
+                }
+                _ => self.pending.push_back(msg),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Writes the value and waits until the server confirms it got it.
+    /// Successful writes are never answered, so a ping follows the write
+    /// and its response, which the server sends only after handling the
+    /// write, serves as the confirmation. Fails with `BlynkError::Rejected`
+    /// if the server answers the write with an error status and with
+    /// `ErrorKind::TimedOut` if the ping isn't answered within `timeout`.
+    /// Other messages received in the meantime are kept for regular
+    /// processing.
+    pub async fn virtual_write_acked<N: Into<VirtualPin>>(
+        &mut self,
+        v_pin: N,
+        val: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        let v_pin = v_pin.into().number();
+        if let Some(history) = &mut self.history {
+            history.record(v_pin, SystemTime::now(), val);
+        }
+        let id = self.msg_id();
+        let ping_id = self.msg_id();
+        self.send_all(vec![
+            proto::virtual_write(id, v_pin, val),
+            proto::ping(ping_id),
+        ])
+        .await?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Timed out waiting for confirmation of message {}", id);
+                return Err(io::Error::from(ErrorKind::TimedOut).into());
+            }
+            self.set_read_timeout(deadline - now);
+
+            let msg = match self.read().await {
+                Ok(msg) => msg,
+                Err(BlynkError::Io(err))
+                    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(err),
+            };
+
+            match (msg.mtype, msg.status) {
+                (MessageType::Rsp, Some(status)) if msg.id == id => {
+                    return proto::check_ack(status);
+                }
+                (MessageType::Rsp, _) if msg.id == ping_id => {
+                    self.acknowledge(ping_id);
+                    return Ok(());
+                }
+                (MessageType::Rsp, _) => {
+                    // frees the window for our message if it's still queued
+                    self.outbox.ack(msg.id);
+                    self.pending.push_back(msg);
+                    self.flush().await?;
+                }
+                _ => self.pending.push_back(msg),
+            }
+        }
     }
 }
 
 /// Provides implementation of all known blynk.io api protocol methods
-pub trait Protocol {
-    type T: std::io::Read + std::io::Write;
+#[maybe_async]
+pub trait Protocol: Send {
+    #[cfg(not(feature = "async"))]
+    type T: io::Read + io::Write + Send;
+    #[cfg(feature = "async")]
+    type T: AsyncRead + AsyncWrite + Unpin + Send;
 
     fn set_reader(&mut self, reader: BufReader<Self::T>);
     fn msg_id(&mut self) -> u16;
@@ -60,27 +388,20 @@ pub trait Protocol {
         self.set_reader(BufReader::new(stream));
     }
 
-    fn read(&mut self) -> Result<Message> {
-        let reader = self.reader().ok_or(BlynkError::ReaderNotAvailable)?;
-
-        let buf = reader.fill_buf()?;
-        if buf.is_empty() {
-            return Err(BlynkError::EmptyBuffer);
-        }
-        let msg = Message::deserilize(buf)?;
-
-        debug!(
-            "size ({}) vs consumed ({})",
-            buf.len(),
-            ProtocolHeader::SIZE + msg.size.unwrap_or(0) as usize
-        );
-
-        // consume bytes (msg header + body) from the reader
-        reader.consume(ProtocolHeader::SIZE + msg.size.unwrap_or(0) as usize);
-        debug!("Got response message: {:?}", msg);
+    async fn read(&mut self) -> Result<Message> {
+        let mut frame = Vec::new();
+        self.read_frame(&mut frame).await?;
+        let (msg, _) = proto::decode(&frame)?;
         Ok(msg)
     }
 
+    /// Reads the next whole message into `frame` without parsing it, so
+    /// it can be parsed in place with `MessageRef::parse`
+    async fn read_frame(&mut self, frame: &mut Vec<u8>) -> Result<()> {
+        let reader = self.reader().ok_or(BlynkError::ReaderNotAvailable)?;
+        rt::read_frame(reader, frame).await
+    }
+
     fn stream(&mut self) -> Result<&mut Self::T> {
         if let Some(r) = self.reader() {
             return Ok(r.get_mut());
@@ -88,126 +409,322 @@ pub trait Protocol {
         Err(BlynkError::StreamIsNone)
     }
 
-    fn login(&mut self, token: &str) -> Result<()> {
-        let msg = Message::new(MessageType::Login, self.msg_id(), None, None, vec![token]);
-        self.send(msg.serialize())
+    async fn login(&mut self, token: &str) -> Result<()> {
+        let msg = proto::login(self.msg_id(), token);
+        self.send(msg).await
     }
 
-    fn heartbeat(&mut self, heartbeat: Duration, rcv_buffer: u16) -> Result<()> {
-        let msg = Message::new(
-            MessageType::Internal,
-            self.msg_id(),
-            None,
-            None,
-            vec![
-                "ver",
-                CARGO_PKG_VERSION,
-                "buff-in",
-                &rcv_buffer.to_string(),
-                "h-beat",
-                &heartbeat.as_secs().to_string(),
-                "dev",
-                "rust",
-            ],
-        );
+    async fn heartbeat(&mut self, heartbeat: Duration, rcv_buffer: u16) -> Result<()> {
+        self.heartbeat_with_id(heartbeat, rcv_buffer, None).await
+    }
 
-        self.send(msg.serialize())
+    /// Same as `heartbeat` but appends `device_id` to the `dev` info field
+    /// (`rust-<device_id>`) so connections sharing a token can be told apart
+    async fn heartbeat_with_id(
+        &mut self,
+        heartbeat: Duration,
+        rcv_buffer: u16,
+        device_id: Option<&str>,
+    ) -> Result<()> {
+        self.heartbeat_with_info(heartbeat, rcv_buffer, device_id, None)
+            .await
     }
 
-    fn ping(&mut self) -> Result<()> {
-        let msg = Message::new(MessageType::Ping, self.msg_id(), None, None, vec![]);
-        self.send(msg.serialize())
+    /// Same as `heartbeat_with_id` and also announces the template and
+    /// firmware version to Blynk IoT
+    async fn heartbeat_with_info(
+        &mut self,
+        heartbeat: Duration,
+        rcv_buffer: u16,
+        device_id: Option<&str>,
+        firmware: Option<&FirmwareInfo>,
+    ) -> Result<()> {
+        let msg = proto::heartbeat(self.msg_id(), heartbeat, rcv_buffer, device_id, firmware);
+        self.send(msg).await
     }
 
-    fn response(&mut self, status: u16, msg_id: u16) -> Result<()> {
-        let msg = Message::new(
-            MessageType::Rsp,
-            msg_id,
-            None,
-            None,
-            vec![&status.to_string()],
-        );
-        self.send(msg.serialize())
+    async fn ping(&mut self) -> Result<()> {
+        let msg = proto::ping(self.msg_id());
+        self.send(msg).await
     }
 
-    fn virtual_write(&mut self, v_pin: u8, val: &str) -> Result<()> {
-        let msg = Message::new(
-            MessageType::Hw,
-            self.msg_id(),
-            None,
-            None,
-            vec!["vw", &v_pin.to_string(), val],
-        );
-        self.send(msg.serialize())
+    async fn response(&mut self, status: u16, msg_id: u16) -> Result<()> {
+        self.send(proto::response(msg_id, status)).await
     }
 
-    fn virtual_sync(&mut self, pins: Vec<u32>) -> Result<()> {
-        let pins: String = pins
-            .into_iter()
-            .map(|x| std::char::from_digit(x, 10).unwrap())
-            .collect();
+    /// Writes text, a number, a bool (as `0`/`1`) or a tuple of them as
+    /// a multi-value write, see `IntoBlynkValue`
+    async fn virtual_write<N, V>(&mut self, v_pin: N, val: V) -> Result<()>
+    where
+        N: Into<VirtualPin> + Send,
+        V: IntoBlynkValue + Send,
+    {
+        let v_pin = v_pin.into().number();
+        let msg = proto::virtual_write(self.msg_id(), v_pin, &val.into_blynk_value());
+        self.send(msg).await
+    }
 
-        let msg = Message::new(
-            MessageType::HwSync,
-            self.msg_id(),
-            None,
-            None,
-            vec!["vr", &pins],
-        );
-        self.send(msg.serialize())
+    /// Reports the state of a physical digital pin (`dw`), also the answer
+    /// to a `dr` request from the server
+    async fn digital_write(&mut self, pin: u8, high: bool) -> Result<()> {
+        let msg = proto::digital_write(self.msg_id(), pin, high);
+        self.send(msg).await
     }
 
-    fn email(&mut self, to: &str, subject: &str, body: &str) -> Result<()> {
-        let msg = Message::new(
-            MessageType::Email,
-            self.msg_id(),
-            None,
-            None,
-            vec![to, subject, body],
-        );
-        self.send(msg.serialize())
+    /// Answers a `dr` request from the app with the current pin state
+    async fn digital_read_report(&mut self, pin: u8, high: bool) -> Result<()> {
+        self.digital_write(pin, high).await
     }
 
-    fn tweet(&mut self, msg: &str) -> Result<()> {
-        let msg = Message::new(MessageType::Tweet, self.msg_id(), None, None, vec![msg]);
-        self.send(msg.serialize())
+    /// Reports the value of a physical analog or PWM pin (`aw`)
+    async fn analog_write(&mut self, pin: u8, val: u16) -> Result<()> {
+        let msg = proto::analog_write(self.msg_id(), pin, val);
+        self.send(msg).await
     }
 
-    fn notify(&mut self, msg: &str) -> Result<()> {
-        let msg = Message::new(MessageType::Notify, self.msg_id(), None, None, vec![msg]);
-        self.send(msg.serialize())
+    /// Announces how physical pins are configured (`pm`)
+    async fn pin_mode(&mut self, pins: &[(u8, PinMode)]) -> Result<()> {
+        let msg = proto::pin_mode(self.msg_id(), pins);
+        self.send(msg).await
     }
 
-    fn set_property(&mut self, pin: u8, prop: &str, val: &str) -> Result<()> {
-        let msg = Message::new(
-            MessageType::Property,
+    /// Writes several values to the pin in one message
+    async fn virtual_write_multi<N: Into<VirtualPin> + Send>(
+        &mut self,
+        v_pin: N,
+        vals: &[&str],
+    ) -> Result<()> {
+        let v_pin = v_pin.into().number();
+        let msg = proto::virtual_write_multi(self.msg_id(), v_pin, vals);
+        self.send(msg).await
+    }
+
+    /// Like `virtual_write_multi` for values of any `Display` type
+    async fn virtual_write_values<N, V>(&mut self, v_pin: N, vals: &[V]) -> Result<()>
+    where
+        N: Into<VirtualPin> + Send,
+        V: std::fmt::Display + Sync,
+    {
+        let vals: Vec<String> = vals.iter().map(|v| v.to_string()).collect();
+        let vals: Vec<&str> = vals.iter().map(String::as_str).collect();
+        self.virtual_write_multi(v_pin, &vals).await
+    }
+
+    /// Reports the position as `lat lon alt speed`, the format of the GPS
+    /// Stream widget, for the map and location datastreams
+    async fn report_location<N: Into<VirtualPin> + Send>(
+        &mut self,
+        v_pin: N,
+        location: &Location,
+    ) -> Result<()> {
+        let Location {
+            lat,
+            lon,
+            alt,
+            speed,
+        } = *location;
+        self.virtual_write_values(v_pin, &[lat, lon, alt, speed])
+            .await
+    }
+
+    /// Writes a value too long for a single message, e.g. terminal output,
+    /// as several writes that each fit `conf::MAX_BODY_LEN`
+    async fn virtual_write_chunked<N: Into<VirtualPin> + Send>(
+        &mut self,
+        v_pin: N,
+        val: &str,
+    ) -> Result<()> {
+        let v_pin = v_pin.into().number();
+        let max = conf::MAX_BODY_LEN - format!("vw\0{}\0", v_pin).len();
+        for chunk in proto::chunks(val, max) {
+            self.virtual_write(v_pin, chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes a binary value, e.g. a blob that doesn't round-trip as text
+    async fn virtual_write_raw<N: Into<VirtualPin> + Send>(
+        &mut self,
+        v_pin: N,
+        val: &[u8],
+    ) -> Result<()> {
+        let v_pin = v_pin.into().number();
+        let msg = proto::virtual_write_raw(self.msg_id(), v_pin, val);
+        self.send(msg).await
+    }
+
+    async fn virtual_sync<I, N>(&mut self, pins: I) -> Result<()>
+    where
+        I: IntoIterator<Item = N> + Send,
+        N: Into<VirtualPin>,
+    {
+        let pins: Vec<u8> = pins.into_iter().map(|pin| pin.into().number()).collect();
+        let msg = proto::virtual_sync(self.msg_id(), &pins);
+        self.send(msg).await
+    }
+
+    /// Asks the server to resend the values of all pins, they arrive as
+    /// regular pin writes
+    async fn sync_all(&mut self) -> Result<()> {
+        let msg = proto::sync_all(self.msg_id());
+        self.send(msg).await
+    }
+
+    async fn email(&mut self, to: &str, subject: &str, body: &str) -> Result<()> {
+        let msg = proto::command(MessageType::Email, self.msg_id(), vec![to, subject, body]);
+        self.send(msg).await
+    }
+
+    async fn tweet(&mut self, msg: &str) -> Result<()> {
+        let msg = proto::command(MessageType::Tweet, self.msg_id(), vec![msg]);
+        self.send(msg).await
+    }
+
+    async fn notify(&mut self, msg: &str) -> Result<()> {
+        let msg = proto::command(MessageType::Notify, self.msg_id(), vec![msg]);
+        self.send(msg).await
+    }
+
+    /// Sends a push notification validated with `Notification` builder
+    async fn notification(&mut self, notification: &Notification) -> Result<()> {
+        self.notify(notification.as_str()).await
+    }
+
+    /// Logs the event `code` defined in the device template, which
+    /// triggers the notifications and automations set up for it in the
+    /// console. Severity is part of the event definition there. The
+    /// server accepts 100 events a day, further ones fail with
+    /// `BlynkError::EventLimit`
+    async fn log_event(&mut self, code: &str, description: &str) -> Result<()> {
+        let msg = proto::log_event(self.msg_id(), code, description);
+        self.send(msg).await
+    }
+
+    async fn set_property<N: Into<VirtualPin> + Send>(
+        &mut self,
+        pin: N,
+        prop: &str,
+        val: &str,
+    ) -> Result<()> {
+        let pin = pin.into().number();
+        let msg = proto::set_property(self.msg_id(), pin, prop, val);
+        self.send(msg).await
+    }
+
+    /// Starts a group of writes the server applies together, all stamped
+    /// with `timestamp` (time of arrival if `None`), until `end_group`
+    async fn begin_group(&mut self, timestamp: Option<SystemTime>) -> Result<()> {
+        let msg = proto::begin_group(self.msg_id(), timestamp.map(unix_millis));
+        self.send(msg).await
+    }
+
+    async fn end_group(&mut self) -> Result<()> {
+        let msg = proto::end_group(self.msg_id());
+        self.send(msg).await
+    }
+
+    /// Writes the values of several virtual pins as one group, so e.g.
+    /// the axes of a sensor are never shown partially updated
+    async fn virtual_write_group(
+        &mut self,
+        values: &[(VirtualPin, &str)],
+        timestamp: Option<SystemTime>,
+    ) -> Result<()> {
+        let mut msgs = vec![proto::begin_group(
             self.msg_id(),
-            None,
-            None,
-            vec![&pin.to_string(), prop, val],
-        );
-        self.send(msg.serialize())
+            timestamp.map(unix_millis),
+        )];
+        for (pin, val) in values {
+            msgs.push(proto::virtual_write(self.msg_id(), pin.number(), val));
+        }
+        msgs.push(proto::end_group(self.msg_id()));
+        self.send_all(msgs).await
     }
 
-    fn internal(&mut self, data: Vec<&str>) -> Result<()> {
-        let msg = Message::new(MessageType::Internal, self.msg_id(), None, None, data);
-        self.send(msg.serialize())
+    /// Writes a value measured at `time`, e.g. while the device was
+    /// offline, so it lands at the right place in the charts
+    async fn virtual_write_at<N: Into<VirtualPin> + Send>(
+        &mut self,
+        pin: N,
+        val: &str,
+        time: SystemTime,
+    ) -> Result<()> {
+        self.virtual_write_group(&[(pin.into(), val)], Some(time))
+            .await
     }
 
-    fn send(&mut self, msg: Vec<u8>) -> Result<()> {
+    /// Uploads buffered `(time, value)` samples of the pin at once, each
+    /// one stamped with its own time
+    async fn virtual_write_batch<N: Into<VirtualPin> + Send>(
+        &mut self,
+        pin: N,
+        samples: &[(SystemTime, &str)],
+    ) -> Result<()> {
+        let pin = pin.into().number();
+        let mut msgs = Vec::with_capacity(samples.len() * 3);
+        for (time, val) in samples {
+            msgs.push(proto::begin_group(self.msg_id(), Some(unix_millis(*time))));
+            msgs.push(proto::virtual_write(self.msg_id(), pin, val));
+            msgs.push(proto::end_group(self.msg_id()));
+        }
+        self.send_all(msgs).await
+    }
+
+    /// Sets the `color` property of the widget on `pin`
+    async fn set_color<N: Into<VirtualPin> + Send>(&mut self, pin: N, color: Color) -> Result<()> {
+        self.set_property(pin, "color", &color.to_string()).await
+    }
+
+    /// Changes several properties of the widget on `pin` at once, e.g.
+    /// color and label on a state change, the messages go out together
+    async fn set_properties<N: Into<VirtualPin> + Send>(
+        &mut self,
+        pin: N,
+        props: &[(&str, &str)],
+    ) -> Result<()> {
+        let pin = pin.into().number();
+        let msgs = props
+            .iter()
+            .map(|(prop, val)| proto::set_property(self.msg_id(), pin, prop, val))
+            .collect();
+        self.send_all(msgs).await
+    }
+
+    async fn internal(&mut self, data: Vec<&str>) -> Result<()> {
+        let msg = proto::command(MessageType::Internal, self.msg_id(), data);
+        self.send(msg).await
+    }
+
+    /// Asks the server for the time zone set in the app, it arrives in
+    /// `Event::handle_timezone`
+    async fn request_timezone(&mut self) -> Result<()> {
+        let msgs = ["tz_name", "tz", "tz_rule"]
+            .iter()
+            .map(|query| proto::command(MessageType::Internal, self.msg_id(), vec!["utc", query]))
+            .collect();
+        self.send_all(msgs).await
+    }
+
+    /// Asks the server for the current time, it arrives in `Event::handle_rtc`
+    async fn request_rtc(&mut self) -> Result<()> {
+        self.internal(vec!["rtc"]).await
+    }
+
+    async fn send(&mut self, msg: Vec<u8>) -> Result<()> {
+        proto::check_size(&msg)?;
         let mut retries = conf::RETRIES_TX_MAX_NUM;
         let stream = self.stream()?;
         while retries > 0 {
-            if let Err(err) = stream.write(&msg) {
+            if let Err(err) = stream.write(&msg).await {
                 error!("Problem sending!: {}", err);
                 retries -= 1;
-                thread::sleep(conf::RETRIES_TX_DELAY);
+                rt::sleep(conf::RETRIES_TX_DELAY).await;
                 continue;
             }
-            if let Err(err) = stream.flush() {
+            if let Err(err) = stream.flush().await {
                 error!("Problem sending!: {}", err);
                 retries -= 1;
-                thread::sleep(conf::RETRIES_TX_DELAY);
+                rt::sleep(conf::RETRIES_TX_DELAY).await;
                 continue;
             }
             debug!("Sent message, awaiting reply...!!");
@@ -215,38 +732,336 @@ pub trait Protocol {
         }
         Err(BlynkError::MessageSend)
     }
+
+    /// Sends the messages one after another, stops at the first failure
+    async fn send_all(&mut self, msgs: Vec<Vec<u8>>) -> Result<()> {
+        for msg in msgs {
+            self.send(msg).await?;
+        }
+        Ok(())
+    }
 }
 
+/// Building blocks for commands that are not part of `Protocol`, e.g.
+/// custom internal messages understood by a forked server. Implemented
+/// for every `Protocol`, so the messages go through the same message id,
+/// queueing and retry logic as the built-in ones
+///
+/// # Example
+#[cfg_attr(not(feature = "async"), doc = "```")]
+#[cfg_attr(feature = "async", doc = "```ignore")]
+/// use blynk_io::{BlynkError, Client, ProtocolExt};
+///
+/// trait Relay {
+///     fn relay_toggle(&mut self, relay: u8) -> Result<u16, BlynkError>;
+/// }
+///
+/// impl Relay for Client {
+///     fn relay_toggle(&mut self, relay: u8) -> Result<u16, BlynkError> {
+///         self.internal_command("relay", &["toggle", &relay.to_string()])
+///     }
+/// }
+/// ```
+#[maybe_async]
+pub trait ProtocolExt: Protocol + sealed::Sealed {
+    /// Sends a message of any type, returns the message id it was sent with
+    async fn command(&mut self, mtype: MessageType, body: Vec<&str>) -> Result<u16> {
+        let id = self.msg_id();
+        self.send(proto::command(mtype, id, body)).await?;
+        Ok(id)
+    }
+
+    /// Sends an internal message named `name` with `args`
+    async fn internal_command(&mut self, name: &str, args: &[&str]) -> Result<u16> {
+        let mut body = vec![name];
+        body.extend_from_slice(args);
+        self.command(MessageType::Internal, body).await
+    }
+}
+
+impl<P: Protocol> ProtocolExt for P {}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl<P: super::Protocol> Sealed for P {}
+}
+
+/// Connection driven by `Blynk`, implemented by `Client`. Another
+/// implementation, e.g. a test double or a transport the crate doesn't
+/// support, reuses the connection, heartbeat and dispatch logic of `Blynk`.
+/// Only `open` is required, the rest defaults to a connection without an
+/// outgoing queue, retransmits or middleware
+#[allow(unused_variables)]
+#[maybe_async]
+pub trait Connection: Protocol {
+    /// Connects to one of `addrs` (the proxy if one is configured) and
+    /// applies the settings of `config`
+    async fn open<S: ConfigSource + Sync>(
+        &mut self,
+        addrs: Vec<SocketAddr>,
+        config: &S,
+    ) -> Result<()>;
+
+    fn set_read_timeout(&mut self, duration: Duration) {}
+
+    /// Marks the handshake as started or completed
+    fn set_handshake(&mut self, handshake: bool) {}
+
+    /// Writes the queued messages
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Queues again messages still not acknowledged, fails once they
+    /// ran out of attempts
+    async fn retransmit(&mut self, now: Instant, heartbeat: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sends the messages held back by a rate limit
+    async fn release_throttled(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Message received while waiting for other responses
+    fn take_pending(&mut self) -> Option<Message> {
+        None
+    }
+
+    /// Returns true if the next message can be read right away
+    fn has_buffered_message(&self) -> bool {
+        false
+    }
+
+    /// Marks the message `id` as acknowledged, returns its type if the
+    /// response was expected
+    fn acknowledge(&mut self, id: u16) -> Option<MessageType> {
+        None
+    }
+
+    fn has_middleware(&self) -> bool {
+        false
+    }
+
+    /// Runs a received message through the middleware, `None` if it was
+    /// dropped
+    fn intercept(&mut self, msg: Message) -> Option<Message> {
+        Some(msg)
+    }
+
+    /// Values recorded for the history requests of the server
+    fn history(&self) -> Option<&PinHistory> {
+        None
+    }
+}
+
+#[maybe_async]
+impl Connection for Client {
+    async fn open<S: ConfigSource + Sync>(
+        &mut self,
+        addrs: Vec<SocketAddr>,
+        config: &S,
+    ) -> Result<()> {
+        let stream = Stream::connect(addrs, config).await?;
+        let capacity = config.rcv_buffer().into();
+        self.set_reader(BufReader::with_capacity(capacity, stream));
+        self.set_write_policy(config.write_policy());
+        self.set_retransmit(config.retransmit());
+        self.set_notify_limit(config.notify_limit());
+        self.set_max_in_flight(config.max_in_flight());
+        self.set_coalesced_pins(config.coalesced_pins().iter().copied());
+        Ok(())
+    }
+
+    fn set_read_timeout(&mut self, duration: Duration) {
+        Client::set_read_timeout(self, duration);
+    }
+
+    /// Marks the handshake as started or completed, queued writes are
+    /// sent with the next flush once it completes
+    fn set_handshake(&mut self, handshake: bool) {
+        self.outbox.set_handshake(handshake);
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Client::flush(self).await
+    }
+
+    /// Queues again messages still not acknowledged, fails once they
+    /// ran out of attempts
+    async fn retransmit(&mut self, now: Instant, heartbeat: Duration) -> Result<()> {
+        let Some(retransmits) = &mut self.retransmits else {
+            return Ok(());
+        };
+        let Some(due) = retransmits.due(now, heartbeat) else {
+            return Err(io::Error::from(ErrorKind::TimedOut).into());
+        };
+        if due.is_empty() {
+            return Ok(());
+        }
+        for msg in due {
+            debug!("Re-sending unacknowledged message");
+            self.outbox.push(msg);
+        }
+        self.flush().await
+    }
+
+    /// Sends the notifications held back by the rate limit once their
+    /// interval passed
+    async fn release_throttled(&mut self) -> Result<()> {
+        let due = match &mut self.throttle {
+            Some(throttle) => throttle.due(Instant::now()),
+            None => return Ok(()),
+        };
+        for msg in due {
+            self.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    fn take_pending(&mut self) -> Option<Message> {
+        self.pending.pop_front()
+    }
+
+    fn has_buffered_message(&self) -> bool {
+        Client::has_buffered_message(self)
+    }
+
+    /// Marks the message `id` as acknowledged, returns its type if the
+    /// response was expected
+    fn acknowledge(&mut self, id: u16) -> Option<MessageType> {
+        self.outbox.ack(id);
+        if let Some(retransmits) = &mut self.retransmits {
+            retransmits.ack(id);
+        }
+        self.responses.resolve(id)
+    }
+
+    fn has_middleware(&self) -> bool {
+        !self.middleware.is_empty()
+    }
+
+    fn intercept(&mut self, msg: Message) -> Option<Message> {
+        self.middleware.incoming(msg)
+    }
+
+    fn history(&self) -> Option<&PinHistory> {
+        Client::history(self)
+    }
+}
+
+#[maybe_async]
 impl Protocol for Client {
-    type T = TcpStream;
+    type T = Stream;
 
-    fn set_reader(&mut self, reader: BufReader<TcpStream>) {
+    fn set_reader(&mut self, reader: BufReader<Stream>) {
         self.reader = Some(reader);
     }
 
-    fn reader(&mut self) -> Option<&mut BufReader<TcpStream>> {
+    fn reader(&mut self) -> Option<&mut BufReader<Stream>> {
         self.reader.as_mut()
     }
 
+    #[cfg(feature = "async")]
+    async fn read_frame(&mut self, frame: &mut Vec<u8>) -> Result<()> {
+        let reader = self.reader.as_mut().ok_or(BlynkError::ReaderNotAvailable)?;
+        rt::read_frame_within(reader, frame, self.read_timeout).await
+    }
+
     fn msg_id(&mut self) -> u16 {
-        self.msg_id += 1;
-        self.msg_id
+        // after a wraparound skip ids still waiting for a response
+        loop {
+            self.msg_id = proto::next_id(self.msg_id);
+            if !self.responses.contains(self.msg_id) {
+                return self.msg_id;
+            }
+        }
     }
 
     fn disconnect(&mut self) {
-        if let Ok(stream) = self.stream() {
-            stream
-                .shutdown(Shutdown::Both)
-                .unwrap_or_else(|err| error!("shutdown call failed, with err {}", err));
+        // take the reader so the socket is shut down only once and dropped
+        // right after, later calls are no-ops
+        if let Some(reader) = self.reader.take() {
+            match reader.into_inner().shutdown(Shutdown::Both) {
+                Ok(()) => (),
+                // server closed the connection first
+                Err(err) if err.kind() == ErrorKind::NotConnected => {
+                    debug!("socket already closed")
+                }
+                Err(err) => error!("shutdown call failed, with err {}", err),
+            }
+        }
+        self.pending.clear();
+        self.outbox.clear();
+        self.responses.clear();
+        if let Some(retransmits) = &mut self.retransmits {
+            retransmits.clear();
         }
         self.msg_id = 0;
     }
+
+    async fn virtual_write<N, V>(&mut self, v_pin: N, val: V) -> Result<()>
+    where
+        N: Into<VirtualPin> + Send,
+        V: IntoBlynkValue + Send,
+    {
+        let (v_pin, val) = (v_pin.into().number(), val.into_blynk_value());
+        if let Some(history) = &mut self.history {
+            history.record(v_pin, SystemTime::now(), &val);
+        }
+        let msg = proto::virtual_write(self.msg_id(), v_pin, &val);
+        self.send(msg).await
+    }
+
+    async fn send(&mut self, msg: Vec<u8>) -> Result<()> {
+        if self.enqueue(msg).await? {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn send_all(&mut self, msgs: Vec<Vec<u8>>) -> Result<()> {
+        let mut queued = false;
+        for msg in msgs {
+            queued |= self.enqueue(msg).await?;
+        }
+        if queued {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+impl<C: Connection> session::Responses for C {
+    fn acknowledge(&mut self, id: u16) -> Option<MessageType> {
+        Connection::acknowledge(self, id)
+    }
+
+    fn history(&self) -> Option<&PinHistory> {
+        Connection::history(self)
+    }
+}
+
+/// Milliseconds since the Unix epoch, 0 for earlier times
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::{Cursor, SeekFrom};
+    use crate::message::{ProtocolHeader, ProtocolStatus};
+    use crate::{pins, testing};
+    #[cfg(feature = "async")]
+    use smol::io::{AsyncBufReadExt, AsyncSeekExt, Cursor, SeekFrom};
+    #[cfg(not(feature = "async"))]
+    use std::io::{BufRead, Cursor, Seek, SeekFrom};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
 
     pub struct FakeClient {
         msg_id: u16,
@@ -259,7 +1074,7 @@ mod tests {
         fn set_reader(&mut self, _reader: BufReader<Self::T>) {}
 
         fn reader(&mut self) -> Option<&mut BufReader<Self::T>> {
-            return self.reader.as_mut();
+            self.reader.as_mut()
         }
 
         fn msg_id(&mut self) -> u16 {
@@ -272,35 +1087,57 @@ mod tests {
         }
     }
 
-    #[test]
-    fn msg_id_incremeneted_on_send() {
+    /// Client connected to `listener` and the server end of the connection
+    fn connected(listener: &TcpListener) -> (Client, TcpStream) {
+        let mut client = Client::default();
+        let sock = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_stream(testing::stream(sock));
+        (client, listener.accept().unwrap().0)
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn msg_id_incremeneted_on_send() {
         let mut client = Client {
             msg_id: 3,
-            reader: None,
+            ..Default::default()
         };
-        client.ping().unwrap_or_default();
+        client.ping().await.unwrap_or_default();
         assert_eq!(4, client.msg_id)
     }
-    #[test]
-    fn msg_id_customized() {
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn msg_id_wraps_around_skipping_pending() {
+        let mut client = Client {
+            msg_id: u16::MAX,
+            ..Default::default()
+        };
+        client.responses.track(&proto::virtual_write(1, 1, "on"));
+        assert_eq!(2, client.msg_id());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn msg_id_customized() {
         let mut client = Client {
             msg_id: 3,
-            reader: None,
+            ..Default::default()
         };
-        client.response(200, 42).unwrap_or_default();
+        client.response(200, 42).await.unwrap_or_default();
         // inspect the message
         assert_eq!(3, client.msg_id)
     }
-    #[test]
-    fn propagate_send_err() {
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn propagate_send_err() {
         let mut client = Client {
             msg_id: 3,
-            reader: None,
+            ..Default::default()
         };
-        assert!(client.ping().is_err());
+        let res = client.ping().await;
+        assert!(res.is_err());
     }
-    #[test]
-    fn ping_generates_seralized_message() {
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn ping_generates_seralized_message() {
         let reader = BufReader::with_capacity(10, Cursor::new(vec![0; 10]));
         let mut client = FakeClient {
             msg_id: 0,
@@ -308,30 +1145,51 @@ mod tests {
         };
 
         // intercept message into fake client
-        client.ping().unwrap();
+        client.ping().await.unwrap();
 
         let mut reader = client.reader.unwrap();
-        reader.seek(SeekFrom::Start(0)).unwrap(); // rewind the buffer
-        let buf = reader.fill_buf().unwrap();
+        reader.seek(SeekFrom::Start(0)).await.unwrap(); // rewind the buffer
+        let buf = reader.fill_buf().await.unwrap();
 
         let msg = Message::new(MessageType::Ping, 1, None, None, vec![""]);
         let data = msg.serialize();
         // compare generated headers
         assert_eq!(&data[..5], &buf[..5]);
     }
-    #[test]
-    fn read_empty_buffer_errors() {
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn heartbeat_appends_device_id() {
+        let reader = BufReader::with_capacity(128, Cursor::new(vec![0; 128]));
+        let mut client = FakeClient {
+            msg_id: 0,
+            reader: Some(reader),
+        };
+        client
+            .heartbeat_with_id(Duration::from_secs(5), 1024, Some("kitchen"))
+            .await
+            .unwrap();
+
+        let mut reader = client.reader.unwrap();
+        reader.seek(SeekFrom::Start(0)).await.unwrap();
+        let buf = reader.fill_buf().await.unwrap();
+        let body = String::from_utf8_lossy(&buf[ProtocolHeader::SIZE..]);
+        assert!(body.contains("dev\0rust-kitchen\0"));
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn read_empty_buffer_errors() {
         // try to read when the buffer is empty
         let reader = BufReader::with_capacity(0, Cursor::new(vec![0]));
         let mut client = FakeClient {
             msg_id: 0,
             reader: Some(reader),
         };
-        let err = client.read().err().unwrap();
+        let err = client.read().await.err().unwrap();
         assert_eq!("No message to process", err.to_string());
     }
-    #[test]
-    fn read_message() {
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn read_message() {
         // succesful message read
 
         // put fake message into the buff
@@ -343,6 +1201,371 @@ mod tests {
             msg_id: 0,
             reader: Some(reader),
         };
-        assert!(client.read().is_ok());
+        let res = client.read().await;
+        assert!(res.is_ok());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn read_message_split_across_reads() {
+        let msg = Message::new(
+            MessageType::Hw,
+            1,
+            None,
+            None,
+            vec!["vw", "1", "long value"],
+        );
+        let mut data = msg.serialize();
+        let status = u16::from(ProtocolStatus::StatusOk);
+        ProtocolHeader::write_to((MessageType::Rsp as u8, 2, status), &mut data).unwrap();
+        // the buffer only ever holds a part of the message
+        let reader = BufReader::with_capacity(4, Cursor::new(data));
+        let mut client = FakeClient {
+            msg_id: 0,
+            reader: Some(reader),
+        };
+        let msg = client.read().await.unwrap();
+        assert_eq!(Some((1, "long value")), msg.virtual_write_value());
+        let msg = client.read().await.unwrap();
+        assert!(matches!(msg.mtype, MessageType::Rsp));
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn read_virtual_many_collects_values() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 64];
+            let size = stream.read(&mut buf).unwrap();
+            assert_eq!(MessageType::HwSync as u8, buf[0]);
+            assert_eq!(b"vr\x001\x0012", &buf[ProtocolHeader::SIZE..size]);
+
+            let replies = vec![
+                Message::new(MessageType::Hw, 1, None, None, vec!["vw", "12", "b"]),
+                Message::new(MessageType::Hw, 2, None, None, vec!["vw", "7", "x"]),
+                Message::new(MessageType::Hw, 3, None, None, vec!["vw", "1", "a"]),
+            ];
+            for reply in replies {
+                stream.write_all(&reply.serialize()).unwrap();
+            }
+            stream
+        });
+
+        let mut client = Client::default();
+        client.set_stream(testing::stream(TcpStream::connect(addr).unwrap()));
+        let values = client
+            .read_virtual_many([pins::V1, pins::V12], Duration::from_secs(1))
+            .await
+            .unwrap();
+        let _stream = server.join().unwrap();
+
+        assert_eq!(Some(&"a".to_string()), values.get(&pins::V1));
+        assert_eq!(Some(&"b".to_string()), values.get(&pins::V12));
+        assert_eq!(2, values.len());
+
+        // unrelated message is kept for regular processing
+        let msg = client.next_message().await.unwrap();
+        assert_eq!(vec!["vw", "7", "x"], msg.body);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn splits_into_reader_and_writer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // echoes every write back
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            std::io::copy(&mut stream.try_clone().unwrap(), &mut stream).unwrap();
+        });
+
+        let mut client = Client::default();
+        client.set_stream(testing::stream(TcpStream::connect(addr).unwrap()));
+        assert!(matches!(
+            Client::default().split(),
+            Err(BlynkError::StreamIsNone)
+        ));
+        let (mut reader, mut writer) = client.split().unwrap();
+        writer.virtual_write(1, "on").await.unwrap();
+        writer.notify("hi").await.unwrap();
+        let msg = reader.read().await.unwrap();
+        assert_eq!(vec!["vw", "1", "on"], msg.body);
+        let msg = reader.read().await.unwrap();
+        assert!(matches!(msg.mtype, MessageType::Notify));
+        writer.disconnect();
+        drop(reader);
+        server.join().unwrap();
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn virtual_write_acked_waits_for_ping_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let read_header = |stream: &mut TcpStream| {
+                let (mtype, id, len) = ProtocolHeader::read_from(stream).unwrap();
+                stream.read_exact(&mut vec![0; len as usize]).unwrap();
+                (mtype, id)
+            };
+            // the server never answers a successful write, only the ping
+            let (mtype, _) = read_header(&mut stream);
+            assert_eq!(MessageType::Hw as u8, mtype);
+            let (mtype, ping) = read_header(&mut stream);
+            assert_eq!(MessageType::Ping as u8, mtype);
+            let other = Message::new(MessageType::Hw, 9, None, None, vec!["vw", "7", "x"]);
+            stream.write_all(&other.serialize()).unwrap();
+            let rsp = (
+                MessageType::Rsp as u8,
+                ping,
+                u16::from(ProtocolStatus::StatusOk),
+            );
+            ProtocolHeader::write_to(rsp, &mut stream).unwrap();
+
+            // a rejected write is answered with an error status
+            let (_, id) = read_header(&mut stream);
+            let (_, ping) = read_header(&mut stream);
+            stream.write_all(&other.serialize()).unwrap();
+            let quota = u16::from(ProtocolStatus::StatusQuotaLimit);
+            ProtocolHeader::write_to((MessageType::Rsp as u8, id, quota), &mut stream).unwrap();
+            let rsp = (
+                MessageType::Rsp as u8,
+                ping,
+                u16::from(ProtocolStatus::StatusOk),
+            );
+            ProtocolHeader::write_to(rsp, &mut stream).unwrap();
+        });
+
+        let mut client = Client::default();
+        client.set_stream(testing::stream(TcpStream::connect(addr).unwrap()));
+        let timeout = Duration::from_secs(1);
+        client.virtual_write_acked(1, "on", timeout).await.unwrap();
+        let err = client
+            .virtual_write_acked(1, "off", timeout)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            BlynkError::Rejected(ProtocolStatus::StatusQuotaLimit)
+        ));
+        server.join().unwrap();
+
+        // unrelated messages are kept for regular processing
+        let msg = client.next_message().await.unwrap();
+        assert_eq!(vec!["vw", "7", "x"], msg.body);
+        assert_eq!(1, client.pending.len());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn virtual_write_acked_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+        let timeout = Duration::from_millis(100);
+        let err = client
+            .virtual_write_acked(1, "off", timeout)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BlynkError::Io(err) if err.kind() == ErrorKind::TimedOut));
+        // the write and the ping went out, the ping was just never answered
+        let (mtype, _, len) = ProtocolHeader::read_from(&mut server).unwrap();
+        assert_eq!(MessageType::Hw as u8, mtype);
+        server.read_exact(&mut vec![0; len as usize]).unwrap();
+        let (mtype, _, _) = ProtocolHeader::read_from(&mut server).unwrap();
+        assert_eq!(MessageType::Ping as u8, mtype);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn high_water_mark_callback_fires_once() {
+        let crossed = Arc::new(Mutex::new(vec![]));
+        let mut client = Client::default();
+        let calls = crossed.clone();
+        client.set_high_water_mark(2, move |depth| calls.lock().unwrap().push(depth));
+
+        for _ in 0..3 {
+            client.outbox.push(vec![0; 5]);
+        }
+        assert_eq!(3, client.queue_depth());
+        assert_eq!(vec![2], *crossed.lock().unwrap());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn buffers_writes_during_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+
+        client.set_handshake(true);
+        client.virtual_write(1, "x").await.unwrap();
+        assert_eq!(1, client.queue_depth());
+        client.login("token").await.unwrap();
+
+        let mut buf = [0; ProtocolHeader::SIZE + 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(MessageType::Login as u8, buf[0]);
+
+        client.set_handshake(false);
+        client.flush().await.unwrap();
+        server.read_exact(&mut buf[..ProtocolHeader::SIZE]).unwrap();
+        assert_eq!(MessageType::Hw as u8, buf[0]);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn rejects_writes_during_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, _server) = connected(&listener);
+        client.set_write_policy(WritePolicy::Reject);
+
+        client.set_handshake(true);
+        let res = client.virtual_write(1, "x").await;
+        assert!(matches!(res, Err(BlynkError::Handshake)));
+        assert_eq!(0, client.queue_depth());
+        let res = client.login("token").await;
+        assert!(res.is_ok());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn sends_custom_internal_command() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+        client.msg_id = 6;
+
+        let id = client
+            .internal_command("relay", &["toggle", "2"])
+            .await
+            .unwrap();
+        assert_eq!(7, id);
+
+        let mut buf = [0; ProtocolHeader::SIZE + 14];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!([MessageType::Internal as u8, 0, 7, 0, 14], buf[..5]);
+        assert_eq!(b"relay\x00toggle\x002", &buf[5..]);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn writes_raw_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+
+        client.virtual_write_raw(2, &[0x80, 0, 0xff]).await.unwrap();
+
+        let mut buf = [0; ProtocolHeader::SIZE + 8];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!([MessageType::Hw as u8, 0, 1, 0, 8], buf[..5]);
+        assert_eq!(b"vw\x002\x00\x80\x00\xff", &buf[5..]);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn sets_several_properties_together() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+
+        client
+            .set_properties(3, &[("color", "#FF0000"), ("label", "Hot")])
+            .await
+            .unwrap();
+
+        let mut buf = [0; 2 * ProtocolHeader::SIZE + 15 + 11];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!([MessageType::Property as u8, 0, 1, 0, 15], buf[..5]);
+        assert_eq!(b"3\x00color\x00#FF0000", &buf[5..20]);
+        assert_eq!([MessageType::Property as u8, 0, 2, 0, 11], buf[20..25]);
+        assert_eq!(b"3\x00label\x00Hot", &buf[25..]);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn writes_values_as_one_group() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+
+        let time = UNIX_EPOCH + Duration::from_millis(1700000000123);
+        client
+            .virtual_write_group(&[(pins::V1, "0.5"), (pins::V2, "-1")], Some(time))
+            .await
+            .unwrap();
+
+        let mut buf = [0; 4 * ProtocolHeader::SIZE + 15 + 8 + 7 + 1];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!([MessageType::Group as u8, 0, 1, 0, 15], buf[..5]);
+        assert_eq!(b"b\x001700000000123", &buf[5..20]);
+        assert_eq!(b"vw\x001\x000.5", &buf[25..33]);
+        assert_eq!(b"vw\x002\x00-1", &buf[38..45]);
+        assert_eq!([MessageType::Group as u8, 0, 4, 0, 1, b'e'], buf[45..]);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn uploads_samples_with_their_times() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        client
+            .virtual_write_batch(4, &[(at(1000), "20"), (at(1060), "21")])
+            .await
+            .unwrap();
+
+        // begin (b, 1000000), write (vw, 4, 20) and end per sample
+        let mut buf = [0; 6 * ProtocolHeader::SIZE + 2 * (9 + 7 + 1)];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(b"b\x001000000", &buf[5..14]);
+        assert_eq!(b"vw\x004\x0020", &buf[19..26]);
+        assert_eq!(b"b\x001060000", &buf[37..46]);
+        assert_eq!(b"vw\x004\x0021", &buf[51..58]);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn flush_drains_outgoing_queue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+
+        client.outbox.push(vec![1; 3]);
+        client.outbox.push(vec![2; 2]);
+        assert_eq!(2, client.queue_depth());
+        client.flush().await.unwrap();
+        assert_eq!(0, client.queue_depth());
+
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!([1, 1, 1, 2, 2], buf);
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn disconnect_closes_socket_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = connected(&listener);
+        client.outbox.push(vec![1; 3]);
+
+        client.disconnect();
+        assert!(client.reader.is_none());
+        assert_eq!(0, client.queue_depth());
+        assert_eq!(0, server.read(&mut [0; 4]).unwrap());
+
+        client.disconnect();
+        let res = client.ping().await;
+        assert!(matches!(res, Err(BlynkError::StreamIsNone)));
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn disconnect_after_server_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = Client::default();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.shutdown(Shutdown::Both).unwrap();
+        client.set_stream(testing::stream(stream));
+        drop(listener.accept().unwrap());
+
+        client.disconnect();
+        assert!(client.reader.is_none());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn read_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, _server) = connected(&listener);
+
+        client.set_read_timeout(Duration::from_millis(10));
+        let res = client.read().await;
+        assert!(matches!(
+            res,
+            Err(BlynkError::Io(err))
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+        ));
     }
 }
@@ -1,10 +1,253 @@
 use log::*;
+use std::time::Duration;
+
+use crate::conf;
+use crate::Proxy;
+
+/// Connection settings consumed by the clients. Implemented by the
+/// runtime `Config` and by `StaticConfig` that is fixed at compile time
+#[allow(unused_variables)]
+pub trait ConfigSource {
+    fn token(&self) -> &str;
+    fn server(&self) -> &str;
+    fn port(&self) -> u64;
+
+    fn tls(&self) -> bool {
+        false
+    }
+
+    fn ca_cert(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn cert_pin(&self) -> Option<&str> {
+        None
+    }
+
+    fn insecure_skip_verify(&self) -> bool {
+        false
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        None
+    }
+
+    fn firmware(&self) -> Option<&FirmwareInfo> {
+        None
+    }
+
+    fn websocket(&self) -> Option<&str> {
+        None
+    }
+
+    fn mqtt(&self) -> bool {
+        false
+    }
+
+    fn proxy(&self) -> Option<&Proxy> {
+        None
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        WritePolicy::default()
+    }
+
+    /// Re-sending of messages the server didn't acknowledge, disabled
+    /// by default
+    fn retransmit(&self) -> Option<Retransmit> {
+        None
+    }
+
+    /// Maximum number of sent messages waiting for a response, further
+    /// writes are queued. Unlimited by default
+    fn max_in_flight(&self) -> Option<usize> {
+        None
+    }
+
+    /// Requests the stored values of all pins right after authentication
+    fn sync_on_connect(&self) -> bool {
+        false
+    }
+
+    /// Client side rate limit of notifications and emails, disabled by
+    /// default
+    fn notify_limit(&self) -> Option<NotifyLimit> {
+        None
+    }
+
+    /// Virtual pins whose writes still queued are replaced by newer ones,
+    /// none by default
+    fn coalesced_pins(&self) -> &[u8] {
+        &[]
+    }
+
+    /// Handling of errors returned by the `Event` hooks, logged by default
+    fn handler_error_policy(&self) -> HandlerErrorPolicy {
+        HandlerErrorPolicy::default()
+    }
+
+    /// Heartbeat period negotiated with the server
+    fn heartbeat(&self) -> Duration {
+        conf::HEARTBEAT_PERIOD
+    }
+
+    /// Size of the receive buffer, also announced to the server
+    fn rcv_buffer(&self) -> u16 {
+        1024
+    }
+
+    /// Points the configuration to the server the device was redirected
+    /// to, returns false if it can't be changed and the redirect is ignored
+    fn redirect(&mut self, server: &str, port: u64) -> bool {
+        false
+    }
+}
+
+/// What happens to writes issued while the client is connecting or
+/// authenticating, before the server accepted the device
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Fail with `BlynkError::Handshake`
+    Reject,
+    /// Queue the writes and send them once authenticated
+    #[default]
+    Buffer,
+    /// Send right away, they may interleave with the handshake
+    Allow,
+}
+
+/// Messages the server answers (logins, internal commands and logged
+/// events) not acknowledged within `heartbeats` heartbeat periods are sent
+/// again, up to `attempts` times before the connection is considered dead
+/// and reconnected. Pin writes and syncs get no response, so they are
+/// never sent again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retransmit {
+    pub heartbeats: u32,
+    pub attempts: u8,
+}
+
+impl Default for Retransmit {
+    fn default() -> Self {
+        Self {
+            heartbeats: 2,
+            attempts: 3,
+        }
+    }
+}
+
+/// Notifications and emails are sent at most once per `interval` each,
+/// the server throttles them too and drops the excess
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotifyLimit {
+    pub interval: Duration,
+    pub policy: LimitPolicy,
+}
+
+impl Default for NotifyLimit {
+    fn default() -> Self {
+        Self {
+            interval: conf::NOTIFY_INTERVAL,
+            policy: LimitPolicy::default(),
+        }
+    }
+}
+
+/// What happens to a notification or email sent too early
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Fail with `BlynkError::RateLimited`
+    #[default]
+    Reject,
+    /// Hold back the latest one and send it once the interval passed,
+    /// earlier ones held back are dropped
+    Queue,
+}
+
+/// What happens when an `Event` hook returns an error
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum HandlerErrorPolicy {
+    /// Log the error and carry on
+    #[default]
+    Log,
+    /// Also log it as the event with the given code of the device
+    /// template, see `Protocol::log_event`
+    LogEvent(String),
+    /// Drop the connection, the next `run` connects again
+    Reconnect,
+}
+
+/// Template and firmware metadata sent in the handshake. The Blynk IoT
+/// cloud links the device to its template through it and reports devices
+/// without it as running outdated firmware
+#[derive(Debug, Clone, Default)]
+pub struct FirmwareInfo {
+    /// Template id from the console (`TMPLxxxxxx`)
+    pub template_id: String,
+    pub version: String,
+    /// Build identifier, e.g. the build date
+    pub build: String,
+}
 
 #[derive(Debug)]
 pub struct Config {
     pub token: String,
     pub server: String,
     pub port: u64,
+    /// Wraps the connection in TLS (requires `tls` feature), `server`
+    /// is used as the name verified against the certificate
+    pub tls: bool,
+    /// PEM encoded CA bundle trusted instead of the default roots, for
+    /// self-hosted servers with their own CA
+    pub ca_cert: Option<Vec<u8>>,
+    /// SHA-256 fingerprint (hex, `:` separators allowed) of the server
+    /// certificate. When set only this certificate is accepted, which
+    /// also works for self-signed ones
+    pub cert_pin: Option<String>,
+    /// Connects without verifying the server certificate when neither
+    /// `ca_cert` nor `cert_pin` is set. Only the `embedded-tls` backend,
+    /// which has no bundled roots, reads it. Anyone on the network path
+    /// can then intercept the connection
+    pub insecure_skip_verify: bool,
+    /// Optional suffix sent in the handshake info (`dev` field), useful when
+    /// many devices run the same firmware against a self-hosted server
+    pub device_id: Option<String>,
+    /// Template id and firmware version announced to Blynk IoT
+    pub firmware: Option<FirmwareInfo>,
+    /// Path of the WebSocket endpoint (e.g. `/websocket`). When set the
+    /// connection is upgraded to WebSocket after the optional TLS
+    /// handshake (requires `websocket` feature), for networks that only
+    /// let HTTP(S) through
+    pub websocket: Option<String>,
+    /// Talks MQTT to the Blynk 2.0 broker instead of the binary protocol
+    /// (requires `mqtt` feature), `port` has to point to the MQTT
+    /// endpoint (1883, or 8883 with `tls`)
+    pub mqtt: bool,
+    /// HTTP CONNECT or SOCKS5 proxy the connection is tunneled through,
+    /// `server` and `port` then only have to be reachable by the proxy
+    pub proxy: Option<Proxy>,
+    /// Handling of writes issued before the handshake completes
+    pub write_policy: WritePolicy,
+    /// Re-sends commands the server didn't acknowledge on flaky links.
+    /// Pin writes are not covered, the server doesn't answer them, so
+    /// telemetry lost with a dropped connection isn't sent again
+    pub retransmit: Option<Retransmit>,
+    /// Caps the number of sent commands waiting for the server response,
+    /// so a fast producer doesn't overrun the server side buffer. Pin
+    /// writes, which get no response, and pings are never held back
+    pub max_in_flight: Option<usize>,
+    /// Syncs all pins once connected, the app state (relays, setpoints)
+    /// is then restored through the write handlers after a power cycle
+    pub sync_on_connect: bool,
+    /// Limits how often notifications and emails are sent, so a sensor
+    /// stuck in alarm doesn't get the device throttled or banned
+    pub notify_limit: Option<NotifyLimit>,
+    /// Virtual pins of fast changing values (RSSI, ADC readings) whose
+    /// writes still queued are replaced by newer ones, so only the latest
+    /// value is sent once the connection catches up
+    pub coalesced_pins: Vec<u8>,
+    /// What to do when a handler hook fails
+    pub handler_error_policy: HandlerErrorPolicy,
 }
 
 impl Default for Config {
@@ -13,10 +256,173 @@ impl Default for Config {
             token: "".to_string(),
             server: "blynk-cloud.com".to_string(),
             port: 80,
+            tls: false,
+            ca_cert: None,
+            cert_pin: None,
+            insecure_skip_verify: false,
+            device_id: None,
+            firmware: None,
+            websocket: None,
+            mqtt: false,
+            proxy: None,
+            write_policy: WritePolicy::default(),
+            retransmit: None,
+            max_in_flight: None,
+            sync_on_connect: false,
+            notify_limit: None,
+            coalesced_pins: Vec::new(),
+            handler_error_policy: HandlerErrorPolicy::default(),
+        }
+    }
+}
+
+impl ConfigSource for Config {
+    fn token(&self) -> &str {
+        &self.token
+    }
+
+    fn server(&self) -> &str {
+        &self.server
+    }
+
+    fn port(&self) -> u64 {
+        self.port
+    }
+
+    fn tls(&self) -> bool {
+        self.tls
+    }
+
+    fn ca_cert(&self) -> Option<&[u8]> {
+        self.ca_cert.as_deref()
+    }
+
+    fn cert_pin(&self) -> Option<&str> {
+        self.cert_pin.as_deref()
+    }
+
+    fn insecure_skip_verify(&self) -> bool {
+        self.insecure_skip_verify
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        self.device_id.as_deref()
+    }
+
+    fn firmware(&self) -> Option<&FirmwareInfo> {
+        self.firmware.as_ref()
+    }
+
+    fn websocket(&self) -> Option<&str> {
+        self.websocket.as_deref()
+    }
+
+    fn mqtt(&self) -> bool {
+        self.mqtt
+    }
+
+    fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        self.write_policy
+    }
+
+    fn retransmit(&self) -> Option<Retransmit> {
+        self.retransmit
+    }
+
+    fn max_in_flight(&self) -> Option<usize> {
+        self.max_in_flight
+    }
+
+    fn sync_on_connect(&self) -> bool {
+        self.sync_on_connect
+    }
+
+    fn notify_limit(&self) -> Option<NotifyLimit> {
+        self.notify_limit
+    }
+
+    fn coalesced_pins(&self) -> &[u8] {
+        &self.coalesced_pins
+    }
+
+    fn handler_error_policy(&self) -> HandlerErrorPolicy {
+        self.handler_error_policy.clone()
+    }
+
+    fn redirect(&mut self, server: &str, port: u64) -> bool {
+        self.server = server.into();
+        self.port = port;
+        true
+    }
+}
+
+/// Configuration fixed at compile time for flash constrained builds.
+/// Heartbeat period (in seconds) and receive buffer size are const
+/// generics, so they end up as constants in the binary.
+///
+/// # Example
+/// ```
+/// use blynk_io::{Blynk, DefaultHandler, StaticConfig};
+///
+/// const CONFIG: StaticConfig<10, 256> = StaticConfig::new("TOKEN", "blynk.cloud", 80);
+/// let blynk = Blynk::<DefaultHandler, _>::with_config(CONFIG);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct StaticConfig<const HEARTBEAT_S: u64, const BUF: usize> {
+    pub token: &'static str,
+    pub server: &'static str,
+    pub port: u64,
+    pub tls: bool,
+}
+
+impl<const HEARTBEAT_S: u64, const BUF: usize> StaticConfig<HEARTBEAT_S, BUF> {
+    const VALID: () = assert!(
+        HEARTBEAT_S > 0 && BUF > 0 && BUF <= u16::MAX as usize,
+        "heartbeat and buffer size must be positive, buffer must fit u16"
+    );
+
+    pub const fn new(token: &'static str, server: &'static str, port: u64) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::VALID;
+        Self {
+            token,
+            server,
+            port,
+            tls: false,
         }
     }
 }
 
+impl<const HEARTBEAT_S: u64, const BUF: usize> ConfigSource for StaticConfig<HEARTBEAT_S, BUF> {
+    fn token(&self) -> &str {
+        self.token
+    }
+
+    fn server(&self) -> &str {
+        self.server
+    }
+
+    fn port(&self) -> u64 {
+        self.port
+    }
+
+    fn tls(&self) -> bool {
+        self.tls
+    }
+
+    fn heartbeat(&self) -> Duration {
+        Duration::from_secs(HEARTBEAT_S)
+    }
+
+    fn rcv_buffer(&self) -> u16 {
+        BUF as u16
+    }
+}
+
 impl Config {
     pub fn new<T>(mut args: T) -> Result<Self, &'static str>
     where
@@ -51,6 +457,7 @@ impl Config {
             token,
             server,
             port,
+            ..Default::default()
         })
     }
 }
@@ -59,6 +466,16 @@ impl Config {
 mod tests {
     use super::*;
 
+    #[test]
+    fn static_config_from_const_generics() {
+        const CONFIG: StaticConfig<10, 256> = StaticConfig::new("token", "example.com", 8080);
+        assert_eq!("token", CONFIG.token());
+        assert_eq!(8080, CONFIG.port());
+        assert_eq!(Duration::from_secs(10), CONFIG.heartbeat());
+        assert_eq!(256, CONFIG.rcv_buffer());
+        assert_eq!(None, CONFIG.cert_pin());
+    }
+
     #[test]
     fn token_required() {
         let args = ["progname"].iter().map(|s| s.to_string());
@@ -70,7 +487,7 @@ mod tests {
     fn server_and_port_parsed() {
         let server = "example.com";
         let port = "1234";
-        let vec = vec!["pogname", "token", server, port];
+        let vec = ["pogname", "token", server, port];
         let args = vec.iter().map(|s| s.to_string());
         let conf = Config::new(args).unwrap();
         assert_eq!(server, conf.server);
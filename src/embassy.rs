@@ -0,0 +1,565 @@
+//! Embassy support for bare-metal async firmware
+//!
+//! `EmbassyClient` runs the protocol over any `embedded-io-async` socket,
+//! typically an `embassy_net::tcp::TcpSocket`, and schedules heartbeats with
+//! `embassy-time` timers. `EmbassyBlynk` adds connecting with a timeout and
+//! reconnecting on failures. The loop needs neither threads nor `Send`
+//! futures, so it runs as a regular Embassy task:
+//!
+//! ```ignore
+//! struct Net {
+//!     stack: Stack<'static>,
+//!     rx: [u8; 1024],
+//!     tx: [u8; 1024],
+//! }
+//!
+//! impl Connect for Net {
+//!     type Socket<'a> = TcpSocket<'a>;
+//!
+//!     async fn connect(&mut self) -> Result<TcpSocket<'_>, BlynkError> {
+//!         let mut socket = TcpSocket::new(self.stack, &mut self.rx, &mut self.tx);
+//!         socket.set_keep_alive(Some(embassy_time::Duration::from_secs(10)));
+//!         socket
+//!             .connect(SERVER)
+//!             .await
+//!             .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+//!         Ok(socket)
+//!     }
+//! }
+//!
+//! #[embassy_executor::task]
+//! async fn blynk(net: Net, config: StaticConfig) {
+//!     EmbassyBlynk::new(net).run(&config, &mut Handler).await
+//! }
+//! ```
+
+use std::io;
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use log::*;
+
+use crate::embedded::into_io_error;
+use crate::message::{Message, MessageRef, MessageType, ProtocolStatus};
+use crate::proto;
+use crate::{conf, BlynkError, ConfigSource, HandlerErrorPolicy, IntoBlynkValue, ParamList};
+use crate::{Result, VirtualPin};
+
+fn timed_out() -> BlynkError {
+    io::Error::from(io::ErrorKind::TimedOut).into()
+}
+
+fn duration(duration: std::time::Duration) -> Duration {
+    Duration::from_millis(duration.as_millis() as u64)
+}
+
+/// Callbacks of `EmbassyClient::run`, same as `Event` without `Send` bounds.
+/// Errors returned by the hooks are handled according to the
+/// `HandlerErrorPolicy` of the config
+// futures of firmware tasks are never sent between threads
+#[allow(async_fn_in_trait, unused_variables)]
+pub trait EmbassyEvent {
+    async fn handle_connect<S: Read + Write>(
+        &mut self,
+        client: &mut EmbassyClient<S>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn handle_disconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+    async fn handle_internal<S: Read + Write>(
+        &mut self,
+        client: &mut EmbassyClient<S>,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn handle_vpin_read<S: Read + Write>(
+        &mut self,
+        client: &mut EmbassyClient<S>,
+        pin_num: VirtualPin,
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn handle_vpin_write<S: Read + Write>(
+        &mut self,
+        client: &mut EmbassyClient<S>,
+        pin_num: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Write of a binary value that is not valid UTF-8
+    async fn handle_vpin_write_raw<S: Read + Write>(
+        &mut self,
+        client: &mut EmbassyClient<S>,
+        pin_num: VirtualPin,
+        data: &[u8],
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Blynk client over a connected `embedded-io-async` socket
+pub struct EmbassyClient<S> {
+    socket: S,
+    msg_id: u16,
+    rx: Vec<u8>,
+    error_policy: HandlerErrorPolicy,
+}
+
+impl<S: Read + Write> EmbassyClient<S> {
+    pub fn new(socket: S) -> EmbassyClient<S> {
+        EmbassyClient {
+            socket,
+            msg_id: 0,
+            rx: Vec::new(),
+            error_policy: HandlerErrorPolicy::default(),
+        }
+    }
+
+    pub fn socket_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    fn msg_id(&mut self) -> u16 {
+        self.msg_id = proto::next_id(self.msg_id);
+        self.msg_id
+    }
+
+    async fn send(&mut self, msg: Vec<u8>) -> Result<()> {
+        proto::check_size(&msg)?;
+        self.socket.write_all(&msg).await.map_err(into_io_error)?;
+        self.socket.flush().await.map_err(into_io_error)?;
+        Ok(())
+    }
+
+    pub async fn virtual_write<N, V>(&mut self, v_pin: N, val: V) -> Result<()>
+    where
+        N: Into<VirtualPin>,
+        V: IntoBlynkValue,
+    {
+        let v_pin = v_pin.into().number();
+        let msg = proto::virtual_write(self.msg_id(), v_pin, &val.into_blynk_value());
+        self.send(msg).await
+    }
+
+    /// Writes the bytes as they are, e.g. image chunks or packed structs
+    pub async fn virtual_write_raw<N: Into<VirtualPin>>(
+        &mut self,
+        v_pin: N,
+        val: &[u8],
+    ) -> Result<()> {
+        let v_pin = v_pin.into().number();
+        let msg = proto::virtual_write_raw(self.msg_id(), v_pin, val);
+        self.send(msg).await
+    }
+
+    pub async fn internal(&mut self, body: Vec<&str>) -> Result<()> {
+        let msg = proto::command(MessageType::Internal, self.msg_id(), body);
+        self.send(msg).await
+    }
+
+    /// Waits for the next complete message from the server
+    pub async fn read(&mut self) -> Result<Message> {
+        loop {
+            if let Some(msg) = self.take_message() {
+                return msg;
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Logs in, sets up the heartbeat and then serves the server requests
+    /// until the connection fails. Returns the reason, after which the
+    /// socket should be reconnected and `run` called again.
+    pub async fn run<C, E>(&mut self, config: &C, handler: &mut E) -> Result<()>
+    where
+        C: ConfigSource,
+        E: EmbassyEvent,
+    {
+        with_timeout(duration(conf::SOCK_MAX_TIMEOUT), self.handshake(config))
+            .await
+            .map_err(|_| timed_out())??;
+        self.error_policy = config.handler_error_policy();
+        let handled = handler.handle_connect(self).await;
+        self.handler_failed(handled).await?;
+
+        let heartbeat = duration(config.heartbeat());
+        let mut last_rcv = Instant::now();
+        let mut next_ping = last_rcv + heartbeat;
+        loop {
+            while let Some(frame) = self.take_frame() {
+                last_rcv = Instant::now();
+                match MessageRef::parse(&frame) {
+                    Ok(msg) => self.process(msg, handler).await?,
+                    Err(err) => warn!("Dropping malformed message: {}", err),
+                }
+            }
+
+            if last_rcv.elapsed() > heartbeat + heartbeat / 2 {
+                return Err(timed_out());
+            }
+            let mut chunk = [0; 256];
+            match select(self.socket.read(&mut chunk), Timer::at(next_ping)).await {
+                Either::First(Ok(0)) => {
+                    return Err(io::Error::from(io::ErrorKind::ConnectionAborted).into())
+                }
+                Either::First(Ok(size)) => self.rx.extend_from_slice(&chunk[..size]),
+                Either::First(Err(err)) => return Err(into_io_error(err).into()),
+                Either::Second(()) => {
+                    let msg = proto::ping(self.msg_id());
+                    self.send(msg).await?;
+                    next_ping += heartbeat;
+                }
+            }
+        }
+    }
+
+    async fn handshake<C: ConfigSource>(&mut self, config: &C) -> Result<()> {
+        info!("Authenticating device...");
+        let msg = proto::login(self.msg_id(), config.token());
+        self.send(msg).await?;
+        match self.read().await?.status {
+            Some(ProtocolStatus::StatusOk) => (),
+            Some(ProtocolStatus::StatusInvalidToken) => return Err(BlynkError::InvalidAuthToken),
+            Some(status) => return Err(BlynkError::Rejected(status)),
+            None => return Err(BlynkError::Redirection),
+        }
+
+        info!("Setting heartbeat");
+        let msg = proto::heartbeat(
+            self.msg_id(),
+            config.heartbeat(),
+            config.rcv_buffer(),
+            config.device_id(),
+            config.firmware(),
+        );
+        self.send(msg).await?;
+        match self.read().await?.status {
+            Some(ProtocolStatus::StatusOk) => Ok(()),
+            Some(status) => Err(BlynkError::HeartbeatSet(status)),
+            None => Err(BlynkError::InvalidMessageHeader),
+        }
+    }
+
+    async fn process<E: EmbassyEvent>(
+        &mut self,
+        msg: MessageRef<'_>,
+        handler: &mut E,
+    ) -> Result<()> {
+        debug!("Message processing {:?}", msg);
+        match msg.mtype {
+            MessageType::Ping => {
+                let msg = proto::response(msg.id, u16::from(ProtocolStatus::StatusOk));
+                self.send(msg).await
+            }
+            MessageType::Internal if !msg.body.is_empty() => {
+                let handled = handler
+                    .handle_internal(self, ParamList::new(&msg.body[1..]))
+                    .await;
+                self.handler_failed(handled).await
+            }
+            MessageType::Hw | MessageType::Bridge => {
+                let pin_num = msg
+                    .body
+                    .get(1)
+                    .and_then(|pin| pin.parse::<u8>().ok().map(VirtualPin::from));
+                let handled = match (msg.body.first().copied(), pin_num) {
+                    (Some("vw"), Some(pin_num)) if msg.raw.is_some() => {
+                        let data = msg.raw_tail(2).unwrap_or_default();
+                        handler.handle_vpin_write_raw(self, pin_num, data).await
+                    }
+                    (Some("vw"), Some(pin_num)) if msg.body.len() >= 3 => {
+                        handler
+                            .handle_vpin_write(self, pin_num, ParamList::new(&msg.body[2..]))
+                            .await
+                    }
+                    (Some("vr"), Some(pin_num)) => handler.handle_vpin_read(self, pin_num).await,
+                    _ => Ok(()),
+                };
+                self.handler_failed(handled).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the handler error policy to the result of a hook, fails
+    /// with the error of the hook if the connection has to be dropped
+    async fn handler_failed(&mut self, handled: Result<()>) -> Result<()> {
+        let Err(err) = handled else {
+            return Ok(());
+        };
+        error!("Handler failed: {}", err);
+        match self.error_policy.clone() {
+            HandlerErrorPolicy::Log => Ok(()),
+            HandlerErrorPolicy::LogEvent(code) => {
+                let msg = proto::log_event(self.msg_id(), &code, &err.to_string());
+                self.send(msg).await
+            }
+            HandlerErrorPolicy::Reconnect => Err(err),
+        }
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        let mut chunk = [0; 256];
+        match self.socket.read(&mut chunk).await.map_err(into_io_error)? {
+            0 => Err(io::Error::from(io::ErrorKind::ConnectionAborted).into()),
+            size => {
+                self.rx.extend_from_slice(&chunk[..size]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Takes the first buffered message out if it's complete
+    fn take_frame(&mut self) -> Option<Vec<u8>> {
+        let len = proto::frame_len(&self.rx)?;
+        Some(self.rx.drain(..len).collect())
+    }
+
+    /// Parses the first buffered message if it's complete
+    fn take_message(&mut self) -> Option<Result<Message>> {
+        let frame = self.take_frame()?;
+        Some(Message::deserilize(&frame))
+    }
+}
+
+/// Opens connections to the Blynk server for `EmbassyBlynk`, e.g. creates
+/// an `embassy_net::tcp::TcpSocket` and connects it
+#[allow(async_fn_in_trait)]
+pub trait Connect {
+    type Socket<'a>: Read + Write
+    where
+        Self: 'a;
+
+    async fn connect(&mut self) -> Result<Self::Socket<'_>>;
+}
+
+/// Keeps the device connected: connects with a timeout, runs
+/// `EmbassyClient` until the connection fails and reconnects after a delay
+pub struct EmbassyBlynk<K> {
+    connector: K,
+    reconnect_delay: Duration,
+}
+
+impl<K: Connect> EmbassyBlynk<K> {
+    pub fn new(connector: K) -> EmbassyBlynk<K> {
+        EmbassyBlynk {
+            connector,
+            reconnect_delay: duration(conf::RECONNECT_SLEEP),
+        }
+    }
+
+    pub fn set_reconnect_delay(&mut self, delay: std::time::Duration) {
+        self.reconnect_delay = duration(delay);
+    }
+
+    pub async fn run<C, E>(&mut self, config: &C, handler: &mut E) -> !
+    where
+        C: ConfigSource,
+        E: EmbassyEvent,
+    {
+        loop {
+            if let Err(err) = self.run_once(config, handler).await {
+                error!("Problem while connecting: {}", err);
+            }
+            if let Err(err) = handler.handle_disconnect().await {
+                error!("Handler failed: {}", err);
+            }
+            Timer::after(self.reconnect_delay).await;
+        }
+    }
+
+    async fn run_once<C, E>(&mut self, config: &C, handler: &mut E) -> Result<()>
+    where
+        C: ConfigSource,
+        E: EmbassyEvent,
+    {
+        info!("Connecting to blynk server");
+        let socket = with_timeout(duration(conf::SOCK_MAX_TIMEOUT), self.connector.connect())
+            .await
+            .map_err(|_| timed_out())??;
+        EmbassyClient::new(socket).run(config, handler).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ProtocolHeader;
+    use crate::Config;
+    use std::convert::Infallible;
+
+    struct Socket {
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    impl embedded_io_async::ErrorType for Socket {
+        type Error = Infallible;
+    }
+
+    impl Read for Socket {
+        async fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, Infallible> {
+            let size = buf.len().min(self.input.len());
+            buf[..size].copy_from_slice(&self.input[..size]);
+            self.input.drain(..size);
+            Ok(size)
+        }
+    }
+
+    impl Write for Socket {
+        async fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, Infallible> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    #[derive(Default)]
+    struct Handler {
+        connected: bool,
+        writes: Vec<(u8, String)>,
+    }
+
+    impl EmbassyEvent for Handler {
+        async fn handle_connect<S: Read + Write>(
+            &mut self,
+            _client: &mut EmbassyClient<S>,
+        ) -> Result<()> {
+            self.connected = true;
+            Ok(())
+        }
+
+        async fn handle_vpin_write<S: Read + Write>(
+            &mut self,
+            client: &mut EmbassyClient<S>,
+            pin_num: VirtualPin,
+            params: ParamList<'_>,
+        ) -> Result<()> {
+            let pin_num = pin_num.number();
+            self.writes.push((pin_num, params.as_str().into()));
+            client.virtual_write(pin_num + 1, params.as_str()).await
+        }
+
+        async fn handle_vpin_read<S: Read + Write>(
+            &mut self,
+            _client: &mut EmbassyClient<S>,
+            _pin_num: VirtualPin,
+        ) -> Result<()> {
+            Err(BlynkError::handler("no sensor"))
+        }
+    }
+
+    fn rsp(id: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        let header = (
+            MessageType::Rsp as u8,
+            id,
+            u16::from(ProtocolStatus::StatusOk),
+        );
+        ProtocolHeader::write_to(header, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn runs_until_connection_closes() {
+        let mut input = [rsp(1), rsp(2)].concat();
+        input.extend([MessageType::Hw as u8, 0, 0, 0, 1, b'x']);
+        input.extend(
+            Message::new(MessageType::Hw, 5, None, None, vec!["vw", "3", "on"]).serialize(),
+        );
+        let socket = Socket {
+            input,
+            output: Vec::new(),
+        };
+
+        let mut client = EmbassyClient::new(socket);
+        let mut handler = Handler::default();
+        let config = Config {
+            token: "token".into(),
+            ..Default::default()
+        };
+        let res = embassy_futures::block_on(client.run(&config, &mut handler));
+        assert!(
+            matches!(res, Err(BlynkError::Io(err)) if err.kind() == io::ErrorKind::ConnectionAborted)
+        );
+        assert!(handler.connected);
+        assert_eq!(vec![(3, "on".to_string())], handler.writes);
+
+        let output = client.into_inner().output;
+        assert_eq!(MessageType::Login as u8, output[0]);
+        let echo = Message::new(MessageType::Hw, 3, None, None, vec!["vw", "4", "on"]);
+        assert!(output.ends_with(&echo.serialize()));
+    }
+
+    #[test]
+    fn applies_error_policy_to_failed_hooks() {
+        let mut input = [rsp(1), rsp(2)].concat();
+        input.extend(Message::new(MessageType::Hw, 5, None, None, vec!["vr", "2"]).serialize());
+        let socket = |input: &[u8]| Socket {
+            input: input.to_vec(),
+            output: Vec::new(),
+        };
+        let mut config = Config {
+            token: "token".into(),
+            handler_error_policy: HandlerErrorPolicy::LogEvent("handler_failed".into()),
+            ..Default::default()
+        };
+        let mut client = EmbassyClient::new(socket(&input));
+        let res = embassy_futures::block_on(client.run(&config, &mut Handler::default()));
+        assert!(
+            matches!(res, Err(BlynkError::Io(err)) if err.kind() == io::ErrorKind::ConnectionAborted)
+        );
+        let output = client.into_inner().output;
+        let event = proto::log_event(3, "handler_failed", "Handler failed: no sensor");
+        assert!(output.ends_with(&event));
+
+        config.handler_error_policy = HandlerErrorPolicy::Reconnect;
+        let mut client = EmbassyClient::new(socket(&input));
+        let res = embassy_futures::block_on(client.run(&config, &mut Handler::default()));
+        assert!(matches!(res, Err(BlynkError::Handler(_))));
+    }
+
+    struct Connector {
+        attempts: usize,
+    }
+
+    impl Connect for Connector {
+        type Socket<'a> = Socket;
+
+        async fn connect(&mut self) -> Result<Socket> {
+            self.attempts += 1;
+            if self.attempts == 1 {
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+            }
+            Ok(Socket {
+                input: [rsp(1), rsp(2)].concat(),
+                output: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn reconnects_after_failures() {
+        let mut blynk = EmbassyBlynk::new(Connector { attempts: 0 });
+        blynk.set_reconnect_delay(std::time::Duration::from_millis(10));
+        let mut handler = Handler::default();
+        let config = Config {
+            token: "token".into(),
+            ..Default::default()
+        };
+        embassy_futures::block_on(embassy_futures::select::select(
+            blynk.run(&config, &mut handler),
+            Timer::after_millis(35),
+        ));
+        assert!(blynk.connector.attempts >= 3);
+        assert!(handler.connected);
+    }
+}
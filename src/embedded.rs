@@ -0,0 +1,154 @@
+//! `embedded-io` support for `Protocol`
+//!
+//! Bare-metal network stacks (smoltcp, Embassy) don't expose `std::net`,
+//! their sockets implement `embedded_io::Read`/`Write` instead. Wrapping
+//! such a socket in `FromEmbedded` makes it usable as `Protocol::T`, so the
+//! whole protocol (login, heartbeat, pin reads and writes) runs on top of it.
+//!
+//! The async `Protocol` requires `Send` streams, which `embedded-io-async`
+//! futures can't guarantee, so only the blocking traits are covered.
+
+use std::io;
+
+/// Adapts `embedded_io` stream to `std::io::Read`/`Write`
+#[derive(Debug)]
+pub struct FromEmbedded<T> {
+    inner: T,
+}
+
+impl<T> FromEmbedded<T> {
+    pub fn new(inner: T) -> FromEmbedded<T> {
+        FromEmbedded { inner }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: embedded_io::Read> io::Read for FromEmbedded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(into_io_error)
+    }
+}
+
+impl<T: embedded_io::Write> io::Write for FromEmbedded<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).map_err(into_io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().map_err(into_io_error)
+    }
+}
+
+pub(crate) fn into_io_error<E: embedded_io::Error>(err: E) -> io::Error {
+    io::Error::new(err.kind().into(), format!("{:?}", err))
+}
+
+#[cfg(all(test, not(feature = "async")))]
+mod tests {
+    use super::*;
+    use crate::message::{MessageType, ProtocolHeader, ProtocolStatus};
+    use crate::Protocol;
+    use std::io::BufReader;
+
+    /// Socket of an imaginary bare-metal stack
+    struct Socket {
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    impl embedded_io::ErrorType for Socket {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    impl embedded_io::Read for Socket {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let size = buf.len().min(self.input.len());
+            buf[..size].copy_from_slice(&self.input[..size]);
+            self.input.drain(..size);
+            Ok(size)
+        }
+    }
+
+    impl embedded_io::Write for Socket {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            if buf.is_empty() {
+                return Err(embedded_io::ErrorKind::WriteZero);
+            }
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Device {
+        msg_id: u16,
+        reader: Option<BufReader<FromEmbedded<Socket>>>,
+    }
+
+    impl Protocol for Device {
+        type T = FromEmbedded<Socket>;
+
+        fn set_reader(&mut self, reader: BufReader<Self::T>) {
+            self.reader = Some(reader);
+        }
+
+        fn reader(&mut self) -> Option<&mut BufReader<Self::T>> {
+            self.reader.as_mut()
+        }
+
+        fn msg_id(&mut self) -> u16 {
+            self.msg_id += 1;
+            self.msg_id
+        }
+
+        fn disconnect(&mut self) {
+            self.reader = None;
+        }
+    }
+
+    #[test]
+    fn protocol_over_embedded_socket() {
+        let mut input = Vec::new();
+        let rsp = (
+            MessageType::Rsp as u8,
+            1,
+            u16::from(ProtocolStatus::StatusOk),
+        );
+        ProtocolHeader::write_to(rsp, &mut input).unwrap();
+        let socket = Socket {
+            input,
+            output: Vec::new(),
+        };
+
+        let mut device = Device::default();
+        device.set_stream(FromEmbedded::new(socket));
+        device.login("token").unwrap();
+        let msg = device.read().unwrap();
+        assert!(matches!(msg.status, Some(ProtocolStatus::StatusOk)));
+
+        let socket = device.reader.unwrap().into_inner().into_inner();
+        assert_eq!(MessageType::Login as u8, socket.output[0]);
+        assert!(socket.output.ends_with(b"token"));
+    }
+
+    #[test]
+    fn maps_error_kind() {
+        let err = into_io_error(embedded_io::ErrorKind::TimedOut);
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    }
+}
@@ -0,0 +1,338 @@
+//! Lightweight TLS 1.3 backend built on `embedded-tls`.
+//!
+//! Intended for esp32 (and other small) targets where pulling in rustls
+//! is too heavy. There are no bundled roots: the server certificate is
+//! verified against the single certificate of `Config::ca_cert`, which has
+//! to be its issuer (intermediates aren't followed) or, for self-signed
+//! servers, the server certificate itself. `Config::cert_pin` then checks
+//! that certificate is the pinned one. Without either the connection is
+//! refused, unless `Config::insecure_skip_verify` is set.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use embedded_tls::blocking::{
+    Aes128GcmSha256, Certificate, NoVerify, TlsClock, TlsConfig, TlsConnection, TlsContext,
+    TlsError,
+};
+use embedded_tls::webpki::CertVerifier;
+use log::*;
+use rand_core::OsRng;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::CertificateDer;
+use self_cell::{self_cell, MutBorrow};
+
+use crate::{pinning, BlynkError, ConfigSource, Result};
+
+/// Largest TLS record the server may send
+const RECORD_READ_BUF: usize = 16640;
+/// Outgoing messages are small, single record is enough for them
+const RECORD_WRITE_BUF: usize = 4096;
+/// Largest certificate chain the server may send
+const CERT_CHAIN_BUF: usize = 8192;
+
+type Verifier<'a> = CertVerifier<'a, Aes128GcmSha256, SystemClock, CERT_CHAIN_BUF>;
+
+/// Validity of the certificates is checked against the system time
+struct SystemClock;
+
+impl TlsClock for SystemClock {
+    fn now() -> Option<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(now.as_secs())
+    }
+}
+
+/// `embedded_io` adapter for the std socket
+struct Socket(TcpStream);
+
+impl embedded_io::ErrorType for Socket {
+    type Error = io::Error;
+}
+
+impl embedded_io::Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl embedded_io::Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+struct RecordBuffers {
+    read: Vec<u8>,
+    write: Vec<u8>,
+}
+
+type Connection<'a> = TlsConnection<'a, Socket, Aes128GcmSha256>;
+
+self_cell!(
+    struct Session {
+        owner: MutBorrow<RecordBuffers>,
+
+        #[not_covariant]
+        dependent: Connection,
+    }
+);
+
+/// TLS session together with the record buffers it works on
+pub struct EmbeddedTlsStream {
+    session: Session,
+    // handle to the same socket, used for timeouts and shutdown
+    sock: TcpStream,
+}
+
+impl EmbeddedTlsStream {
+    /// Performs TLS handshake over connected socket, `Config::server`
+    /// is sent as SNI
+    pub fn new(sock: TcpStream, config: &impl ConfigSource) -> Result<EmbeddedTlsStream> {
+        let anchor = trust_anchor(config)?;
+        if anchor.is_none() {
+            warn!("Config::insecure_skip_verify set, server certificate is not verified");
+        }
+
+        let buffers = RecordBuffers {
+            read: vec![0; RECORD_READ_BUF],
+            write: vec![0; RECORD_WRITE_BUF],
+        };
+        let handle = sock.try_clone()?;
+        let session = Session::try_new(MutBorrow::new(buffers), |buffers| {
+            let buffers = buffers.borrow_mut();
+            let mut conn = Connection::new(Socket(sock), &mut buffers.read, &mut buffers.write);
+
+            let tls_config = TlsConfig::new().with_server_name(config.server());
+            let opened = match &anchor {
+                Some(der) => {
+                    let tls_config = tls_config.with_ca(Certificate::X509(der));
+                    conn.open::<OsRng, Verifier>(TlsContext::new(&tls_config, &mut OsRng))
+                }
+                None => conn.open::<OsRng, NoVerify>(TlsContext::new(&tls_config, &mut OsRng)),
+            };
+            opened.map_err(handshake_error)?;
+            Ok::<_, BlynkError>(conn)
+        })?;
+
+        Ok(EmbeddedTlsStream {
+            session,
+            sock: handle,
+        })
+    }
+
+    /// Returns the underlying socket
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+impl Read for EmbeddedTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.session
+            .with_dependent_mut(|_, conn| conn.read(buf))
+            .map_err(into_io_error)
+    }
+}
+
+impl Write for EmbeddedTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.session
+            .with_dependent_mut(|_, conn| {
+                let size = conn.write(buf)?;
+                conn.flush()?;
+                Ok(size)
+            })
+            .map_err(into_io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session
+            .with_dependent_mut(|_, conn| conn.flush())
+            .map_err(into_io_error)
+    }
+}
+
+/// DER certificate the server is verified against, `None` only when the
+/// verification is explicitly skipped
+fn trust_anchor(config: &impl ConfigSource) -> Result<Option<Vec<u8>>> {
+    let pem = match (config.ca_cert(), config.cert_pin()) {
+        (Some(pem), _) => pem,
+        (None, Some(_)) => {
+            return Err(BlynkError::Tls(
+                "embedded-tls backend pins the certificate given in Config::ca_cert".into(),
+            ))
+        }
+        (None, None) if config.insecure_skip_verify() => return Ok(None),
+        (None, None) => {
+            return Err(BlynkError::Tls(
+                "embedded-tls backend needs Config::ca_cert (or Config::insecure_skip_verify)"
+                    .into(),
+            ))
+        }
+    };
+
+    let mut certs = CertificateDer::pem_slice_iter(pem);
+    let cert = match (certs.next(), certs.next()) {
+        (Some(cert), None) => {
+            cert.map_err(|err| BlynkError::Tls(format!("invalid CA certificate: {}", err)))?
+        }
+        (None, _) => return Err(BlynkError::Tls("CA bundle has no certificates".into())),
+        (Some(_), Some(_)) => {
+            return Err(BlynkError::Tls(
+                "embedded-tls backend trusts a single CA certificate".into(),
+            ))
+        }
+    };
+    if let Some(pin) = config.cert_pin() {
+        pinning::verify(&pinning::parse(pin)?, &cert)?;
+    }
+    Ok(Some(cert.to_vec()))
+}
+
+/// Rejected certificates are reported as `BlynkError::TlsVerification`
+fn handshake_error(err: TlsError) -> BlynkError {
+    match err {
+        TlsError::InvalidCertificate | TlsError::InvalidSignature => {
+            BlynkError::TlsVerification(format!("{:?}", err))
+        }
+        err => BlynkError::Tls(format!("{:?}", err)),
+    }
+}
+
+fn into_io_error(err: TlsError) -> io::Error {
+    match err {
+        TlsError::Io(kind) => io::Error::from(io::ErrorKind::from(kind)),
+        TlsError::ConnectionClosed => io::ErrorKind::ConnectionAborted.into(),
+        err => io::Error::other(format!("{:?}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn handshake_fails_on_plain_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut buf = [0; 512];
+            let _ = sock.read(&mut buf);
+            sock.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").unwrap();
+        });
+
+        let config = Config {
+            server: "localhost".into(),
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let sock = TcpStream::connect(addr).unwrap();
+        match EmbeddedTlsStream::new(sock, &config) {
+            Err(BlynkError::Tls(_)) => (),
+            _ => panic!("expected TLS error"),
+        }
+    }
+
+    #[test]
+    fn refuses_unverified_server() {
+        const CERT: &[u8] = include_bytes!("testdata/localhost.crt");
+        const PIN: &str = "52:51:F1:BD:9F:E7:DE:50:93:92:32:05:C8:FE:00:54:\
+                           6C:FB:9E:82:78:95:D5:94:84:B7:EC:4D:7F:B9:99:D7";
+
+        let config = Config::default();
+        assert!(matches!(trust_anchor(&config), Err(BlynkError::Tls(_))));
+        // the pin alone can't be checked before the certificate is trusted
+        let config = Config {
+            cert_pin: Some(PIN.into()),
+            ..Default::default()
+        };
+        assert!(matches!(trust_anchor(&config), Err(BlynkError::Tls(_))));
+
+        let config = Config {
+            ca_cert: Some(CERT.to_vec()),
+            ..config
+        };
+        assert!(trust_anchor(&config).unwrap().is_some());
+        let config = Config {
+            cert_pin: Some("00".repeat(32)),
+            ..config
+        };
+        assert!(matches!(
+            trust_anchor(&config),
+            Err(BlynkError::TlsVerification(_))
+        ));
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn verifies_server_against_ca() {
+        use rustls::pki_types::PrivateKeyDer;
+        use std::sync::Arc;
+
+        const CERT: &[u8] = include_bytes!("testdata/localhost.crt");
+        let server = |listener: TcpListener| {
+            let certs = vec![CertificateDer::from_pem_slice(CERT).unwrap()];
+            let key =
+                PrivateKeyDer::from_pem_slice(include_bytes!("testdata/localhost.key")).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let server_config = rustls::ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .unwrap();
+            thread::spawn(move || {
+                let (mut sock, _) = listener.accept().unwrap();
+                let mut conn = rustls::ServerConnection::new(Arc::new(server_config)).unwrap();
+                while conn.is_handshaking() {
+                    if conn.complete_io(&mut sock).is_err() {
+                        return;
+                    }
+                }
+            })
+        };
+
+        let config = Config {
+            server: "localhost".into(),
+            ca_cert: Some(CERT.to_vec()),
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sock = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let handle = server(listener);
+        assert!(EmbeddedTlsStream::new(sock, &config).is_ok());
+        handle.join().unwrap();
+
+        // the certificate isn't valid for another name
+        let config = Config {
+            server: "blynk.cloud".into(),
+            ..config
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sock = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let handle = server(listener);
+        assert!(matches!(
+            EmbeddedTlsStream::new(sock, &config),
+            Err(BlynkError::TlsVerification(_))
+        ));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn io_errors_keep_their_kind() {
+        let err = into_io_error(TlsError::Io(embedded_io::ErrorKind::TimedOut));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = into_io_error(TlsError::ConnectionClosed);
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    }
+}
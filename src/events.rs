@@ -0,0 +1,123 @@
+//! Events consumed without implementing `Event`
+//!
+//! Applications with a state machine of their own (RTIC tasks, game
+//! loops) would rather ask for what happened than be called back.
+//! `EventQueue` is a handler keeping the events as `BlynkEvent` values,
+//! `Blynk::<EventQueue>::poll` drives the connection and hands them out
+//! one at a time.
+
+use std::collections::VecDeque;
+
+use crate::{maybe_async, Event, ParamList, Protocol, Result, VirtualPin};
+
+/// Event received from the server or a change of the connection state
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BlynkEvent {
+    Connected,
+    Disconnected,
+    /// All values of the write, most widgets send just one
+    VPinWrite {
+        pin: VirtualPin,
+        values: Vec<String>,
+    },
+    /// App asks for the value of the pin, answer with `virtual_write`
+    VPinRead {
+        pin: VirtualPin,
+    },
+    AppConnected,
+    AppDisconnected,
+    /// Property of the widget on `pin` was changed
+    Property {
+        pin: VirtualPin,
+        prop: String,
+        value: String,
+    },
+    DigitalWrite {
+        pin: u8,
+        high: bool,
+    },
+    AnalogWrite {
+        pin: u8,
+        value: String,
+    },
+}
+
+/// Handler queueing the events it gets, see `Blynk::poll`
+#[derive(Debug, Default)]
+pub struct EventQueue {
+    events: VecDeque<BlynkEvent>,
+}
+
+impl EventQueue {
+    /// Oldest event not taken yet
+    pub fn pop(&mut self) -> Option<BlynkEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    fn push(&mut self, event: BlynkEvent) -> Result<()> {
+        self.events.push_back(event);
+        Ok(())
+    }
+}
+
+#[maybe_async]
+impl<P: Protocol> Event<P> for EventQueue {
+    async fn handle_connect(&mut self, _client: &mut P) -> Result<()> {
+        self.push(BlynkEvent::Connected)
+    }
+
+    async fn handle_disconnect(&mut self) -> Result<()> {
+        self.push(BlynkEvent::Disconnected)
+    }
+
+    async fn handle_app_connected(&mut self, _client: &mut P) -> Result<()> {
+        self.push(BlynkEvent::AppConnected)
+    }
+
+    async fn handle_app_disconnected(&mut self, _client: &mut P) -> Result<()> {
+        self.push(BlynkEvent::AppDisconnected)
+    }
+
+    async fn handle_vpin_read(&mut self, _client: &mut P, pin: VirtualPin) -> Result<()> {
+        self.push(BlynkEvent::VPinRead { pin })
+    }
+
+    async fn handle_vpin_write(
+        &mut self,
+        _client: &mut P,
+        pin: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+        let values = params.iter().map(String::from).collect();
+        self.push(BlynkEvent::VPinWrite { pin, values })
+    }
+
+    async fn handle_digital_write(&mut self, _client: &mut P, pin: u8, high: bool) -> Result<()> {
+        self.push(BlynkEvent::DigitalWrite { pin, high })
+    }
+
+    async fn handle_analog_write(&mut self, _client: &mut P, pin: u8, data: &str) -> Result<()> {
+        let value = data.to_string();
+        self.push(BlynkEvent::AnalogWrite { pin, value })
+    }
+
+    async fn handle_property(
+        &mut self,
+        _client: &mut P,
+        pin: VirtualPin,
+        prop: &str,
+        value: &str,
+    ) -> Result<()> {
+        let (prop, value) = (prop.to_string(), value.to_string());
+        self.push(BlynkEvent::Property { pin, prop, value })
+    }
+}
@@ -0,0 +1,773 @@
+//! Handlers of the events `Blynk` receives from the server
+
+use std::time::SystemTime;
+
+use crate::client::{Client, Protocol};
+use crate::message::{PinMode, ProtocolStatus};
+use crate::rt::{ConnectFn, DisconnectFn, InternalFn, PinReadFn, PinWriteFn};
+use crate::session::Hook;
+use crate::{maybe_async, BlynkError, DefaultHandler, InternalCommand, OtaRequest, ParamList};
+use crate::{Result, TimeZone, VirtualPin};
+
+/// Used in order to implement handler logic for requests coming
+/// from Blynk.io servers and various transitions between connection states.
+/// Errors returned by the hooks are handled according to
+/// `HandlerErrorPolicy`.
+///
+/// `Blynk` passes its `Client`, a handler implementing `Event<P>` for any
+/// `P: Protocol` can be unit tested with a double writing to memory.
+///
+/// # Example
+#[cfg_attr(not(feature = "async"), doc = "```")]
+#[cfg_attr(feature = "async", doc = "```ignore")]
+/// use blynk_io::*;
+///
+/// struct EventsHandler;
+/// impl Event for EventsHandler {
+///     fn handle_vpin_write(
+///         &mut self,
+///         client: &mut Client,
+///         pin_num: VirtualPin,
+///         params: ParamList<'_>,
+///     ) -> Result<(), BlynkError> {
+///         println!("pin {} write {:?}", pin_num, params);
+///         client.virtual_write(pin_num.number() + 1, params.as_str())
+///     }
+/// }
+///
+/// // the same handler for any client
+/// struct Echo;
+/// impl<P: Protocol> Event<P> for Echo {
+///     fn handle_vpin_write(&mut self, client: &mut P, pin_num: VirtualPin, params: ParamList<'_>) -> Result<(), BlynkError> {
+///         client.virtual_write(pin_num, params.as_str())
+///     }
+/// }
+/// ```
+#[allow(unused_variables)]
+#[maybe_async]
+pub trait Event<P: Protocol = Client>: Send {
+    async fn handle_connect(&mut self, client: &mut P) -> Result<()> {
+        Ok(())
+    }
+    async fn handle_disconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Arguments of every internal command, without the command name
+    async fn handle_internal(&mut self, client: &mut P, params: ParamList<'_>) -> Result<()> {
+        Ok(())
+    }
+    /// Parsed internal command that has no hook of its own
+    async fn handle_internal_command(
+        &mut self,
+        client: &mut P,
+        command: &InternalCommand,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Current time, the answer to `request_rtc`
+    async fn handle_rtc(&mut self, client: &mut P, time: SystemTime) -> Result<()> {
+        Ok(())
+    }
+    /// Time zone set in the app changed, the answer to `request_timezone`
+    async fn handle_timezone(&mut self, client: &mut P, timezone: &TimeZone) -> Result<()> {
+        Ok(())
+    }
+    /// Server asks the device to update its firmware
+    async fn handle_ota(&mut self, client: &mut P, ota: &OtaRequest) -> Result<()> {
+        Ok(())
+    }
+    /// App connected to the server
+    async fn handle_app_connected(&mut self, client: &mut P) -> Result<()> {
+        Ok(())
+    }
+    /// App disconnected from the server
+    async fn handle_app_disconnected(&mut self, client: &mut P) -> Result<()> {
+        Ok(())
+    }
+    async fn handle_vpin_read(&mut self, client: &mut P, pin_num: VirtualPin) -> Result<()> {
+        Ok(())
+    }
+    async fn handle_vpin_write(
+        &mut self,
+        client: &mut P,
+        pin_num: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Write of a binary value that is not valid UTF-8
+    async fn handle_vpin_write_raw(
+        &mut self,
+        client: &mut P,
+        pin_num: VirtualPin,
+        data: &[u8],
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Server answered the command sent with `msg_id`, statuses other than
+    /// `StatusOk` (e.g. `StatusQuotaLimit`) mean the device should back off
+    async fn handle_response(
+        &mut self,
+        client: &mut P,
+        msg_id: u16,
+        status: ProtocolStatus,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// App switched a physical digital pin
+    async fn handle_digital_write(&mut self, client: &mut P, pin: u8, high: bool) -> Result<()> {
+        Ok(())
+    }
+    /// App wrote a physical analog or PWM pin
+    async fn handle_analog_write(&mut self, client: &mut P, pin: u8, data: &str) -> Result<()> {
+        Ok(())
+    }
+    /// App asks for the state of a physical pin, answer with
+    /// `digital_read_report`
+    async fn handle_digital_read(&mut self, client: &mut P, pin: u8) -> Result<()> {
+        Ok(())
+    }
+    /// App configured the mode of a physical pin
+    async fn handle_pin_mode(&mut self, client: &mut P, pin: u8, mode: PinMode) -> Result<()> {
+        Ok(())
+    }
+    /// Property of the widget on `pin` was changed, e.g. by another client
+    /// or an automation
+    async fn handle_property(
+        &mut self,
+        client: &mut P,
+        pin: VirtualPin,
+        prop: &str,
+        value: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Malformed message from the server, e.g. `BlynkError::InvalidPin`,
+    /// the message is skipped and the run loop carries on
+    async fn handle_protocol_error(&mut self, client: &mut P, err: &BlynkError) -> Result<()> {
+        Ok(())
+    }
+    /// Whether the events of the virtual pin go on to the handlers after
+    /// this one in a `HandlerChain`, asked before each event is delivered
+    fn propagation(&self, pin_num: VirtualPin) -> Propagation {
+        Propagation::Continue
+    }
+}
+
+/// What happens to a virtual pin event after a handler of a `HandlerChain`
+/// got it, see `Event::propagation`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// The handlers added later get it as well
+    Continue,
+    /// The handlers added later don't get it
+    Stop,
+}
+
+#[maybe_async]
+impl<P: Protocol> Event<P> for DefaultHandler {}
+
+/// Handlers chosen at runtime, e.g. `Blynk<Box<dyn Event>>` switching
+/// between provisioning and normal mode with `set_handler`
+#[maybe_async]
+impl<P: Protocol, E: Event<P> + ?Sized> Event<P> for Box<E> {
+    async fn handle_connect(&mut self, client: &mut P) -> Result<()> {
+        (**self).handle_connect(client).await
+    }
+
+    async fn handle_disconnect(&mut self) -> Result<()> {
+        (**self).handle_disconnect().await
+    }
+
+    async fn handle_internal(&mut self, client: &mut P, params: ParamList<'_>) -> Result<()> {
+        (**self).handle_internal(client, params).await
+    }
+
+    async fn handle_internal_command(
+        &mut self,
+        client: &mut P,
+        command: &InternalCommand,
+    ) -> Result<()> {
+        (**self).handle_internal_command(client, command).await
+    }
+
+    async fn handle_rtc(&mut self, client: &mut P, time: SystemTime) -> Result<()> {
+        (**self).handle_rtc(client, time).await
+    }
+
+    async fn handle_timezone(&mut self, client: &mut P, timezone: &TimeZone) -> Result<()> {
+        (**self).handle_timezone(client, timezone).await
+    }
+
+    async fn handle_ota(&mut self, client: &mut P, ota: &OtaRequest) -> Result<()> {
+        (**self).handle_ota(client, ota).await
+    }
+
+    async fn handle_app_connected(&mut self, client: &mut P) -> Result<()> {
+        (**self).handle_app_connected(client).await
+    }
+
+    async fn handle_app_disconnected(&mut self, client: &mut P) -> Result<()> {
+        (**self).handle_app_disconnected(client).await
+    }
+
+    async fn handle_vpin_read(&mut self, client: &mut P, pin_num: VirtualPin) -> Result<()> {
+        (**self).handle_vpin_read(client, pin_num).await
+    }
+
+    async fn handle_vpin_write(
+        &mut self,
+        client: &mut P,
+        pin_num: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+        (**self).handle_vpin_write(client, pin_num, params).await
+    }
+
+    async fn handle_vpin_write_raw(
+        &mut self,
+        client: &mut P,
+        pin_num: VirtualPin,
+        data: &[u8],
+    ) -> Result<()> {
+        (**self).handle_vpin_write_raw(client, pin_num, data).await
+    }
+
+    async fn handle_response(
+        &mut self,
+        client: &mut P,
+        msg_id: u16,
+        status: ProtocolStatus,
+    ) -> Result<()> {
+        (**self).handle_response(client, msg_id, status).await
+    }
+
+    async fn handle_digital_write(&mut self, client: &mut P, pin: u8, high: bool) -> Result<()> {
+        (**self).handle_digital_write(client, pin, high).await
+    }
+
+    async fn handle_analog_write(&mut self, client: &mut P, pin: u8, data: &str) -> Result<()> {
+        (**self).handle_analog_write(client, pin, data).await
+    }
+
+    async fn handle_digital_read(&mut self, client: &mut P, pin: u8) -> Result<()> {
+        (**self).handle_digital_read(client, pin).await
+    }
+
+    async fn handle_pin_mode(&mut self, client: &mut P, pin: u8, mode: PinMode) -> Result<()> {
+        (**self).handle_pin_mode(client, pin, mode).await
+    }
+
+    async fn handle_property(
+        &mut self,
+        client: &mut P,
+        pin: VirtualPin,
+        prop: &str,
+        value: &str,
+    ) -> Result<()> {
+        (**self).handle_property(client, pin, prop, value).await
+    }
+
+    async fn handle_protocol_error(&mut self, client: &mut P, err: &BlynkError) -> Result<()> {
+        (**self).handle_protocol_error(client, err).await
+    }
+
+    fn propagation(&self, pin_num: VirtualPin) -> Propagation {
+        (**self).propagation(pin_num)
+    }
+}
+
+struct Link<P> {
+    handler: Box<dyn Event<P>>,
+    /// Virtual pins whose events stop at this handler
+    pins: Vec<VirtualPin>,
+}
+
+impl<P: Protocol> Link<P> {
+    fn stops(&self, pin: VirtualPin) -> bool {
+        self.pins.contains(&pin) || self.handler.propagation(pin) == Propagation::Stop
+    }
+}
+
+/// Several handlers called in the order they were added, so reusable
+/// components (OTA, diagnostics) don't have to be merged with the
+/// application handler. Events of a virtual pin stop at the first handler
+/// owning the pin (see `with_pins`) or returning `Propagation::Stop` for
+/// it, the handlers added later don't get them. Other events reach every
+/// handler. A failing handler doesn't stop the chain, the first error is
+/// returned. Like `Event` it
+/// is generic over the client, so a chain can be tested with a double.
+///
+/// # Example
+/// ```
+/// use blynk_io::*;
+///
+/// struct Diagnostics;
+/// impl Event for Diagnostics {}
+/// struct App;
+/// impl Event for App {}
+///
+/// let mut blynk = Blynk::<HandlerChain>::new("BLYNK TOKEN".to_string());
+/// blynk.set_handler(HandlerChain::default().with_pins(Diagnostics, &[pins::V60, pins::V61]).with(App));
+/// ```
+pub struct HandlerChain<P: Protocol = Client> {
+    links: Vec<Link<P>>,
+}
+
+impl<P: Protocol> Default for HandlerChain<P> {
+    fn default() -> HandlerChain<P> {
+        HandlerChain { links: Vec::new() }
+    }
+}
+
+impl<P: Protocol> HandlerChain<P> {
+    /// Adds a handler getting every event that reaches it
+    pub fn with<E: Event<P> + 'static>(self, handler: E) -> HandlerChain<P> {
+        self.with_pins(handler, &[])
+    }
+
+    /// Adds a handler owning the virtual pins, their events don't reach
+    /// the handlers added after it
+    pub fn with_pins<E: Event<P> + 'static>(
+        mut self,
+        handler: E,
+        pins: &[VirtualPin],
+    ) -> HandlerChain<P> {
+        self.links.push(Link {
+            handler: Box::new(handler),
+            pins: pins.to_vec(),
+        });
+        self
+    }
+
+    /// Handlers getting events of the virtual pin, up to the one stopping
+    /// them
+    fn handlers(&mut self, pin: VirtualPin) -> impl Iterator<Item = &mut Box<dyn Event<P>>> {
+        let end = self
+            .links
+            .iter()
+            .position(|link| link.stops(pin))
+            .map_or(self.links.len(), |pos| pos + 1);
+        self.links[..end].iter_mut().map(|link| &mut link.handler)
+    }
+}
+
+#[maybe_async]
+impl<P: Protocol> Event<P> for HandlerChain<P> {
+    async fn handle_connect(&mut self, client: &mut P) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_connect(client).await);
+        }
+        res
+    }
+
+    async fn handle_disconnect(&mut self) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_disconnect().await);
+        }
+        res
+    }
+
+    async fn handle_internal(&mut self, client: &mut P, params: ParamList<'_>) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_internal(client, params).await);
+        }
+        res
+    }
+
+    async fn handle_internal_command(
+        &mut self,
+        client: &mut P,
+        command: &InternalCommand,
+    ) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_internal_command(client, command).await);
+        }
+        res
+    }
+
+    async fn handle_rtc(&mut self, client: &mut P, time: SystemTime) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_rtc(client, time).await);
+        }
+        res
+    }
+
+    async fn handle_timezone(&mut self, client: &mut P, timezone: &TimeZone) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_timezone(client, timezone).await);
+        }
+        res
+    }
+
+    async fn handle_ota(&mut self, client: &mut P, ota: &OtaRequest) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_ota(client, ota).await);
+        }
+        res
+    }
+
+    async fn handle_app_connected(&mut self, client: &mut P) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_app_connected(client).await);
+        }
+        res
+    }
+
+    async fn handle_app_disconnected(&mut self, client: &mut P) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_app_disconnected(client).await);
+        }
+        res
+    }
+
+    async fn handle_vpin_read(&mut self, client: &mut P, pin_num: VirtualPin) -> Result<()> {
+        let mut res = Ok(());
+        for handler in self.handlers(pin_num) {
+            res = res.and(handler.handle_vpin_read(client, pin_num).await);
+        }
+        res
+    }
+
+    async fn handle_vpin_write(
+        &mut self,
+        client: &mut P,
+        pin_num: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+        let mut res = Ok(());
+        for handler in self.handlers(pin_num) {
+            res = res.and(handler.handle_vpin_write(client, pin_num, params).await);
+        }
+        res
+    }
+
+    async fn handle_vpin_write_raw(
+        &mut self,
+        client: &mut P,
+        pin_num: VirtualPin,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut res = Ok(());
+        for handler in self.handlers(pin_num) {
+            res = res.and(handler.handle_vpin_write_raw(client, pin_num, data).await);
+        }
+        res
+    }
+
+    async fn handle_response(
+        &mut self,
+        client: &mut P,
+        msg_id: u16,
+        status: ProtocolStatus,
+    ) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_response(client, msg_id, status).await);
+        }
+        res
+    }
+
+    async fn handle_digital_write(&mut self, client: &mut P, pin: u8, high: bool) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_digital_write(client, pin, high).await);
+        }
+        res
+    }
+
+    async fn handle_analog_write(&mut self, client: &mut P, pin: u8, data: &str) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_analog_write(client, pin, data).await);
+        }
+        res
+    }
+
+    async fn handle_digital_read(&mut self, client: &mut P, pin: u8) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_digital_read(client, pin).await);
+        }
+        res
+    }
+
+    async fn handle_pin_mode(&mut self, client: &mut P, pin: u8, mode: PinMode) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_pin_mode(client, pin, mode).await);
+        }
+        res
+    }
+
+    async fn handle_property(
+        &mut self,
+        client: &mut P,
+        pin: VirtualPin,
+        prop: &str,
+        value: &str,
+    ) -> Result<()> {
+        let mut res = Ok(());
+        for handler in self.handlers(pin) {
+            res = res.and(handler.handle_property(client, pin, prop, value).await);
+        }
+        res
+    }
+
+    async fn handle_protocol_error(&mut self, client: &mut P, err: &BlynkError) -> Result<()> {
+        let mut res = Ok(());
+        for link in &mut self.links {
+            res = res.and(link.handler.handle_protocol_error(client, err).await);
+        }
+        res
+    }
+
+    fn propagation(&self, pin_num: VirtualPin) -> Propagation {
+        if self.links.iter().any(|link| link.stops(pin_num)) {
+            Propagation::Stop
+        } else {
+            Propagation::Continue
+        }
+    }
+}
+
+/// Implements `Event` for a tuple of handlers, every hook is called on
+/// each of them in order
+macro_rules! tuple_event {
+    ($($name:ident $idx:tt),+) => {
+        #[maybe_async]
+        impl<P: Protocol, $($name: Event<P>),+> Event<P> for ($($name,)+) {
+            async fn handle_connect(&mut self, client: &mut P) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_connect(client).await))+
+            }
+
+            async fn handle_disconnect(&mut self) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_disconnect().await))+
+            }
+
+            async fn handle_internal(&mut self, client: &mut P, params: ParamList<'_>) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_internal(client, params).await))+
+            }
+
+            async fn handle_internal_command(
+                &mut self,
+                client: &mut P,
+                command: &InternalCommand,
+            ) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_internal_command(client, command).await))+
+            }
+
+            async fn handle_rtc(&mut self, client: &mut P, time: SystemTime) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_rtc(client, time).await))+
+            }
+
+            async fn handle_timezone(&mut self, client: &mut P, timezone: &TimeZone) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_timezone(client, timezone).await))+
+            }
+
+            async fn handle_ota(&mut self, client: &mut P, ota: &OtaRequest) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_ota(client, ota).await))+
+            }
+
+            async fn handle_app_connected(&mut self, client: &mut P) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_app_connected(client).await))+
+            }
+
+            async fn handle_app_disconnected(&mut self, client: &mut P) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_app_disconnected(client).await))+
+            }
+
+            async fn handle_vpin_read(&mut self, client: &mut P, pin_num: VirtualPin) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_vpin_read(client, pin_num).await))+
+            }
+
+            async fn handle_vpin_write(
+        &mut self,
+        client: &mut P,
+        pin_num: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_vpin_write(client, pin_num, params).await))+
+            }
+
+            async fn handle_vpin_write_raw(
+                &mut self,
+                client: &mut P,
+                pin_num: VirtualPin,
+                data: &[u8],
+            ) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_vpin_write_raw(client, pin_num, data).await))+
+            }
+
+            async fn handle_response(
+                &mut self,
+                client: &mut P,
+                msg_id: u16,
+                status: ProtocolStatus,
+            ) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_response(client, msg_id, status).await))+
+            }
+
+            async fn handle_digital_write(&mut self, client: &mut P, pin: u8, high: bool) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_digital_write(client, pin, high).await))+
+            }
+
+            async fn handle_analog_write(&mut self, client: &mut P, pin: u8, data: &str) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_analog_write(client, pin, data).await))+
+            }
+
+            async fn handle_digital_read(&mut self, client: &mut P, pin: u8) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_digital_read(client, pin).await))+
+            }
+
+            async fn handle_pin_mode(&mut self, client: &mut P, pin: u8, mode: PinMode) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_pin_mode(client, pin, mode).await))+
+            }
+
+            async fn handle_property(
+                &mut self,
+                client: &mut P,
+                pin: VirtualPin,
+                prop: &str,
+                value: &str,
+            ) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_property(client, pin, prop, value).await))+
+            }
+
+            async fn handle_protocol_error(&mut self, client: &mut P, err: &BlynkError) -> Result<()> {
+                Ok(())$(.and(self.$idx.handle_protocol_error(client, err).await))+
+            }
+
+            fn propagation(&self, pin_num: VirtualPin) -> Propagation {
+                $(if self.$idx.propagation(pin_num) == Propagation::Stop {
+                    return Propagation::Stop;
+                })+
+                Propagation::Continue
+            }
+        }
+    };
+}
+
+// Handlers composed at compile time, e.g. `Blynk<(Ota, App)>`. Unlike
+// `HandlerChain` every handler gets every event, a failing one doesn't
+// stop the others and the first error is returned
+tuple_event!(A 0, B 1);
+tuple_event!(A 0, B 1, C 2);
+
+/// Handler made of closures, so scripts and examples handling a pin or
+/// two don't have to declare a struct. Hooks without a closure do nothing,
+/// see the `on_*` methods for the closures taken
+pub struct FnEvent<P: Protocol = Client> {
+    pub(crate) connect: Option<ConnectFn<P>>,
+    pub(crate) disconnect: Option<DisconnectFn>,
+    pub(crate) vpin_read: Option<PinReadFn<P>>,
+    pub(crate) vpin_write: Option<PinWriteFn<P>>,
+    pub(crate) internal: Option<InternalFn<P>>,
+}
+
+impl<P: Protocol> Default for FnEvent<P> {
+    fn default() -> FnEvent<P> {
+        FnEvent {
+            connect: None,
+            disconnect: None,
+            vpin_read: None,
+            vpin_write: None,
+            internal: None,
+        }
+    }
+}
+
+impl FnEvent {
+    /// Closures taking the `Client`, use `FnEvent::<P>::default()` for
+    /// another `Protocol`
+    pub fn new() -> FnEvent {
+        FnEvent::default()
+    }
+}
+
+#[maybe_async]
+impl<P: Protocol> Event<P> for FnEvent<P> {
+    async fn handle_connect(&mut self, client: &mut P) -> Result<()> {
+        match &mut self.connect {
+            Some(f) => f(client).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_disconnect(&mut self) -> Result<()> {
+        match &mut self.disconnect {
+            Some(f) => f().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_internal(&mut self, client: &mut P, params: ParamList<'_>) -> Result<()> {
+        match &mut self.internal {
+            Some(f) => f(client, params).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_vpin_read(&mut self, client: &mut P, pin_num: VirtualPin) -> Result<()> {
+        match &mut self.vpin_read {
+            Some(f) => f(client, pin_num).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_vpin_write(
+        &mut self,
+        client: &mut P,
+        pin_num: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+        match &mut self.vpin_write {
+            Some(f) => f(client, pin_num, params).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Calls the hook of `handler` matching the event
+#[maybe_async]
+pub(crate) async fn call_hook<P: Protocol, E: Event<P> + ?Sized>(
+    handler: &mut E,
+    client: &mut P,
+    timezone: &TimeZone,
+    hook: Hook<'_>,
+) -> Result<()> {
+    match hook {
+        Hook::Response(id, status) => handler.handle_response(client, id, status).await,
+        Hook::Internal(data) => handler.handle_internal(client, ParamList::new(data)).await,
+        Hook::Rtc(time) => handler.handle_rtc(client, time).await,
+        Hook::Timezone => handler.handle_timezone(client, timezone).await,
+        Hook::Ota(ota) => handler.handle_ota(client, &ota).await,
+        Hook::AppConnected => handler.handle_app_connected(client).await,
+        Hook::AppDisconnected => handler.handle_app_disconnected(client).await,
+        Hook::Command(command) => handler.handle_internal_command(client, &command).await,
+        Hook::VirtualWriteRaw(pin, data) => {
+            handler
+                .handle_vpin_write_raw(client, pin.into(), data)
+                .await
+        }
+        Hook::DigitalWrite(pin, high) => handler.handle_digital_write(client, pin, high).await,
+        Hook::AnalogWrite(pin, data) => handler.handle_analog_write(client, pin, data).await,
+        Hook::DigitalRead(pin) => handler.handle_digital_read(client, pin).await,
+        Hook::PinMode(pin, mode) => handler.handle_pin_mode(client, pin, mode).await,
+        Hook::Property(pin, prop, value) => {
+            handler
+                .handle_property(client, pin.into(), prop, value)
+                .await
+        }
+        Hook::ProtocolError(err) => handler.handle_protocol_error(client, &err).await,
+    }
+}
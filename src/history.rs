@@ -0,0 +1,110 @@
+//! On-device history of recent virtual pin values
+//!
+//! Keeps the last few values written by the device to selected pins, so
+//! the app can show "last N readings" without server side history. The
+//! app can query it with `history` internal command (`history\0<pin>`)
+//! or the device can dump it into a terminal widget.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::VirtualPin;
+
+/// Name of the internal command used to query the history
+pub const COMMAND: &str = "history";
+
+/// Fixed-size rings of `(timestamp, value)` pairs for tracked pins
+#[derive(Debug, Clone)]
+pub struct PinHistory {
+    capacity: usize,
+    pins: HashMap<u8, VecDeque<(SystemTime, String)>>,
+}
+
+impl PinHistory {
+    /// Creates history keeping up to `capacity` entries per pin
+    pub fn new(capacity: usize) -> PinHistory {
+        PinHistory {
+            capacity,
+            pins: HashMap::new(),
+        }
+    }
+
+    /// Starts tracking values written to the pin
+    pub fn track<N: Into<VirtualPin>>(mut self, pin: N) -> PinHistory {
+        self.pins.entry(pin.into().number()).or_default();
+        self
+    }
+
+    /// Stores the value if the pin is tracked, dropping the oldest entry
+    /// when the ring is full
+    pub fn record<N: Into<VirtualPin>>(&mut self, pin: N, timestamp: SystemTime, value: &str) {
+        if let Some(ring) = self.pins.get_mut(&pin.into().number()) {
+            if ring.len() == self.capacity {
+                ring.pop_front();
+            }
+            if self.capacity > 0 {
+                ring.push_back((timestamp, value.into()));
+            }
+        }
+    }
+
+    /// Returns entries for the pin, oldest first
+    pub fn get<N: Into<VirtualPin>>(&self, pin: N) -> impl Iterator<Item = &(SystemTime, String)> {
+        self.pins.get(&pin.into().number()).into_iter().flatten()
+    }
+
+    /// Formats entries as `<unix seconds> <value>` lines, oldest first
+    pub fn lines<N: Into<VirtualPin>>(&self, pin: N) -> Vec<String> {
+        self.get(pin)
+            .map(|(timestamp, value)| {
+                let secs = timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                format!("{} {}", secs, value)
+            })
+            .collect()
+    }
+
+    /// Builds reply body for the `history` internal command, `args` are
+    /// the command arguments (pin number). Returns `None` if the pin
+    /// is not valid
+    pub fn reply(&self, args: &[&str]) -> Option<Vec<String>> {
+        let pin = args.first()?.parse::<u8>().ok()?;
+        let mut body = vec![COMMAND.to_string(), pin.to_string()];
+        body.extend(self.lines(pin));
+        Some(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn keeps_last_values_of_tracked_pins() {
+        let mut history = PinHistory::new(2).track(4);
+        history.record(4, at(1), "a");
+        history.record(4, at(2), "b");
+        history.record(4, at(3), "c");
+        history.record(5, at(3), "x");
+
+        assert_eq!(vec!["2 b", "3 c"], history.lines(4));
+        assert_eq!(0, history.get(5).count());
+    }
+
+    #[test]
+    fn reply_to_internal_command() {
+        let mut history = PinHistory::new(10).track(4);
+        history.record(4, at(7), "21.5");
+
+        let reply = history.reply(&["4"]).unwrap();
+        assert_eq!(vec!["history", "4", "7 21.5"], reply);
+        assert!(history.reply(&["x"]).is_none());
+    }
+}
@@ -0,0 +1,152 @@
+//! Internal commands sent by the server
+//!
+//! Besides pin writes the server talks to the device with `internal`
+//! messages, the first field names the command and the rest are its
+//! arguments. The commands this crate knows are parsed into
+//! `InternalCommand`, so handlers don't have to match on strings.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Internal command from the server, commands this crate doesn't know
+/// end up in `Other` together with their name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InternalCommand {
+    /// Current time, seconds since the Unix epoch
+    Rtc(u64),
+    /// Time zone information
+    Utc(Vec<String>),
+    /// Firmware update
+    Ota(OtaRequest),
+    /// App connected to the server
+    AppConnected,
+    /// App disconnected from the server
+    AppDisconnected,
+    /// File system request
+    Vfs(Vec<String>),
+    Other(Vec<String>),
+}
+
+/// Firmware update requested by the server (Blynk.Air), the application
+/// downloads and applies the image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtaRequest {
+    /// Where to download the firmware image from
+    pub url: String,
+    /// Fields sent after the URL, as they are
+    pub metadata: Vec<String>,
+}
+
+/// Time zone of the device as set in the app, filled from the answers to
+/// `request_timezone`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimeZone {
+    /// Zone name, e.g. `Europe/Warsaw`
+    pub name: Option<String>,
+    /// Offset from UTC in seconds, currently in effect
+    pub offset: Option<i32>,
+    /// Whether the zone observes daylight saving time
+    pub dst: bool,
+    /// POSIX TZ rule, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`
+    pub rule: Option<String>,
+}
+
+impl TimeZone {
+    /// Applies the arguments of an `utc` answer, returns false if it isn't
+    /// about the time zone
+    pub fn update<S: AsRef<str>>(&mut self, args: &[S]) -> bool {
+        let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+        match args.as_slice() {
+            ["tz_name", name, ..] => self.name = Some(name.to_string()),
+            ["tz", offset, ..] => match offset.parse() {
+                Ok(offset) => self.offset = Some(offset),
+                Err(_) => return false,
+            },
+            ["tz_rule", rule, ..] => {
+                // rules of zones with DST name the summer time and its dates
+                self.dst = rule.contains(',');
+                self.rule = Some(rule.to_string());
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl InternalCommand {
+    /// Time sent with `Rtc`
+    pub fn time(&self) -> Option<SystemTime> {
+        match self {
+            InternalCommand::Rtc(secs) => Some(UNIX_EPOCH + Duration::from_secs(*secs)),
+            _ => None,
+        }
+    }
+
+    pub fn parse(name: &str, args: &[&str]) -> InternalCommand {
+        let owned = || args.iter().map(|arg| arg.to_string()).collect();
+        let other = || {
+            let mut fields = vec![name.to_string()];
+            fields.extend(args.iter().map(|arg| arg.to_string()));
+            InternalCommand::Other(fields)
+        };
+        match (name, args) {
+            ("rtc", [time, ..]) => time.parse().map_or_else(|_| other(), InternalCommand::Rtc),
+            ("utc", _) => InternalCommand::Utc(owned()),
+            ("ota", [url, metadata @ ..]) if !url.is_empty() => InternalCommand::Ota(OtaRequest {
+                url: url.to_string(),
+                metadata: metadata.iter().map(|field| field.to_string()).collect(),
+            }),
+            ("acon", _) => InternalCommand::AppConnected,
+            ("adis", _) => InternalCommand::AppDisconnected,
+            ("vfs", _) => InternalCommand::Vfs(owned()),
+            _ => other(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_time_zone() {
+        let mut tz = TimeZone::default();
+        assert!(tz.update(&["tz_name", "Europe/Warsaw"]));
+        assert!(tz.update(&["tz", "7200"]));
+        assert!(tz.update(&["tz_rule", "CET-1CEST,M3.5.0,M10.5.0/3"]));
+        assert!(!tz.update(&["time", "1700000000000"]));
+        assert_eq!(Some("Europe/Warsaw".into()), tz.name);
+        assert_eq!(Some(7200), tz.offset);
+        assert!(tz.dst);
+    }
+
+    #[test]
+    fn parses_known_commands() {
+        assert_eq!(
+            InternalCommand::Rtc(1700000000),
+            InternalCommand::parse("rtc", &["1700000000"])
+        );
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(1700000000)),
+            InternalCommand::parse("rtc", &["1700000000"]).time()
+        );
+        assert_eq!(
+            InternalCommand::AppConnected,
+            InternalCommand::parse("acon", &[])
+        );
+        assert_eq!(
+            InternalCommand::Ota(OtaRequest {
+                url: "http://fw".into(),
+                metadata: vec!["esp32".into()],
+            }),
+            InternalCommand::parse("ota", &["http://fw", "esp32"])
+        );
+        assert!(matches!(
+            InternalCommand::parse("ota", &[]),
+            InternalCommand::Other(_)
+        ));
+        assert_eq!(
+            InternalCommand::Other(vec!["rtc".into(), "soon".into()]),
+            InternalCommand::parse("rtc", &["soon"])
+        );
+    }
+}
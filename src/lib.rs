@@ -25,35 +25,166 @@
 
 use std::error::Error;
 
+mod blynk;
+mod client;
 mod config;
+#[cfg(feature = "embassy")]
+mod embassy;
+#[cfg(feature = "embedded-io")]
+mod embedded;
+#[cfg(all(feature = "embedded-tls", not(feature = "async")))]
+mod embedded_tls;
+mod events;
+mod handler;
+mod history;
+mod internal;
+mod local_server;
+mod logger;
 mod message;
+mod middleware;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod net;
+mod notification;
+#[cfg(all(feature = "esp-ota", not(feature = "async")))]
+mod ota;
+mod panic_report;
+mod params;
+mod pin;
+#[cfg(any(feature = "tls", feature = "native-tls", feature = "embedded-tls"))]
+mod pinning;
+mod proto;
+mod proxy;
+mod reporter;
+mod sender;
+mod session;
+#[cfg(feature = "smoltcp")]
+mod smoltcp;
+mod split;
+#[cfg(feature = "heapless")]
+mod static_message;
+mod sync_client;
+#[cfg(all(feature = "native-tls", not(feature = "tls")))]
+mod system_tls;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "tls")]
+mod tls;
+mod value;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 #[cfg(feature = "async")]
 mod async_impl;
 #[cfg(feature = "async")]
-pub use self::async_impl::{Blynk, Client, Event, Protocol};
+use self::async_impl as rt;
+#[cfg(feature = "async")]
+pub use self::async_impl::{Events, RouteFuture};
+#[cfg(feature = "async")]
+use ::maybe_async::must_be_async as maybe_async;
 
 #[cfg(not(feature = "async"))]
 mod blocking;
 #[cfg(not(feature = "async"))]
-pub use self::blocking::{Blynk, Client, Event, Protocol};
+use self::blocking as rt;
+#[cfg(not(feature = "async"))]
+pub use self::blocking::BlynkThread;
+#[cfg(not(feature = "async"))]
+use ::maybe_async::must_be_sync as maybe_async;
+
+pub use self::blynk::{Blynk, Clock, PinHandle, Resolver, SystemClock, SystemResolver};
+pub use self::client::{Client, Connection, Protocol, ProtocolExt};
+pub use self::config::{Config, ConfigSource, FirmwareInfo, Retransmit, StaticConfig, WritePolicy};
+pub use self::config::{HandlerErrorPolicy, LimitPolicy, NotifyLimit};
+#[cfg(feature = "embassy")]
+pub use self::embassy::{Connect, EmbassyBlynk, EmbassyClient, EmbassyEvent};
+#[cfg(feature = "embedded-io")]
+pub use self::embedded::FromEmbedded;
+pub use self::events::{BlynkEvent, EventQueue};
+pub use self::handler::{Event, FnEvent, HandlerChain, Propagation};
+pub use self::history::PinHistory;
+pub use self::internal::{InternalCommand, OtaRequest, TimeZone};
+pub use self::local_server::LocalServer;
+pub use self::logger::{LogDrain, TerminalLogger};
+pub use self::message::{Message, MessageType, PinMode, ProtocolStatus};
+pub use self::middleware::{Action, Middleware};
+pub use self::notification::{Notification, NotificationBuilder, Placeholder};
+#[cfg(all(feature = "esp-ota", not(feature = "async")))]
+pub use self::ota::{OtaSink, OtaUpdater};
+pub use self::panic_report::PanicReporter;
+pub use self::params::ParamList;
+pub use self::pin::{pins, VirtualPin};
+pub use self::proxy::{Proxy, ProxyKind};
+pub use self::reporter::ChangeReporter;
+pub use self::rt::Stream;
+pub use self::sender::BlynkSender;
+#[cfg(feature = "smoltcp")]
+pub use self::smoltcp::{SmoltcpClient, SmoltcpEvent};
+pub use self::split::{ClientReader, ClientWriter};
+#[cfg(feature = "heapless")]
+pub use self::static_message::StaticMessage;
+pub use self::sync_client::SyncClient;
+pub use self::value::{IntoBlynkValue, Precision};
+
+#[cfg(feature = "macros")]
+pub use blynk_io_macros::{handler, on_read, on_write};
+
+pub mod widgets;
+
+/// Used by the code generated by `macros`
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(feature = "async")]
+    pub use async_trait::async_trait;
+}
 
-pub use self::config::Config;
+// lets the tests use the `::blynk_io` paths of the generated code
+#[cfg(all(test, feature = "macros"))]
+extern crate self as blynk_io;
+
+/// Commonly needed traits and types, so that a single glob import is enough
+///
+/// # Example
+/// ```
+/// use blynk_io::prelude::*;
+///
+/// let mut blynk = Blynk::<DefaultHandler>::new("BLYNK TOKEN".to_string());
+/// blynk.set_config(Config::default());
+///
+/// let chain: HandlerChain = HandlerChain::default()
+///     .with_pins(FnEvent::new(), &[pins::V1])
+///     .with(DefaultHandler {});
+/// let led = widgets::Led::new(pins::V2);
+/// assert_eq!(VirtualPin::new(2), led.pin());
+///
+/// let params = ParamList::new(&["21.5", "on"]);
+/// assert_eq!(21.5, params.as_f64().unwrap());
+/// assert_eq!(Some("on"), params.get(1));
+/// assert_eq!("21.5", 21.5.into_blynk_value());
+///
+/// let event = BlynkEvent::VPinRead { pin: pins::V1 };
+/// assert!(matches!(event, BlynkEvent::VPinRead { pin } if pin == 1));
+/// ```
+pub mod prelude {
+    pub use crate::StaticConfig;
+    pub use crate::{pins, widgets, IntoBlynkValue, ParamList, VirtualPin};
+    pub use crate::{Blynk, BlynkError, Client, Config, DefaultHandler, Event, Protocol};
+    pub use crate::{BlynkEvent, FnEvent, HandlerChain};
+    pub use crate::{ConfigSource, InternalCommand, Notification, PinHistory, Placeholder};
+    pub use crate::{MessageType, PinMode, ProtocolExt, ProtocolStatus, WritePolicy};
+}
 
 /// Represents the current state of connection to Blynk servers
+#[derive(Default)]
 pub enum ConnectionState {
+    #[default]
     Disconnected,
     Connecting,
     Authentiacting,
     Authenticated,
 }
 
-impl Default for ConnectionState {
-    fn default() -> Self {
-        ConnectionState::Disconnected
-    }
-}
-
 /// Various defaults, mostly around connection timeouts and retry logic
 mod conf {
     use std::time::Duration;
@@ -64,7 +195,31 @@ mod conf {
     pub const RETRIES_TX_DELAY: Duration = Duration::from_millis(2);
     pub const RETRIES_TX_MAX_NUM: u8 = 3;
     pub const RECONNECT_SLEEP: Duration = Duration::from_secs(1);
+    pub const DNS_TTL: Duration = Duration::from_secs(300);
+    pub const DNS_MAX_FAILURES: u8 = 3;
+    pub const MAX_REDIRECTS: u8 = 3;
+    pub const MAX_TRACKED_RESPONSES: usize = 32;
+    /// Largest message body the server accepts, its default `buff-in`
+    pub const MAX_BODY_LEN: usize = 1024;
+    /// Events the server accepts from a device within a day
+    pub const MAX_EVENTS_PER_DAY: usize = 100;
+    /// Minimal period between notifications the server accepts
+    pub const NOTIFY_INTERVAL: Duration = Duration::from_secs(5);
+    /// Head start given to each connection attempt before the next
+    /// resolved address is tried in parallel
+    #[cfg(feature = "async")]
+    pub const CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
     pub const HEARTBEAT_PERIOD: Duration = Duration::from_secs(5);
+    /// Longest an idle reader of a split TLS, WebSocket or MQTT stream
+    /// holds it, writes wait at most that long
+    #[cfg(not(feature = "async"))]
+    pub const SPLIT_READ_SLICE: Duration = Duration::from_millis(50);
+    /// Messages a `SyncClient` keeps until the next `run`
+    pub const SYNC_CLIENT_CAPACITY: usize = 64;
+    /// Handlers spawned by `Blynk::spawn_virtual_write` running at once,
+    /// further writes are dropped
+    #[cfg(feature = "async")]
+    pub const MAX_HANDLER_TASKS: usize = 8;
 }
 
 /// Default events handler implementation that can be used
@@ -72,6 +227,7 @@ mod conf {
 pub struct DefaultHandler {}
 
 use std::result;
+use std::time::Duration;
 use std::{fmt, io};
 
 #[derive(Debug)]
@@ -85,9 +241,40 @@ pub enum BlynkError {
     InvalidAuthToken,
     InvalidMessageId,
     InvalidMessageHeader,
+    /// Message type this crate doesn't know, the message is skipped
+    UnknownMessageType(u8),
     InvalidMessageBody,
     StreamIsNone,
     ReaderNotAvailable,
+    NotificationLength(usize),
+    InvalidPlaceholder(String),
+    Tls(String),
+    TlsVerification(String),
+    Handshake,
+    Capacity,
+    /// Server answered the message with an error status
+    Rejected(message::ProtocolStatus),
+    /// Message body of the given length doesn't fit a single message
+    MessageTooLarge(usize),
+    /// Pin number in a message from the server is not a number
+    InvalidPin(String),
+    /// Daily limit of logged events was used up
+    EventLimit(usize),
+    /// URL is not absolute or has a scheme the widget can't play
+    InvalidUrl(String),
+    /// Sent too soon after the previous one, can be sent again after
+    /// the given time
+    RateLimited(Duration),
+    /// `Event` hook failed, see `BlynkError::handler`
+    Handler(String),
+    /// Value at the index of a pin write can't be parsed as `expected`
+    InvalidValue {
+        index: usize,
+        value: String,
+        expected: &'static str,
+    },
+    /// Pin write has no value at the index
+    MissingValue(usize),
 }
 
 impl fmt::Display for BlynkError {
@@ -102,13 +289,49 @@ impl fmt::Display for BlynkError {
             BlynkError::InvalidAuthToken => write!(f, "Invalid auth token"),
             BlynkError::InvalidMessageId => write!(f, "Message id is zero"),
             BlynkError::InvalidMessageHeader => write!(f, "Problem parsing message header"),
+            BlynkError::UnknownMessageType(mtype) => write!(f, "Unknown message type {}", mtype),
             BlynkError::InvalidMessageBody => write!(f, "Malformed message body"),
             BlynkError::StreamIsNone => write!(f, "Stream not available"),
             BlynkError::ReaderNotAvailable => write!(f, "Unable to access reader"),
+            BlynkError::NotificationLength(len) => {
+                write!(f, "Notification length {} out of range", len)
+            }
+            BlynkError::InvalidPlaceholder(ref token) => {
+                write!(f, "Invalid notification placeholder {}", token)
+            }
+            BlynkError::Tls(ref err) => write!(f, "TLS error: {}", err),
+            BlynkError::TlsVerification(ref err) => {
+                write!(f, "TLS certificate verification failed: {}", err)
+            }
+            BlynkError::Handshake => write!(f, "Write rejected, handshake in progress"),
+            BlynkError::Capacity => write!(f, "Message exceeds static buffer capacity"),
+            BlynkError::Rejected(ref ps) => write!(f, "Message rejected by server {:?}", ps),
+            BlynkError::MessageTooLarge(len) => {
+                write!(f, "Message body of {} bytes is too large", len)
+            }
+            BlynkError::InvalidPin(ref pin) => write!(f, "Invalid pin number {:?}", pin),
+            BlynkError::EventLimit(max) => write!(f, "Daily limit of {} events reached", max),
+            BlynkError::InvalidUrl(ref url) => write!(f, "Invalid URL {:?}", url),
+            BlynkError::RateLimited(wait) => write!(f, "Rate limited, retry in {:?}", wait),
+            BlynkError::Handler(ref err) => write!(f, "Handler failed: {}", err),
+            BlynkError::InvalidValue {
+                index,
+                ref value,
+                expected,
+            } => write!(f, "Value {} {:?} is not a valid {}", index, value, expected),
+            BlynkError::MissingValue(index) => write!(f, "Value {} is missing", index),
         }
     }
 }
 
+impl BlynkError {
+    /// Wraps an application error returned from an `Event` hook, e.g.
+    /// `sensor.read().map_err(BlynkError::handler)?`
+    pub fn handler<E: fmt::Display>(err: E) -> BlynkError {
+        BlynkError::Handler(err.to_string())
+    }
+}
+
 impl Error for BlynkError {}
 
 impl From<io::Error> for BlynkError {
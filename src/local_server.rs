@@ -0,0 +1,158 @@
+//! Minimal protocol compatible server for demos and CI
+//!
+//! Accepts any auth token, keeps virtual pin values written by devices in
+//! memory and answers sync requests with them. There is no app side, so
+//! it's only useful for exercising the client without a Blynk account.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::*;
+
+use crate::message::{MessageType, ProtocolHeader, ProtocolStatus};
+use crate::VirtualPin;
+
+/// In-memory Blynk server, see module docs
+#[derive(Clone)]
+pub struct LocalServer {
+    listener: Arc<TcpListener>,
+    pins: Arc<Mutex<HashMap<u8, String>>>,
+}
+
+impl LocalServer {
+    /// Address used by `blynk_io serve-local` when none is given
+    pub const DEFAULT_ADDR: &'static str = "127.0.0.1:8080";
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<LocalServer> {
+        Ok(LocalServer {
+            listener: Arc::new(TcpListener::bind(addr)?),
+            pins: Default::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns last value written to the virtual pin
+    pub fn pin<N: Into<VirtualPin>>(&self, pin: N) -> Option<String> {
+        let pin = pin.into().number();
+        self.pins.lock().unwrap().get(&pin).cloned()
+    }
+
+    /// Accepts connections until the listener fails, each connection is
+    /// served on its own thread
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let (stream, peer) = self.listener.accept()?;
+            info!("Device connected from {}", peer);
+            let pins = self.pins.clone();
+            thread::spawn(move || {
+                if let Err(err) = serve(stream, pins) {
+                    debug!("Connection with {} closed: {}", peer, err);
+                }
+            });
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, pins: Arc<Mutex<HashMap<u8, String>>>) -> io::Result<()> {
+    loop {
+        let (mtype, id, size) = ProtocolHeader::read_from(&mut stream)?;
+        let mut body = vec![0; size.into()];
+        stream.read_exact(&mut body)?;
+        let body = String::from_utf8_lossy(&body);
+        let body: Vec<&str> = body.split('\0').collect();
+
+        let mut reply = Vec::new();
+        match MessageType::try_from(mtype) {
+            Ok(MessageType::Login) | Ok(MessageType::Internal) | Ok(MessageType::Ping) => {
+                let header = (
+                    MessageType::Rsp as u8,
+                    id,
+                    u16::from(ProtocolStatus::StatusOk),
+                );
+                ProtocolHeader::write_to(header, &mut reply)?;
+            }
+            Ok(MessageType::Hw) if body.len() >= 3 && body[0] == "vw" => {
+                if let Ok(pin) = body[1].parse::<u8>() {
+                    debug!("V{} = {}", pin, body[2..].join(" "));
+                    pins.lock().unwrap().insert(pin, body[2..].join("\0"));
+                }
+            }
+            Ok(MessageType::HwSync) => {
+                let pins = pins.lock().unwrap();
+                let mut requested: Vec<u8> = body
+                    .iter()
+                    .skip_while(|&&part| part == "vr")
+                    .filter_map(|pin| pin.parse().ok())
+                    .collect();
+                if requested.is_empty() {
+                    requested = pins.keys().copied().collect();
+                }
+                for pin in requested {
+                    if let Some(value) = pins.get(&pin) {
+                        let data = format!("vw\0{}\0{}", pin, value);
+                        let header = (MessageType::Hw as u8, id, data.len() as u16);
+                        ProtocolHeader::write_to(header, &mut reply)?;
+                        reply.extend_from_slice(data.as_bytes());
+                    }
+                }
+            }
+            _ => debug!("Ignoring message type {} ({:?})", mtype, body),
+        }
+
+        if !reply.is_empty() {
+            stream.write_all(&reply)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    fn start() -> (LocalServer, TcpStream) {
+        let server = LocalServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let runner = server.clone();
+        thread::spawn(move || runner.run());
+        (server, TcpStream::connect(addr).unwrap())
+    }
+
+    fn send(stream: &mut TcpStream, mtype: MessageType, id: u16, body: Vec<&str>) {
+        let msg = Message::new(mtype, id, None, None, body);
+        stream.write_all(&msg.serialize()).unwrap();
+    }
+
+    #[test]
+    fn accepts_any_token() {
+        let (_server, mut stream) = start();
+        send(&mut stream, MessageType::Login, 1, vec!["whatever"]);
+
+        let (mtype, id, status) = ProtocolHeader::read_from(&mut stream).unwrap();
+        assert_eq!(mtype, MessageType::Rsp as u8);
+        assert_eq!(id, 1);
+        assert_eq!(status, u16::from(ProtocolStatus::StatusOk));
+    }
+
+    #[test]
+    fn answers_sync_with_stored_values() {
+        let (server, mut stream) = start();
+        send(&mut stream, MessageType::Hw, 1, vec!["vw", "4", "on"]);
+        send(&mut stream, MessageType::HwSync, 2, vec!["vr", "4", "5"]);
+
+        let (mtype, id, size) = ProtocolHeader::read_from(&mut stream).unwrap();
+        let mut body = vec![0; size.into()];
+        stream.read_exact(&mut body).unwrap();
+        assert_eq!(mtype, MessageType::Hw as u8);
+        assert_eq!(id, 2);
+        assert_eq!(body, b"vw\x004\x00on");
+        assert_eq!(server.pin(4).as_deref(), Some("on"));
+        assert_eq!(server.pin(5), None);
+    }
+}
@@ -0,0 +1,170 @@
+//! Mirroring `log` records to a Terminal widget
+//!
+//! A logger can't reach the connection, so `TerminalLogger` only keeps the
+//! formatted lines and `LogDrain` writes them to the terminal pin, either
+//! from `Blynk::run` (see `Blynk::set_log_drain`) or from the application
+//! loop. Both the buffer and the number of lines written at once are
+//! capped, so a chatty module can't flood the connection.
+
+#[cfg(not(feature = "async"))]
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::{maybe_async, Protocol, Result, VirtualPin};
+
+/// Lines kept until the next drain, older ones are dropped first
+const MAX_BUFFERED: usize = 64;
+/// Lines written by a single drain
+const MAX_PER_DRAIN: usize = 16;
+
+#[cfg(not(feature = "async"))]
+thread_local! {
+    /// Set while draining, so logs of the client writing the lines don't
+    /// end up in the buffer again
+    static DRAINING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Default)]
+struct Lines {
+    lines: VecDeque<String>,
+    dropped: usize,
+}
+
+/// `log::Log` implementation collecting records up to `level`
+pub struct TerminalLogger {
+    level: LevelFilter,
+    lines: Arc<Mutex<Lines>>,
+}
+
+impl TerminalLogger {
+    pub fn new(level: LevelFilter) -> (TerminalLogger, LogDrain) {
+        let lines = Arc::new(Mutex::new(Lines::default()));
+        let logger = TerminalLogger {
+            level,
+            lines: lines.clone(),
+        };
+        (logger, LogDrain { pin: None, lines })
+    }
+
+    /// Installs the logger as the global one, the returned drain writes
+    /// the lines to the terminal on `pin`
+    pub fn init<N: Into<VirtualPin>>(
+        pin: N,
+        level: LevelFilter,
+    ) -> std::result::Result<LogDrain, SetLoggerError> {
+        let (logger, drain) = TerminalLogger::new(level);
+        log::set_logger(Box::leak(Box::new(logger)))?;
+        log::set_max_level(level);
+        Ok(drain.with_pin(pin))
+    }
+}
+
+impl Log for TerminalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        #[cfg(not(feature = "async"))]
+        if DRAINING.get() {
+            return false;
+        }
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("{} {}: {}", record.level(), record.target(), record.args());
+        let mut lines = self.lines.lock().unwrap_or_else(|err| err.into_inner());
+        if lines.lines.len() == MAX_BUFFERED {
+            lines.lines.pop_front();
+            lines.dropped += 1;
+        }
+        lines.lines.push_back(line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Writes the lines collected by `TerminalLogger` to the terminal pin
+#[derive(Clone)]
+pub struct LogDrain {
+    pin: Option<u8>,
+    lines: Arc<Mutex<Lines>>,
+}
+
+impl LogDrain {
+    pub fn with_pin<N: Into<VirtualPin>>(mut self, pin: N) -> LogDrain {
+        self.pin = Some(pin.into().number());
+        self
+    }
+
+    /// Terminal text of the lines to be written next, `None` if there
+    /// are none
+    fn take(&self) -> Option<String> {
+        let mut lines = self.lines.lock().unwrap_or_else(|err| err.into_inner());
+        let mut text = String::new();
+        if lines.dropped > 0 {
+            text.push_str(&format!("... {} lines dropped\n", lines.dropped));
+            lines.dropped = 0;
+        }
+        let count = lines.lines.len().min(MAX_PER_DRAIN);
+        for line in lines.lines.drain(..count) {
+            text.push_str(&line);
+            text.push('\n');
+        }
+        Some(text).filter(|text| !text.is_empty())
+    }
+}
+
+#[maybe_async]
+impl LogDrain {
+    /// Writes the waiting lines, best effort: lines that failed to be
+    /// written are not retried. An async task may move between threads,
+    /// so there the debug logs of the client writing them are collected
+    /// as well
+    pub async fn drain<P: Protocol>(&self, client: &mut P) -> Result<()> {
+        let (Some(pin), Some(text)) = (self.pin, self.take()) else {
+            return Ok(());
+        };
+        #[cfg(not(feature = "async"))]
+        DRAINING.set(true);
+        let res = client.virtual_write_chunked(pin, &text).await;
+        #[cfg(not(feature = "async"))]
+        DRAINING.set(false);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn log(logger: &TerminalLogger, level: Level, msg: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target("app")
+                .args(format_args!("{}", msg))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn collects_filtered_lines() {
+        let (logger, drain) = TerminalLogger::new(LevelFilter::Info);
+        log(&logger, Level::Debug, "hidden");
+        log(&logger, Level::Warn, "low battery");
+        assert_eq!(Some("WARN app: low battery\n".into()), drain.take());
+        assert_eq!(None, drain.take());
+
+        for i in 0..MAX_BUFFERED + 2 {
+            log(&logger, Level::Info, &i.to_string());
+        }
+        let text = drain.take().unwrap();
+        assert!(text.starts_with("... 2 lines dropped\nINFO app: 2\n"));
+        assert_eq!(MAX_PER_DRAIN + 1, text.lines().count());
+    }
+}
@@ -3,74 +3,69 @@ use blynk_io::*;
 use log::*;
 #[cfg(feature = "build-binary")]
 use simple_logger::SimpleLogger;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{env, process};
 
-struct EventsHandler {
-    i: Instant,
-}
-
 #[cfg(feature = "async")]
-use async_trait::async_trait;
+use maybe_async::must_be_async as maybe_async;
+#[cfg(not(feature = "async"))]
+use maybe_async::must_be_sync as maybe_async;
 
-#[cfg(feature = "async")]
-#[async_trait]
-impl Event for EventsHandler {
-    async fn handle_vpin_read(&mut self, client: &mut Client, pin_num: u8) {
-        info!("Wanting to read the state of pin {:?}", pin_num);
-        match pin_num {
-            5 => {
-                client
-                    .virtual_write(5, &format!("V5 {}", self.i.elapsed().as_secs()))
-                    .await
-                    .unwrap_or_default();
-                info!("sent info about pin 5");
-            }
-            4 => {
-                client
-                    .virtual_write(4, &format!("V4 {}", self.i.elapsed().as_secs()))
-                    .await
-                    .unwrap_or_default();
-                info!("sent info about pin 4");
-            }
-            pin => info!("pin not handled: v{}", pin),
-        }
-    }
+mod soak;
 
-    async fn handle_vpin_write(&mut self, _client: &mut Client, pin_num: u8, data: &str) {
-        info!("Wanting to write the state of pin {:?} {:?}", pin_num, data);
-    }
+struct EventsHandler {
+    i: Instant,
 }
 
-#[cfg(not(feature = "async"))]
+#[maybe_async]
 impl Event for EventsHandler {
-    fn handle_vpin_read(&mut self, client: &mut Client, pin_num: u8) {
-        info!("Wanting to read the state of pin {:?}", pin_num);
+    async fn handle_vpin_read(
+        &mut self,
+        client: &mut Client,
+        pin_num: VirtualPin,
+    ) -> Result<(), BlynkError> {
+        info!("Wanting to read the state of pin {}", pin_num);
         match pin_num {
-            5 => {
+            pins::V5 => {
                 client
-                    .virtual_write(5, &format!("V5 {}", self.i.elapsed().as_secs()))
-                    .unwrap_or_default();
+                    .virtual_write(5, format!("V5 {}", self.i.elapsed().as_secs()))
+                    .await?;
                 info!("sent info about pin 5");
             }
-            4 => {
+            pins::V4 => {
                 client
-                    .virtual_write(4, &format!("V4 {}", self.i.elapsed().as_secs()))
-                    .unwrap_or_default();
+                    .virtual_write(4, format!("V4 {}", self.i.elapsed().as_secs()))
+                    .await?;
                 info!("sent info about pin 4");
             }
-            pin => info!("pin not handled: v{}", pin),
+            pin => info!("pin not handled: {}", pin),
         }
+        Ok(())
     }
 
-    fn handle_vpin_write(&mut self, _client: &mut Client, pin_num: u8, data: &str) {
-        info!("Wanting to write the state of pin {:?} {:?}", pin_num, data);
+    async fn handle_vpin_write(
+        &mut self,
+        _client: &mut Client,
+        pin_num: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<(), BlynkError> {
+        info!("Wanting to write the state of pin {} {:?}", pin_num, params);
+        Ok(())
     }
 }
 
 fn main() {
     SimpleLogger::new().init().unwrap();
 
+    if env::args().nth(1).as_deref() == Some("serve-local") {
+        serve_local(env::args().nth(2));
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("soak") {
+        soak(env::args().skip(2).collect());
+        return;
+    }
+
     let config = Config::new(env::args()).unwrap_or_else(|err| {
         eprintln!("Problem parsing arguments: {}", err);
         process::exit(1);
@@ -98,3 +93,41 @@ fn main() {
 
     unreachable!("This code is not reachable ;-)");
 }
+
+fn serve_local(addr: Option<String>) {
+    let addr = addr.unwrap_or_else(|| LocalServer::DEFAULT_ADDR.into());
+    let server = LocalServer::bind(&addr).unwrap_or_else(|err| {
+        eprintln!("Can't listen on {}: {}", addr, err);
+        process::exit(1);
+    });
+
+    println!("Serving local Blynk server on {}", addr);
+    if let Err(err) = server.run() {
+        eprintln!("Server failed: {}", err);
+        process::exit(1);
+    }
+}
+
+/// `soak [SECONDS] [SERVER:PORT TOKEN]`, runs against the in-memory server
+/// unless the server address is given
+fn soak(args: Vec<String>) {
+    let seconds = match args.first() {
+        Some(seconds) => seconds.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid duration {}", seconds);
+            process::exit(1);
+        }),
+        None => 3600,
+    };
+    let upstream = args.get(1).map(|addr| {
+        addr.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid server address {}", addr);
+            process::exit(1);
+        })
+    });
+    let token = args.get(2).cloned().unwrap_or_else(|| "soak".into());
+
+    if let Err(err) = soak::run(Duration::from_secs(seconds), upstream, token) {
+        eprintln!("Soak test failed: {}", err);
+        process::exit(1);
+    }
+}
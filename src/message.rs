@@ -1,7 +1,8 @@
 use crate::BlynkError;
 use crate::Result as MyResult;
-use num_enum::TryFromPrimitive;
+use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 use std::convert::TryFrom;
+use std::io::{self, Read, Write};
 
 /// Represents all type of mesasges that are part of the blynk protocol
 #[derive(TryFromPrimitive, Debug, Clone, Copy)]
@@ -18,7 +19,9 @@ pub enum MessageType {
     Internal = 17,
     Property = 19,
     Hw = 20,
+    Group = 21,
     Redirect = 41,
+    EventLog = 64,
 }
 
 /// Represtantion of Blynk Header structure. It consists of following elements:
@@ -27,18 +30,105 @@ pub enum MessageType {
 /// - payload zie (2 bytes)
 ///
 /// The header is followed by payload (is payload size is > 0)
-#[derive(restruct_derive::Struct)]
-#[fmt = "!BHH"]
+///
+/// All multi-byte fields are encoded in network (big-endian) byte order.
 pub struct ProtocolHeader;
 
-/// Possible protocol statuses
-#[derive(TryFromPrimitive, Debug)]
+impl ProtocolHeader {
+    pub const SIZE: usize = 5;
+
+    /// Writes `(message type, message id, payload size)` header into the writer
+    pub fn write_to<W: Write>(header: (u8, u16, u16), writer: &mut W) -> io::Result<()> {
+        let (mtype, id, size) = header;
+        let mut buf = [0u8; Self::SIZE];
+        buf[0] = mtype;
+        buf[1..3].copy_from_slice(&id.to_be_bytes());
+        buf[3..5].copy_from_slice(&size.to_be_bytes());
+        writer.write_all(&buf)
+    }
+
+    /// Reads `(message type, message id, payload size)` header from the reader
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<(u8, u16, u16)> {
+        let mut buf = [0u8; Self::SIZE];
+        reader.read_exact(&mut buf)?;
+        Ok((
+            buf[0],
+            u16::from_be_bytes([buf[1], buf[2]]),
+            u16::from_be_bytes([buf[3], buf[4]]),
+        ))
+    }
+}
+
+/// Checks if the serialized message is part of the handshake (login or
+/// heartbeat setup) that has to reach the server before anything else
+pub fn is_handshake(data: &[u8]) -> bool {
+    match data.first().map(|&mtype| MessageType::try_from(mtype)) {
+        Some(Ok(MessageType::Login)) => true,
+        Some(Ok(MessageType::Internal)) => data[ProtocolHeader::SIZE..].starts_with(b"ver\0"),
+        _ => false,
+    }
+}
+
+/// Possible protocol statuses, codes this crate doesn't know end up in
+/// `Other`
+#[derive(FromPrimitive, IntoPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
 pub enum ProtocolStatus {
+    /// Too many messages sent, the device is flooding the server
+    StatusQuotaLimit = 1,
+    StatusIllegalCommand = 2,
+    StatusNotRegistered = 3,
+    StatusAlreadyRegistered = 4,
+    StatusNotAuthenticated = 5,
+    StatusNotAllowed = 6,
+    StatusDeviceNotInNetwork = 7,
+    StatusNoActiveDashboard = 8,
     StatusInvalidToken = 9,
+    StatusIllegalCommandBody = 11,
+    StatusGetGraphDataException = 12,
+    StatusNotificationInvalidBody = 13,
+    StatusNotificationNotAuthorized = 14,
+    StatusNotificationException = 15,
+    StatusTimeout = 16,
     StatusNoData = 17,
+    StatusDeviceWentOffline = 18,
+    StatusServerException = 19,
+    StatusNotSupportedVersion = 20,
+    StatusEnergyLimit = 21,
     StatusOk = 200,
     VpinMaxNum = 32,
+    #[num_enum(catch_all)]
+    Other(u16),
+}
+
+/// Mode of a physical pin, as sent in `pm` hardware commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinMode {
+    Input,
+    Output,
+    InputPullUp,
+    InputPullDown,
+}
+
+impl PinMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PinMode::Input => "in",
+            PinMode::Output => "out",
+            PinMode::InputPullUp => "pu",
+            PinMode::InputPullDown => "pd",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<PinMode> {
+        match mode {
+            "in" => Some(PinMode::Input),
+            "out" => Some(PinMode::Output),
+            "pu" => Some(PinMode::InputPullUp),
+            "pd" => Some(PinMode::InputPullDown),
+            _ => None,
+        }
+    }
 }
 
 /// Represents a single message (in our out) between client and blynk servers
@@ -49,6 +139,9 @@ pub struct Message {
     pub size: Option<u16>,
     pub status: Option<ProtocolStatus>,
     pub body: Vec<String>,
+    /// Body bytes of a message that is not valid UTF-8, fields that are
+    /// not valid UTF-8 are empty in `body`
+    pub raw: Option<Vec<u8>>,
 }
 
 impl Message {
@@ -66,12 +159,29 @@ impl Message {
             size,
             status,
             body,
+            raw: None,
+        }
+    }
+
+    /// Extracts pin number and value if it's a `vw` hardware message
+    pub fn virtual_write_value(&self) -> Option<(u8, &str)> {
+        match self.mtype {
+            MessageType::Hw | MessageType::Bridge
+                if self.body.len() >= 3 && self.body[0] == "vw" =>
+            {
+                let pin = self.body[1].parse::<u8>().ok()?;
+                Some((pin, &self.body[2]))
+            }
+            _ => None,
         }
     }
 
     /// Converts the `Message` into byte array
     pub fn serialize(&self) -> Vec<u8> {
-        let mut data = self.body.join("\0").as_bytes().to_vec();
+        let mut data = match &self.raw {
+            Some(raw) => raw.clone(),
+            None => self.body.join("\0").into_bytes(),
+        };
 
         let mut buffer = Vec::new();
         let input: (u8, u16, u16) = (self.mtype as u8, self.id, data.len() as u16);
@@ -83,44 +193,116 @@ impl Message {
 
     /// Converts byte array into Message object or returns error
     /// if it's not possible
-    pub fn deserilize(mut rsp_data: &[u8]) -> MyResult<Message> {
-        let mut msg_body = vec![];
+    pub fn deserilize(rsp_data: &[u8]) -> MyResult<Message> {
+        MessageRef::parse(rsp_data).map(|msg| msg.into_owned())
+    }
+
+    /// Borrows the message, e.g. in order to dispatch it like a received one
+    pub fn to_ref(&self) -> MessageRef<'_> {
+        MessageRef {
+            mtype: self.mtype,
+            id: self.id,
+            size: self.size,
+            status: self.status,
+            body: self.body.iter().map(String::as_str).collect(),
+            raw: self.raw.as_deref(),
+        }
+    }
+}
+
+/// Received message with the body fields borrowed from the buffer it was
+/// parsed from, so dispatching it to the handlers doesn't copy them. It's
+/// turned into `Message` only when it has to outlive the buffer
+#[derive(Debug)]
+pub struct MessageRef<'a> {
+    pub mtype: MessageType,
+    pub id: u16,
+    pub size: Option<u16>,
+    pub status: Option<ProtocolStatus>,
+    pub body: Vec<&'a str>,
+    /// Body bytes if they are not valid UTF-8, see `Message::raw`
+    pub raw: Option<&'a [u8]>,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parses the message at the start of `rsp_data`
+    pub fn parse(mut rsp_data: &'a [u8]) -> MyResult<MessageRef<'a>> {
+        let mut body = vec![];
+        let mut raw = None;
         let (msg_type_raw, msg_id, h_data) = ProtocolHeader::read_from(&mut rsp_data)?;
 
         if msg_id == 0 {
             return Err(BlynkError::InvalidMessageId);
         }
 
-        let msg_type =
-            MessageType::try_from(msg_type_raw).map_err(|_e| BlynkError::InvalidMessageHeader)?;
+        let msg_type = MessageType::try_from(msg_type_raw)
+            .map_err(|_| BlynkError::UnknownMessageType(msg_type_raw))?;
         let mut size = None;
         let mut status = None;
 
         match msg_type {
             MessageType::Rsp | MessageType::Ping => {
-                status = Some(ProtocolStatus::try_from(h_data).expect("Incorrect response status"));
+                status = Some(ProtocolStatus::from(h_data));
             }
-            MessageType::Hw
-            | MessageType::Bridge
-            | MessageType::Internal
-            | MessageType::Redirect => {
+            _ => {
                 size = Some(h_data);
-                let msg_body_raw = match String::from_utf8(rsp_data[..h_data.into()].to_vec()) {
-                    Ok(msg_body_raw) => msg_body_raw,
-                    Err(_) => return Err(BlynkError::InvalidMessageBody),
+                let body_raw = rsp_data
+                    .get(..h_data.into())
+                    .ok_or(BlynkError::InvalidMessageBody)?;
+                body = match std::str::from_utf8(body_raw) {
+                    Ok(body_raw) => body_raw.split('\0').collect(),
+                    // binary payload, the text fields are still available
+                    Err(_) => {
+                        raw = Some(body_raw);
+                        body_raw
+                            .split(|&b| b == 0)
+                            .map(|field| std::str::from_utf8(field).unwrap_or_default())
+                            .collect()
+                    }
                 };
-                msg_body = msg_body_raw.split('\0').map(String::from).collect();
             }
-            _ => panic!("Unknown message type {:?}", msg_type),
         }
 
-        Ok(Message::new(
-            msg_type,
-            msg_id,
+        Ok(MessageRef {
+            mtype: msg_type,
+            id: msg_id,
             size,
             status,
-            msg_body.iter().map(|s| s as &str).collect(),
-        ))
+            body,
+            raw,
+        })
+    }
+
+    /// Raw body bytes following the first `fields` fields of a binary
+    /// message, `None` if the body is valid UTF-8
+    pub fn raw_tail(&self, fields: usize) -> Option<&'a [u8]> {
+        let mut rest = self.raw?;
+        for _ in 0..fields {
+            let pos = rest.iter().position(|&b| b == 0)?;
+            rest = &rest[pos + 1..];
+        }
+        Some(rest)
+    }
+
+    /// Extracts pin number and value if it's a `vw` hardware message
+    pub fn virtual_write_value(&self) -> Option<(u8, &'a str)> {
+        match self.mtype {
+            MessageType::Hw | MessageType::Bridge
+                if self.body.len() >= 3 && self.body[0] == "vw" =>
+            {
+                let pin = self.body[1].parse::<u8>().ok()?;
+                Some((pin, self.body[2]))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn into_owned(self) -> Message {
+        let raw = self.raw.map(<[u8]>::to_vec);
+        Message {
+            raw,
+            ..Message::new(self.mtype, self.id, self.size, self.status, self.body)
+        }
     }
 }
 
@@ -142,13 +324,13 @@ mod tests {
         assert_eq!(msg.mtype as u8, dmsg.mtype as u8);
         assert_eq!(msg.id, dmsg.id);
         assert_eq!(msg.size, dmsg.size);
-        assert_eq!(msg.status.unwrap() as u16, dmsg.status.unwrap() as u16);
+        assert_eq!(msg.status, dmsg.status);
         assert_ne!(msg.body, dmsg.body);
     }
 
     #[test]
     fn deserialize_response() {
-        let mut data = vec!["test", "it"].join("\0").as_bytes().to_vec();
+        let mut data = ["test", "it"].join("\0").as_bytes().to_vec();
 
         let mut buffer = Vec::new();
         let input: (u8, u16, u16) = (MessageType::Hw as u8, 32, data.len() as u16);
@@ -160,10 +342,57 @@ mod tests {
         assert_eq!(MessageType::Hw as u8, dmsg.mtype as u8);
         assert_eq!(32, dmsg.id);
         assert_eq!(7, dmsg.size.unwrap());
-        assert_eq!(true, dmsg.status.is_none());
+        assert!(dmsg.status.is_none());
         assert_eq!(vec!["test", "it"], dmsg.body);
     }
 
+    #[test]
+    fn unknown_status_codes_are_kept() {
+        assert_eq!(
+            ProtocolStatus::StatusNotAuthenticated,
+            ProtocolStatus::from(5)
+        );
+        assert_eq!(ProtocolStatus::Other(77), ProtocolStatus::from(77));
+        assert_eq!(77, u16::from(ProtocolStatus::Other(77)));
+        assert_eq!(200, u16::from(ProtocolStatus::StatusOk));
+    }
+
+    #[test]
+    fn unknown_message_type_is_an_error() {
+        let mut data = Vec::new();
+        ProtocolHeader::write_to((99, 1, 2), &mut data).unwrap();
+        data.extend_from_slice(b"ab");
+        assert!(matches!(
+            MessageRef::parse(&data),
+            Err(BlynkError::UnknownMessageType(99))
+        ));
+
+        // known types the client never expects are parsed like any other
+        let data =
+            Message::new(MessageType::Property, 1, None, None, vec!["1", "label"]).serialize();
+        assert_eq!(vec!["1", "label"], MessageRef::parse(&data).unwrap().body);
+    }
+
+    #[test]
+    fn keeps_binary_body() {
+        let mut data = Vec::new();
+        ProtocolHeader::write_to((MessageType::Hw as u8, 1, 8), &mut data).unwrap();
+        data.extend_from_slice(b"vw\x001\x00\xff\x00\x02");
+        let msg = MessageRef::parse(&data).unwrap();
+        assert_eq!(vec!["vw", "1", "", "\x02"], msg.body);
+        assert_eq!(Some(&b"\xff\x00\x02"[..]), msg.raw_tail(2));
+        assert_eq!(data, msg.into_owned().serialize());
+    }
+
+    #[test]
+    fn parse_borrows_body_from_buffer() {
+        let data = Message::new(MessageType::Hw, 3, None, None, vec!["vw", "4", "on"]).serialize();
+        let msg = MessageRef::parse(&data).unwrap();
+        assert_eq!(Some((4, "on")), msg.virtual_write_value());
+        assert!(std::ptr::eq(&data[data.len() - 2], msg.body[2].as_ptr()));
+        assert_eq!(vec!["vw", "4", "on"], msg.into_owned().body);
+    }
+
     #[test]
     fn serialize_with_payload() {
         let msg = Message::new(MessageType::Hw, 32, None, None, vec!["a", "b", "c"]);
@@ -172,7 +401,7 @@ mod tests {
         let header: Vec<u8> = vec![MessageType::Hw as u8, 0, 32, 0, 5];
         assert_eq!(header, &data[..5]);
 
-        let payload: Vec<u8> = vec!['a', '\0', 'b', '\0', 'c']
+        let payload: Vec<u8> = ['a', '\0', 'b', '\0', 'c']
             .iter()
             .map(|c| *c as u8)
             .collect::<Vec<_>>();
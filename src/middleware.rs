@@ -0,0 +1,119 @@
+//! Interception of the messages exchanged with the server
+//!
+//! Middleware registered with `Client::add_middleware` (or
+//! `Blynk::add_middleware`) sees every outgoing message before it's queued
+//! and every received one before it's dispatched to the handler, in the
+//! order it was added. It's meant for logging, filtering, or injecting
+//! faults in tests.
+
+use crate::message::{Message, MessageType};
+
+/// What to do with an intercepted message
+#[derive(Debug)]
+pub enum Action {
+    /// Passes the message on, with the changes made to an outgoing one
+    Forward,
+    /// Drops the message, the next middleware doesn't see it either
+    Drop,
+    /// Passes this message on instead
+    Replace(Message),
+}
+
+#[allow(unused_variables)]
+pub trait Middleware: Send {
+    /// Message about to be sent, pings and responses to the server are
+    /// not passed here
+    fn on_outgoing(&mut self, msg: &mut Message) -> Action {
+        Action::Forward
+    }
+
+    /// Message received from the server
+    fn on_incoming(&mut self, msg: &Message) -> Action {
+        Action::Forward
+    }
+}
+
+/// Middleware registered with a client
+#[derive(Default)]
+pub(crate) struct Middlewares(Vec<Box<dyn Middleware>>);
+
+impl Middlewares {
+    pub fn add(&mut self, middleware: Box<dyn Middleware>) {
+        self.0.push(middleware);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Runs the serialized message through the middleware, `None` if it
+    /// was dropped
+    pub fn outgoing(&mut self, data: Vec<u8>) -> Option<Vec<u8>> {
+        if self.0.is_empty() {
+            return Some(data);
+        }
+        // responses keep their status in the body, they can't be parsed back
+        let mut msg = match Message::deserilize(&data) {
+            Ok(msg) if !matches!(msg.mtype, MessageType::Rsp | MessageType::Ping) => msg,
+            _ => return Some(data),
+        };
+        for middleware in &mut self.0 {
+            match middleware.on_outgoing(&mut msg) {
+                Action::Forward => (),
+                Action::Drop => return None,
+                Action::Replace(replaced) => msg = replaced,
+            }
+        }
+        Some(msg.serialize())
+    }
+
+    /// Runs the received message through the middleware, `None` if it was
+    /// dropped
+    pub fn incoming(&mut self, mut msg: Message) -> Option<Message> {
+        for middleware in &mut self.0 {
+            match middleware.on_incoming(&msg) {
+                Action::Forward => (),
+                Action::Drop => return None,
+                Action::Replace(replaced) => msg = replaced,
+            }
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto;
+
+    struct Upper;
+    impl Middleware for Upper {
+        fn on_outgoing(&mut self, msg: &mut Message) -> Action {
+            match msg.body.last_mut() {
+                Some(value) if value == "secret" => Action::Drop,
+                Some(value) => {
+                    *value = value.to_uppercase();
+                    Action::Forward
+                }
+                None => Action::Forward,
+            }
+        }
+    }
+
+    #[test]
+    fn modifies_and_drops_outgoing_messages() {
+        let mut middlewares = Middlewares::default();
+        middlewares.add(Box::new(Upper));
+        assert_eq!(
+            Some(proto::virtual_write(1, 2, "ON")),
+            middlewares.outgoing(proto::virtual_write(1, 2, "on"))
+        );
+        let msg = Message::new(MessageType::Hw, 2, None, None, vec!["vw", "2", "secret"]);
+        assert_eq!(None, middlewares.outgoing(msg.serialize()));
+        // responses are passed as they are
+        assert_eq!(
+            Some(proto::response(3, 200)),
+            middlewares.outgoing(proto::response(3, 200))
+        );
+    }
+}
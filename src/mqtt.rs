@@ -0,0 +1,448 @@
+//! MQTT transport for the Blynk 2.0 cloud
+//!
+//! Instead of a second client, `Bridge` translates the binary protocol
+//! spoken by `Client` into MQTT 3.1.1 packets and back, so the same
+//! `Client`/`Event` code works with both transports:
+//!
+//! - `Login` becomes `CONNECT` (user `device`, token as password),
+//!   answered from `CONNACK`, followed by subscription to `downlink/#`
+//! - `vw` writes are published to `ds/V<pin>`, `vr` syncs to `get/ds`
+//!   and properties to `ds/V<pin>/prop/<name>`
+//! - logged events are published to `event/<code>` with the description
+//! - `Ping` becomes `PINGREQ`, the heartbeat setup is answered locally
+//! - `downlink/ds/V<pin>` publishes are delivered as `vw` messages
+//!
+//! Datastreams have to be named after the virtual pins (`V0`, `V1`, ...).
+//! Other messages have no MQTT counterpart and are dropped.
+
+use std::collections::VecDeque;
+use std::io;
+#[cfg(not(feature = "async"))]
+use std::io::{Read, Write};
+
+use log::*;
+
+use crate::message::{MessageType, ProtocolHeader, ProtocolStatus};
+use crate::ConfigSource;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+
+/// Topic prefix of values sent by the cloud
+const DOWNLINK: &str = "downlink/ds/";
+
+/// Translates between Blynk binary messages and MQTT packets without
+/// doing any IO, shared by the blocking and async streams
+pub struct Bridge {
+    client_id: String,
+    keep_alive: u16,
+    /// Blynk bytes written by the client, not yet a complete message
+    pending: Vec<u8>,
+    /// MQTT bytes received from the broker, not yet a complete packet
+    received: Vec<u8>,
+    /// MQTT packets to be sent to the broker
+    outgoing: Vec<u8>,
+    /// Blynk messages to be read by the client
+    incoming: VecDeque<u8>,
+    login_id: u16,
+    pings: VecDeque<u16>,
+    msg_id: u16,
+}
+
+impl Bridge {
+    pub fn new(client_id: &str, keep_alive: u16) -> Bridge {
+        Bridge {
+            client_id: client_id.into(),
+            keep_alive,
+            pending: Vec::new(),
+            received: Vec::new(),
+            outgoing: Vec::new(),
+            incoming: VecDeque::new(),
+            login_id: 0,
+            pings: VecDeque::new(),
+            msg_id: 0,
+        }
+    }
+
+    /// Uses `device_id` as the MQTT client id and heartbeat as keep alive
+    pub fn for_config(config: &impl ConfigSource) -> Bridge {
+        let client_id = match config.device_id() {
+            Some(id) => format!("rust-{}", id),
+            None => "rust".to_string(),
+        };
+        let keep_alive = config.heartbeat().as_secs().min(u16::MAX.into()) as u16;
+        Bridge::new(&client_id, keep_alive)
+    }
+
+    /// Consumes Blynk messages written by the client
+    pub fn write(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        while self.pending.len() >= ProtocolHeader::SIZE {
+            let (mtype, id, size) = header(&self.pending);
+            let len = ProtocolHeader::SIZE + size as usize;
+            if self.pending.len() < len {
+                break;
+            }
+            let body: Vec<u8> = self
+                .pending
+                .drain(..len)
+                .skip(ProtocolHeader::SIZE)
+                .collect();
+            let body = String::from_utf8_lossy(&body).into_owned();
+            let args: Vec<&str> = body.split('\0').collect();
+            self.uplink(mtype, id, &args);
+        }
+    }
+
+    fn uplink(&mut self, mtype: u8, id: u16, args: &[&str]) {
+        match MessageType::try_from(mtype) {
+            Ok(MessageType::Login) => {
+                self.login_id = id;
+                self.connect(args[0]);
+            }
+            Ok(MessageType::Ping) => {
+                self.pings.push_back(id);
+                self.outgoing.extend([PINGREQ << 4, 0]);
+            }
+            // heartbeat and buffer size are MQTT keep alive already
+            Ok(MessageType::Internal) if args[0] == "ver" => {
+                self.respond(id, ProtocolStatus::StatusOk);
+            }
+            Ok(MessageType::Hw) if args.len() >= 3 && args[0] == "vw" => {
+                let topic = format!("ds/V{}", args[1]);
+                self.publish(&topic, args[2..].join("\0").as_bytes());
+            }
+            Ok(MessageType::HwSync) if args.len() >= 2 && args[0] == "vr" => {
+                let names: Vec<String> = args[1..].iter().map(|pin| format!("V{}", pin)).collect();
+                self.publish("get/ds", names.join(",").as_bytes());
+            }
+            Ok(MessageType::Property) if args.len() >= 3 => {
+                let topic = format!("ds/V{}/prop/{}", args[0], args[1]);
+                self.publish(&topic, args[2].as_bytes());
+            }
+            Ok(MessageType::EventLog) if !args[0].is_empty() => {
+                let topic = format!("event/{}", args[0]);
+                let description = args.get(1).copied().unwrap_or_default();
+                self.publish(&topic, description.as_bytes());
+            }
+            _ => debug!("No MQTT counterpart for message type {}, dropping", mtype),
+        }
+    }
+
+    fn connect(&mut self, token: &str) {
+        let mut packet = Vec::new();
+        put_str(&mut packet, "MQTT");
+        // protocol level 4, user name + password + clean session flags
+        packet.extend([4, 0xc2]);
+        packet.extend(self.keep_alive.to_be_bytes());
+        put_str(&mut packet, &self.client_id);
+        put_str(&mut packet, "device");
+        put_str(&mut packet, token);
+        self.packet(CONNECT << 4, &packet);
+    }
+
+    fn subscribe(&mut self) {
+        let mut packet = 1u16.to_be_bytes().to_vec();
+        put_str(&mut packet, "downlink/#");
+        packet.push(0);
+        self.packet(SUBSCRIBE << 4 | 0x02, &packet);
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) {
+        let mut packet = Vec::new();
+        put_str(&mut packet, topic);
+        packet.extend_from_slice(payload);
+        self.packet(PUBLISH << 4, &packet);
+    }
+
+    fn packet(&mut self, kind: u8, packet: &[u8]) {
+        self.outgoing.push(kind);
+        let mut len = packet.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                self.outgoing.push(byte);
+                break;
+            }
+            self.outgoing.push(byte | 0x80);
+        }
+        self.outgoing.extend_from_slice(packet);
+    }
+
+    fn respond(&mut self, id: u16, status: ProtocolStatus) {
+        let mut buf = Vec::new();
+        let header = (MessageType::Rsp as u8, id, u16::from(status));
+        if ProtocolHeader::write_to(header, &mut buf).is_ok() {
+            self.incoming.extend(buf);
+        }
+    }
+
+    /// Consumes bytes received from the broker
+    pub fn decode(&mut self, data: &[u8]) -> io::Result<()> {
+        self.received.extend_from_slice(data);
+        while let Some((header, len)) = remaining_length(&self.received)? {
+            if self.received.len() < header + len {
+                break;
+            }
+            let kind = self.received[0];
+            let packet: Vec<u8> = self.received.drain(..header + len).skip(header).collect();
+            self.downlink(kind, &packet);
+        }
+        Ok(())
+    }
+
+    fn downlink(&mut self, kind: u8, packet: &[u8]) {
+        match kind >> 4 {
+            CONNACK => {
+                let status = match packet.get(1) {
+                    Some(0) => ProtocolStatus::StatusOk,
+                    _ => ProtocolStatus::StatusInvalidToken,
+                };
+                if matches!(status, ProtocolStatus::StatusOk) {
+                    self.subscribe();
+                }
+                self.respond(self.login_id, status);
+            }
+            PUBLISH => self.downlink_publish(kind, packet),
+            PINGRESP => {
+                let id = self.pings.pop_front().unwrap_or(1);
+                self.respond(id, ProtocolStatus::StatusOk);
+            }
+            SUBACK => (),
+            kind => debug!("Ignoring MQTT packet {}", kind),
+        }
+    }
+
+    fn downlink_publish(&mut self, kind: u8, packet: &[u8]) {
+        if packet.len() < 2 {
+            return;
+        }
+        let topic_len = u16::from_be_bytes([packet[0], packet[1]]) as usize;
+        let Some(topic) = packet.get(2..2 + topic_len) else {
+            return;
+        };
+        let topic = String::from_utf8_lossy(topic);
+        // QoS > 0 publishes carry packet identifier
+        let mut offset = 2 + topic_len;
+        if kind & 0x06 != 0 {
+            offset += 2;
+        }
+        let payload = String::from_utf8_lossy(packet.get(offset..).unwrap_or_default());
+
+        let pin = topic
+            .strip_prefix(DOWNLINK)
+            .and_then(|name| name.strip_prefix('V'))
+            .and_then(|pin| pin.parse::<u8>().ok());
+        let Some(pin) = pin else {
+            debug!("Ignoring publish to {}", topic);
+            return;
+        };
+
+        let body = ["vw", &pin.to_string(), &payload].join("\0");
+        let mut buf = Vec::new();
+        let header = (MessageType::Hw as u8, self.next_id(), body.len() as u16);
+        if ProtocolHeader::write_to(header, &mut buf).is_ok() {
+            buf.extend(body.as_bytes());
+            self.incoming.extend(buf);
+        }
+    }
+
+    /// Message ids of translated messages, never 0 which is invalid
+    fn next_id(&mut self) -> u16 {
+        self.msg_id = self.msg_id.checked_add(1).unwrap_or(1);
+        self.msg_id
+    }
+
+    /// Returns MQTT bytes to be sent to the broker
+    pub fn take_outgoing(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Moves translated messages into the buffer, returns number of bytes copied
+    pub fn read_incoming(&mut self, buf: &mut [u8]) -> usize {
+        let size = buf.len().min(self.incoming.len());
+        for (dst, src) in buf.iter_mut().zip(self.incoming.drain(..size)) {
+            *dst = src;
+        }
+        size
+    }
+
+    pub fn has_incoming(&self) -> bool {
+        !self.incoming.is_empty()
+    }
+}
+
+fn header(data: &[u8]) -> (u8, u16, u16) {
+    (
+        data[0],
+        u16::from_be_bytes([data[1], data[2]]),
+        u16::from_be_bytes([data[3], data[4]]),
+    )
+}
+
+/// Returns `(fixed header size, remaining length)` of the first packet
+/// once its fixed header was received
+fn remaining_length(data: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let mut len = 0;
+    for (i, byte) in data.iter().skip(1).take(4).enumerate() {
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((i + 2, len)));
+        }
+    }
+    if data.len() > 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed MQTT remaining length",
+        ));
+    }
+    Ok(None)
+}
+
+fn put_str(packet: &mut Vec<u8>, value: &str) {
+    packet.extend((value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value.as_bytes());
+}
+
+/// Blocking MQTT connection carrying Blynk messages
+#[cfg(not(feature = "async"))]
+pub struct Mqtt<S> {
+    inner: S,
+    bridge: Bridge,
+}
+
+#[cfg(not(feature = "async"))]
+impl<S: Read + Write> Mqtt<S> {
+    pub fn new(inner: S, bridge: Bridge) -> Mqtt<S> {
+        Mqtt { inner, bridge }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl<S: Read + Write> Read for Mqtt<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0; 512];
+        while !self.bridge.has_incoming() {
+            let size = self.inner.read(&mut raw)?;
+            if size == 0 {
+                return Ok(0);
+            }
+            self.bridge.decode(&raw[..size])?;
+
+            let outgoing = self.bridge.take_outgoing();
+            if !outgoing.is_empty() {
+                self.inner.write_all(&outgoing)?;
+            }
+        }
+        Ok(self.bridge.read_incoming(buf))
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl<S: Read + Write> Write for Mqtt<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bridge.write(buf);
+        self.inner.write_all(&self.bridge.take_outgoing())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    fn blynk(mtype: MessageType, id: u16, body: Vec<&str>) -> Vec<u8> {
+        Message::new(mtype, id, None, None, body).serialize()
+    }
+
+    fn read_all(bridge: &mut Bridge) -> Vec<u8> {
+        let mut buf = [0; 256];
+        let size = bridge.read_incoming(&mut buf);
+        buf[..size].to_vec()
+    }
+
+    #[test]
+    fn login_connects_and_subscribes() {
+        let mut bridge = Bridge::new("rust", 10);
+        bridge.write(&blynk(MessageType::Login, 1, vec!["token"]));
+        let connect = bridge.take_outgoing();
+        assert_eq!(CONNECT << 4, connect[0]);
+        assert_eq!(connect.len() - 2, connect[1] as usize);
+        assert_eq!(b"\x00\x04MQTT\x04\xc2\x00\x0a", &connect[2..12]);
+        assert!(connect.ends_with(b"\x00\x06device\x00\x05token"));
+        assert!(!bridge.has_incoming());
+
+        bridge.decode(&[CONNACK << 4, 2, 0, 0]).unwrap();
+        assert_eq!(vec![0, 0, 1, 0, 200], read_all(&mut bridge));
+        let subscribe = bridge.take_outgoing();
+        assert_eq!(SUBSCRIBE << 4 | 0x02, subscribe[0]);
+        assert!(subscribe.ends_with(b"downlink/#\x00"));
+
+        let mut rejected = Bridge::new("rust", 10);
+        rejected.write(&blynk(MessageType::Login, 1, vec!["bad"]));
+        rejected.decode(&[CONNACK << 4, 2, 0, 5]).unwrap();
+        assert_eq!(vec![0, 0, 1, 0, 9], read_all(&mut rejected));
+    }
+
+    #[test]
+    fn translates_uplink_messages() {
+        let mut bridge = Bridge::new("rust", 10);
+        let mut data = blynk(MessageType::Hw, 2, vec!["vw", "4", "21.5"]);
+        data.extend(blynk(MessageType::HwSync, 3, vec!["vr", "1", "2"]));
+        // messages may be split across writes
+        bridge.write(&data[..7]);
+        bridge.write(&data[7..]);
+
+        let mut expected = vec![PUBLISH << 4, 11, 0, 5];
+        expected.extend(b"ds/V421.5");
+        expected.extend([PUBLISH << 4, 13, 0, 6]);
+        expected.extend(b"get/dsV1,V2");
+        assert_eq!(expected, bridge.take_outgoing());
+
+        bridge.write(&blynk(MessageType::EventLog, 5, vec!["leak", "Water!"]));
+        let mut expected = vec![PUBLISH << 4, 18, 0, 10];
+        expected.extend(b"event/leakWater!");
+        assert_eq!(expected, bridge.take_outgoing());
+
+        bridge.write(&blynk(MessageType::Internal, 4, vec!["ver", "0.3.0"]));
+        assert_eq!(vec![0, 0, 4, 0, 200], read_all(&mut bridge));
+        assert!(bridge.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn translates_downlink_publish_and_ping() {
+        let mut bridge = Bridge::new("rust", 10);
+        let mut packet = vec![PUBLISH << 4, 17, 0, 14];
+        packet.extend(b"downlink/ds/V71");
+        bridge.decode(&packet[..5]).unwrap();
+        assert!(!bridge.has_incoming());
+        bridge.decode(&packet[5..]).unwrap();
+
+        let msg = Message::deserilize(&read_all(&mut bridge)).unwrap();
+        assert_eq!(Some((7, "1")), msg.virtual_write_value());
+
+        bridge.write(&blynk(MessageType::Ping, 9, vec![]));
+        assert_eq!(vec![PINGREQ << 4, 0], bridge.take_outgoing());
+        bridge.decode(&[PINGRESP << 4, 0]).unwrap();
+        assert_eq!(vec![0, 0, 9, 0, 200], read_all(&mut bridge));
+    }
+}
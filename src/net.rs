@@ -0,0 +1,198 @@
+//! Picking the server address to connect to
+//!
+//! Dual-stack hosts usually resolve to both IPv6 and IPv4 addresses and
+//! only some of them may be reachable. Instead of taking the first one the
+//! clients try all of them in happy eyeballs order (RFC 8305): families
+//! alternate starting with IPv6, so an unreachable family costs at most
+//! one failed attempt before the other one is tried.
+//!
+//! Resolved addresses are kept in `DnsCache` between reconnects, so flaky
+//! links don't hit the resolver on every attempt.
+
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::{conf, BlynkError, Result};
+
+/// Addresses of the last resolved server, reused until they get older
+/// than `conf::DNS_TTL` or connecting to them failed too many times
+#[derive(Default)]
+pub struct DnsCache {
+    entry: Option<(Vec<SocketAddr>, Instant)>,
+    failures: u8,
+}
+
+impl DnsCache {
+    /// Cached addresses, `None` if they have to be resolved again
+    pub fn get(&self, now: Instant) -> Option<Vec<SocketAddr>> {
+        match &self.entry {
+            Some((addrs, resolved)) if now.saturating_duration_since(*resolved) < conf::DNS_TTL => {
+                Some(addrs.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn store(&mut self, addrs: Vec<SocketAddr>, now: Instant) {
+        self.entry = Some((addrs, now));
+        self.failures = 0;
+    }
+
+    pub fn connected(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Records a failed connection, the entry is dropped after
+    /// `conf::DNS_MAX_FAILURES` failures in a row
+    pub fn failed(&mut self) {
+        self.failures += 1;
+        if self.failures >= conf::DNS_MAX_FAILURES {
+            debug!("Dropping cached server addresses");
+            self.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entry = None;
+        self.failures = 0;
+    }
+}
+
+/// Orders resolved addresses alternating IPv6 and IPv4, keeping the
+/// resolver order within each family
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Tries the addresses one by one, returns the first connected socket
+/// or the last error, `BlynkError::Dns` if there was nothing to try
+#[cfg(not(feature = "async"))]
+pub fn connect_any(addrs: Vec<SocketAddr>, timeout: Duration) -> Result<TcpStream> {
+    let mut last_err = None;
+    for addr in interleave(addrs) {
+        debug!("Connecting to {}", addr);
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(sock) => return Ok(sock),
+            Err(err) => {
+                warn!("Connection to {} failed: {}", addr, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.map_or(BlynkError::Dns, BlynkError::from))
+}
+
+/// Races connection attempts, starting the next one whenever the previous
+/// didn't succeed within `delay`, returns the first connected socket
+#[cfg(feature = "async")]
+pub async fn race(addrs: Vec<SocketAddr>, delay: Duration, timeout: Duration) -> Result<TcpStream> {
+    let addrs = interleave(addrs);
+    if addrs.is_empty() {
+        return Err(BlynkError::Dns);
+    }
+
+    let (tx, rx) = smol::channel::bounded(addrs.len());
+    // attempts still running are cancelled once the tasks are dropped
+    let _attempts: Vec<_> = addrs
+        .into_iter()
+        .enumerate()
+        .map(|(i, addr)| {
+            let tx = tx.clone();
+            smol::spawn(async move {
+                smol::Timer::after(delay * i as u32).await;
+                debug!("Connecting to {}", addr);
+                // opening async TcpStream connection does not work yet with esp-rs
+                let res = smol::unblock(move || TcpStream::connect_timeout(&addr, timeout)).await;
+                if let Err(err) = &res {
+                    warn!("Connection to {} failed: {}", addr, err);
+                }
+                let _ = tx.send(res).await;
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut last_err = std::io::Error::from(std::io::ErrorKind::NotConnected);
+    while let Ok(res) = rx.recv().await {
+        match res {
+            Ok(sock) => return Ok(sock),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn caches_addresses_until_expired_or_failing() {
+        let addrs = vec!["127.0.0.1:80".parse().unwrap()];
+        let now = Instant::now();
+        let mut cache = DnsCache::default();
+        assert_eq!(None, cache.get(now));
+
+        cache.store(addrs.clone(), now);
+        assert_eq!(Some(addrs.clone()), cache.get(now + Duration::from_secs(1)));
+        assert_eq!(None, cache.get(now + conf::DNS_TTL));
+
+        for _ in 1..conf::DNS_MAX_FAILURES {
+            cache.failed();
+        }
+        assert_eq!(Some(addrs), cache.get(now));
+        cache.failed();
+        assert_eq!(None, cache.get(now));
+    }
+
+    #[test]
+    fn alternates_address_families() {
+        let addrs: Vec<SocketAddr> = ["1.1.1.1:80", "2.2.2.2:80", "[::1]:80", "[::2]:80"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave(addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            vec!["[::1]:80", "1.1.1.1:80", "[::2]:80", "2.2.2.2:80"],
+            ordered
+        );
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn falls_back_to_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = vec![closed_port(), listener.local_addr().unwrap()];
+        let sock = connect_any(addrs, Duration::from_secs(1)).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), sock.peer_addr().unwrap());
+
+        let err = connect_any(vec![], Duration::from_secs(1)).unwrap_err();
+        assert!(matches!(err, BlynkError::Dns));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn races_connection_attempts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = vec![closed_port(), listener.local_addr().unwrap()];
+        let delay = Duration::from_millis(250);
+        let sock = smol::block_on(race(addrs, delay, Duration::from_secs(1))).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), sock.peer_addr().unwrap());
+    }
+}
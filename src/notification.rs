@@ -0,0 +1,209 @@
+use crate::{BlynkError, Result};
+use std::fmt;
+
+/// Maximum allowed length (in characters) of a push notification body
+pub const MAX_LENGTH: usize = 120;
+
+/// Placeholders that Blynk servers substitute in notification bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    DeviceName,
+    DeviceOwnerEmail,
+    VendorEmail,
+}
+
+impl Placeholder {
+    const ALL: [Placeholder; 3] = [
+        Placeholder::DeviceName,
+        Placeholder::DeviceOwnerEmail,
+        Placeholder::VendorEmail,
+    ];
+
+    /// Returns the placeholder token as it appears in the message body
+    pub fn token(&self) -> &'static str {
+        match self {
+            Placeholder::DeviceName => "{DEVICE_NAME}",
+            Placeholder::DeviceOwnerEmail => "{DEVICE_OWNER_EMAIL}",
+            Placeholder::VendorEmail => "{VENDOR_EMAIL}",
+        }
+    }
+
+    fn from_token(token: &str) -> Option<Placeholder> {
+        Self::ALL.iter().copied().find(|p| p.token() == token)
+    }
+}
+
+/// Validated push notification body that can be sent with `Protocol::notification`
+///
+/// # Example
+/// ```
+/// use blynk_io::Notification;
+///
+/// let notification = Notification::builder()
+///     .placeholder(blynk_io::Placeholder::DeviceName)
+///     .text(": door opened")
+///     .build()
+///     .unwrap();
+/// assert_eq!("{DEVICE_NAME}: door opened", notification.as_str());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    body: String,
+}
+
+impl Notification {
+    /// Validates the body and returns the notification
+    ///
+    /// Fails if the body is empty, exceeds `MAX_LENGTH` characters
+    /// or contains unknown/unbalanced placeholders
+    pub fn new(body: &str) -> Result<Notification> {
+        validate(body)?;
+        Ok(Notification { body: body.into() })
+    }
+
+    pub fn builder() -> NotificationBuilder {
+        NotificationBuilder::default()
+    }
+
+    /// `{DEVICE_NAME}: <text>` alert
+    pub fn alert(text: &str) -> Result<Notification> {
+        Self::builder()
+            .placeholder(Placeholder::DeviceName)
+            .text(": ")
+            .text(text)
+            .build()
+    }
+
+    /// Alert about a value crossing the configured limit
+    pub fn threshold_exceeded(label: &str, value: f64, limit: f64) -> Result<Notification> {
+        Self::alert(&format!("{} is {} (limit {})", label, value, limit))
+    }
+
+    /// Alert about a low battery level (in percent)
+    pub fn low_battery(level: u8) -> Result<Notification> {
+        Self::alert(&format!("battery low ({}%)", level))
+    }
+
+    /// Alert sent once the device came back after a reboot
+    pub fn device_restarted() -> Result<Notification> {
+        Self::alert("device restarted")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.body
+    }
+
+    /// Returns the body with placeholders substituted locally, e.g. for logging
+    pub fn render(&self, device_name: &str, owner_email: &str, vendor_email: &str) -> String {
+        self.body
+            .replace(Placeholder::DeviceName.token(), device_name)
+            .replace(Placeholder::DeviceOwnerEmail.token(), owner_email)
+            .replace(Placeholder::VendorEmail.token(), vendor_email)
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.body)
+    }
+}
+
+/// Incrementally composes notification body out of text and placeholders
+#[derive(Default)]
+pub struct NotificationBuilder {
+    body: String,
+}
+
+impl NotificationBuilder {
+    pub fn text(mut self, text: &str) -> Self {
+        self.body.push_str(text);
+        self
+    }
+
+    pub fn placeholder(mut self, placeholder: Placeholder) -> Self {
+        self.body.push_str(placeholder.token());
+        self
+    }
+
+    pub fn build(self) -> Result<Notification> {
+        Notification::new(&self.body)
+    }
+}
+
+fn validate(body: &str) -> Result<()> {
+    let len = body.chars().count();
+    if len == 0 || len > MAX_LENGTH {
+        return Err(BlynkError::NotificationLength(len));
+    }
+
+    let mut rest = body;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(BlynkError::InvalidPlaceholder(rest[start..].into()));
+        }
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end + 1,
+            None => return Err(BlynkError::InvalidPlaceholder(rest[start..].into())),
+        };
+        let token = &rest[start..end];
+        if Placeholder::from_token(token).is_none() {
+            return Err(BlynkError::InvalidPlaceholder(token.into()));
+        }
+        rest = &rest[end..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_composes_body() {
+        let notification = Notification::builder()
+            .text("Hello from ")
+            .placeholder(Placeholder::DeviceName)
+            .build()
+            .unwrap();
+        assert_eq!("Hello from {DEVICE_NAME}", notification.as_str());
+    }
+
+    #[test]
+    fn rejects_empty_and_too_long() {
+        let err = Notification::new("").unwrap_err();
+        assert_eq!("Notification length 0 out of range", err.to_string());
+
+        let body = "x".repeat(MAX_LENGTH + 1);
+        assert!(Notification::new(&body).is_err());
+        assert!(Notification::new(&body[1..]).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let err = Notification::new("hi {DEVICE}").unwrap_err();
+        assert_eq!("Invalid notification placeholder {DEVICE}", err.to_string());
+        assert!(Notification::new("unbalanced {DEVICE_NAME").is_err());
+        assert!(Notification::new("unbalanced DEVICE_NAME}").is_err());
+    }
+
+    #[test]
+    fn convenience_constructors() {
+        let notification = Notification::low_battery(7).unwrap();
+        assert_eq!("{DEVICE_NAME}: battery low (7%)", notification.as_str());
+
+        let notification = Notification::threshold_exceeded("temp", 31.5, 30.0).unwrap();
+        assert_eq!(
+            "{DEVICE_NAME}: temp is 31.5 (limit 30)",
+            notification.to_string()
+        );
+    }
+
+    #[test]
+    fn render_substitutes_placeholders() {
+        let notification = Notification::device_restarted().unwrap();
+        assert_eq!(
+            "kitchen: device restarted",
+            notification.render("kitchen", "", "")
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Applying Blynk.Air firmware updates
+//!
+//! When the server sends the `ota` internal command (`Event::handle_ota`)
+//! `OtaUpdater` downloads the image over HTTP and streams it into an
+//! `OtaSink`, on esp32 a thin wrapper around the esp-idf OTA API
+//! (`EspOta::initiate_update`, `write`, `complete` and `restart`). The
+//! image never has to fit in memory, it's written in small chunks as it
+//! arrives. Progress can be reported back to a virtual pin.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use log::*;
+
+use crate::internal::OtaRequest;
+use crate::{conf, Client, Protocol, Result, VirtualPin};
+
+/// Size of the chunks the image is streamed in
+const CHUNK_SIZE: usize = 4096;
+/// Upper bound of the HTTP response header
+const MAX_HEADER: usize = 4096;
+
+/// Destination of the firmware image, e.g. the next OTA partition
+pub trait OtaSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Whole image was written, make it the one to boot
+    fn complete(&mut self) -> io::Result<()>;
+    /// Download failed, whatever was written is discarded
+    fn abort(&mut self) {}
+    /// Boots into the new image, called after `complete`
+    fn restart(&mut self) {}
+}
+
+/// Downloads images requested with `OtaRequest` into an `OtaSink`
+#[derive(Debug, Default, Clone)]
+pub struct OtaUpdater {
+    /// Virtual pin receiving the progress in percent
+    pub progress_pin: Option<u8>,
+}
+
+impl OtaUpdater {
+    pub fn new() -> OtaUpdater {
+        OtaUpdater::default()
+    }
+
+    pub fn with_progress_pin<N: Into<VirtualPin>>(mut self, pin: N) -> OtaUpdater {
+        self.progress_pin = Some(pin.into().number());
+        self
+    }
+
+    /// Downloads the image and restarts the device into it, returns only
+    /// if the update failed (or the sink doesn't restart)
+    pub fn apply<S: OtaSink>(
+        &self,
+        client: &mut Client,
+        ota: &OtaRequest,
+        sink: &mut S,
+    ) -> Result<()> {
+        info!("Updating firmware from {}", ota.url);
+        match self.download(client, &ota.url, sink) {
+            Ok(()) => {
+                sink.complete()?;
+                info!("Firmware updated, restarting");
+                sink.restart();
+                Ok(())
+            }
+            Err(err) => {
+                error!("Firmware update failed: {}", err);
+                sink.abort();
+                Err(err)
+            }
+        }
+    }
+
+    fn download<S: OtaSink>(&self, client: &mut Client, url: &str, sink: &mut S) -> Result<()> {
+        let (host, port, path) = parse_url(url)?;
+        let mut sock = TcpStream::connect((host, port))?;
+        sock.set_read_timeout(Some(conf::SOCK_MAX_TIMEOUT))?;
+        // HTTP/1.0 so the body is not chunked
+        write!(sock, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host)?;
+
+        let (len, head) = read_header(&mut sock)?;
+        let mut written = 0;
+        let mut reported = None;
+        let mut buf = [0; CHUNK_SIZE];
+        let mut chunk = head.as_slice();
+        loop {
+            if !chunk.is_empty() {
+                sink.write(chunk)?;
+                written += chunk.len();
+                if let Some(percent) = len.map(|len| written * 100 / len.max(1)) {
+                    if reported.is_none_or(|last| percent >= last + 10 || percent == 100) {
+                        self.report(client, percent);
+                        reported = Some(percent);
+                    }
+                }
+            }
+            let size = sock.read(&mut buf)?;
+            if size == 0 {
+                break;
+            }
+            chunk = &buf[..size];
+        }
+        match len {
+            Some(len) if written != len => {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "firmware image truncated").into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn report(&self, client: &mut Client, percent: usize) {
+        if let Some(pin) = self.progress_pin {
+            if let Err(err) = client.virtual_write(pin, percent) {
+                warn!("Problem reporting update progress: {}", err);
+            }
+        }
+    }
+}
+
+/// Splits `http://host[:port]/path` into its parts
+fn parse_url(url: &str) -> Result<(&str, u16, &str)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "unsupported firmware URL");
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, "/"),
+    };
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Ok((host, port.parse().map_err(|_| invalid())?, path)),
+        None => Ok((authority, 80, path)),
+    }
+}
+
+/// Reads the response header, returns the content length and the part of
+/// the body received with it
+fn read_header<R: Read>(reader: &mut R) -> Result<(Option<usize>, Vec<u8>)> {
+    let mut data = Vec::new();
+    let mut buf = [0; 512];
+    let end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if data.len() > MAX_HEADER {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "response header too long").into(),
+            );
+        }
+        let size = reader.read(&mut buf)?;
+        if size == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        data.extend_from_slice(&buf[..size]);
+    };
+
+    let header = String::from_utf8_lossy(&data[..end]);
+    let mut lines = header.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        let msg = format!("firmware download failed: {}", status);
+        return Err(io::Error::other(msg).into());
+    }
+    let len = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, val)| val.trim().parse().ok());
+    Ok((len, data[end + 4..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[derive(Default)]
+    struct Image {
+        data: Vec<u8>,
+        completed: bool,
+        aborted: bool,
+    }
+
+    impl OtaSink for Image {
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.data.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn complete(&mut self) -> io::Result<()> {
+            self.completed = true;
+            Ok(())
+        }
+
+        fn abort(&mut self) {
+            self.aborted = true;
+        }
+    }
+
+    fn serve(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut request = [0; 256];
+            let _ = sock.read(&mut request).unwrap();
+            sock.write_all(response).unwrap();
+        });
+        format!("http://{}/ota?token=abc", addr)
+    }
+
+    #[test]
+    fn parses_firmware_url() {
+        assert_eq!(("fw.local", 80, "/"), parse_url("http://fw.local").unwrap());
+        assert_eq!(
+            ("10.0.0.1", 8080, "/ota?id=1"),
+            parse_url("http://10.0.0.1:8080/ota?id=1").unwrap()
+        );
+        assert!(parse_url("ftp://fw.local/image").is_err());
+    }
+
+    #[test]
+    fn streams_image_into_sink() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nfw-bin");
+        let ota = OtaRequest {
+            url,
+            metadata: vec![],
+        };
+        let mut image = Image::default();
+        let updater = OtaUpdater::new().with_progress_pin(9);
+        updater
+            .apply(&mut Client::default(), &ota, &mut image)
+            .unwrap();
+        assert_eq!(b"fw-bin", image.data.as_slice());
+        assert!(image.completed);
+    }
+
+    #[test]
+    fn aborts_truncated_download() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nfw");
+        let ota = OtaRequest {
+            url,
+            metadata: vec![],
+        };
+        let mut image = Image::default();
+        let res = OtaUpdater::new().apply(&mut Client::default(), &ota, &mut image);
+        assert!(res.is_err());
+        assert!(image.aborted && !image.completed);
+    }
+}
@@ -0,0 +1,161 @@
+//! Reporting panics to the Blynk console
+//!
+//! A panic usually ends with the device restarting, so there's no
+//! connection left to report it over. The hook installed by
+//! `PanicReporter` keeps the message in memory and, if a file is given,
+//! saves it there. `Blynk::run` sends it as an event (and to a terminal)
+//! as soon as it's connected again, in the same run or after the restart.
+
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::*;
+
+use crate::{maybe_async, Protocol, Result, VirtualPin};
+
+/// Longest description sent with the event, longer ones are cut
+const MAX_DESCRIPTION: usize = 255;
+
+#[derive(Clone)]
+pub struct PanicReporter {
+    event_code: String,
+    terminal_pin: Option<u8>,
+    file: Option<PathBuf>,
+    report: Arc<Mutex<Option<String>>>,
+    /// Whether the file saved before the restart was looked for already
+    file_checked: Arc<AtomicBool>,
+}
+
+impl PanicReporter {
+    /// Panics are logged as the `event_code` event of the device template
+    pub fn new(event_code: &str) -> PanicReporter {
+        PanicReporter {
+            event_code: event_code.to_string(),
+            terminal_pin: None,
+            file: None,
+            report: Arc::new(Mutex::new(None)),
+            file_checked: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Also writes the whole message to the terminal on `pin`
+    pub fn with_terminal_pin<N: Into<VirtualPin>>(mut self, pin: N) -> PanicReporter {
+        self.terminal_pin = Some(pin.into().number());
+        self
+    }
+
+    /// Saves the message to `file`, so it's reported after a restart
+    pub fn with_file<P: Into<PathBuf>>(mut self, file: P) -> PanicReporter {
+        self.file = Some(file.into());
+        self
+    }
+
+    /// Installs the panic hook, the previous hook still runs after it
+    pub fn install(self) -> PanicReporter {
+        let reporter = self.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let payload = info.payload();
+            let msg = match (
+                payload.downcast_ref::<&str>(),
+                payload.downcast_ref::<String>(),
+            ) {
+                (Some(msg), _) => msg,
+                (_, Some(msg)) => msg.as_str(),
+                _ => "unknown panic",
+            };
+            match info.location() {
+                Some(location) => reporter.record(&format!("{} at {}", msg, location)),
+                None => reporter.record(msg),
+            }
+            previous(info);
+        }));
+        self
+    }
+
+    fn record(&self, msg: &str) {
+        // best effort, the hook may run while the lock is poisoned
+        if let Ok(mut report) = self.report.lock() {
+            *report = Some(msg.to_string());
+        }
+        if let Some(file) = &self.file {
+            let _ = fs::write(file, msg);
+        }
+    }
+
+    /// Report waiting to be sent, from this run or saved before a restart
+    fn take(&self) -> Option<String> {
+        let report = self.report.lock().ok().and_then(|mut report| report.take());
+        // the file only has to be read once, or to remove a new report
+        if report.is_none() && self.file_checked.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        let saved = self.file.as_ref().and_then(|file| {
+            let saved = fs::read_to_string(file).ok()?;
+            if let Err(err) = fs::remove_file(file) {
+                warn!("Problem removing panic report: {}", err);
+            }
+            Some(saved)
+        });
+        report.or(saved).filter(|report| !report.is_empty())
+    }
+}
+
+/// Cuts the message to `MAX_DESCRIPTION` bytes on a char boundary
+fn description(report: &str) -> &str {
+    let mut end = report.len().min(MAX_DESCRIPTION);
+    while !report.is_char_boundary(end) {
+        end -= 1;
+    }
+    &report[..end]
+}
+
+#[maybe_async]
+impl PanicReporter {
+    /// Sends the waiting report, if any
+    pub async fn report<P: Protocol>(&self, client: &mut P) -> Result<()> {
+        let Some(report) = self.take() else {
+            return Ok(());
+        };
+        if let Some(pin) = self.terminal_pin {
+            client
+                .virtual_write_chunked(pin, &format!("{}\n", report))
+                .await?;
+        }
+        client
+            .log_event(&self.event_code, description(&report))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_report_until_sent() {
+        let file = std::env::temp_dir().join(format!("blynk-panic-{}", std::process::id()));
+        let reporter = PanicReporter::new("crash").with_file(&file);
+        assert_eq!(None, reporter.take());
+
+        reporter.record("boom at src/main.rs:3:5");
+        assert_eq!(Some("boom at src/main.rs:3:5".into()), reporter.take());
+        assert!(!file.exists());
+
+        // saved before the restart
+        fs::write(&file, "boom").unwrap();
+        let reporter = PanicReporter::new("crash").with_file(&file);
+        assert_eq!(Some("boom".into()), reporter.take());
+        assert_eq!(None, reporter.take());
+    }
+
+    #[test]
+    fn cuts_long_descriptions() {
+        let report = "ł".repeat(200);
+        assert_eq!(254, description(&report).len());
+        assert_eq!("short", description("short"));
+    }
+}
@@ -0,0 +1,133 @@
+//! Values of a pin write
+//!
+//! Every value arrives as text, `ParamList` parses them on access and
+//! reports which value was wrong and why instead of a bare parse error.
+
+use std::any;
+use std::iter::Copied;
+use std::slice::Iter;
+use std::str::FromStr;
+
+use crate::{BlynkError, Result};
+
+/// Values of a pin write, most widgets send just one
+///
+/// # Example
+/// ```
+/// use blynk_io::ParamList;
+///
+/// let params = ParamList::new(&["21.5", "1"]);
+/// assert_eq!(21.5, params.as_f32().unwrap());
+/// assert!(params.parse::<bool>(1).is_err());
+/// assert_eq!(Some("1"), params.get(1));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParamList<'a> {
+    values: &'a [&'a str],
+}
+
+impl<'a> ParamList<'a> {
+    pub fn new(values: &'a [&'a str]) -> ParamList<'a> {
+        ParamList { values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Value at `n`, `None` if the write had fewer values
+    pub fn get(&self, n: usize) -> Option<&'a str> {
+        self.values.get(n).copied()
+    }
+
+    /// First value as it arrived, empty if there is none
+    pub fn as_str(&self) -> &'a str {
+        self.get(0).unwrap_or_default()
+    }
+
+    pub fn as_i64(&self) -> Result<i64> {
+        self.parse(0)
+    }
+
+    pub fn as_f32(&self) -> Result<f32> {
+        self.parse(0)
+    }
+
+    pub fn as_f64(&self) -> Result<f64> {
+        self.parse(0)
+    }
+
+    /// `1` or `0` as sent by buttons and switches, `true` and `false`
+    /// are accepted as well
+    pub fn as_bool(&self) -> Result<bool> {
+        match self.as_str() {
+            "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            _ => Err(self.invalid::<bool>(0)),
+        }
+    }
+
+    /// Parses the value at `n`, fails with `BlynkError::MissingValue` or
+    /// `BlynkError::InvalidValue`
+    pub fn parse<T: FromStr>(&self, n: usize) -> Result<T> {
+        let value = self.get(n).ok_or(BlynkError::MissingValue(n))?;
+        value.parse().map_err(|_| self.invalid::<T>(n))
+    }
+
+    pub fn iter(&self) -> Copied<Iter<'a, &'a str>> {
+        self.values.iter().copied()
+    }
+
+    /// The values as a slice, e.g. for the decoders of `widgets`
+    pub fn as_slice(&self) -> &'a [&'a str] {
+        self.values
+    }
+
+    fn invalid<T>(&self, n: usize) -> BlynkError {
+        BlynkError::InvalidValue {
+            index: n,
+            value: self.get(n).unwrap_or_default().to_string(),
+            expected: any::type_name::<T>(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for ParamList<'a> {
+    type Item = &'a str;
+    type IntoIter = Copied<Iter<'a, &'a str>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        let params = ParamList::new(&["1", "-20", "2.5", "on"]);
+        assert!(params.as_bool().unwrap());
+        assert_eq!(1, params.as_i64().unwrap());
+        assert_eq!(-20, params.parse::<i32>(1).unwrap());
+        assert_eq!(2.5, params.parse::<f64>(2).unwrap());
+        assert_eq!(
+            vec!["1", "-20", "2.5", "on"],
+            params.iter().collect::<Vec<_>>()
+        );
+
+        let err = params.parse::<u8>(3).unwrap_err();
+        assert_eq!("Value 3 \"on\" is not a valid u8", err.to_string());
+        assert!(matches!(
+            params.parse::<u8>(4),
+            Err(BlynkError::MissingValue(4))
+        ));
+        assert!(ParamList::new(&["2"]).as_bool().is_err());
+        assert_eq!("", ParamList::new(&[]).as_str());
+    }
+}
@@ -0,0 +1,66 @@
+//! Server certificate pinning shared by the TLS backends
+
+use sha2::{Digest, Sha256};
+
+use crate::{BlynkError, Result};
+
+/// SHA-256 fingerprint of DER encoded certificate
+pub type Fingerprint = [u8; 32];
+
+/// Parses hex encoded fingerprint, bytes may be separated with `:` as
+/// printed by `openssl x509 -noout -fingerprint -sha256`
+pub fn parse(pin: &str) -> Result<Fingerprint> {
+    let invalid = || BlynkError::Tls(format!("invalid certificate pin {}", pin));
+
+    let digits: Vec<u8> = pin.bytes().filter(|&b| b != b':').collect();
+    if digits.len() != 64 {
+        return Err(invalid());
+    }
+
+    let mut fingerprint = [0; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(fingerprint)
+}
+
+pub fn fingerprint(cert_der: &[u8]) -> Fingerprint {
+    Sha256::digest(cert_der).into()
+}
+
+/// Checks the server certificate against the pinned fingerprint
+pub fn verify(pin: &Fingerprint, cert_der: &[u8]) -> Result<()> {
+    if fingerprint(cert_der) != *pin {
+        return Err(BlynkError::TlsVerification(
+            "server certificate doesn't match the pin".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_openssl_format() {
+        let pin = "00:01:02:03:04:05:06:07:08:09:0A:0B:0C:0D:0E:0F:\
+                   10:11:12:13:14:15:16:17:18:19:1a:1b:1c:1d:1e:ff";
+        let fingerprint = parse(pin).unwrap();
+        assert_eq!(0x0a, fingerprint[10]);
+        assert_eq!(0xff, fingerprint[31]);
+
+        assert!(parse("00:01").is_err());
+        assert!(parse(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_other_certificate() {
+        let pin = fingerprint(b"certificate");
+        assert!(verify(&pin, b"certificate").is_ok());
+
+        let err = verify(&pin, b"other certificate").unwrap_err();
+        assert!(matches!(err, BlynkError::TlsVerification(_)));
+    }
+}
@@ -0,0 +1,1214 @@
+//! Sans-io protocol core shared by the blocking and async implementations
+//!
+//! Everything that doesn't touch the socket lives here once: building the
+//! frames, queueing outgoing messages, routing messages from the server and
+//! deciding when to ping it. `session` keeps the connection state on top of
+//! it, `blocking` and `async_impl` only add the reads, writes and sleeps.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::history;
+use crate::message::{
+    self, Message, MessageRef, MessageType, PinMode, ProtocolHeader, ProtocolStatus,
+};
+use crate::{
+    conf, BlynkError, FirmwareInfo, LimitPolicy, NotifyLimit, Result, Retransmit, WritePolicy,
+};
+
+const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Callback fired with the current depth once the outgoing queue
+/// grows to the configured high-water mark
+pub type HighWaterCallback = Box<dyn FnMut(usize) + Send>;
+
+/// Id following `id`, wraps around to 1 since 0 is not a valid id
+pub fn next_id(id: u16) -> u16 {
+    id.wrapping_add(1).max(1)
+}
+
+pub fn command(mtype: MessageType, id: u16, body: Vec<&str>) -> Vec<u8> {
+    Message::new(mtype, id, None, None, body).serialize()
+}
+
+pub fn login(id: u16, token: &str) -> Vec<u8> {
+    command(MessageType::Login, id, vec![token])
+}
+
+/// Heartbeat setup, `device_id` is appended to the `dev` info field
+/// (`rust-<device_id>`) so connections sharing a token can be told apart,
+/// `firmware` adds the `tmpl`, `fw` and `build` fields Blynk IoT expects
+pub fn heartbeat(
+    id: u16,
+    heartbeat: Duration,
+    rcv_buffer: u16,
+    device_id: Option<&str>,
+    firmware: Option<&FirmwareInfo>,
+) -> Vec<u8> {
+    let dev = match device_id {
+        Some(id) => format!("rust-{}", id),
+        None => "rust".into(),
+    };
+    let (rcv_buffer, heartbeat) = (rcv_buffer.to_string(), heartbeat.as_secs().to_string());
+    let mut body = vec![
+        "ver",
+        CARGO_PKG_VERSION,
+        "buff-in",
+        &rcv_buffer,
+        "h-beat",
+        &heartbeat,
+        "dev",
+        &dev,
+    ];
+    if let Some(fw) = firmware {
+        body.extend([
+            "tmpl",
+            &fw.template_id,
+            "fw-type",
+            &fw.template_id,
+            "fw",
+            &fw.version,
+            "build",
+            &fw.build,
+        ]);
+    }
+    command(MessageType::Internal, id, body)
+}
+
+pub fn ping(id: u16) -> Vec<u8> {
+    command(MessageType::Ping, id, vec![])
+}
+
+pub fn response(id: u16, status: u16) -> Vec<u8> {
+    command(MessageType::Rsp, id, vec![&status.to_string()])
+}
+
+pub fn virtual_write(id: u16, v_pin: u8, val: &str) -> Vec<u8> {
+    command(MessageType::Hw, id, vec!["vw", &v_pin.to_string(), val])
+}
+
+/// Reports the state of a physical digital pin
+pub fn digital_write(id: u16, pin: u8, high: bool) -> Vec<u8> {
+    let val = if high { "1" } else { "0" };
+    command(MessageType::Hw, id, vec!["dw", &pin.to_string(), val])
+}
+
+/// Reports the value of a physical analog (or PWM) pin
+pub fn analog_write(id: u16, pin: u8, val: u16) -> Vec<u8> {
+    command(
+        MessageType::Hw,
+        id,
+        vec!["aw", &pin.to_string(), &val.to_string()],
+    )
+}
+
+/// Announces the modes of physical pins, several pins fit one message
+pub fn pin_mode(id: u16, pins: &[(u8, PinMode)]) -> Vec<u8> {
+    let names: Vec<String> = pins.iter().map(|(pin, _)| pin.to_string()).collect();
+    let mut body = vec!["pm"];
+    for (name, (_, mode)) in names.iter().zip(pins) {
+        body.extend([name.as_str(), mode.as_str()]);
+    }
+    command(MessageType::Hw, id, body)
+}
+
+/// Virtual pin write of several values, e.g. joystick x/y or RGB
+pub fn virtual_write_multi(id: u16, v_pin: u8, vals: &[&str]) -> Vec<u8> {
+    let pin = v_pin.to_string();
+    let mut body = vec!["vw", &pin];
+    body.extend_from_slice(vals);
+    command(MessageType::Hw, id, body)
+}
+
+/// Virtual pin write with a binary value, e.g. a blob for a property or
+/// bridge, the value may contain any bytes including `\0`
+pub fn virtual_write_raw(id: u16, v_pin: u8, val: &[u8]) -> Vec<u8> {
+    let mut body = format!("vw\0{}\0", v_pin).into_bytes();
+    body.extend_from_slice(val);
+    let mut data = Vec::with_capacity(ProtocolHeader::SIZE + body.len());
+    ProtocolHeader::write_to((MessageType::Hw as u8, id, body.len() as u16), &mut data)
+        .expect("writing into a Vec");
+    data.extend(body);
+    data
+}
+
+/// Asks the server to resend the values of `pins`, each pin is a field
+pub fn virtual_sync(id: u16, pins: &[u8]) -> Vec<u8> {
+    let pins: Vec<String> = pins.iter().map(|p| p.to_string()).collect();
+    let mut body = vec!["vr"];
+    body.extend(pins.iter().map(|p| p as &str));
+    command(MessageType::HwSync, id, body)
+}
+
+/// Asks the server to resend the values of all pins
+pub fn sync_all(id: u16) -> Vec<u8> {
+    command(MessageType::HwSync, id, vec![])
+}
+
+/// Starts a group of writes the server applies at once, stamped with
+/// `timestamp` (milliseconds since the Unix epoch) if given
+pub fn begin_group(id: u16, timestamp: Option<u64>) -> Vec<u8> {
+    match timestamp {
+        Some(ts) => command(MessageType::Group, id, vec!["b", &ts.to_string()]),
+        None => command(MessageType::Group, id, vec!["b"]),
+    }
+}
+
+pub fn end_group(id: u16) -> Vec<u8> {
+    command(MessageType::Group, id, vec!["e"])
+}
+
+/// Logs the event `code` from the device template, an empty description
+/// is left out
+pub fn log_event(id: u16, code: &str, description: &str) -> Vec<u8> {
+    let mut body = vec![code];
+    if !description.is_empty() {
+        body.push(description);
+    }
+    command(MessageType::EventLog, id, body)
+}
+
+pub fn set_property(id: u16, pin: u8, prop: &str, val: &str) -> Vec<u8> {
+    command(MessageType::Property, id, vec![&pin.to_string(), prop, val])
+}
+
+/// Property with several values, e.g. the `labels` of a menu
+pub fn set_property_multi(id: u16, pin: u8, prop: &str, vals: &[&str]) -> Vec<u8> {
+    let pin = pin.to_string();
+    let mut body = vec![pin.as_str(), prop];
+    body.extend_from_slice(vals);
+    command(MessageType::Property, id, body)
+}
+
+/// Fails with `BlynkError::MessageTooLarge` if the body of the frame
+/// exceeds `conf::MAX_BODY_LEN`, the size field would be truncated or the
+/// server would drop the connection
+pub fn check_size(msg: &[u8]) -> Result<()> {
+    let len = msg.len().saturating_sub(ProtocolHeader::SIZE);
+    if len > conf::MAX_BODY_LEN {
+        return Err(BlynkError::MessageTooLarge(len));
+    }
+    Ok(())
+}
+
+/// Splits `val` into pieces of at most `max` bytes, breaking after the
+/// last newline that fits so terminal lines stay in one piece
+pub fn chunks(mut val: &str, max: usize) -> Vec<&str> {
+    let mut chunks = vec![];
+    while val.len() > max {
+        let mut end = max;
+        while !val.is_char_boundary(end) {
+            end -= 1;
+        }
+        if let Some(newline) = val[..end].rfind('\n') {
+            end = newline + 1;
+        }
+        if end == 0 {
+            end = val.chars().next().map_or(1, char::len_utf8);
+        }
+        let (chunk, rest) = val.split_at(end);
+        chunks.push(chunk);
+        val = rest;
+    }
+    chunks.push(val);
+    chunks
+}
+
+/// Parses the message at the start of `buf`, returns it together with the
+/// number of bytes it took
+pub fn decode(buf: &[u8]) -> Result<(Message, usize)> {
+    if buf.is_empty() {
+        return Err(BlynkError::EmptyBuffer);
+    }
+    let msg = Message::deserilize(buf)?;
+    let size = ProtocolHeader::SIZE + msg.size.unwrap_or(0) as usize;
+    debug!("size ({}) vs consumed ({})", buf.len(), size);
+    debug!("Got response message: {:?}", msg);
+    Ok((msg, size))
+}
+
+/// Number of body bytes following the message `header`, responses and
+/// pings carry the status in place of the size
+pub fn body_len(header: &[u8]) -> usize {
+    match ProtocolHeader::read_from(&mut &header[..]) {
+        Ok((mtype, _, h_data)) => match MessageType::try_from(mtype) {
+            Ok(MessageType::Rsp) | Ok(MessageType::Ping) => 0,
+            _ => h_data as usize,
+        },
+        Err(_) => 0,
+    }
+}
+
+/// Length of the first frame in `data`, `None` until all of it arrived
+pub fn frame_len(data: &[u8]) -> Option<usize> {
+    if data.len() < ProtocolHeader::SIZE {
+        return None;
+    }
+    let len = ProtocolHeader::SIZE + body_len(data);
+    (data.len() >= len).then_some(len)
+}
+
+/// Checks the server answer to the login
+pub fn check_login(msg: &Message) -> Result<()> {
+    if let MessageType::Redirect = msg.mtype {
+        return Err(BlynkError::Redirection);
+    }
+    match msg.status {
+        Some(ProtocolStatus::StatusOk) => Ok(()),
+        Some(ProtocolStatus::StatusInvalidToken) => Err(BlynkError::InvalidAuthToken),
+        Some(status) => Err(BlynkError::Rejected(status)),
+        None => Err(BlynkError::InvalidMessageHeader),
+    }
+}
+
+/// Server and port the device is told to connect to instead, the port
+/// stays the same if the server doesn't send one
+pub fn redirect_target(msg: &Message, port: u64) -> Option<(&str, u64)> {
+    match (msg.mtype, msg.body.as_slice()) {
+        (MessageType::Redirect, [server]) if !server.is_empty() => Some((server, port)),
+        (MessageType::Redirect, [server, new_port, ..]) if !server.is_empty() => {
+            Some((server, new_port.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// Checks the server answer to the heartbeat setup
+pub fn check_heartbeat(msg: &Message) -> Result<()> {
+    match msg.status {
+        Some(ProtocolStatus::StatusOk) => Ok(()),
+        Some(status) => Err(BlynkError::HeartbeatSet(status)),
+        None => Err(BlynkError::InvalidMessageHeader),
+    }
+}
+
+/// Turns the status acknowledging a message into the result
+pub fn check_ack(status: ProtocolStatus) -> Result<()> {
+    match status {
+        ProtocolStatus::StatusOk => Ok(()),
+        status => Err(BlynkError::Rejected(status)),
+    }
+}
+
+/// What the client should do about a message from the server
+#[derive(Debug, PartialEq)]
+pub enum Request<'a> {
+    /// Answer the ping with `StatusOk`
+    Ping(u16),
+    /// Server response to the command sent with the message id
+    Response(u16, ProtocolStatus),
+    /// Reply to the history command with `PinHistory::reply`
+    History(&'a [&'a str]),
+    /// Internal command name and its arguments, see `InternalCommand`
+    Internal(&'a str, &'a [&'a str]),
+    /// Pin and the values written to it, usually just one
+    VirtualWrite(u8, &'a [&'a str]),
+    /// Write of a value that is not valid UTF-8
+    VirtualWriteRaw(u8, &'a [u8]),
+    /// Pin read or write with a pin that is not a number
+    InvalidPin(&'a str),
+    VirtualRead(u8),
+    /// App switched a physical digital pin
+    DigitalWrite(u8, bool),
+    /// App wrote a physical analog or PWM pin, the value as sent
+    AnalogWrite(u8, &'a str),
+    /// Report the pin state with `digital_read_report`
+    DigitalRead(u8),
+    /// Pin and mode pairs, see `pin_modes`
+    PinMode(&'a [&'a str]),
+    /// Widget property changed by another client, pin, property and value
+    Property(u8, &'a str, &'a str),
+    None,
+}
+
+pub fn route<'a>(msg: &'a MessageRef<'_>) -> Request<'a> {
+    match msg.mtype {
+        MessageType::Ping => Request::Ping(msg.id),
+        MessageType::Rsp => match msg.status {
+            Some(status) => Request::Response(msg.id, status),
+            None => Request::None,
+        },
+        MessageType::Internal => match msg.body.as_slice() {
+            [name, args @ ..] if *name == history::COMMAND => Request::History(args),
+            [name, args @ ..] => Request::Internal(name, args),
+            [] => Request::None,
+        },
+        MessageType::Property => match msg.body.as_slice() {
+            [pin, prop, val, ..] => match pin.parse() {
+                Ok(pin_num) => Request::Property(pin_num, prop, val),
+                Err(_) => Request::InvalidPin(pin),
+            },
+            _ => Request::None,
+        },
+        MessageType::Hw | MessageType::Bridge => {
+            let cmd = msg.body.first().copied().unwrap_or_default();
+            let pin = msg.body.get(1).copied().unwrap_or_default();
+            match (cmd, pin.parse::<u8>()) {
+                ("vw", Ok(pin_num)) if msg.body.len() >= 3 => match msg.raw_tail(2) {
+                    Some(data) => Request::VirtualWriteRaw(pin_num, data),
+                    None => Request::VirtualWrite(pin_num, &msg.body[2..]),
+                },
+                ("vr", Ok(pin_num)) if msg.body.len() == 2 => Request::VirtualRead(pin_num),
+                ("dw", Ok(pin_num)) if msg.body.len() >= 3 => {
+                    Request::DigitalWrite(pin_num, msg.body[2] != "0")
+                }
+                ("aw", Ok(pin_num)) if msg.body.len() >= 3 => {
+                    Request::AnalogWrite(pin_num, msg.body[2])
+                }
+                ("dr", Ok(pin_num)) if msg.body.len() == 2 => Request::DigitalRead(pin_num),
+                ("pm", _) => Request::PinMode(&msg.body[1..]),
+                ("vw" | "dw" | "aw", Err(_)) if msg.body.len() >= 3 => Request::InvalidPin(pin),
+                ("vr" | "dr", Err(_)) if msg.body.len() == 2 => Request::InvalidPin(pin),
+                _ => Request::None,
+            }
+        }
+        _ => Request::None,
+    }
+}
+
+/// Pin and mode pairs of a `pm` command, malformed pairs are skipped
+pub fn pin_modes(args: &[&str]) -> Vec<(u8, PinMode)> {
+    args.chunks_exact(2)
+        .filter_map(|pair| match (pair[0].parse(), PinMode::parse(pair[1])) {
+            (Ok(pin), Some(mode)) => Some((pin, mode)),
+            _ => {
+                warn!("Skipping pin mode {:?}", pair);
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Liveness {
+    Alive,
+    /// Alive, but quiet for too long, ping it
+    Ping,
+    /// Nothing received for one and a half heartbeat
+    Dead,
+}
+
+/// Decides if the server is still there from the time elapsed since the
+/// last received message, ping and sent message
+pub fn liveness(heartbeat: Duration, rcv: Duration, ping: Duration, send: Duration) -> Liveness {
+    if rcv > heartbeat + heartbeat / 2 {
+        Liveness::Dead
+    } else if ping > heartbeat / 10 && (send > heartbeat || rcv > heartbeat) {
+        Liveness::Ping
+    } else {
+        Liveness::Alive
+    }
+}
+
+/// Commands sent to the server that may still get a response, so the
+/// response statuses can be matched with them. Only the most recent
+/// `conf::MAX_TRACKED_RESPONSES` are kept
+#[derive(Default)]
+pub struct Responses {
+    awaiting: VecDeque<(u16, MessageType)>,
+}
+
+impl Responses {
+    /// Records the serialized message, pings, responses and the
+    /// handshake are not tracked
+    pub fn track(&mut self, msg: &[u8]) {
+        if let Some((id, mtype)) = awaits_response(msg) {
+            if self.resolve(id).is_some() {
+                warn!("Message id {} reused before its response arrived", id);
+            }
+            if self.awaiting.len() == conf::MAX_TRACKED_RESPONSES {
+                self.awaiting.pop_front();
+            }
+            self.awaiting.push_back((id, mtype));
+        }
+    }
+
+    pub fn contains(&self, id: u16) -> bool {
+        self.awaiting.iter().any(|&(msg_id, _)| msg_id == id)
+    }
+
+    /// Type of the command sent with `id`, `None` if it's not tracked
+    pub fn resolve(&mut self, id: u16) -> Option<MessageType> {
+        let pos = self.awaiting.iter().position(|&(msg_id, _)| msg_id == id)?;
+        self.awaiting.remove(pos).map(|(_, mtype)| mtype)
+    }
+
+    pub fn clear(&mut self) {
+        self.awaiting.clear();
+    }
+}
+
+/// Id and type of a serialized message the server may respond to
+fn awaits_response(msg: &[u8]) -> Option<(u16, MessageType)> {
+    if message::is_handshake(msg) {
+        return None;
+    }
+    let (mtype, id, _) = ProtocolHeader::read_from(&mut &msg[..]).ok()?;
+    match MessageType::try_from(mtype) {
+        Ok(MessageType::Ping | MessageType::Rsp) | Err(_) => None,
+        Ok(mtype) => Some((id, mtype)),
+    }
+}
+
+/// Id of a serialized message the server answers with a response. Writes
+/// get none and syncs are answered with the pin values instead
+fn answered(msg: &[u8]) -> Option<u16> {
+    let (mtype, id, _) = ProtocolHeader::read_from(&mut &msg[..]).ok()?;
+    match MessageType::try_from(mtype) {
+        Ok(MessageType::Login | MessageType::Internal | MessageType::EventLog) => Some(id),
+        _ => None,
+    }
+}
+
+struct Unacked {
+    id: u16,
+    msg: Vec<u8>,
+    /// Time of the last (re)send, set on the first check after queueing
+    sent: Option<Instant>,
+    attempts: u8,
+}
+
+struct Held {
+    quiet: Duration,
+    /// Values of the last write and when it arrived
+    last: Option<(Vec<String>, Instant)>,
+}
+
+/// Holds back writes of debounced pins until no other write arrived for
+/// the quiet period, only the last one is delivered
+#[derive(Default)]
+pub struct Debouncer {
+    pins: BTreeMap<u8, Held>,
+}
+
+impl Debouncer {
+    pub fn add(&mut self, pin: u8, quiet: Duration) {
+        self.pins.insert(pin, Held { quiet, last: None });
+    }
+
+    /// Holds the write back, returns false if the pin isn't debounced and
+    /// the write has to be delivered right away
+    pub fn hold(&mut self, pin: u8, values: &[&str], now: Instant) -> bool {
+        let Some(held) = self.pins.get_mut(&pin) else {
+            return false;
+        };
+        let values = values.iter().map(|val| val.to_string()).collect();
+        held.last = Some((values, now));
+        true
+    }
+
+    /// Writes that settled by `now`
+    pub fn settled(&mut self, now: Instant) -> Vec<(u8, Vec<String>)> {
+        let mut settled = Vec::new();
+        for (&pin, held) in self.pins.iter_mut() {
+            let quiet = held.quiet;
+            if let Some((values, _)) = held
+                .last
+                .take_if(|(_, at)| now.saturating_duration_since(*at) >= quiet)
+            {
+                settled.push((pin, values));
+            }
+        }
+        settled
+    }
+}
+
+/// Times of the events logged within the last day, the server accepts
+/// only `conf::MAX_EVENTS_PER_DAY` of them
+#[derive(Default)]
+pub struct EventQuota {
+    logged: VecDeque<Instant>,
+}
+
+impl EventQuota {
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Counts the serialized message if it logs an event, fails with
+    /// `BlynkError::EventLimit` once the limit is reached
+    pub fn admit(&mut self, msg: &[u8], now: Instant) -> Result<()> {
+        if msg.first() != Some(&(MessageType::EventLog as u8)) {
+            return Ok(());
+        }
+        if self.remaining(now) == 0 {
+            return Err(BlynkError::EventLimit(conf::MAX_EVENTS_PER_DAY));
+        }
+        self.logged.push_back(now);
+        Ok(())
+    }
+
+    /// Events that can still be logged
+    pub fn remaining(&mut self, now: Instant) -> usize {
+        while let Some(&logged) = self.logged.front() {
+            if now.saturating_duration_since(logged) < Self::DAY {
+                break;
+            }
+            self.logged.pop_front();
+        }
+        conf::MAX_EVENTS_PER_DAY.saturating_sub(self.logged.len())
+    }
+}
+
+/// Applies `NotifyLimit` to notifications and emails, each type has its
+/// own interval
+pub struct Throttle {
+    limit: NotifyLimit,
+    /// Time of the last one sent and the one held back, per type
+    slots: [(Option<Instant>, Option<Vec<u8>>); 2],
+}
+
+impl Throttle {
+    pub fn new(limit: NotifyLimit) -> Self {
+        Self {
+            limit,
+            slots: Default::default(),
+        }
+    }
+
+    pub fn limit(&self) -> NotifyLimit {
+        self.limit
+    }
+
+    fn slot(msg: &[u8]) -> Option<usize> {
+        match msg.first().map(|&mtype| MessageType::try_from(mtype)) {
+            Some(Ok(MessageType::Notify)) => Some(0),
+            Some(Ok(MessageType::Email)) => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Returns the serialized message if it can be sent now, `None` if it
+    /// was held back. Fails with `BlynkError::RateLimited` if rejected
+    pub fn admit(&mut self, msg: Vec<u8>, now: Instant) -> Result<Option<Vec<u8>>> {
+        let Some(slot) = Self::slot(&msg) else {
+            return Ok(Some(msg));
+        };
+        let (last, held) = &mut self.slots[slot];
+        let elapsed = last.map(|last| now.saturating_duration_since(last));
+        match elapsed {
+            Some(elapsed) if elapsed < self.limit.interval => match self.limit.policy {
+                LimitPolicy::Reject => Err(BlynkError::RateLimited(self.limit.interval - elapsed)),
+                LimitPolicy::Queue => {
+                    if held.replace(msg).is_some() {
+                        debug!("Dropping notification superseded by a newer one");
+                    }
+                    Ok(None)
+                }
+            },
+            _ => {
+                // a newer one goes out, the one held back is outdated
+                *held = None;
+                *last = Some(now);
+                Ok(Some(msg))
+            }
+        }
+    }
+
+    /// Messages held back that can be sent now
+    pub fn due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let interval = self.limit.interval;
+        self.slots
+            .iter_mut()
+            .filter(|(last, _)| {
+                last.is_none_or(|last| now.saturating_duration_since(last) >= interval)
+            })
+            .filter_map(|(_, held)| held.take())
+            .collect()
+    }
+}
+
+/// Copies of sent messages kept until the server acknowledges them, so
+/// they can be sent again when the acknowledgement doesn't arrive within
+/// `Retransmit::heartbeats` heartbeat periods
+pub struct Retransmits {
+    policy: Retransmit,
+    unacked: VecDeque<Unacked>,
+}
+
+impl Retransmits {
+    pub fn new(policy: Retransmit) -> Self {
+        Self {
+            policy,
+            unacked: VecDeque::new(),
+        }
+    }
+
+    /// Keeps a copy of the serialized message if the server answers it:
+    /// logins, internal commands and logged events
+    pub fn track(&mut self, msg: &[u8]) {
+        if let Some(id) = answered(msg) {
+            if self.unacked.len() == conf::MAX_TRACKED_RESPONSES {
+                self.unacked.pop_front();
+            }
+            self.unacked.push_back(Unacked {
+                id,
+                msg: msg.to_vec(),
+                sent: None,
+                attempts: 0,
+            });
+        }
+    }
+
+    pub fn ack(&mut self, id: u16) {
+        self.unacked.retain(|unacked| unacked.id != id);
+    }
+
+    /// Messages to be sent again, `None` once one of them was re-sent
+    /// `Retransmit::attempts` times and the connection should be dropped
+    pub fn due(&mut self, now: Instant, heartbeat: Duration) -> Option<Vec<Vec<u8>>> {
+        let timeout = heartbeat * self.policy.heartbeats;
+        let mut due = Vec::new();
+        for unacked in self.unacked.iter_mut() {
+            let sent = *unacked.sent.get_or_insert(now);
+            if now.saturating_duration_since(sent) < timeout {
+                continue;
+            }
+            if unacked.attempts >= self.policy.attempts {
+                warn!(
+                    "Message {} not acknowledged after {} attempts",
+                    unacked.id, unacked.attempts
+                );
+                return None;
+            }
+            unacked.attempts += 1;
+            unacked.sent = Some(now);
+            due.push(unacked.msg.clone());
+        }
+        Some(due)
+    }
+
+    pub fn clear(&mut self) {
+        self.unacked.clear();
+    }
+}
+
+/// Queue of outgoing messages, applies `WritePolicy` during the handshake
+/// and keeps track of partially written messages. With a window set, only
+/// that many written commands the server answers (logins, internal
+/// commands, logged events) may wait for a response, the rest stays
+/// queued until the server acknowledges them, pin writes don't count.
+/// Writes to coalesced pins replace the ones still queued, only the
+/// newest value is sent
+#[derive(Default)]
+pub struct Outbox {
+    queue: VecDeque<Vec<u8>>,
+    offset: usize,
+    high_water_mark: Option<(usize, HighWaterCallback)>,
+    write_policy: WritePolicy,
+    handshake: bool,
+    window: Option<usize>,
+    in_flight: VecDeque<u16>,
+    coalesced: BTreeSet<u8>,
+}
+
+/// Ping or response, neither is answered so they never take a window slot
+fn is_keepalive(msg: &[u8]) -> bool {
+    matches!(
+        msg.first().map(|&mtype| MessageType::try_from(mtype)),
+        Some(Ok(MessageType::Ping | MessageType::Rsp))
+    )
+}
+
+/// Virtual pin written by the serialized message, `None` for other messages
+fn written_pin(msg: &[u8]) -> Option<u8> {
+    let msg = MessageRef::parse(msg).ok()?;
+    match (msg.mtype, msg.body.as_slice()) {
+        (MessageType::Hw, ["vw", pin, ..]) => pin.parse().ok(),
+        _ => None,
+    }
+}
+
+impl Outbox {
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn set_high_water_mark(&mut self, mark: usize, callback: HighWaterCallback) {
+        self.high_water_mark = Some((mark, callback));
+    }
+
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
+    pub fn set_handshake(&mut self, handshake: bool) {
+        self.handshake = handshake;
+    }
+
+    pub fn handshake(&self) -> bool {
+        self.handshake
+    }
+
+    pub fn set_window(&mut self, window: Option<usize>) {
+        self.window = window;
+    }
+
+    pub fn set_coalesced_pins(&mut self, pins: &[u8]) {
+        self.coalesced = pins.iter().copied().collect();
+    }
+
+    /// Drops the queued writes to the pin `msg` writes to if the pin is
+    /// coalesced, returns ids of the dropped messages. A partially written
+    /// message is kept, the rest of it has to go out
+    pub fn coalesce(&mut self, msg: &[u8]) -> Vec<u16> {
+        let Some(pin) = written_pin(msg).filter(|pin| self.coalesced.contains(pin)) else {
+            return Vec::new();
+        };
+        let mut dropped = Vec::new();
+        let mut index = 0;
+        self.queue.retain(|queued| {
+            let partial = index == 0 && self.offset > 0;
+            index += 1;
+            if partial || written_pin(queued) != Some(pin) {
+                return true;
+            }
+            if let Some((id, _)) = awaits_response(queued) {
+                dropped.push(id);
+            }
+            false
+        });
+        if !dropped.is_empty() {
+            debug!("Dropped {} queued writes to V{}", dropped.len(), pin);
+        }
+        dropped
+    }
+
+    /// Frees the window slot taken by the message `id`
+    pub fn ack(&mut self, id: u16) {
+        self.in_flight.retain(|&msg_id| msg_id != id);
+    }
+
+    /// Applies `WritePolicy` to writes issued during the handshake,
+    /// returns the message if it should be sent right away
+    pub fn admit(&mut self, msg: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if !self.handshake || message::is_handshake(&msg) {
+            return Ok(Some(msg));
+        }
+        match self.write_policy {
+            WritePolicy::Allow => Ok(Some(msg)),
+            WritePolicy::Buffer => {
+                debug!("Handshake in progress, queueing message");
+                self.push(msg);
+                Ok(None)
+            }
+            WritePolicy::Reject => Err(BlynkError::Handshake),
+        }
+    }
+
+    pub fn push(&mut self, msg: Vec<u8>) {
+        self.queue.push_back(msg);
+
+        let depth = self.queue.len();
+        if let Some((mark, callback)) = &mut self.high_water_mark {
+            if depth == *mark {
+                warn!("Outgoing queue reached high-water mark ({})", depth);
+                callback(depth);
+            }
+        }
+    }
+
+    /// Part of the first message that is still to be written, `None` if
+    /// it has to wait for a free slot in the window. Pings and responses
+    /// never wait, they go out ahead of the messages held back
+    pub fn front(&mut self) -> Option<&[u8]> {
+        if self.offset == 0 && self.is_full() && answered(self.queue.front()?).is_some() {
+            let pos = self.queue.iter().position(|msg| is_keepalive(msg))?;
+            let msg = self.queue.remove(pos)?;
+            self.queue.push_front(msg);
+        }
+        let msg = self.queue.front()?;
+        Some(&msg[self.offset..])
+    }
+
+    fn is_full(&self) -> bool {
+        self.window
+            .is_some_and(|window| self.in_flight.len() >= window)
+    }
+
+    /// Marks `size` bytes of the first message as written, returns true
+    /// once the whole message is out
+    pub fn advance(&mut self, size: usize) -> bool {
+        self.offset += size;
+        match self.queue.front() {
+            Some(msg) if self.offset >= msg.len() => {
+                if let (Some(_), Some(id)) = (self.window, answered(msg)) {
+                    self.in_flight.push_back(id);
+                }
+                self.pop();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops the first message, e.g. after it failed to send too many times
+    pub fn pop(&mut self) {
+        self.queue.pop_front();
+        self.offset = 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.offset = 0;
+        self.handshake = false;
+        self.in_flight.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_server_requests() {
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "3", "on"]);
+        assert_eq!(Request::VirtualWrite(3, &["on"]), route(&msg.to_ref()));
+        let msg = Message::new(MessageType::Bridge, 1, None, None, vec!["vr", "7"]);
+        assert_eq!(Request::VirtualRead(7), route(&msg.to_ref()));
+        let msg = Message::new(MessageType::Ping, 9, None, None, vec![]);
+        assert_eq!(Request::Ping(9), route(&msg.to_ref()));
+        let msg = Message::new(MessageType::Internal, 1, None, None, vec!["rtc", "1"]);
+        assert_eq!(Request::Internal("rtc", &["1"]), route(&msg.to_ref()));
+
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["dw", "5", "1"]);
+        assert_eq!(Request::DigitalWrite(5, true), route(&msg.to_ref()));
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["aw", "34", "512"]);
+        assert_eq!(Request::AnalogWrite(34, "512"), route(&msg.to_ref()));
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["dr", "5"]);
+        assert_eq!(Request::DigitalRead(5), route(&msg.to_ref()));
+        let msg = Message::new(
+            MessageType::Hw,
+            1,
+            None,
+            None,
+            vec!["pm", "2", "out", "x", "in"],
+        );
+        let msg = msg.to_ref();
+        let Request::PinMode(args) = route(&msg) else {
+            panic!("not a pin mode request");
+        };
+        assert_eq!(vec![(2, PinMode::Output)], pin_modes(args));
+
+        let msg = Message::new(
+            MessageType::Property,
+            1,
+            None,
+            None,
+            vec!["4", "color", "#FF0000"],
+        );
+        assert_eq!(
+            Request::Property(4, "color", "#FF0000"),
+            route(&msg.to_ref())
+        );
+
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vw", "x1", "on"]);
+        assert_eq!(Request::InvalidPin("x1"), route(&msg.to_ref()));
+        let msg = Message::new(MessageType::Hw, 1, None, None, vec!["vr", "300"]);
+        assert_eq!(Request::InvalidPin("300"), route(&msg.to_ref()));
+    }
+
+    #[test]
+    fn throttles_notifications() {
+        let notify = |id, text| command(MessageType::Notify, id, vec![text]);
+        let now = Instant::now();
+        let mut throttle = Throttle::new(NotifyLimit::default());
+        assert!(throttle.admit(notify(1, "a"), now).unwrap().is_some());
+        assert!(matches!(
+            throttle.admit(notify(2, "b"), now + Duration::from_secs(1)),
+            Err(BlynkError::RateLimited(wait)) if wait == Duration::from_secs(4)
+        ));
+        // emails and other messages have limits of their own
+        let email = command(MessageType::Email, 3, vec!["a@b.c", "hi", "x"]);
+        assert!(throttle.admit(email, now).unwrap().is_some());
+        assert!(throttle.admit(ping(4), now).unwrap().is_some());
+
+        let mut throttle = Throttle::new(NotifyLimit {
+            policy: LimitPolicy::Queue,
+            ..Default::default()
+        });
+        throttle.admit(notify(1, "a"), now).unwrap();
+        assert_eq!(None, throttle.admit(notify(2, "b"), now).unwrap());
+        assert_eq!(None, throttle.admit(notify(3, "c"), now).unwrap());
+        assert!(throttle.due(now + Duration::from_secs(1)).is_empty());
+        // only the latest one is sent
+        let due = throttle.due(now + conf::NOTIFY_INTERVAL);
+        assert_eq!(vec![notify(3, "c")], due);
+        assert!(throttle.due(now + conf::NOTIFY_INTERVAL).is_empty());
+    }
+
+    #[test]
+    fn caps_events_per_day() {
+        let mut quota = EventQuota::default();
+        let now = Instant::now();
+        quota.admit(&ping(1), now).unwrap();
+        for id in 0..conf::MAX_EVENTS_PER_DAY as u16 {
+            quota.admit(&log_event(id, "leak", ""), now).unwrap();
+        }
+        assert_eq!(0, quota.remaining(now));
+        assert!(matches!(
+            quota.admit(&log_event(1, "leak", ""), now),
+            Err(BlynkError::EventLimit(_))
+        ));
+        // other messages are not limited
+        quota.admit(&ping(2), now).unwrap();
+
+        let later = now + Duration::from_secs(24 * 60 * 60);
+        assert_eq!(conf::MAX_EVENTS_PER_DAY, quota.remaining(later));
+        quota.admit(&log_event(2, "leak", "again"), later).unwrap();
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let val = "x".repeat(conf::MAX_BODY_LEN);
+        assert!(check_size(&virtual_write(1, 1, &val[5..])).is_ok());
+        let err = check_size(&virtual_write(1, 1, &val)).unwrap_err();
+        assert!(matches!(err, BlynkError::MessageTooLarge(len) if len == conf::MAX_BODY_LEN + 5));
+
+        assert_eq!(vec!["ab\n", "cdef", "g"], chunks("ab\ncdefg", 4));
+        assert_eq!(vec!["é", "é"], chunks("éé", 3));
+    }
+
+    #[test]
+    fn reports_physical_pins() {
+        let body = |data: &[u8]| data[ProtocolHeader::SIZE..].to_vec();
+        assert_eq!(b"dw\x005\x001".to_vec(), body(&digital_write(1, 5, true)));
+        assert_eq!(b"aw\x0034\x00512".to_vec(), body(&analog_write(1, 34, 512)));
+        let modes = [(2, PinMode::Output), (4, PinMode::InputPullUp)];
+        assert_eq!(
+            b"pm\x002\x00out\x004\x00pu".to_vec(),
+            body(&pin_mode(1, &modes))
+        );
+    }
+
+    #[test]
+    fn writes_several_values_at_once() {
+        let data = virtual_write_multi(1, 4, &["10", "20", "30"]);
+        let msg = MessageRef::parse(&data).unwrap();
+        assert_eq!(vec!["vw", "4", "10", "20", "30"], msg.body);
+    }
+
+    #[test]
+    fn syncs_multi_digit_pins() {
+        let data = virtual_sync(1, &[10, 25]);
+        assert_eq!(b"vr\x0010\x0025", &data[ProtocolHeader::SIZE..]);
+        assert_eq!(ProtocolHeader::SIZE, sync_all(1).len());
+    }
+
+    #[test]
+    fn binary_values_round_trip() {
+        let data = virtual_write_raw(1, 3, &[0xff, 0, 1]);
+        let msg = MessageRef::parse(&data).unwrap();
+        assert_eq!(Request::VirtualWriteRaw(3, &[0xff, 0, 1]), route(&msg));
+    }
+
+    #[test]
+    fn heartbeat_announces_firmware() {
+        let fw = FirmwareInfo {
+            template_id: "TMPL1234".into(),
+            version: "1.2.0".into(),
+            build: "Jan 1 2024".into(),
+        };
+        let data = heartbeat(1, Duration::from_secs(10), 1024, None, Some(&fw));
+        let (msg, _) = decode(&data).unwrap();
+        let pos = msg.body.iter().position(|f| f == "tmpl").unwrap();
+        assert_eq!(
+            [
+                "tmpl",
+                "TMPL1234",
+                "fw-type",
+                "TMPL1234",
+                "fw",
+                "1.2.0",
+                "build",
+                "Jan 1 2024"
+            ],
+            msg.body[pos..]
+        );
+    }
+
+    #[test]
+    fn parses_redirect_target() {
+        let msg = Message::new(
+            MessageType::Redirect,
+            1,
+            None,
+            None,
+            vec!["fra1.blynk.cloud", "443"],
+        );
+        assert_eq!(Some(("fra1.blynk.cloud", 443)), redirect_target(&msg, 80));
+        assert!(matches!(check_login(&msg), Err(BlynkError::Redirection)));
+        let msg = Message::new(
+            MessageType::Redirect,
+            1,
+            None,
+            None,
+            vec!["fra1.blynk.cloud"],
+        );
+        assert_eq!(Some(("fra1.blynk.cloud", 80)), redirect_target(&msg, 80));
+        let msg = Message::new(MessageType::Redirect, 1, None, None, vec!["host", "port"]);
+        assert_eq!(None, redirect_target(&msg, 80));
+    }
+
+    #[test]
+    fn waits_for_complete_frame() {
+        let data = virtual_write(1, 1, "2");
+        assert_eq!(None, frame_len(&data[..4]));
+        assert_eq!(None, frame_len(&data[..9]));
+        assert_eq!(Some(data.len()), frame_len(&data));
+        assert_eq!(Some(5), frame_len(&ping(1)));
+    }
+
+    #[test]
+    fn pings_quiet_server() {
+        let (hb, s) = (Duration::from_secs(10), Duration::from_secs);
+        assert_eq!(Liveness::Alive, liveness(hb, s(1), s(5), s(1)));
+        assert_eq!(Liveness::Ping, liveness(hb, s(11), s(5), s(1)));
+        assert_eq!(Liveness::Alive, liveness(hb, s(11), s(0), s(1)));
+        assert_eq!(Liveness::Dead, liveness(hb, s(16), s(0), s(0)));
+    }
+
+    #[test]
+    fn matches_responses_with_commands() {
+        let mut responses = Responses::default();
+        responses.track(&virtual_write(4, 1, "on"));
+        responses.track(&ping(5));
+        responses.track(&set_property(6, 1, "color", "red"));
+
+        assert!(matches!(responses.resolve(6), Some(MessageType::Property)));
+        assert!(responses.resolve(5).is_none());
+        assert!(matches!(responses.resolve(4), Some(MessageType::Hw)));
+        assert!(responses.resolve(4).is_none());
+
+        let msg = Message::new(
+            MessageType::Rsp,
+            4,
+            None,
+            Some(ProtocolStatus::StatusQuotaLimit),
+            vec![],
+        );
+        assert!(matches!(
+            route(&msg.to_ref()),
+            Request::Response(4, ProtocolStatus::StatusQuotaLimit)
+        ));
+    }
+
+    #[test]
+    fn message_ids_skip_zero() {
+        assert_eq!(2, next_id(1));
+        assert_eq!(1, next_id(u16::MAX));
+
+        let mut responses = Responses::default();
+        responses.track(&virtual_write(7, 1, "on"));
+        responses.track(&virtual_write(7, 1, "off"));
+        assert!(responses.contains(7));
+        assert!(responses.resolve(7).is_some());
+        assert!(!responses.contains(7));
+    }
+
+    #[test]
+    fn retransmits_unacknowledged_messages() {
+        let policy = Retransmit {
+            heartbeats: 2,
+            attempts: 1,
+        };
+        let heartbeat = Duration::from_secs(5);
+        let mut retransmits = Retransmits::new(policy);
+        retransmits.track(&login(1, "abc"));
+        retransmits.track(&ping(2));
+        // writes and syncs get no response, nothing to wait for
+        retransmits.track(&virtual_write(3, 2, "off"));
+        retransmits.track(&sync_all(5));
+        retransmits.track(&log_event(4, "door", "open"));
+
+        let now = Instant::now();
+        assert_eq!(Some(vec![]), retransmits.due(now, heartbeat));
+        retransmits.ack(1);
+        let later = now + heartbeat * 2;
+        assert_eq!(
+            Some(vec![log_event(4, "door", "open")]),
+            retransmits.due(later, heartbeat)
+        );
+        assert_eq!(Some(vec![]), retransmits.due(later + heartbeat, heartbeat));
+        assert_eq!(None, retransmits.due(later + heartbeat * 2, heartbeat));
+    }
+
+    #[test]
+    fn outbox_holds_messages_outside_window() {
+        let mut outbox = Outbox::default();
+        outbox.set_window(Some(1));
+        let event = log_event(1, "door", "open");
+        outbox.push(event.clone());
+        outbox.push(log_event(2, "door", "closed"));
+
+        let size = outbox.front().unwrap().len();
+        assert!(outbox.advance(size));
+        assert_eq!(None, outbox.front());
+
+        // pings go out ahead of the held back messages
+        outbox.push(ping(3));
+        assert_eq!(Some(&ping(3)[..]), outbox.front());
+        assert!(outbox.advance(ping(3).len()));
+        assert_eq!(None, outbox.front());
+
+        outbox.ack(1);
+        assert_eq!(Some(&log_event(2, "door", "closed")[..]), outbox.front());
+    }
+
+    #[test]
+    fn outbox_window_skips_writes() {
+        let mut outbox = Outbox::default();
+        outbox.set_window(Some(1));
+        for id in 1..=3 {
+            outbox.push(virtual_write(id, 1, "on"));
+        }
+        // the server never answers writes, they don't take a slot
+        for id in 1..=3 {
+            let size = outbox.front().unwrap().len();
+            assert_eq!(virtual_write(id, 1, "on").len(), size);
+            assert!(outbox.advance(size));
+        }
+        assert_eq!(0, outbox.len());
+    }
+
+    #[test]
+    fn outbox_tracks_partial_writes() {
+        let mut outbox = Outbox::default();
+        outbox.push(vec![1, 2, 3]);
+        outbox.push(vec![4]);
+        assert!(!outbox.advance(2));
+        assert_eq!(Some(&[3][..]), outbox.front());
+        assert!(outbox.advance(1));
+        assert_eq!(Some(&[4][..]), outbox.front());
+        assert_eq!(1, outbox.len());
+    }
+
+    #[test]
+    fn outbox_coalesces_pin_writes() {
+        let mut outbox = Outbox::default();
+        outbox.set_coalesced_pins(&[1]);
+        outbox.push(virtual_write(1, 1, "-70"));
+        outbox.push(virtual_write(2, 2, "on"));
+        assert!(!outbox.advance(2));
+
+        // the partially written one still has to go out
+        assert_eq!(
+            Vec::<u16>::new(),
+            outbox.coalesce(&virtual_write(3, 1, "-71"))
+        );
+        outbox.push(virtual_write(3, 1, "-71"));
+        assert_eq!(vec![3], outbox.coalesce(&virtual_write(4, 1, "-72")));
+        outbox.push(virtual_write(4, 1, "-72"));
+        assert_eq!(
+            Vec::<u16>::new(),
+            outbox.coalesce(&virtual_write(5, 2, "off"))
+        );
+        assert_eq!(3, outbox.len());
+
+        for _ in 0..2 {
+            let size = outbox.front().unwrap().len();
+            assert!(outbox.advance(size));
+        }
+        assert_eq!(Some(&virtual_write(4, 1, "-72")[..]), outbox.front());
+    }
+}
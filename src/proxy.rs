@@ -0,0 +1,349 @@
+//! Tunneling the connection through HTTP CONNECT and SOCKS5 proxies
+//!
+//! Gateways in corporate networks often can't reach the Blynk server
+//! directly. When `Config::proxy` is set the client connects to the proxy
+//! instead and asks it to open a tunnel to the server, everything else
+//! (TLS, WebSocket, the Blynk login) runs through that tunnel. Building
+//! and checking the handshake messages doesn't do any IO, so the blocking
+//! and async tunnels share it.
+
+use std::io;
+
+use crate::maybe_async;
+use crate::rt::Socket;
+#[cfg(feature = "async")]
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Upper bound of the HTTP CONNECT response we are willing to read
+const MAX_RESPONSE: usize = 4096;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_USER_PASS: u8 = 2;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// HTTP proxy supporting the `CONNECT` method
+    Http,
+    Socks5,
+}
+
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub server: String,
+    pub port: u64,
+    /// User name and password, sent as basic auth to HTTP proxies and with
+    /// username/password authentication to SOCKS5 ones
+    pub credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn http(server: &str, port: u64) -> Proxy {
+        Proxy {
+            kind: ProxyKind::Http,
+            server: server.into(),
+            port,
+            credentials: None,
+        }
+    }
+
+    pub fn socks5(server: &str, port: u64) -> Proxy {
+        Proxy {
+            kind: ProxyKind::Socks5,
+            server: server.into(),
+            port,
+            credentials: None,
+        }
+    }
+
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Proxy {
+        self.credentials = Some((user.into(), password.into()));
+        self
+    }
+
+    /// HTTP request asking the proxy to open a tunnel to `host:port`
+    pub fn connect_request(&self, host: &str, port: u64) -> String {
+        let auth = match &self.credentials {
+            Some((user, password)) => format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64(format!("{}:{}", user, password).as_bytes())
+            ),
+            None => String::new(),
+        };
+        format!(
+            "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n{auth}\r\n",
+            host = host,
+            port = port,
+            auth = auth
+        )
+    }
+
+    /// SOCKS5 greeting listing the supported authentication methods
+    pub fn socks5_greeting(&self) -> Vec<u8> {
+        match self.credentials {
+            Some(_) => vec![SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USER_PASS],
+            None => vec![SOCKS_VERSION, 1, SOCKS_NO_AUTH],
+        }
+    }
+
+    /// Username/password authentication request (RFC 1929)
+    pub fn socks5_auth(&self) -> io::Result<Vec<u8>> {
+        let (user, password) = self
+            .credentials
+            .as_ref()
+            .ok_or_else(|| refused("SOCKS5 proxy requires credentials"))?;
+        let mut request = vec![1];
+        for field in [user, password] {
+            let len = u8::try_from(field.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "credentials too long"))?;
+            request.push(len);
+            request.extend_from_slice(field.as_bytes());
+        }
+        Ok(request)
+    }
+}
+
+/// SOCKS5 request to connect to `host:port`, the proxy resolves the name
+pub fn socks5_request(host: &str, port: u64) -> io::Result<Vec<u8>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let host_len = u8::try_from(host.len()).map_err(|_| invalid("host name too long"))?;
+    let port = u16::try_from(port).map_err(|_| invalid("port out of range"))?;
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0, SOCKS_DOMAIN, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+/// Checks the authentication method picked by the proxy, returns true if
+/// username/password authentication has to follow
+pub fn check_socks5_method(reply: [u8; 2]) -> io::Result<bool> {
+    match reply {
+        [SOCKS_VERSION, SOCKS_NO_AUTH] => Ok(false),
+        [SOCKS_VERSION, SOCKS_USER_PASS] => Ok(true),
+        [SOCKS_VERSION, _] => Err(refused("SOCKS5 proxy rejected authentication methods")),
+        _ => Err(refused("not a SOCKS5 proxy")),
+    }
+}
+
+pub fn check_socks5_auth(reply: [u8; 2]) -> io::Result<()> {
+    match reply {
+        [_, 0] => Ok(()),
+        _ => Err(refused("SOCKS5 proxy rejected credentials")),
+    }
+}
+
+/// Checks the first 5 bytes of the reply to the connect request, returns
+/// how many bytes of the bound address and port are still to be read
+pub fn check_socks5_reply(reply: [u8; 5]) -> io::Result<usize> {
+    if reply[0] != SOCKS_VERSION {
+        return Err(refused("not a SOCKS5 proxy"));
+    }
+    if reply[1] != 0 {
+        return Err(refused(&format!(
+            "SOCKS5 proxy failed to connect (code {})",
+            reply[1]
+        )));
+    }
+    match reply[3] {
+        1 => Ok(4 + 2 - 1),
+        4 => Ok(16 + 2 - 1),
+        SOCKS_DOMAIN => Ok(reply[4] as usize + 2),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "SOCKS5 reply with unknown address type",
+        )),
+    }
+}
+
+/// Checks the HTTP response to the CONNECT request
+pub fn check_connect_response(response: &[u8]) -> io::Result<()> {
+    let status = response.split(|&b| b == b'\n').next().unwrap_or_default();
+    let status = String::from_utf8_lossy(status);
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(refused(&format!(
+            "proxy refused to connect: {}",
+            status.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Returns true once the whole HTTP response header was received, it
+/// fails if the response is suspiciously long
+pub fn connect_response_complete(response: &[u8]) -> io::Result<bool> {
+    if response.len() > MAX_RESPONSE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "proxy response too long",
+        ));
+    }
+    Ok(response.ends_with(b"\r\n\r\n"))
+}
+
+fn refused(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg.to_string())
+}
+
+pub(crate) fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Opens a tunnel to `host:port` through the proxy `sock` is connected to
+#[maybe_async]
+pub(crate) async fn tunnel<S: Socket>(
+    sock: &mut S,
+    proxy: &Proxy,
+    host: &str,
+    port: u64,
+) -> io::Result<()> {
+    match proxy.kind {
+        ProxyKind::Http => {
+            sock.write_all(proxy.connect_request(host, port).as_bytes())
+                .await?;
+            sock.flush().await?;
+
+            // read byte by byte so we don't consume the server data
+            let mut response = Vec::new();
+            let mut byte = [0];
+            while !connect_response_complete(&response)? {
+                if sock.read(&mut byte).await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                response.push(byte[0]);
+            }
+            check_connect_response(&response)
+        }
+        ProxyKind::Socks5 => {
+            sock.write_all(&proxy.socks5_greeting()).await?;
+            let mut reply = [0; 2];
+            sock.read_exact(&mut reply).await?;
+            if check_socks5_method(reply)? {
+                sock.write_all(&proxy.socks5_auth()?).await?;
+                sock.read_exact(&mut reply).await?;
+                check_socks5_auth(reply)?;
+            }
+
+            sock.write_all(&socks5_request(host, port)?).await?;
+            let mut reply = [0; 5];
+            sock.read_exact(&mut reply).await?;
+            let mut bound = vec![0; check_socks5_reply(reply)?];
+            sock.read_exact(&mut bound).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn http_connect_request_with_basic_auth() {
+        let proxy = Proxy::http("proxy", 3128).with_credentials("user", "pass");
+        let request = proxy.connect_request("blynk.cloud", 443);
+        assert!(request.starts_with("CONNECT blynk.cloud:443 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+
+        assert!(check_connect_response(b"HTTP/1.1 200 Connection established\r\n\r\n").is_ok());
+        let err = check_connect_response(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+        assert_eq!(io::ErrorKind::ConnectionRefused, err.unwrap_err().kind());
+    }
+
+    #[test]
+    fn socks5_handshake_messages() {
+        let proxy = Proxy::socks5("proxy", 1080).with_credentials("u", "pw");
+        assert_eq!(vec![5, 2, 0, 2], proxy.socks5_greeting());
+        assert_eq!(
+            vec![1, 1, b'u', 2, b'p', b'w'],
+            proxy.socks5_auth().unwrap()
+        );
+        assert_eq!(
+            vec![5, 1, 0, 3, 2, b'h', b'x', 0x01, 0xbb],
+            socks5_request("hx", 443).unwrap()
+        );
+
+        assert!(check_socks5_method([5, 2]).unwrap());
+        assert!(check_socks5_method([5, 0xff]).is_err());
+        assert_eq!(5, check_socks5_reply([5, 0, 0, 1, 10]).unwrap());
+        assert_eq!(7, check_socks5_reply([5, 0, 0, 3, 5]).unwrap());
+        assert!(check_socks5_reply([5, 5, 0, 1, 0]).is_err());
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn tunnels_through_http_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sock = testing::stream(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0];
+            while !request.ends_with(b"\r\n\r\n") {
+                sock.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            sock.write_all(b"HTTP/1.1 200 OK\r\n\r\nblynk").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let proxy = Proxy::http("127.0.0.1", 3128);
+        tunnel(&mut sock, &proxy, "blynk.cloud", 80).await.unwrap();
+        let mut data = [0; 5];
+        sock.read_exact(&mut data).await.unwrap();
+        assert_eq!(b"blynk", &data);
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("CONNECT blynk.cloud:80 HTTP/1.1"));
+    }
+
+    #[maybe_async::test(not(feature = "async"), async(feature = "async", smol_potat::test))]
+    async fn tunnels_through_socks5_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sock = testing::stream(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            sock.read_exact(&mut greeting).unwrap();
+            sock.write_all(&[5, 0]).unwrap();
+            let mut request = [0; 18];
+            sock.read_exact(&mut request).unwrap();
+            sock.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+            sock.write_all(b"blynk").unwrap();
+            request
+        });
+
+        let proxy = Proxy::socks5("127.0.0.1", 1080);
+        tunnel(&mut sock, &proxy, "blynk.cloud", 80).await.unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(&request[5..16], b"blynk.cloud");
+
+        // data after the handshake is left for the Blynk client
+        let mut data = [0; 5];
+        sock.read_exact(&mut data).await.unwrap();
+        assert_eq!(b"blynk", &data);
+    }
+}
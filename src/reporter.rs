@@ -0,0 +1,124 @@
+//! Reporting sensor values only when they change
+//!
+//! Analog sensors hardly ever read the same value twice, writing every
+//! reading wastes traffic and the server side quota. `ChangeReporter`
+//! writes a value only when it moved by more than the configured delta
+//! from the last one written, or when the last write is older than the
+//! maximal interval, so the dashboard still sees the device is alive.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::{maybe_async, Protocol, Result, VirtualPin};
+
+#[derive(Debug, Clone, Copy)]
+struct Written {
+    value: f64,
+    at: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChangeReporter {
+    delta: f64,
+    max_interval: Option<Duration>,
+    /// Deltas of pins that don't use the default one
+    pin_deltas: HashMap<u8, f64>,
+    written: HashMap<u8, Written>,
+}
+
+impl ChangeReporter {
+    /// Values are written when they differ by more than `delta` from the
+    /// last one written to the pin
+    pub fn new(delta: f64) -> ChangeReporter {
+        ChangeReporter {
+            delta,
+            max_interval: None,
+            pin_deltas: HashMap::new(),
+            written: HashMap::new(),
+        }
+    }
+
+    /// Writes the value anyway once the last write is older than `interval`
+    pub fn with_max_interval(mut self, interval: Duration) -> ChangeReporter {
+        self.max_interval = Some(interval);
+        self
+    }
+
+    /// Uses `delta` for the pin instead of the default one
+    pub fn with_pin_delta<N: Into<VirtualPin>>(mut self, pin: N, delta: f64) -> ChangeReporter {
+        self.pin_deltas.insert(pin.into().number(), delta);
+        self
+    }
+
+    /// Forgets the values written, so the next update of every pin is
+    /// sent, e.g. after a reconnect
+    pub fn reset(&mut self) {
+        self.written.clear();
+    }
+
+    /// Records the value if it has to be written
+    fn changed(&mut self, pin: u8, value: f64, now: Instant) -> bool {
+        let delta = self.pin_deltas.get(&pin).copied().unwrap_or(self.delta);
+        let due = match self.written.get(&pin) {
+            Some(last) => {
+                (value - last.value).abs() > delta
+                    || self
+                        .max_interval
+                        .is_some_and(|max| now.saturating_duration_since(last.at) >= max)
+            }
+            None => true,
+        };
+        if due {
+            self.written.insert(pin, Written { value, at: now });
+        }
+        due
+    }
+}
+
+#[maybe_async]
+impl ChangeReporter {
+    /// Writes the value if it changed enough, returns whether it was written
+    pub async fn update<P: Protocol>(
+        &mut self,
+        client: &mut P,
+        pin: u8,
+        value: f64,
+    ) -> Result<bool> {
+        if !self.changed(pin, value, Instant::now()) {
+            return Ok(false);
+        }
+        if let Err(err) = client.virtual_write(pin, value).await {
+            self.written.remove(&pin);
+            return Err(err);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_significant_changes() {
+        let now = Instant::now();
+        let mut reporter = ChangeReporter::new(0.5)
+            .with_max_interval(Duration::from_secs(60))
+            .with_pin_delta(2, 5.0);
+
+        assert!(reporter.changed(1, 20.0, now));
+        assert!(!reporter.changed(1, 20.4, now));
+        // compared with the value written, not the last update
+        assert!(!reporter.changed(1, 20.5, now));
+        assert!(reporter.changed(1, 20.6, now));
+        assert!(reporter.changed(2, 100.0, now));
+        assert!(!reporter.changed(2, 104.0, now));
+
+        let later = now + Duration::from_secs(60);
+        assert!(reporter.changed(1, 20.6, later));
+        assert!(!reporter.changed(1, 20.6, later));
+
+        reporter.reset();
+        assert!(reporter.changed(2, 100.0, later));
+    }
+}
@@ -0,0 +1,147 @@
+//! Sending from tasks that don't own the connection
+//!
+//! `Blynk::run` needs `&mut Blynk`, so a sensor thread (or task) can't
+//! write to the client while the loop runs. `BlynkSender` is a cheap handle
+//! that queues the commands in a channel instead, `run` takes them off and
+//! sends them once it's connected. Get one with `Blynk::sender` and clone
+//! it for every producer.
+
+#[cfg(feature = "async")]
+use smol::channel::{unbounded as channel, Receiver, Sender};
+#[cfg(not(feature = "async"))]
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::{maybe_async, BlynkError, IntoBlynkValue, Protocol, Result, VirtualPin};
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    VirtualWrite {
+        pin: u8,
+        value: String,
+    },
+    Notify(String),
+    SetProperty {
+        pin: u8,
+        prop: String,
+        value: String,
+    },
+}
+
+/// Clonable handle queueing commands for `Blynk::run`, sending fails with
+/// `BlynkError::MessageSend` once the `Blynk` is dropped
+#[derive(Debug, Clone)]
+pub struct BlynkSender {
+    tx: Sender<Command>,
+}
+
+impl BlynkSender {
+    pub fn virtual_write<P, V>(&self, pin: P, value: V) -> Result<()>
+    where
+        P: Into<VirtualPin>,
+        V: IntoBlynkValue,
+    {
+        self.send(Command::VirtualWrite {
+            pin: pin.into().number(),
+            value: value.into_blynk_value(),
+        })
+    }
+
+    pub fn notify(&self, msg: &str) -> Result<()> {
+        self.send(Command::Notify(msg.to_string()))
+    }
+
+    pub fn set_property<P: Into<VirtualPin>>(&self, pin: P, prop: &str, value: &str) -> Result<()> {
+        self.send(Command::SetProperty {
+            pin: pin.into().number(),
+            prop: prop.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn send(&self, command: Command) -> Result<()> {
+        self.tx.send(command).map_err(|_| BlynkError::MessageSend)
+    }
+
+    #[cfg(feature = "async")]
+    fn send(&self, command: Command) -> Result<()> {
+        self.tx
+            .try_send(command)
+            .map_err(|_| BlynkError::MessageSend)
+    }
+}
+
+/// Receiving end of the senders, kept by `Blynk`
+pub(crate) struct Commands {
+    tx: Sender<Command>,
+    rx: Receiver<Command>,
+}
+
+impl Default for Commands {
+    fn default() -> Commands {
+        let (tx, rx) = channel();
+        Commands { tx, rx }
+    }
+}
+
+impl Commands {
+    pub fn sender(&self) -> BlynkSender {
+        BlynkSender {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+#[maybe_async]
+impl Commands {
+    /// Sends the queued commands, the first failure is returned after the
+    /// rest were tried
+    pub async fn drain<P: Protocol>(&self, client: &mut P) -> Result<()> {
+        let mut res = Ok(());
+        while let Ok(command) = self.rx.try_recv() {
+            let sent = match command {
+                Command::VirtualWrite { pin, value } => client.virtual_write(pin, &value).await,
+                Command::Notify(msg) => client.notify(&msg).await,
+                Command::SetProperty { pin, prop, value } => {
+                    client.set_property(pin, &prop, &value).await
+                }
+            };
+            res = res.and(sent);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_commands_of_all_senders() {
+        let commands = Commands::default();
+        let sender = commands.sender();
+        let other = sender.clone();
+        sender.virtual_write(1, "20.5").unwrap();
+        std::thread::spawn(move || other.notify("door open").unwrap())
+            .join()
+            .unwrap();
+
+        let queued: Vec<Command> = std::iter::from_fn(|| commands.rx.try_recv().ok()).collect();
+        assert_eq!(
+            vec![
+                Command::VirtualWrite {
+                    pin: 1,
+                    value: "20.5".into()
+                },
+                Command::Notify("door open".into()),
+            ],
+            queued
+        );
+
+        drop(commands);
+        assert!(matches!(
+            sender.set_property(1, "color", "#FF0000"),
+            Err(BlynkError::MessageSend)
+        ));
+    }
+}
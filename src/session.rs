@@ -0,0 +1,416 @@
+//! Connection state and message dispatch of the blocking and async `Blynk`
+//!
+//! `Session` decides what a message from the server leads to and moves the
+//! connection through its states, `blocking` and `async_impl` only perform
+//! the reads, writes and handler calls it asks for.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::*;
+
+use crate::history::PinHistory;
+use crate::message::{Message, MessageRef, MessageType, PinMode, ProtocolStatus};
+use crate::proto::{self, Debouncer, Liveness, Request};
+use crate::{
+    conf, BlynkError, ConfigSource, ConnectionState, HandlerErrorPolicy, InternalCommand,
+    OtaRequest, Result, TimeZone,
+};
+
+/// Client state the dispatch depends on
+pub trait Responses {
+    /// Marks the message `id` as acknowledged, returns its type if the
+    /// response was expected
+    fn acknowledge(&mut self, id: u16) -> Option<MessageType>;
+
+    fn history(&self) -> Option<&PinHistory>;
+}
+
+/// Handler hook a message from the server is passed to
+#[derive(Debug)]
+pub enum Hook<'a> {
+    Response(u16, ProtocolStatus),
+    Internal(&'a [&'a str]),
+    Rtc(SystemTime),
+    /// The time zone of the session changed
+    Timezone,
+    Ota(OtaRequest),
+    AppConnected,
+    AppDisconnected,
+    Command(InternalCommand),
+    VirtualWriteRaw(u8, &'a [u8]),
+    DigitalWrite(u8, bool),
+    AnalogWrite(u8, &'a str),
+    DigitalRead(u8),
+    PinMode(u8, PinMode),
+    Property(u8, &'a str, &'a str),
+    ProtocolError(BlynkError),
+}
+
+/// What the I/O layer does about a message from the server
+#[derive(Debug)]
+pub enum Dispatch<'a> {
+    Done,
+    /// Answer the ping with `StatusOk`
+    Pong(u16),
+    /// Send the values as an internal command
+    Reply(Vec<String>),
+    /// Pass the values to the route of the pin, or to the handler
+    Write(u8, &'a [&'a str]),
+    /// Pass the read request to the route of the pin, or to the handler
+    Read(u8),
+    /// Call all the hooks in order, the first failure is the result
+    Hooks(Vec<Hook<'a>>),
+}
+
+pub struct Session {
+    state: ConnectionState,
+    /// Server and port received in the last redirect
+    redirect: Option<(String, u64)>,
+    timezone: TimeZone,
+    /// Stateful pins and the last value written to them
+    tracked: BTreeMap<u8, Option<String>>,
+    debouncer: Debouncer,
+    /// Set by a failed hook under `HandlerErrorPolicy::Reconnect`
+    reconnect: bool,
+    last_rcv_time: Instant,
+    last_ping_time: Instant,
+    last_send_time: Instant,
+}
+
+impl Session {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            state: ConnectionState::Disconnected,
+            redirect: None,
+            timezone: TimeZone::default(),
+            tracked: BTreeMap::new(),
+            debouncer: Debouncer::default(),
+            reconnect: false,
+            last_rcv_time: now,
+            last_ping_time: now,
+            last_send_time: now,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        matches!(self.state, ConnectionState::Authenticated)
+    }
+
+    pub fn timezone(&self) -> &TimeZone {
+        &self.timezone
+    }
+
+    /// Restarts the heartbeat timing from `now`, e.g. after the clock
+    /// was replaced
+    pub fn restart(&mut self, now: Instant) {
+        self.last_rcv_time = now;
+        self.last_ping_time = now;
+        self.last_send_time = now;
+    }
+
+    pub fn connecting(&mut self) {
+        self.state = ConnectionState::Connecting;
+    }
+
+    pub fn authenticating(&mut self) {
+        info!("Authenticating device...");
+        self.state = ConnectionState::Authentiacting;
+    }
+
+    /// Checks the answer to the login, keeping the server the device is
+    /// redirected to
+    pub fn logged_in(&mut self, msg: &Message, port: u64) -> Result<()> {
+        if let Some((server, port)) = proto::redirect_target(msg, port) {
+            self.redirect = Some((server.to_string(), port));
+        }
+        proto::check_login(msg)?;
+
+        self.state = ConnectionState::Authenticated;
+        info!("Access granted");
+        Ok(())
+    }
+
+    /// Server to connect to after the `redirects`-th redirect, `None` if
+    /// there are too many of them or `config` refuses it
+    pub fn follow_redirect<S: ConfigSource>(
+        &mut self,
+        redirects: u8,
+        config: &mut S,
+    ) -> Option<(String, u64)> {
+        if redirects >= conf::MAX_REDIRECTS {
+            return None;
+        }
+        let (server, port) = self.redirect.take()?;
+        if !config.redirect(&server, port) {
+            return None;
+        }
+        info!("Redirected to {}:{}", server, port);
+        Some((server, port))
+    }
+
+    /// Completes the handshake, returns the stateful pins to sync
+    pub fn connected(&mut self, now: Instant) -> Vec<u8> {
+        self.last_rcv_time = now;
+        self.tracked.keys().copied().collect()
+    }
+
+    pub fn disconnected(&mut self, msg: &str) {
+        self.state = ConnectionState::Disconnected;
+        error!("{}", msg);
+    }
+
+    pub fn received(&mut self, now: Instant) {
+        self.last_rcv_time = now;
+    }
+
+    pub fn sent(&mut self, now: Instant) {
+        self.last_send_time = now;
+    }
+
+    /// Decides if the server is still there, `Liveness::Ping` asks for a
+    /// ping followed by `pinged`
+    pub fn liveness(&self, now: Instant, heartbeat: Duration) -> Liveness {
+        let liveness = proto::liveness(
+            heartbeat,
+            now.duration_since(self.last_rcv_time),
+            now.duration_since(self.last_ping_time),
+            now.duration_since(self.last_send_time),
+        );
+        if liveness == Liveness::Dead {
+            warn!("Server not alive, will initiate disconnect");
+        }
+        liveness
+    }
+
+    pub fn pinged(&mut self, now: Instant) {
+        let delta = now.duration_since(self.last_ping_time);
+        info!("Heartbeat delta: {}ms", delta.as_millis());
+        self.last_ping_time = now;
+        self.last_send_time = now;
+    }
+
+    pub fn track_pin(&mut self, pin: u8) {
+        self.tracked.entry(pin).or_default();
+    }
+
+    pub fn debounce(&mut self, pin: u8, quiet: Duration) {
+        self.debouncer.add(pin, quiet);
+    }
+
+    /// Writes of debounced pins that settled by `now`
+    pub fn settled(&mut self, now: Instant) -> Vec<(u8, Vec<String>)> {
+        self.debouncer.settled(now)
+    }
+
+    /// Returns true once after a failed hook asked for a reconnect
+    pub fn take_reconnect(&mut self) -> bool {
+        std::mem::take(&mut self.reconnect)
+    }
+
+    /// Applies `policy` to the failure of a handler hook, returns the code
+    /// of the event to log it as
+    pub fn handler_failed(
+        &mut self,
+        err: &BlynkError,
+        policy: HandlerErrorPolicy,
+    ) -> Option<String> {
+        error!("Handler failed: {}", err);
+        match policy {
+            HandlerErrorPolicy::Log => None,
+            HandlerErrorPolicy::LogEvent(code) => Some(code),
+            HandlerErrorPolicy::Reconnect => {
+                self.reconnect = true;
+                None
+            }
+        }
+    }
+
+    /// Decides what the message received at `now` leads to
+    pub fn dispatch<'a, R: Responses>(
+        &mut self,
+        msg: &'a MessageRef<'_>,
+        client: &mut R,
+        now: Instant,
+    ) -> Dispatch<'a> {
+        match proto::route(msg) {
+            Request::Response(id, status) => {
+                let Some(mtype) = client.acknowledge(id) else {
+                    debug!("Response to unknown message {}: {:?}", id, status);
+                    return Dispatch::Done;
+                };
+                if !matches!(status, ProtocolStatus::StatusOk) {
+                    warn!("Server rejected {:?} message {}: {:?}", mtype, id, status);
+                }
+                Dispatch::Hooks(vec![Hook::Response(id, status)])
+            }
+            Request::Ping(id) => Dispatch::Pong(id),
+            Request::History(args) => match client.history().and_then(|h| h.reply(args)) {
+                Some(reply) => Dispatch::Reply(reply),
+                None => Dispatch::Done,
+            },
+            Request::Internal(name, data) => {
+                let command = match InternalCommand::parse(name, data) {
+                    command @ InternalCommand::Rtc(_) => {
+                        Hook::Rtc(command.time().unwrap_or(UNIX_EPOCH))
+                    }
+                    InternalCommand::Utc(args) if self.timezone.update(&args) => Hook::Timezone,
+                    InternalCommand::Ota(ota) => Hook::Ota(ota),
+                    InternalCommand::AppConnected => Hook::AppConnected,
+                    InternalCommand::AppDisconnected => Hook::AppDisconnected,
+                    command => Hook::Command(command),
+                };
+                Dispatch::Hooks(vec![Hook::Internal(data), command])
+            }
+            Request::VirtualWrite(pin_num, values) => {
+                if let Some(last) = self.tracked.get_mut(&pin_num) {
+                    let value = values.join("\0");
+                    if last.as_deref() == Some(value.as_str()) {
+                        debug!("Ignoring repeated write of pin {}", pin_num);
+                        return Dispatch::Done;
+                    }
+                    *last = Some(value);
+                }
+                if self.debouncer.hold(pin_num, values, now) {
+                    return Dispatch::Done;
+                }
+                Dispatch::Write(pin_num, values)
+            }
+            Request::VirtualWriteRaw(pin_num, data) => {
+                Dispatch::Hooks(vec![Hook::VirtualWriteRaw(pin_num, data)])
+            }
+            Request::VirtualRead(pin_num) => Dispatch::Read(pin_num),
+            Request::DigitalWrite(pin, high) => {
+                Dispatch::Hooks(vec![Hook::DigitalWrite(pin, high)])
+            }
+            Request::AnalogWrite(pin, data) => Dispatch::Hooks(vec![Hook::AnalogWrite(pin, data)]),
+            Request::DigitalRead(pin) => Dispatch::Hooks(vec![Hook::DigitalRead(pin)]),
+            Request::PinMode(args) => Dispatch::Hooks(
+                proto::pin_modes(args)
+                    .into_iter()
+                    .map(|(pin, mode)| Hook::PinMode(pin, mode))
+                    .collect(),
+            ),
+            Request::Property(pin, prop, value) => {
+                Dispatch::Hooks(vec![Hook::Property(pin, prop, value)])
+            }
+            Request::InvalidPin(pin) => {
+                let err = BlynkError::InvalidPin(pin.into());
+                warn!("Skipping message {}: {}", msg.id, err);
+                Dispatch::Hooks(vec![Hook::ProtocolError(err)])
+            }
+            Request::None => Dispatch::Done,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Sent {
+        acked: Vec<u16>,
+    }
+
+    impl Responses for Sent {
+        fn acknowledge(&mut self, id: u16) -> Option<MessageType> {
+            self.acked.push(id);
+            (id == 1).then_some(MessageType::Hw)
+        }
+
+        fn history(&self) -> Option<&PinHistory> {
+            None
+        }
+    }
+
+    fn dispatch(session: &mut Session, mtype: MessageType, id: u16, body: Vec<&str>) -> String {
+        let msg = Message::new(mtype, id, None, None, body);
+        let msg = msg.to_ref();
+        format!(
+            "{:?}",
+            session.dispatch(&msg, &mut Sent::default(), Instant::now())
+        )
+    }
+
+    #[test]
+    fn dispatches_server_requests() {
+        let mut session = Session::new(Instant::now());
+        assert_eq!(
+            "Pong(7)",
+            dispatch(&mut session, MessageType::Ping, 7, vec![])
+        );
+        let body = vec!["vw", "3", "on"];
+        assert_eq!(
+            r#"Write(3, ["on"])"#,
+            dispatch(&mut session, MessageType::Hw, 1, body)
+        );
+        let body = vec!["pm", "1", "out", "2", "in"];
+        assert_eq!(
+            "Hooks([PinMode(1, Output), PinMode(2, Input)])",
+            dispatch(&mut session, MessageType::Hw, 1, body)
+        );
+        let body = vec!["rtc", "1"];
+        assert!(dispatch(&mut session, MessageType::Internal, 1, body)
+            .starts_with(r#"Hooks([Internal(["1"]), Rtc("#));
+    }
+
+    #[test]
+    fn ignores_responses_to_unknown_messages() {
+        let mut session = Session::new(Instant::now());
+        let ok = Some(ProtocolStatus::StatusOk);
+        let msg = Message::new(MessageType::Rsp, 2, None, ok, vec![]);
+        let mut sent = Sent::default();
+        let msg = msg.to_ref();
+        assert!(matches!(
+            session.dispatch(&msg, &mut sent, Instant::now()),
+            Dispatch::Done
+        ));
+        assert_eq!(vec![2], sent.acked);
+    }
+
+    #[test]
+    fn drops_repeated_writes_of_tracked_pins() {
+        let mut session = Session::new(Instant::now());
+        session.track_pin(4);
+        let body = vec!["vw", "4", "1"];
+        assert_eq!(
+            r#"Write(4, ["1"])"#,
+            dispatch(&mut session, MessageType::Hw, 1, body.clone())
+        );
+        assert_eq!("Done", dispatch(&mut session, MessageType::Hw, 2, body));
+        assert_eq!(vec![4], session.connected(Instant::now()));
+    }
+
+    #[test]
+    fn moves_through_connection_states() {
+        let mut session = Session::new(Instant::now());
+        session.connecting();
+        session.authenticating();
+        assert!(!session.is_authenticated());
+
+        let msg = Message::new(MessageType::Redirect, 1, None, None, vec!["other", "9443"]);
+        assert!(matches!(
+            session.logged_in(&msg, 80),
+            Err(BlynkError::Redirection)
+        ));
+        let mut config = crate::Config::default();
+        assert_eq!(
+            Some(("other".to_string(), 9443)),
+            session.follow_redirect(0, &mut config)
+        );
+        assert_eq!(None, session.follow_redirect(0, &mut config));
+
+        let ok = Some(ProtocolStatus::StatusOk);
+        let msg = Message::new(MessageType::Rsp, 1, None, ok, vec![]);
+        session.logged_in(&msg, 80).unwrap();
+        assert!(session.is_authenticated());
+        session.disconnected("test");
+        assert!(matches!(session.state(), ConnectionState::Disconnected));
+    }
+}
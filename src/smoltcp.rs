@@ -0,0 +1,580 @@
+//! `smoltcp` support for firmware owning its network stack
+//!
+//! smoltcp sockets never block, they are polled together with the interface.
+//! `SmoltcpClient` follows the same model: every `poll` consumes whatever the
+//! socket received, runs the handshake, heartbeat and handler callbacks, and
+//! writes as much of the outgoing queue as the socket buffer takes. Nothing
+//! waits for the network, so it fits a bare-metal main loop:
+//!
+//! ```ignore
+//! loop {
+//!     let now = Instant::now();
+//!     iface.poll(now, &mut device, &mut sockets);
+//!     let socket = sockets.get_mut::<tcp::Socket>(handle);
+//!     if !socket.is_open() {
+//!         socket.connect(iface.context(), SERVER, next_port())?;
+//!     }
+//!     if let Err(err) = blynk.poll(socket, now, &mut handler) {
+//!         warn!("Blynk connection failed: {}", err);
+//!         socket.abort();
+//!     }
+//! }
+//! ```
+
+use std::io;
+use std::time::Duration;
+
+use log::*;
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+
+use crate::message::{MessageRef, MessageType, PinMode, ProtocolStatus};
+use crate::proto::{self, Liveness, Outbox, Request};
+use crate::{BlynkError, ConfigSource, FirmwareInfo, IntoBlynkValue, ParamList, Result};
+use crate::{HandlerErrorPolicy, VirtualPin};
+
+/// Callbacks of `SmoltcpClient::poll`, writes issued from them are sent
+/// within the same poll. Errors returned by the hooks are handled
+/// according to the `HandlerErrorPolicy` of the config, as with `Event`
+#[allow(unused_variables)]
+pub trait SmoltcpEvent {
+    fn handle_connect(&mut self, client: &mut SmoltcpClient) -> Result<()> {
+        Ok(())
+    }
+    fn handle_internal(&mut self, client: &mut SmoltcpClient, params: ParamList<'_>) -> Result<()> {
+        Ok(())
+    }
+    fn handle_vpin_read(&mut self, client: &mut SmoltcpClient, pin_num: VirtualPin) -> Result<()> {
+        Ok(())
+    }
+    fn handle_vpin_write(
+        &mut self,
+        client: &mut SmoltcpClient,
+        pin_num: VirtualPin,
+        params: ParamList<'_>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn handle_vpin_write_raw(
+        &mut self,
+        client: &mut SmoltcpClient,
+        pin_num: VirtualPin,
+        data: &[u8],
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn handle_digital_write(
+        &mut self,
+        client: &mut SmoltcpClient,
+        pin: u8,
+        high: bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn handle_analog_write(
+        &mut self,
+        client: &mut SmoltcpClient,
+        pin: u8,
+        data: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn handle_digital_read(&mut self, client: &mut SmoltcpClient, pin: u8) -> Result<()> {
+        Ok(())
+    }
+    fn handle_pin_mode(
+        &mut self,
+        client: &mut SmoltcpClient,
+        pin: u8,
+        mode: PinMode,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn handle_property(
+        &mut self,
+        client: &mut SmoltcpClient,
+        pin: VirtualPin,
+        prop: &str,
+        value: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Malformed message from the server, the message is skipped
+    fn handle_protocol_error(
+        &mut self,
+        client: &mut SmoltcpClient,
+        err: &BlynkError,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Disconnected,
+    Authenticating,
+    SettingHeartbeat,
+    Ready,
+}
+
+/// Poll driven Blynk client over a `smoltcp` TCP socket
+pub struct SmoltcpClient {
+    token: String,
+    device_id: Option<String>,
+    firmware: Option<FirmwareInfo>,
+    heartbeat: Duration,
+    rcv_buffer: u16,
+    error_policy: HandlerErrorPolicy,
+    state: State,
+    msg_id: u16,
+    rx: Vec<u8>,
+    /// handshake frames, sent ahead of the queued writes
+    urgent: Vec<u8>,
+    outbox: Outbox,
+    last_rcv: Instant,
+    last_ping: Instant,
+    last_send: Instant,
+}
+
+impl SmoltcpClient {
+    pub fn new<C: ConfigSource>(config: &C) -> SmoltcpClient {
+        let mut outbox = Outbox::default();
+        outbox.set_write_policy(config.write_policy());
+        outbox.set_coalesced_pins(config.coalesced_pins());
+        SmoltcpClient {
+            token: config.token().into(),
+            device_id: config.device_id().map(String::from),
+            firmware: config.firmware().cloned(),
+            heartbeat: config.heartbeat(),
+            rcv_buffer: config.rcv_buffer(),
+            error_policy: config.handler_error_policy(),
+            state: State::Disconnected,
+            msg_id: 0,
+            rx: Vec::new(),
+            urgent: Vec::new(),
+            outbox,
+            last_rcv: Instant::ZERO,
+            last_ping: Instant::ZERO,
+            last_send: Instant::ZERO,
+        }
+    }
+
+    /// True once the device is logged in and the heartbeat is set
+    pub fn is_ready(&self) -> bool {
+        self.state == State::Ready
+    }
+
+    /// Number of messages waiting for room in the socket buffer
+    pub fn queue_depth(&self) -> usize {
+        self.outbox.len()
+    }
+
+    fn msg_id(&mut self) -> u16 {
+        self.msg_id = proto::next_id(self.msg_id);
+        self.msg_id
+    }
+
+    /// Queues the message, it's written to the socket by the next `poll`
+    fn send(&mut self, msg: Vec<u8>) -> Result<()> {
+        proto::check_size(&msg)?;
+        self.outbox.coalesce(&msg);
+        match self.outbox.admit(msg)? {
+            Some(msg) if self.outbox.handshake() => self.urgent.extend(msg),
+            Some(msg) => self.outbox.push(msg),
+            None => (),
+        }
+        Ok(())
+    }
+
+    pub fn virtual_write<N, V>(&mut self, v_pin: N, val: V) -> Result<()>
+    where
+        N: Into<VirtualPin>,
+        V: IntoBlynkValue,
+    {
+        let v_pin = v_pin.into().number();
+        let msg = proto::virtual_write(self.msg_id(), v_pin, &val.into_blynk_value());
+        self.send(msg)
+    }
+
+    /// Writes the bytes as they are, e.g. image chunks or packed structs
+    pub fn virtual_write_raw<N: Into<VirtualPin>>(&mut self, v_pin: N, val: &[u8]) -> Result<()> {
+        let v_pin = v_pin.into().number();
+        let msg = proto::virtual_write_raw(self.msg_id(), v_pin, val);
+        self.send(msg)
+    }
+
+    pub fn set_property<N: Into<VirtualPin>>(
+        &mut self,
+        pin: N,
+        prop: &str,
+        val: &str,
+    ) -> Result<()> {
+        let pin = pin.into().number();
+        let msg = proto::set_property(self.msg_id(), pin, prop, val);
+        self.send(msg)
+    }
+
+    pub fn internal(&mut self, data: Vec<&str>) -> Result<()> {
+        let msg = proto::command(MessageType::Internal, self.msg_id(), data);
+        self.send(msg)
+    }
+
+    /// Drives the connection, to be called after every `Interface::poll`.
+    ///
+    /// Returns an error when the connection failed (socket closed, server
+    /// silent, login rejected), the socket should then be aborted and
+    /// connected again, the handshake restarts once it's established.
+    pub fn poll<E: SmoltcpEvent>(
+        &mut self,
+        socket: &mut tcp::Socket,
+        now: Instant,
+        handler: &mut E,
+    ) -> Result<()> {
+        if socket.state() != tcp::State::Established {
+            if self.state == State::Disconnected {
+                return Ok(());
+            }
+            self.reset();
+            return Err(io::Error::from(io::ErrorKind::ConnectionAborted).into());
+        }
+
+        if self.state == State::Disconnected {
+            info!("Authenticating device...");
+            self.outbox.set_handshake(true);
+            let msg = proto::login(self.msg_id(), &self.token);
+            self.send(msg)?;
+            self.state = State::Authenticating;
+            self.last_rcv = now;
+        }
+
+        while socket.can_recv() {
+            let mut chunk = [0; 256];
+            let size = socket
+                .recv_slice(&mut chunk)
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            self.rx.extend_from_slice(&chunk[..size]);
+        }
+
+        while let Some(len) = proto::frame_len(&self.rx) {
+            let frame: Vec<u8> = self.rx.drain(..len).collect();
+            self.last_rcv = now;
+            match MessageRef::parse(&frame) {
+                Ok(msg) => {
+                    if let Err(err) = self.process(msg, handler) {
+                        self.reset();
+                        return Err(err);
+                    }
+                }
+                Err(err) => warn!("Dropping malformed message: {}", err),
+            }
+        }
+
+        if self.state == State::Ready {
+            let since = |then: Instant| Duration::from(now - then);
+            match proto::liveness(
+                self.heartbeat,
+                since(self.last_rcv),
+                since(self.last_ping),
+                since(self.last_send),
+            ) {
+                Liveness::Dead => {
+                    warn!("Server not alive, will initiate disconnect");
+                    self.reset();
+                    return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+                }
+                Liveness::Ping => {
+                    let msg = proto::ping(self.msg_id());
+                    self.send(msg)?;
+                    self.last_ping = now;
+                }
+                Liveness::Alive => (),
+            }
+        }
+
+        self.flush(socket, now)
+    }
+
+    fn process<E: SmoltcpEvent>(&mut self, msg: MessageRef<'_>, handler: &mut E) -> Result<()> {
+        match self.state {
+            State::Authenticating => {
+                proto::check_login(&msg.into_owned())?;
+                info!("Setting heartbeat");
+                let msg = proto::heartbeat(
+                    self.msg_id(),
+                    self.heartbeat,
+                    self.rcv_buffer,
+                    self.device_id.as_deref(),
+                    self.firmware.as_ref(),
+                );
+                self.send(msg)?;
+                self.state = State::SettingHeartbeat;
+            }
+            State::SettingHeartbeat => {
+                proto::check_heartbeat(&msg.into_owned())?;
+                self.outbox.set_handshake(false);
+                self.state = State::Ready;
+                let handled = handler.handle_connect(self);
+                self.handler_failed(handled)?;
+            }
+            State::Ready => {
+                let handled = match proto::route(&msg) {
+                    Request::Ping(id) => {
+                        return self.send(proto::response(id, u16::from(ProtocolStatus::StatusOk)));
+                    }
+                    Request::Internal(_, data) => {
+                        handler.handle_internal(self, ParamList::new(data))
+                    }
+                    Request::VirtualWrite(pin_num, values) => {
+                        handler.handle_vpin_write(self, pin_num.into(), ParamList::new(values))
+                    }
+                    Request::VirtualWriteRaw(pin_num, data) => {
+                        handler.handle_vpin_write_raw(self, pin_num.into(), data)
+                    }
+                    Request::VirtualRead(pin_num) => handler.handle_vpin_read(self, pin_num.into()),
+                    Request::DigitalWrite(pin, high) => {
+                        handler.handle_digital_write(self, pin, high)
+                    }
+                    Request::AnalogWrite(pin, data) => handler.handle_analog_write(self, pin, data),
+                    Request::DigitalRead(pin) => handler.handle_digital_read(self, pin),
+                    Request::PinMode(args) => {
+                        let mut handled = Ok(());
+                        for (pin, mode) in proto::pin_modes(args) {
+                            handled = handled.and(handler.handle_pin_mode(self, pin, mode));
+                        }
+                        handled
+                    }
+                    Request::Property(pin, prop, value) => {
+                        handler.handle_property(self, pin.into(), prop, value)
+                    }
+                    Request::InvalidPin(pin) => {
+                        let err = BlynkError::InvalidPin(pin.into());
+                        warn!("Skipping message {}: {}", msg.id, err);
+                        handler.handle_protocol_error(self, &err)
+                    }
+                    Request::History(_) | Request::Response(..) | Request::None => Ok(()),
+                };
+                self.handler_failed(handled)?;
+            }
+            State::Disconnected => (),
+        }
+        Ok(())
+    }
+
+    /// Applies the handler error policy to the result of a hook, fails
+    /// with the error of the hook if the connection has to be dropped
+    fn handler_failed(&mut self, handled: Result<()>) -> Result<()> {
+        let Err(err) = handled else {
+            return Ok(());
+        };
+        error!("Handler failed: {}", err);
+        match self.error_policy.clone() {
+            HandlerErrorPolicy::Log => Ok(()),
+            HandlerErrorPolicy::LogEvent(code) => {
+                let msg = proto::log_event(self.msg_id(), &code, &err.to_string());
+                self.send(msg)
+            }
+            HandlerErrorPolicy::Reconnect => Err(err),
+        }
+    }
+
+    /// Writes as much as the socket buffer takes, the rest waits for the
+    /// next poll
+    fn flush(&mut self, socket: &mut tcp::Socket, now: Instant) -> Result<()> {
+        let send_err = |err: tcp::SendError| BlynkError::from(io::Error::other(err.to_string()));
+        while !self.urgent.is_empty() {
+            match socket.send_slice(&self.urgent).map_err(send_err)? {
+                0 => return Ok(()),
+                size => {
+                    self.urgent.drain(..size);
+                    self.last_send = now;
+                }
+            }
+        }
+        if self.outbox.handshake() {
+            return Ok(());
+        }
+        while let Some(msg) = self.outbox.front() {
+            match socket.send_slice(msg).map_err(send_err)? {
+                0 => break,
+                size => {
+                    self.outbox.advance(size);
+                    self.last_send = now;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Disconnected;
+        self.msg_id = 0;
+        self.rx.clear();
+        self.urgent.clear();
+        self.outbox.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Message, ProtocolHeader};
+    use crate::Config;
+    use smoltcp::iface::{Config as IfaceConfig, Interface, SocketSet};
+    use smoltcp::phy::{Loopback, Medium};
+    use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr};
+
+    #[derive(Default)]
+    struct Handler {
+        connected: bool,
+        writes: Vec<(u8, String)>,
+    }
+
+    impl SmoltcpEvent for Handler {
+        fn handle_connect(&mut self, _client: &mut SmoltcpClient) -> Result<()> {
+            self.connected = true;
+            Ok(())
+        }
+
+        fn handle_vpin_write(
+            &mut self,
+            client: &mut SmoltcpClient,
+            pin_num: VirtualPin,
+            params: ParamList<'_>,
+        ) -> Result<()> {
+            let pin_num = pin_num.number();
+            self.writes.push((pin_num, params.as_str().into()));
+            client.virtual_write(pin_num + 1, params.as_str())
+        }
+
+        fn handle_vpin_read(
+            &mut self,
+            _client: &mut SmoltcpClient,
+            _pin: VirtualPin,
+        ) -> Result<()> {
+            Err(BlynkError::handler("no sensor"))
+        }
+    }
+
+    fn socket<'a>() -> tcp::Socket<'a> {
+        let buffer = || tcp::SocketBuffer::new(vec![0; 1024]);
+        tcp::Socket::new(buffer(), buffer())
+    }
+
+    fn rsp(id: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        let header = (
+            MessageType::Rsp as u8,
+            id,
+            u16::from(ProtocolStatus::StatusOk),
+        );
+        ProtocolHeader::write_to(header, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn handshake_and_handlers_over_loopback() {
+        let mut device = Loopback::new(Medium::Ip);
+        let mut now = Instant::ZERO;
+        let mut iface = Interface::new(IfaceConfig::new(HardwareAddress::Ip), &mut device, now);
+        iface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8))
+                .unwrap();
+        });
+
+        let mut sockets = SocketSet::new(vec![]);
+        let mut server = socket();
+        server.listen(8080).unwrap();
+        let server = sockets.add(server);
+        let mut client = socket();
+        let remote = (IpAddress::v4(127, 0, 0, 1), 8080);
+        client.connect(iface.context(), remote, 49152).unwrap();
+        let client = sockets.add(client);
+
+        let config = Config {
+            token: "token".into(),
+            ..Default::default()
+        };
+        let mut blynk = SmoltcpClient::new(&config);
+        let mut handler = Handler::default();
+        let mut received = Vec::new();
+        let mut replies =
+            vec![rsp(1), [rsp(2), proto::virtual_write(7, 3, "on")].concat()].into_iter();
+
+        for _ in 0..50 {
+            iface.poll(now, &mut device, &mut sockets);
+            let socket = sockets.get_mut::<tcp::Socket>(client);
+            blynk.poll(socket, now, &mut handler).unwrap();
+
+            let server = sockets.get_mut::<tcp::Socket>(server);
+            while server.can_recv() {
+                let mut frames = Vec::new();
+                server
+                    .recv(|data| {
+                        frames.extend_from_slice(data);
+                        (data.len(), ())
+                    })
+                    .unwrap();
+                received.extend(frames);
+                if let Some(reply) = replies.next() {
+                    server.send_slice(&reply).unwrap();
+                }
+            }
+            now += smoltcp::time::Duration::from_millis(10);
+        }
+
+        assert!(blynk.is_ready());
+        assert!(handler.connected);
+        assert_eq!(vec![(3, "on".to_string())], handler.writes);
+        assert_eq!(MessageType::Login as u8, received[0]);
+        assert!(received.ends_with(&proto::virtual_write(3, 4, "on")));
+    }
+
+    #[test]
+    fn closed_socket_resets_the_session() {
+        let config = Config {
+            token: "token".into(),
+            ..Default::default()
+        };
+        let mut blynk = SmoltcpClient::new(&config);
+        let mut socket = socket();
+        // never connected, nothing to report
+        assert!(blynk
+            .poll(&mut socket, Instant::ZERO, &mut Handler::default())
+            .is_ok());
+
+        blynk.state = State::Ready;
+        let res = blynk.poll(&mut socket, Instant::ZERO, &mut Handler::default());
+        assert!(
+            matches!(res, Err(BlynkError::Io(err)) if err.kind() == io::ErrorKind::ConnectionAborted)
+        );
+        assert!(!blynk.is_ready());
+    }
+
+    #[test]
+    fn applies_error_policy_to_failed_hooks() {
+        let read = Message::new(MessageType::Hw, 1, None, None, vec!["vr", "2"]);
+        let mut config = Config {
+            token: "token".into(),
+            handler_error_policy: HandlerErrorPolicy::LogEvent("handler_failed".into()),
+            ..Default::default()
+        };
+        let mut blynk = SmoltcpClient::new(&config);
+        blynk.state = State::Ready;
+        blynk
+            .process(read.to_ref(), &mut Handler::default())
+            .unwrap();
+        assert_eq!(1, blynk.queue_depth());
+        let msg = blynk.outbox.front().unwrap();
+        assert_eq!(
+            proto::log_event(1, "handler_failed", "Handler failed: no sensor"),
+            msg
+        );
+
+        config.handler_error_policy = HandlerErrorPolicy::Reconnect;
+        let mut blynk = SmoltcpClient::new(&config);
+        blynk.state = State::Ready;
+        let res = blynk.process(read.to_ref(), &mut Handler::default());
+        assert!(matches!(res, Err(BlynkError::Handler(_))));
+    }
+}
@@ -0,0 +1,334 @@
+//! Long running soak test of the client with fault injection
+//!
+//! The client talks to the server (the in-memory `LocalServer` unless an
+//! address is given) through a proxy that randomly drops connections,
+//! delays the traffic and injects malformed frames. The proxy also checks
+//! that message ids sent by the client grow by one within a connection.
+//! Stats are reported every minute, together with memory and thread usage
+//! of the process, so leaks show up as a steady growth.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use blynk_io::*;
+use log::*;
+
+use crate::maybe_async;
+
+const REPORT_PERIOD: Duration = Duration::from_secs(60);
+/// Connections are dropped after a random time from this range
+const DISCONNECT_AFTER_S: (u64, u64) = (30, 300);
+/// Chance (per mille) of the fault, rolled for every server chunk or
+/// every second without traffic
+const SLOW_READ: u64 = 50;
+const MALFORMED: u64 = 10;
+const PIN_READ: u64 = 200;
+
+#[derive(Default)]
+pub struct Stats {
+    pub connections: AtomicUsize,
+    pub reconnects: AtomicUsize,
+    pub disconnects: AtomicUsize,
+    pub slow_reads: AtomicUsize,
+    pub malformed: AtomicUsize,
+    pub pin_reads: AtomicUsize,
+    pub messages: AtomicUsize,
+    pub msg_id_drift: AtomicUsize,
+}
+
+impl Stats {
+    fn report(&self, elapsed: Duration, queue_depth: usize) {
+        let get = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        println!(
+            "[soak {}s] connections {} reconnects {} | injected: disconnects {} slow reads {} \
+             malformed {} pin reads {} | messages {} msg id drift {} | queue {} rss {}kB threads {}",
+            elapsed.as_secs(),
+            get(&self.connections),
+            get(&self.reconnects),
+            get(&self.disconnects),
+            get(&self.slow_reads),
+            get(&self.malformed),
+            get(&self.pin_reads),
+            get(&self.messages),
+            get(&self.msg_id_drift),
+            queue_depth,
+            proc_status("VmRSS:").unwrap_or_default(),
+            proc_status("Threads:").unwrap_or_default(),
+        );
+    }
+}
+
+/// Xorshift generator, good enough to pick faults
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Rng {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn range(&mut self, (low, high): (u64, u64)) -> u64 {
+        low + self.next() % (high - low + 1)
+    }
+
+    /// Returns true with `chance` per mille probability
+    fn roll(&mut self, chance: u64) -> bool {
+        self.next() % 1000 < chance
+    }
+}
+
+struct SoakHandler {
+    stats: Arc<Stats>,
+}
+
+impl SoakHandler {
+    fn connected(&self) {
+        if self.stats.connections.fetch_add(1, Ordering::Relaxed) > 0 {
+            self.stats.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[maybe_async]
+impl Event for SoakHandler {
+    async fn handle_connect(&mut self, _client: &mut Client) -> Result<(), BlynkError> {
+        self.connected();
+        Ok(())
+    }
+
+    async fn handle_vpin_read(
+        &mut self,
+        client: &mut Client,
+        pin_num: VirtualPin,
+    ) -> Result<(), BlynkError> {
+        let value = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        client.virtual_write(pin_num, value).await
+    }
+}
+
+/// Runs the soak test for `duration` against `upstream` (or an in-memory
+/// server) and prints the stats
+pub fn run(duration: Duration, upstream: Option<SocketAddr>, token: String) -> io::Result<()> {
+    let upstream = match upstream {
+        Some(addr) => addr,
+        None => {
+            let server = LocalServer::bind("127.0.0.1:0")?;
+            let addr = server.local_addr()?;
+            thread::spawn(move || server.run());
+            addr
+        }
+    };
+
+    let stats = Arc::new(Stats::default());
+    let proxy = TcpListener::bind("127.0.0.1:0")?;
+    let proxy_addr = proxy.local_addr()?;
+    let proxy_stats = stats.clone();
+    thread::spawn(move || {
+        for client in proxy.incoming().flatten() {
+            let stats = proxy_stats.clone();
+            thread::spawn(move || {
+                if let Err(err) = relay(client, upstream, stats) {
+                    warn!("Proxy connection failed: {}", err);
+                }
+            });
+        }
+    });
+
+    println!(
+        "Soak testing against {} for {}s",
+        upstream,
+        duration.as_secs()
+    );
+    let config = Config {
+        token,
+        server: proxy_addr.ip().to_string(),
+        port: proxy_addr.port().into(),
+        ..Default::default()
+    };
+    let mut blynk = Blynk::with_config(config);
+    blynk.set_handler(SoakHandler {
+        stats: stats.clone(),
+    });
+
+    let start = Instant::now();
+    let mut last_report = start;
+    while start.elapsed() < duration {
+        #[cfg(feature = "async")]
+        smol::block_on(blynk.run());
+        #[cfg(not(feature = "async"))]
+        blynk.run();
+
+        if last_report.elapsed() >= REPORT_PERIOD {
+            stats.report(start.elapsed(), blynk.queue_depth());
+            last_report = Instant::now();
+        }
+    }
+    stats.report(start.elapsed(), blynk.queue_depth());
+    Ok(())
+}
+
+/// Forwards one client connection to the upstream server, injecting faults
+fn relay(client: TcpStream, upstream: SocketAddr, stats: Arc<Stats>) -> io::Result<()> {
+    let server = TcpStream::connect(upstream)?;
+    let mut rng = Rng::new();
+
+    // drop the connection at a random point in time
+    let (client_handle, server_handle) = (client.try_clone()?, server.try_clone()?);
+    let lifetime = Duration::from_secs(rng.range(DISCONNECT_AFTER_S));
+    let closed = Arc::new(AtomicBool::new(false));
+    let closer = closed.clone();
+    let disconnect_stats = stats.clone();
+    thread::spawn(move || {
+        thread::sleep(lifetime);
+        if !closer.load(Ordering::Relaxed) {
+            disconnect_stats.disconnects.fetch_add(1, Ordering::Relaxed);
+            let _ = client_handle.shutdown(Shutdown::Both);
+            let _ = server_handle.shutdown(Shutdown::Both);
+        }
+    });
+
+    let uplink_stats = stats.clone();
+    let (uplink_client, uplink_server) = (client.try_clone()?, server.try_clone()?);
+    let uplink = thread::spawn(move || forward_uplink(uplink_client, uplink_server, uplink_stats));
+    let res = forward_downlink(server, client, &stats, &mut rng);
+
+    let _ = uplink.join();
+    closed.store(true, Ordering::Relaxed);
+    res
+}
+
+/// Client to server traffic, checks that message ids grow by one
+fn forward_uplink(mut client: TcpStream, mut server: TcpStream, stats: Arc<Stats>) {
+    let mut buf = [0; 1024];
+    let mut frames = Vec::new();
+    let mut last_id: u16 = 0;
+    loop {
+        let size = match client.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(size) => size,
+        };
+        if server.write_all(&buf[..size]).is_err() {
+            break;
+        }
+
+        frames.extend_from_slice(&buf[..size]);
+        while frames.len() >= 5 {
+            let mtype = frames[0];
+            let id = u16::from_be_bytes([frames[1], frames[2]]);
+            let body = u16::from_be_bytes([frames[3], frames[4]]) as usize;
+            // responses have no body and reuse the server message id
+            let len = if mtype == MessageType::Rsp as u8 {
+                5
+            } else {
+                5 + body
+            };
+            if frames.len() < len {
+                break;
+            }
+            frames.drain(..len);
+
+            stats.messages.fetch_add(1, Ordering::Relaxed);
+            if mtype != MessageType::Rsp as u8 {
+                if id != last_id.wrapping_add(1) {
+                    warn!("Message id {} after {}", id, last_id);
+                    stats.msg_id_drift.fetch_add(1, Ordering::Relaxed);
+                }
+                last_id = id;
+            }
+        }
+    }
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = server.shutdown(Shutdown::Both);
+}
+
+/// Server to client traffic with slow reads, malformed frames and pin
+/// read requests mixed in
+fn forward_downlink(
+    mut server: TcpStream,
+    mut client: TcpStream,
+    stats: &Stats,
+    rng: &mut Rng,
+) -> io::Result<()> {
+    server.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut buf = [0; 1024];
+    let mut id: u16 = 0x8000;
+    loop {
+        let size = match server.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(size) => size,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                0
+            }
+            Err(_) => return Ok(()),
+        };
+
+        if rng.roll(SLOW_READ) {
+            stats.slow_reads.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(rng.range((100, 3000))));
+        }
+        if size > 0 && client.write_all(&buf[..size]).is_err() {
+            return Ok(());
+        }
+
+        id = id.wrapping_add(1).max(0x8000);
+        if rng.roll(MALFORMED) {
+            stats.malformed.fetch_add(1, Ordering::Relaxed);
+            client.write_all(&malformed(rng, id))?;
+        } else if rng.roll(PIN_READ) {
+            stats.pin_reads.fetch_add(1, Ordering::Relaxed);
+            let pin = rng.range((0, 9)).to_string();
+            client.write_all(&frame(MessageType::Hw as u8, id, &["vr", &pin].join("\0")))?;
+        }
+    }
+}
+
+fn frame(mtype: u8, id: u16, body: &str) -> Vec<u8> {
+    let mut data = vec![mtype];
+    data.extend(id.to_be_bytes());
+    data.extend((body.len() as u16).to_be_bytes());
+    data.extend(body.as_bytes());
+    data
+}
+
+/// Frames the client has to survive: zero message id, unknown message
+/// type and body that is not UTF-8
+fn malformed(rng: &mut Rng, id: u16) -> Vec<u8> {
+    match rng.range((0, 2)) {
+        0 => frame(MessageType::Hw as u8, 0, "vw\x001\x001"),
+        1 => frame(0xff, id, ""),
+        _ => {
+            let mut data = frame(MessageType::Hw as u8, id, "vw\x001\x00 ");
+            *data.last_mut().unwrap() = 0xff;
+            data
+        }
+    }
+}
+
+/// Reads a numeric field (e.g. `VmRSS:`) of `/proc/self/status`
+fn proc_status(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(field))?;
+    line[field.len()..].split_whitespace().next()?.parse().ok()
+}
@@ -0,0 +1,82 @@
+//! Halves of a connection, see `Client::split`
+
+use log::*;
+
+use crate::client::Protocol;
+use crate::message::Message;
+use crate::rt::{self, prelude::*, SplitStream, Stream};
+use crate::{maybe_async, proto, Result};
+
+/// Receiving half of a split `Client`
+pub struct ClientReader {
+    /// Bytes the client had buffered already are read first
+    reader: BufReader<Chain<Cursor<Vec<u8>>, SplitStream>>,
+}
+
+#[maybe_async]
+impl ClientReader {
+    /// Next message. Blocking reads fail with `BlynkError::EmptyBuffer` if
+    /// none started to arrive within the read timeout, async ones wait
+    /// until one arrives
+    pub async fn read(&mut self) -> Result<Message> {
+        let mut frame = Vec::new();
+        self.read_frame(&mut frame).await?;
+        let (msg, _) = proto::decode(&frame)?;
+        Ok(msg)
+    }
+
+    /// Reads the next whole message into `frame` without parsing it
+    pub async fn read_frame(&mut self, frame: &mut Vec<u8>) -> Result<()> {
+        rt::read_frame(&mut self.reader, frame).await
+    }
+}
+
+/// Sending half of a split `Client`, messages are written right away
+pub struct ClientWriter {
+    msg_id: u16,
+    reader: Option<BufReader<SplitStream>>,
+}
+
+impl Protocol for ClientWriter {
+    type T = SplitStream;
+
+    fn set_reader(&mut self, reader: BufReader<SplitStream>) {
+        self.reader = Some(reader);
+    }
+
+    fn reader(&mut self) -> Option<&mut BufReader<SplitStream>> {
+        self.reader.as_mut()
+    }
+
+    fn msg_id(&mut self) -> u16 {
+        self.msg_id = proto::next_id(self.msg_id);
+        self.msg_id
+    }
+
+    /// Shuts the connection down, the reader sees its end
+    fn disconnect(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            if let Err(err) = reader.into_inner().shutdown() {
+                debug!("shutdown call failed, with err {}", err);
+            }
+        }
+    }
+}
+
+/// Splits the stream of a client, `buffered` are the bytes its reader
+/// received but didn't consume yet
+pub(crate) fn split(
+    stream: Stream,
+    buffered: Vec<u8>,
+    msg_id: u16,
+) -> Result<(ClientReader, ClientWriter)> {
+    let (read, write) = SplitStream::pair(stream)?;
+    let reader = ClientReader {
+        reader: BufReader::new(Cursor::new(buffered).chain(read)),
+    };
+    let writer = ClientWriter {
+        msg_id,
+        reader: Some(BufReader::new(write)),
+    };
+    Ok((reader, writer))
+}
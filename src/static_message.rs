@@ -0,0 +1,178 @@
+//! Allocation free messages for tiny targets
+//!
+//! `StaticMessage` mirrors `Message` but keeps the body in `heapless`
+//! collections with capacities fixed at compile time: up to `ARGS`
+//! arguments of at most `LEN` bytes each. Serializing and deserializing
+//! it never touches the heap, messages that don't fit are rejected with
+//! `BlynkError::Capacity`.
+
+use std::convert::TryFrom;
+
+use heapless::{String, Vec};
+
+use crate::message::{MessageType, ProtocolHeader, ProtocolStatus};
+use crate::{BlynkError, Result};
+
+#[derive(Debug)]
+pub struct StaticMessage<const ARGS: usize, const LEN: usize> {
+    pub mtype: MessageType,
+    pub id: u16,
+    pub status: Option<ProtocolStatus>,
+    pub body: Vec<String<LEN>, ARGS>,
+}
+
+impl<const ARGS: usize, const LEN: usize> StaticMessage<ARGS, LEN> {
+    pub fn new(mtype: MessageType, id: u16, body: &[&str]) -> Result<StaticMessage<ARGS, LEN>> {
+        let mut msg = StaticMessage {
+            mtype,
+            id,
+            status: None,
+            body: Vec::new(),
+        };
+        for arg in body {
+            msg.push(arg)?;
+        }
+        Ok(msg)
+    }
+
+    /// Response carrying only the status
+    pub fn response(id: u16, status: ProtocolStatus) -> StaticMessage<ARGS, LEN> {
+        StaticMessage {
+            mtype: MessageType::Rsp,
+            id,
+            status: Some(status),
+            body: Vec::new(),
+        }
+    }
+
+    /// Appends an argument to the body
+    pub fn push(&mut self, arg: &str) -> Result<()> {
+        let arg = String::try_from(arg).map_err(|_| BlynkError::Capacity)?;
+        self.body.push(arg).map_err(|_| BlynkError::Capacity)
+    }
+
+    /// Extracts pin number and value if it's a `vw` hardware message
+    pub fn virtual_write_value(&self) -> Option<(u8, &str)> {
+        match self.mtype {
+            MessageType::Hw | MessageType::Bridge
+                if self.body.len() >= 3 && self.body[0] == "vw" =>
+            {
+                let pin = self.body[1].parse::<u8>().ok()?;
+                Some((pin, &self.body[2]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the message into `buf`, returns number of bytes written
+    pub fn serialize_into(&self, buf: &mut [u8]) -> Result<usize> {
+        let body_len = match self.status {
+            Some(_) => 0,
+            None => {
+                let args: usize = self.body.iter().map(|arg| arg.len()).sum();
+                args + self.body.len().saturating_sub(1)
+            }
+        };
+        let size = ProtocolHeader::SIZE + body_len;
+        if buf.len() < size {
+            return Err(BlynkError::Capacity);
+        }
+
+        let h_data = match self.status {
+            Some(status) => status.into(),
+            None => body_len as u16,
+        };
+        ProtocolHeader::write_to((self.mtype as u8, self.id, h_data), &mut &mut buf[..])?;
+
+        let mut pos = ProtocolHeader::SIZE;
+        for (i, arg) in self.body.iter().enumerate() {
+            if i > 0 {
+                buf[pos] = 0;
+                pos += 1;
+            }
+            buf[pos..pos + arg.len()].copy_from_slice(arg.as_bytes());
+            pos += arg.len();
+        }
+        Ok(size)
+    }
+
+    /// Parses the message, returns it together with number of bytes consumed
+    pub fn deserialize(mut data: &[u8]) -> Result<(StaticMessage<ARGS, LEN>, usize)> {
+        let (mtype_raw, id, h_data) = ProtocolHeader::read_from(&mut data)?;
+        if id == 0 {
+            return Err(BlynkError::InvalidMessageId);
+        }
+        let mtype = MessageType::try_from(mtype_raw)
+            .map_err(|_| BlynkError::UnknownMessageType(mtype_raw))?;
+
+        if let MessageType::Rsp | MessageType::Ping = mtype {
+            let status = ProtocolStatus::from(h_data);
+            return Ok((StaticMessage::response(id, status), ProtocolHeader::SIZE));
+        }
+
+        let body = data
+            .get(..h_data.into())
+            .ok_or(BlynkError::InvalidMessageBody)?;
+        let body = std::str::from_utf8(body).map_err(|_| BlynkError::InvalidMessageBody)?;
+        let mut msg = StaticMessage {
+            mtype,
+            id,
+            status: None,
+            body: Vec::new(),
+        };
+        if !body.is_empty() {
+            for arg in body.split('\0') {
+                msg.push(arg)?;
+            }
+        }
+        Ok((msg, ProtocolHeader::SIZE + body.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    type Msg = StaticMessage<4, 16>;
+
+    #[test]
+    fn serialize_like_message() {
+        let msg = Msg::new(MessageType::Hw, 32, &["vw", "4", "21.5"]).unwrap();
+        let mut buf = [0; 64];
+        let size = msg.serialize_into(&mut buf).unwrap();
+
+        let expected = Message::new(MessageType::Hw, 32, None, None, vec!["vw", "4", "21.5"]);
+        assert_eq!(expected.serialize(), &buf[..size]);
+        assert!(matches!(
+            msg.serialize_into(&mut buf[..8]),
+            Err(BlynkError::Capacity)
+        ));
+    }
+
+    #[test]
+    fn deserialize_without_allocation() {
+        let mut data =
+            Message::new(MessageType::Hw, 7, None, None, vec!["vw", "1", "on"]).serialize();
+        data.extend([0; 3]);
+        let (msg, size) = Msg::deserialize(&data).unwrap();
+        assert_eq!(12, size);
+        assert_eq!(7, msg.id);
+        assert_eq!(["vw", "1", "on"], msg.body.as_slice());
+        assert_eq!(Some((1, "on")), msg.virtual_write_value());
+
+        let (rsp, size) = Msg::deserialize(&[0, 0, 3, 0, 200]).unwrap();
+        assert_eq!(5, size);
+        assert!(matches!(rsp.status, Some(ProtocolStatus::StatusOk)));
+    }
+
+    #[test]
+    fn rejects_messages_over_capacity() {
+        let err = Msg::new(MessageType::Hw, 1, &["vw", "1", "2", "3", "4"]).unwrap_err();
+        assert!(matches!(err, BlynkError::Capacity));
+
+        let long = "x".repeat(17);
+        let data = Message::new(MessageType::Hw, 1, None, None, vec!["vw", &long]).serialize();
+        assert!(matches!(Msg::deserialize(&data), Err(BlynkError::Capacity)));
+    }
+}
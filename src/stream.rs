@@ -0,0 +1,524 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{conf, net, ConfigSource, Result};
+
+/// Connection to Blynk servers, either plain TCP or TLS wrapped
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+    #[cfg(feature = "native-tls")]
+    NativeTls(Box<native_tls::TlsStream<TcpStream>>),
+    #[cfg(feature = "embedded-tls")]
+    EmbeddedTls(Box<crate::embedded_tls::EmbeddedTlsStream>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<crate::websocket::WebSocket<Stream>>),
+    #[cfg(feature = "mqtt")]
+    Mqtt(Box<crate::mqtt::Mqtt<Stream>>),
+}
+
+impl Stream {
+    /// Wraps connected socket according to the `Config`, performing
+    /// TLS handshake if `tls` is enabled, WebSocket upgrade if
+    /// `websocket` path is set and speaking MQTT if `mqtt` is enabled
+    pub fn new(sock: TcpStream, config: &impl ConfigSource) -> Result<Stream> {
+        let stream = Stream::secure(sock, config)?;
+        let stream = Stream::upgrade(stream, config)?;
+        if !config.mqtt() {
+            return Ok(stream);
+        }
+
+        #[cfg(feature = "mqtt")]
+        {
+            let bridge = crate::mqtt::Bridge::for_config(config);
+            Ok(Stream::Mqtt(Box::new(crate::mqtt::Mqtt::new(
+                stream, bridge,
+            ))))
+        }
+
+        #[cfg(not(feature = "mqtt"))]
+        Err(io::Error::new(io::ErrorKind::Unsupported, "built without `mqtt` feature").into())
+    }
+
+    fn upgrade(stream: Stream, config: &impl ConfigSource) -> Result<Stream> {
+        match config.websocket() {
+            None => Ok(stream),
+            #[cfg(feature = "websocket")]
+            Some(path) => {
+                let ws = crate::websocket::WebSocket::connect(stream, config.server(), path)?;
+                Ok(Stream::WebSocket(Box::new(ws)))
+            }
+            #[cfg(not(feature = "websocket"))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "built without `websocket` feature",
+            )
+            .into()),
+        }
+    }
+
+    /// When several TLS features are turned on the backend is picked
+    /// in order: `tls` (rustls), `native-tls`, `embedded-tls`
+    fn secure(sock: TcpStream, config: &impl ConfigSource) -> Result<Stream> {
+        if !config.tls() {
+            return Ok(Stream::Tcp(sock));
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            let mut sock = sock;
+            let mut conn = rustls::ClientConnection::new(
+                crate::tls::client_config(config)?,
+                crate::tls::server_name(config)?,
+            )
+            .map_err(|err| crate::BlynkError::Tls(err.to_string()))?;
+            while conn.is_handshaking() {
+                conn.complete_io(&mut sock)
+                    .map_err(crate::tls::handshake_error)?;
+            }
+            Ok(Stream::Tls(Box::new(rustls::StreamOwned::new(conn, sock))))
+        }
+
+        #[cfg(all(feature = "native-tls", not(feature = "tls")))]
+        {
+            let connector = crate::system_tls::builder(config)?
+                .build()
+                .map_err(|err| crate::BlynkError::Tls(err.to_string()))?;
+            let stream = connector
+                .connect(config.server(), sock)
+                .map_err(|err| crate::BlynkError::Tls(err.to_string()))?;
+            crate::system_tls::verify_peer(config, stream.peer_certificate().ok().flatten())?;
+            Ok(Stream::NativeTls(Box::new(stream)))
+        }
+
+        #[cfg(all(
+            feature = "embedded-tls",
+            not(any(feature = "tls", feature = "native-tls"))
+        ))]
+        {
+            let stream = crate::embedded_tls::EmbeddedTlsStream::new(sock, config)?;
+            Ok(Stream::EmbeddedTls(Box::new(stream)))
+        }
+
+        #[cfg(not(any(feature = "tls", feature = "native-tls", feature = "embedded-tls")))]
+        Err(crate::BlynkError::Tls("built without `tls` feature".into()))
+    }
+
+    /// Returns the underlying socket
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Tcp(sock) => sock,
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.get_ref(),
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => tls.get_ref(),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => ws.get_ref().socket(),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => mqtt.get_ref().socket(),
+            #[cfg(feature = "embedded-tls")]
+            Stream::EmbeddedTls(tls) => tls.get_ref(),
+        }
+    }
+
+    pub fn set_read_timeout(&self, duration: Option<Duration>) -> io::Result<()> {
+        self.socket().set_read_timeout(duration)
+    }
+
+    pub fn set_write_timeout(&self, duration: Option<Duration>) -> io::Result<()> {
+        self.socket().set_write_timeout(duration)
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        #[cfg(feature = "websocket")]
+        if let Stream::WebSocket(ws) = self {
+            return ws.get_mut().shutdown(how);
+        }
+        #[cfg(feature = "mqtt")]
+        if let Stream::Mqtt(mqtt) = self {
+            return mqtt.get_mut().shutdown(how);
+        }
+        // close notify is best effort, the peer may be gone already and
+        // the socket has to be shut down anyway
+        #[cfg(feature = "tls")]
+        if let Stream::Tls(tls) = self {
+            tls.conn.send_close_notify();
+            let _ = tls.flush();
+        }
+        #[cfg(feature = "native-tls")]
+        if let Stream::NativeTls(tls) = self {
+            let _ = tls.shutdown();
+        }
+        self.socket().shutdown(how)
+    }
+}
+
+impl Stream {
+    /// Connects to one of `addrs` and opens the tunnel to the server if
+    /// they are the proxy's
+    pub(crate) fn connect<S: ConfigSource>(addrs: Vec<SocketAddr>, config: &S) -> Result<Stream> {
+        let mut sock = net::connect_any(addrs, conf::SOCK_TIMEOUT)?;
+        sock.set_write_timeout(Some(conf::SOCK_TIMEOUT))?;
+        sock.set_read_timeout(Some(conf::SOCK_MAX_TIMEOUT))?;
+        if let Some(proxy) = config.proxy() {
+            crate::proxy::tunnel(&mut sock, proxy, config.server(), config.port())?;
+        }
+        Stream::new(sock, config)
+    }
+
+    /// Writes a part of `buf`, `None` if the socket is not ready for it
+    /// (write timeout)
+    pub(crate) fn try_write(&mut self, buf: &[u8]) -> Option<io::Result<usize>> {
+        match self.write(buf) {
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => None,
+            res => Some(res),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(sock: TcpStream) -> Stream {
+        Stream::Tcp(sock)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(sock) => sock.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.read(buf),
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => tls.read(buf),
+            #[cfg(feature = "embedded-tls")]
+            Stream::EmbeddedTls(tls) => tls.read(buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => ws.read(buf),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => mqtt.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(sock) => sock.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.write(buf),
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => tls.write(buf),
+            #[cfg(feature = "embedded-tls")]
+            Stream::EmbeddedTls(tls) => tls.write(buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => ws.write(buf),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => mqtt.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(sock) => sock.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.flush(),
+            #[cfg(feature = "native-tls")]
+            Stream::NativeTls(tls) => tls.flush(),
+            #[cfg(feature = "embedded-tls")]
+            Stream::EmbeddedTls(tls) => tls.flush(),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ws) => ws.flush(),
+            #[cfg(feature = "mqtt")]
+            Stream::Mqtt(mqtt) => mqtt.flush(),
+        }
+    }
+}
+
+enum Shared {
+    /// Plain TCP is split by cloning the socket, the halves don't wait
+    /// for each other
+    Socket(TcpStream),
+    /// TLS and the other wrapped streams keep a single state, see `Locked`
+    Locked(Arc<Locked>),
+}
+
+/// Wrapped stream shared by the halves. Its socket read timeout is cut to
+/// `conf::SPLIT_READ_SLICE`, so an idle reader gives the lock up that
+/// often and steps aside for the waiting writers, while the reads keep
+/// waiting as long as the read timeout the stream had before the split
+struct Locked {
+    stream: Mutex<Stream>,
+    read_timeout: Option<Duration>,
+    /// Writers waiting for the lock
+    writers: AtomicUsize,
+}
+
+impl Locked {
+    fn lock(&self) -> MutexGuard<'_, Stream> {
+        self.stream.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Lock for writing, taken at the latest when the current read slice
+    /// ends
+    fn lock_for_write(&self) -> MutexGuard<'_, Stream> {
+        self.writers.fetch_add(1, Ordering::SeqCst);
+        let guard = self.lock();
+        self.writers.fetch_sub(1, Ordering::SeqCst);
+        guard
+    }
+
+    /// Lock for a read slice, taken once the waiting writers are done
+    fn lock_for_read(&self) -> MutexGuard<'_, Stream> {
+        while self.writers.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+        self.lock()
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let res = self.lock_for_read().read(buf);
+            match res {
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(err);
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Stream of a `ClientReader` or a `ClientWriter`
+pub struct SplitStream(Shared);
+
+impl SplitStream {
+    pub(crate) fn pair(stream: Stream) -> io::Result<(SplitStream, SplitStream)> {
+        match stream {
+            Stream::Tcp(sock) => {
+                let clone = sock.try_clone()?;
+                Ok((
+                    SplitStream(Shared::Socket(sock)),
+                    SplitStream(Shared::Socket(clone)),
+                ))
+            }
+            #[allow(unreachable_patterns)]
+            stream => {
+                let read_timeout = stream.socket().read_timeout()?;
+                stream.set_read_timeout(Some(conf::SPLIT_READ_SLICE))?;
+                let locked = Arc::new(Locked {
+                    stream: Mutex::new(stream),
+                    read_timeout,
+                    writers: AtomicUsize::new(0),
+                });
+                Ok((
+                    SplitStream(Shared::Locked(locked.clone())),
+                    SplitStream(Shared::Locked(locked)),
+                ))
+            }
+        }
+    }
+
+    pub(crate) fn shutdown(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            Shared::Socket(sock) => sock.shutdown(Shutdown::Both),
+            Shared::Locked(locked) => locked.lock_for_write().shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for SplitStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Shared::Socket(sock) => sock.read(buf),
+            Shared::Locked(locked) => locked.read(buf),
+        }
+    }
+}
+
+impl Write for SplitStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Shared::Socket(sock) => sock.write(buf),
+            Shared::Locked(locked) => locked.lock_for_write().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            Shared::Socket(sock) => sock.flush(),
+            Shared::Locked(locked) => locked.lock_for_write().flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::net::TcpListener;
+
+    fn connect() -> (TcpListener, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sock = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (listener, sock)
+    }
+
+    #[test]
+    fn plain_tcp_when_tls_disabled() {
+        let (_listener, sock) = connect();
+        let stream = Stream::new(sock, &Config::default()).unwrap();
+        assert!(matches!(stream, Stream::Tcp(_)));
+    }
+
+    #[test]
+    fn tls_handshake_errors_on_plain_server() {
+        let (listener, sock) = connect();
+        // server closes the connection right away
+        drop(listener);
+        let config = Config {
+            server: "localhost".into(),
+            tls: true,
+            ..Default::default()
+        };
+        assert!(Stream::new(sock, &config).is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_server() -> (std::thread::JoinHandle<()>, TcpStream) {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+        use std::sync::Arc;
+
+        let certs = vec![CertificateDer::from_pem_slice(CERT).unwrap()];
+        let key = PrivateKeyDer::from_pem_slice(include_bytes!("testdata/localhost.key")).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+
+        let (listener, sock) = connect();
+        let server = std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut conn = rustls::ServerConnection::new(Arc::new(server_config)).unwrap();
+            while conn.is_handshaking() {
+                if conn.complete_io(&mut sock).is_err() {
+                    return;
+                }
+            }
+        });
+        (server, sock)
+    }
+
+    #[cfg(feature = "tls")]
+    const CERT: &[u8] = include_bytes!("testdata/localhost.crt");
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn pinned_certificate_accepted() {
+        let (server, sock) = tls_server();
+        let config = Config {
+            server: "localhost".into(),
+            tls: true,
+            cert_pin: Some(
+                "52:51:F1:BD:9F:E7:DE:50:93:92:32:05:C8:FE:00:54:\
+                 6C:FB:9E:82:78:95:D5:94:84:B7:EC:4D:7F:B9:99:D7"
+                    .into(),
+            ),
+            ..Default::default()
+        };
+        assert!(matches!(Stream::new(sock, &config), Ok(Stream::Tls(_))));
+        server.join().unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn pin_mismatch_is_verification_error() {
+        let (server, sock) = tls_server();
+        let config = Config {
+            server: "localhost".into(),
+            tls: true,
+            cert_pin: Some("00".repeat(32)),
+            ..Default::default()
+        };
+        let err = Stream::new(sock, &config).err().unwrap();
+        assert!(matches!(err, crate::BlynkError::TlsVerification(_)));
+        server.join().unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn custom_ca_trusted() {
+        let (server, sock) = tls_server();
+        let config = Config {
+            server: "localhost".into(),
+            tls: true,
+            ca_cert: Some(CERT.to_vec()),
+            ..Default::default()
+        };
+        assert!(Stream::new(sock, &config).is_ok());
+        server.join().unwrap();
+
+        // the same self-signed certificate isn't trusted by default roots
+        let (server, sock) = tls_server();
+        let config = Config {
+            ca_cert: None,
+            ..config
+        };
+        let err = Stream::new(sock, &config).err().unwrap();
+        assert!(matches!(err, crate::BlynkError::TlsVerification(_)));
+        server.join().unwrap();
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn split_writer_passes_idle_reader() {
+        use std::io::BufRead;
+
+        let (listener, sock) = connect();
+        let server = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut reader = io::BufReader::new(sock.try_clone().unwrap());
+            let mut key = String::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(value) = line.strip_prefix("Sec-WebSocket-Key: ") {
+                    key = value.trim().to_string();
+                }
+                line.clear();
+            }
+            write!(
+                &sock,
+                "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                crate::websocket::accept_key(&key)
+            )
+            .unwrap();
+            // the frame written while the client is reading
+            let mut frame = [0; 8];
+            reader.read_exact(&mut frame).unwrap();
+            sock.shutdown(Shutdown::Both).unwrap();
+        });
+
+        sock.set_read_timeout(Some(conf::SOCK_MAX_TIMEOUT)).unwrap();
+        let ws = crate::websocket::WebSocket::connect(Stream::Tcp(sock), "localhost", "/").unwrap();
+        let (mut read, mut write) = SplitStream::pair(Stream::WebSocket(Box::new(ws))).unwrap();
+        let reader = std::thread::spawn(move || read.read(&mut [0; 8]));
+        std::thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        write.write_all(b"hi").unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        server.join().unwrap();
+        reader.join().unwrap().ok();
+    }
+}
@@ -0,0 +1,139 @@
+//! Writing from other threads while `Blynk::run` owns the socket
+//!
+//! `SyncClient` is shared by reference (or cloned) between OS threads,
+//! its methods take `&self` and only put the message into a bounded queue
+//! behind a mutex held for the push. The messages get their ids and are
+//! sent by the next `run`, in the order they were queued. Unlike
+//! `BlynkSender` the queue doesn't grow past its capacity, a producer
+//! outpacing the connection gets `BlynkError::Capacity` instead.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::message::MessageType;
+use crate::{maybe_async, proto, BlynkError, IntoBlynkValue, Protocol, Result, VirtualPin};
+
+/// Type and body of a message waiting for its id
+type Queued = (MessageType, Vec<String>);
+
+struct Queue {
+    messages: VecDeque<Queued>,
+    capacity: usize,
+}
+
+/// Thread-safe writer feeding `Blynk::run`, see `Blynk::sync_client`
+#[derive(Clone)]
+pub struct SyncClient {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl SyncClient {
+    pub(crate) fn new(capacity: usize) -> SyncClient {
+        let queue = Queue {
+            messages: VecDeque::new(),
+            capacity,
+        };
+        SyncClient {
+            queue: Arc::new(Mutex::new(queue)),
+        }
+    }
+
+    pub fn virtual_write<P, V>(&self, v_pin: P, val: V) -> Result<()>
+    where
+        P: Into<VirtualPin>,
+        V: IntoBlynkValue,
+    {
+        self.virtual_write_multi(v_pin, &[&val.into_blynk_value()])
+    }
+
+    pub fn virtual_write_multi<P: Into<VirtualPin>>(&self, v_pin: P, vals: &[&str]) -> Result<()> {
+        let mut body = vec!["vw".to_string(), v_pin.into().number().to_string()];
+        body.extend(vals.iter().map(|val| val.to_string()));
+        self.push(MessageType::Hw, body)
+    }
+
+    pub fn set_property<P: Into<VirtualPin>>(&self, pin: P, prop: &str, val: &str) -> Result<()> {
+        let body = vec![
+            pin.into().number().to_string(),
+            prop.to_string(),
+            val.to_string(),
+        ];
+        self.push(MessageType::Property, body)
+    }
+
+    pub fn notify(&self, msg: &str) -> Result<()> {
+        self.push(MessageType::Notify, vec![msg.to_string()])
+    }
+
+    pub fn log_event(&self, code: &str, description: &str) -> Result<()> {
+        let mut body = vec![code.to_string()];
+        if !description.is_empty() {
+            body.push(description.to_string());
+        }
+        self.push(MessageType::EventLog, body)
+    }
+
+    /// Number of messages waiting for the next `run`
+    pub fn pending(&self) -> usize {
+        self.lock().messages.len()
+    }
+
+    fn push(&self, mtype: MessageType, body: Vec<String>) -> Result<()> {
+        let mut queue = self.lock();
+        if queue.messages.len() >= queue.capacity {
+            return Err(BlynkError::Capacity);
+        }
+        queue.messages.push_back((mtype, body));
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // a producer panicking mid-push leaves the queue consistent
+        self.queue.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Takes the queued messages, the lock isn't held while sending
+    fn take(&self) -> VecDeque<Queued> {
+        std::mem::take(&mut self.lock().messages)
+    }
+}
+
+#[maybe_async]
+impl SyncClient {
+    /// Sends the queued messages, the first failure is returned after the
+    /// rest were tried
+    pub(crate) async fn drain<P: Protocol>(&self, client: &mut P) -> Result<()> {
+        let mut res = Ok(());
+        for (mtype, body) in self.take() {
+            let body = body.iter().map(String::as_str).collect();
+            let msg = proto::command(mtype, client.msg_id(), body);
+            res = res.and(client.send(msg).await);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn queues_messages_from_threads_up_to_capacity() {
+        let client = SyncClient::new(3);
+        thread::scope(|scope| {
+            scope.spawn(|| client.virtual_write(1, "20.5").unwrap());
+        });
+        client.set_property(1, "color", "#FF0000").unwrap();
+        client.log_event("overheat", "").unwrap();
+        assert!(matches!(client.notify("full"), Err(BlynkError::Capacity)));
+        assert_eq!(3, client.pending());
+
+        let queued: Vec<Queued> = client.take().into();
+        assert!(matches!(queued[0].0, MessageType::Hw));
+        assert_eq!(vec!["vw", "1", "20.5"], queued[0].1);
+        assert!(matches!(queued[2].0, MessageType::EventLog));
+        assert_eq!(vec!["overheat"], queued[2].1);
+        assert_eq!(0, client.pending());
+    }
+}
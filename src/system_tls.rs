@@ -0,0 +1,60 @@
+//! native-tls configuration shared by blocking and async clients
+
+use native_tls::{Certificate, TlsConnector, TlsConnectorBuilder};
+
+use crate::{pinning, BlynkError, ConfigSource, Result};
+
+/// Configures connector trusting the system store, or only `Config::ca_cert`
+/// if provided. With `Config::cert_pin` set the certificate chain is not
+/// validated, `verify_peer` has to be called after the handshake
+pub fn builder(config: &impl ConfigSource) -> Result<TlsConnectorBuilder> {
+    let mut builder = TlsConnector::builder();
+
+    if let Some(pem) = config.ca_cert() {
+        let certs = Certificate::stack_from_pem(pem)
+            .map_err(|err| BlynkError::Tls(format!("invalid CA bundle: {}", err)))?;
+        if certs.is_empty() {
+            return Err(BlynkError::Tls("CA bundle has no certificates".into()));
+        }
+        for cert in certs {
+            builder.add_root_certificate(cert);
+        }
+        builder.disable_built_in_roots(true);
+    }
+    if config.cert_pin().is_some() {
+        builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder)
+}
+
+/// Checks the certificate presented by the server against `Config::cert_pin`
+pub fn verify_peer(config: &impl ConfigSource, cert: Option<Certificate>) -> Result<()> {
+    let pin = match config.cert_pin() {
+        Some(pin) => pinning::parse(pin)?,
+        None => return Ok(()),
+    };
+
+    let cert = cert.ok_or_else(|| BlynkError::TlsVerification("no server certificate".into()))?;
+    let der = cert
+        .to_der()
+        .map_err(|err| BlynkError::Tls(err.to_string()))?;
+    pinning::verify(&pin, &der)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn missing_certificate_fails_pinning() {
+        let config = Config {
+            cert_pin: Some("ab".repeat(32)),
+            ..Default::default()
+        };
+        let err = verify_peer(&config, None).unwrap_err();
+        assert!(matches!(err, BlynkError::TlsVerification(_)));
+
+        assert!(verify_peer(&Config::default(), None).is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+//! Test fixtures for simulating server outages deterministically
+//!
+//! `FakeClock` replaces wall-clock time inside `Blynk` so tests can jump
+//! minutes ahead without waiting, while `FakeServer` is a scripted local
+//! server speaking just enough of the protocol (login, heartbeat, ping)
+//! that can be told to go silent and come back later.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::message::{MessageType, ProtocolHeader, ProtocolStatus};
+use crate::{maybe_async, Config};
+
+/// Clock that only moves forward when told to (or when something sleeps on it)
+#[derive(Clone)]
+pub struct FakeClock {
+    base: Instant,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl FakeClock {
+    pub fn new() -> FakeClock {
+        FakeClock {
+            base: Instant::now(),
+            offset: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration;
+    }
+}
+
+#[maybe_async]
+impl crate::Clock for FakeClock {
+    fn now(&self) -> Instant {
+        FakeClock::now(self)
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Client side of a connection accepted by a test listener
+pub fn stream(sock: TcpStream) -> crate::Stream {
+    #[cfg(not(feature = "async"))]
+    return sock.into();
+    #[cfg(feature = "async")]
+    return smol::Async::new(sock).unwrap().into();
+}
+
+#[derive(Default)]
+struct ServerState {
+    silent: AtomicBool,
+    connections: AtomicUsize,
+    pings: AtomicUsize,
+    syncs: AtomicUsize,
+}
+
+/// Local server accepting any token that can be scripted to stop responding
+pub struct FakeServer {
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+}
+
+impl FakeServer {
+    pub fn start() -> FakeServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(ServerState::default());
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                server_state.connections.fetch_add(1, Ordering::SeqCst);
+                let state = server_state.clone();
+                thread::spawn(move || serve(stream, state));
+            }
+        });
+
+        FakeServer { addr, state }
+    }
+
+    /// Returns the configuration pointing `Blynk` to this server
+    pub fn config(&self) -> Config {
+        Config {
+            token: "token".into(),
+            server: self.addr.ip().to_string(),
+            port: self.addr.port().into(),
+            ..Default::default()
+        }
+    }
+
+    /// When silent, the server keeps the connections open but never replies
+    pub fn set_silent(&self, silent: bool) {
+        self.state.silent.store(silent, Ordering::SeqCst);
+    }
+
+    /// Number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    /// Number of pings received so far
+    pub fn pings(&self) -> usize {
+        self.state.pings.load(Ordering::SeqCst)
+    }
+
+    /// Number of sync requests received so far
+    pub fn syncs(&self) -> usize {
+        self.state.syncs.load(Ordering::SeqCst)
+    }
+}
+
+/// Waits (in real time) until the condition is met, e.g. until the server
+/// thread processed the message that has just been sent
+pub fn wait_for<F: Fn() -> bool>(condition: F) {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn serve(mut stream: TcpStream, state: Arc<ServerState>) {
+    loop {
+        let (mtype, id, size) = match ProtocolHeader::read_from(&mut stream) {
+            Ok(header) => header,
+            Err(_) => return,
+        };
+        let mut body = vec![0; size.into()];
+        if stream.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        if mtype == MessageType::Ping as u8 {
+            state.pings.fetch_add(1, Ordering::SeqCst);
+        }
+        if mtype == MessageType::HwSync as u8 {
+            state.syncs.fetch_add(1, Ordering::SeqCst);
+        }
+        if state.silent.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        let reply = match mtype {
+            m if m == MessageType::Login as u8
+                || m == MessageType::Internal as u8
+                || m == MessageType::Ping as u8 =>
+            {
+                (
+                    MessageType::Rsp as u8,
+                    id,
+                    u16::from(ProtocolStatus::StatusOk),
+                )
+            }
+            _ => continue,
+        };
+
+        let mut buf = Vec::new();
+        ProtocolHeader::write_to(reply, &mut buf).unwrap();
+        if stream.write_all(&buf).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! rustls based TLS configuration shared by blocking and async clients
+
+use std::io;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use crate::pinning::{self, Fingerprint};
+use crate::{BlynkError, ConfigSource, Result};
+
+/// Builds rustls client configuration. The server is verified against
+/// `Config::cert_pin` if set, otherwise against `Config::ca_cert` or
+/// the bundled webpki roots
+pub fn client_config(config: &impl ConfigSource) -> Result<Arc<ClientConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| BlynkError::Tls(err.to_string()))?;
+
+    let config = match (config.cert_pin(), config.ca_cert()) {
+        (Some(pin), _) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinVerifier {
+                pin: pinning::parse(pin)?,
+                provider,
+            })),
+        (None, Some(pem)) => builder.with_root_certificates(root_store(pem)?),
+        (None, None) => builder.with_root_certificates(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        }),
+    };
+    Ok(Arc::new(config.with_no_client_auth()))
+}
+
+fn root_store(pem: &[u8]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(pem) {
+        let cert = cert.map_err(|err| BlynkError::Tls(format!("invalid CA bundle: {}", err)))?;
+        roots
+            .add(cert)
+            .map_err(|err| BlynkError::Tls(format!("invalid CA certificate: {}", err)))?;
+    }
+    if roots.is_empty() {
+        return Err(BlynkError::Tls("CA bundle has no certificates".into()));
+    }
+    Ok(roots)
+}
+
+/// Converts failed handshake into error, rejected certificates are
+/// reported as `BlynkError::TlsVerification`
+pub fn handshake_error(err: io::Error) -> BlynkError {
+    let rejected = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .filter(|inner| matches!(inner, rustls::Error::InvalidCertificate(_)))
+        .map(|inner| inner.to_string());
+    match rejected {
+        Some(reason) => BlynkError::TlsVerification(reason),
+        None => BlynkError::Io(err),
+    }
+}
+
+/// Accepts only the certificate matching the pinned fingerprint, the
+/// chain is not validated so it works with self-signed certificates
+#[derive(Debug)]
+struct PinVerifier {
+    pin: Fingerprint,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        pinning::verify(&self.pin, end_entity)
+            .map(|_| ServerCertVerified::assertion())
+            .map_err(|_| {
+                rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+            })
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Returns the name the server certificate is verified against
+pub fn server_name(config: &impl ConfigSource) -> Result<ServerName<'static>> {
+    ServerName::try_from(config.server().to_string())
+        .map_err(|_| BlynkError::Tls(format!("invalid server name {}", config.server())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn server_name_taken_from_config() {
+        let config = Config {
+            server: "blynk.cloud".into(),
+            ..Default::default()
+        };
+        assert!(server_name(&config).is_ok());
+
+        let config = Config {
+            server: "not a host".into(),
+            ..Default::default()
+        };
+        let err = server_name(&config).unwrap_err();
+        assert_eq!("TLS error: invalid server name not a host", err.to_string());
+    }
+
+    #[test]
+    fn client_config_builds() {
+        assert!(client_config(&Config::default()).is_ok());
+
+        let config = Config {
+            cert_pin: Some("ab".repeat(32)),
+            ..Default::default()
+        };
+        assert!(client_config(&config).is_ok());
+    }
+
+    #[test]
+    fn empty_ca_bundle_rejected() {
+        let config = Config {
+            ca_cert: Some(b"not a certificate".to_vec()),
+            ..Default::default()
+        };
+        let err = client_config(&config).unwrap_err();
+        assert_eq!("TLS error: CA bundle has no certificates", err.to_string());
+    }
+
+    #[test]
+    fn rejected_certificate_is_verification_error() {
+        let cause = rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer);
+        let err = handshake_error(io::Error::new(io::ErrorKind::InvalidData, cause));
+        assert!(matches!(err, BlynkError::TlsVerification(_)));
+
+        let err = handshake_error(io::ErrorKind::ConnectionReset.into());
+        assert!(matches!(err, BlynkError::Io(_)));
+    }
+}
@@ -0,0 +1,100 @@
+//! Values written to the pins
+//!
+//! Blynk widgets take every value as text: booleans as `0` and `1`,
+//! numbers in their decimal form, and several values of a single write
+//! separated by `\0`. `IntoBlynkValue` does the formatting, so
+//! `virtual_write` takes numbers, booleans and tuples of them as they are.
+
+/// Conversion of a value into the text of a pin write
+pub trait IntoBlynkValue {
+    fn into_blynk_value(self) -> String;
+}
+
+impl IntoBlynkValue for &str {
+    fn into_blynk_value(self) -> String {
+        self.to_string()
+    }
+}
+
+impl IntoBlynkValue for String {
+    fn into_blynk_value(self) -> String {
+        self
+    }
+}
+
+impl IntoBlynkValue for &String {
+    fn into_blynk_value(self) -> String {
+        self.clone()
+    }
+}
+
+impl IntoBlynkValue for bool {
+    fn into_blynk_value(self) -> String {
+        if self { "1" } else { "0" }.to_string()
+    }
+}
+
+macro_rules! display_value {
+    ($($ty:ty),+) => {
+        $(
+            impl IntoBlynkValue for $ty {
+                fn into_blynk_value(self) -> String {
+                    self.to_string()
+                }
+            }
+        )+
+    };
+}
+
+display_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64);
+
+/// Number written with a fixed number of decimal places, e.g.
+/// `Precision(21.456, 1)` as `21.5`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Precision<T>(pub T, pub usize);
+
+impl IntoBlynkValue for Precision<f32> {
+    fn into_blynk_value(self) -> String {
+        format!("{:.*}", self.1, self.0)
+    }
+}
+
+impl IntoBlynkValue for Precision<f64> {
+    fn into_blynk_value(self) -> String {
+        format!("{:.*}", self.1, self.0)
+    }
+}
+
+macro_rules! tuple_value {
+    ($($name:ident),+) => {
+        impl<$($name: IntoBlynkValue),+> IntoBlynkValue for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_blynk_value(self) -> String {
+                let ($($name,)+) = self;
+                [$($name.into_blynk_value()),+].join("\0")
+            }
+        }
+    };
+}
+
+// tuples are written as the values of a single multi-value write
+tuple_value!(A, B);
+tuple_value!(A, B, C);
+tuple_value!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_values() {
+        assert_eq!("on", "on".into_blynk_value());
+        assert_eq!("1", true.into_blynk_value());
+        assert_eq!("0", false.into_blynk_value());
+        assert_eq!("-42", (-42i32).into_blynk_value());
+        assert_eq!("20.5", 20.5f32.into_blynk_value());
+        assert_eq!("21.5", Precision(21.456, 1).into_blynk_value());
+        assert_eq!("3", Precision(2.6f32, 0).into_blynk_value());
+        assert_eq!("1\0-2\0ok", (1u8, -2i64, "ok").into_blynk_value());
+    }
+}
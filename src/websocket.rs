@@ -0,0 +1,503 @@
+//! WebSocket framing for the Blynk protocol
+//!
+//! Blynk servers accept the same binary protocol framed as WebSocket
+//! binary messages, which helps devices behind proxies that only let
+//! HTTP(S) traffic through. `Framer` implements the framing without doing
+//! any IO so it can be shared by the blocking and async streams.
+
+use std::collections::VecDeque;
+use std::io;
+#[cfg(not(feature = "async"))]
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha1::{Digest, Sha1};
+#[cfg(feature = "async")]
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::maybe_async;
+use crate::message::ProtocolHeader;
+use crate::proxy::base64;
+use crate::rt::Socket;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Upper bound of the HTTP upgrade response we are willing to read
+const MAX_RESPONSE: usize = 4096;
+
+/// Largest frame payload accepted, the largest Blynk message
+const MAX_PAYLOAD: usize = ProtocolHeader::SIZE + u16::MAX as usize;
+
+/// Appended to the key to compute `Sec-WebSocket-Accept` (RFC 6455 §4.2.2)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// `(opcode, header size, payload size)` of a received frame
+type FrameHeader = (u8, usize, usize);
+
+/// Frames outgoing data and extracts payload from incoming frames
+pub struct Framer {
+    seed: u32,
+    /// `Sec-WebSocket-Key` of the handshake request
+    key: String,
+    incoming: Vec<u8>,
+    payload: VecDeque<u8>,
+    control: Vec<u8>,
+    closed: bool,
+}
+
+impl Framer {
+    pub fn new() -> Framer {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        Framer {
+            seed: nanos | 1,
+            key: String::new(),
+            incoming: Vec::new(),
+            payload: VecDeque::new(),
+            control: Vec::new(),
+            closed: false,
+        }
+    }
+
+    /// Masking keys only have to be unpredictable for the proxies,
+    /// xorshift is good enough for that
+    fn random(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+
+    /// Returns HTTP request upgrading the connection to WebSocket
+    pub fn handshake_request(&mut self, host: &str, path: &str) -> String {
+        let mut key = [0; 16];
+        for chunk in key.chunks_mut(4) {
+            chunk.copy_from_slice(&self.random().to_be_bytes());
+        }
+        self.key = base64(&key);
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, host, self.key
+        )
+    }
+
+    /// Checks the HTTP response to the upgrade request, the server has to
+    /// accept the key of `handshake_request`
+    pub fn check_handshake_response(&self, response: &[u8]) -> io::Result<()> {
+        let response = String::from_utf8_lossy(response);
+        let mut lines = response.lines();
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("WebSocket upgrade rejected: {}", status.trim()),
+            ));
+        }
+
+        let accept = lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("Sec-WebSocket-Accept")
+                .then(|| value.trim())
+        });
+        if accept != Some(accept_key(&self.key).as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WebSocket upgrade not accepted",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Wraps the data into a single masked binary frame
+    pub fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        self.frame(OP_BINARY, data)
+    }
+
+    fn frame(&mut self, opcode: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode];
+        match data.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let mask = self.random().to_be_bytes();
+        frame.extend_from_slice(&mask);
+        frame.extend(data.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Consumes bytes received from the server
+    pub fn decode(&mut self, data: &[u8]) -> io::Result<()> {
+        self.incoming.extend_from_slice(data);
+        while let Some((opcode, header, len)) = self.next_frame()? {
+            let body: Vec<u8> = self.incoming.drain(..header + len).skip(header).collect();
+            match opcode {
+                OP_CONTINUATION | OP_TEXT | OP_BINARY => self.payload.extend(body),
+                OP_PING => {
+                    let pong = self.frame(OP_PONG, &body);
+                    self.control.extend(pong);
+                }
+                OP_CLOSE => {
+                    if !self.closed {
+                        let close = self.frame(OP_CLOSE, &body);
+                        self.control.extend(close);
+                    }
+                    self.closed = true;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns header of the first frame if it was received completely
+    fn next_frame(&self) -> io::Result<Option<FrameHeader>> {
+        let data = &self.incoming;
+        if data.len() < 2 {
+            return Ok(None);
+        }
+        let opcode = data[0] & 0x0f;
+        // only the client masks its frames (RFC 6455 §5.1)
+        if data[1] & 0x80 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "masked frame from server",
+            ));
+        }
+        let (header, len) = match data[1] & 0x7f {
+            126 if data.len() >= 4 => (4, u16::from_be_bytes([data[2], data[3]]).into()),
+            127 if data.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&data[2..10]);
+                (10, u64::from_be_bytes(len))
+            }
+            126 | 127 => return Ok(None),
+            len => (2, len.into()),
+        };
+        let len = match usize::try_from(len) {
+            Ok(len) if len <= MAX_PAYLOAD => len,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too big")),
+        };
+
+        if data.len() < header + len {
+            return Ok(None);
+        }
+        Ok(Some((opcode, header, len)))
+    }
+
+    /// Moves decoded payload into the buffer, returns number of bytes copied
+    pub fn read_payload(&mut self, buf: &mut [u8]) -> usize {
+        let size = buf.len().min(self.payload.len());
+        for (dst, src) in buf.iter_mut().zip(self.payload.drain(..size)) {
+            *dst = src;
+        }
+        size
+    }
+
+    pub fn has_payload(&self) -> bool {
+        !self.payload.is_empty()
+    }
+
+    /// True once the server sent close frame
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Takes control frames (pongs, close reply) that should be sent back
+    pub fn take_control(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.control)
+    }
+}
+
+/// `Sec-WebSocket-Accept` the server answers `key` with
+pub(crate) fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(ACCEPT_GUID.as_bytes());
+    base64(&sha.finalize())
+}
+
+/// Returns true once the whole HTTP response header was received, it
+/// fails if the response is suspiciously long
+pub fn handshake_response_complete(response: &[u8]) -> io::Result<bool> {
+    if response.len() > MAX_RESPONSE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "WebSocket upgrade response too long",
+        ));
+    }
+    Ok(response.ends_with(b"\r\n\r\n"))
+}
+
+/// Sends the upgrade request and checks the response, the socket is
+/// left right before the first frame
+#[maybe_async]
+pub(crate) async fn handshake<S: Socket>(
+    inner: &mut S,
+    host: &str,
+    path: &str,
+) -> io::Result<Framer> {
+    let mut framer = Framer::new();
+    inner
+        .write_all(framer.handshake_request(host, path).as_bytes())
+        .await?;
+    inner.flush().await?;
+
+    // read byte by byte so we don't consume the first frames
+    let mut response = Vec::new();
+    let mut byte = [0];
+    while !handshake_response_complete(&response)? {
+        if inner.read(&mut byte).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        response.push(byte[0]);
+    }
+    framer.check_handshake_response(&response)?;
+    Ok(framer)
+}
+
+/// Blocking WebSocket connection carrying Blynk messages
+#[cfg(not(feature = "async"))]
+pub struct WebSocket<S> {
+    inner: S,
+    framer: Framer,
+}
+
+#[cfg(not(feature = "async"))]
+impl<S: Read + Write> WebSocket<S> {
+    /// Upgrades the connection performing the HTTP handshake
+    pub fn connect(mut inner: S, host: &str, path: &str) -> io::Result<WebSocket<S>> {
+        let framer = handshake(&mut inner, host, path)?;
+        Ok(WebSocket { inner, framer })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl<S: Read + Write> Read for WebSocket<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0; 512];
+        while !self.framer.has_payload() && !self.framer.is_closed() {
+            let size = self.inner.read(&mut raw)?;
+            if size == 0 {
+                return Ok(0);
+            }
+            self.framer.decode(&raw[..size])?;
+
+            let control = self.framer.take_control();
+            if !control.is_empty() {
+                self.inner.write_all(&control)?;
+            }
+        }
+        Ok(self.framer.read_payload(buf))
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl<S: Read + Write> Write for WebSocket<S> {
+    /// Sends the whole buffer as one binary frame
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frame = self.framer.encode(buf);
+        self.inner.write_all(&frame)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_frame(opcode: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode, data.len() as u8];
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// Response accepting the key of the last handshake request
+    fn upgrade_response(framer: &Framer) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&framer.key)
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn encodes_masked_binary_frame() {
+        let mut framer = Framer::new();
+        let frame = framer.encode(b"hello");
+        assert_eq!(0x82, frame[0]);
+        assert_eq!(0x80 | 5, frame[1]);
+
+        let mask = &frame[2..6];
+        let payload: Vec<u8> = frame[6..]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        assert_eq!(b"hello", payload.as_slice());
+    }
+
+    #[test]
+    fn rejects_masked_and_oversized_frames() {
+        let frame = Framer::new().encode(b"hello");
+        let err = Framer::new().decode(&frame).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        let mut frame = vec![0x80 | OP_BINARY, 127];
+        frame.extend_from_slice(&u64::MAX.to_be_bytes());
+        let err = Framer::new().decode(&frame).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn encodes_extended_length() {
+        let mut framer = Framer::new();
+        let frame = framer.encode(&[7; 300]);
+        assert_eq!(0x80 | 126, frame[1]);
+        assert_eq!(300, u16::from_be_bytes([frame[2], frame[3]]));
+        assert_eq!(4 + 4 + 300, frame.len());
+    }
+
+    #[test]
+    fn decodes_split_frames_and_answers_ping() {
+        let mut framer = Framer::new();
+        let mut data = server_frame(OP_BINARY, b"abc");
+        data.extend(server_frame(OP_PING, b"p"));
+        data.extend(server_frame(OP_BINARY, b"def"));
+
+        framer.decode(&data[..4]).unwrap();
+        framer.decode(&data[4..]).unwrap();
+
+        let mut buf = [0; 16];
+        assert_eq!(6, framer.read_payload(&mut buf));
+        assert_eq!(b"abcdef", &buf[..6]);
+        let pong = framer.take_control();
+        assert_eq!(0x8a, pong[0]);
+        assert!(!framer.is_closed());
+
+        framer.decode(&server_frame(OP_CLOSE, b"")).unwrap();
+        assert!(framer.is_closed());
+    }
+
+    #[test]
+    fn handshake_key_is_base64() {
+        assert_eq!(
+            "AAECAwQFBgcICQoLDA0ODw==",
+            base64(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])
+        );
+        let request = Framer::new().handshake_request("blynk.cloud", "/websocket");
+        assert!(request.starts_with("GET /websocket HTTP/1.1\r\nHost: blynk.cloud\r\n"));
+    }
+
+    #[test]
+    fn upgrade_rejected_by_server() {
+        let mut framer = Framer::new();
+        framer.handshake_request("blynk.cloud", "/websocket");
+        assert!(framer
+            .check_handshake_response(&upgrade_response(&framer))
+            .is_ok());
+        let err = framer
+            .check_handshake_response(b"HTTP/1.1 404 Not Found\r\n\r\n")
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionRefused, err.kind());
+        let err = framer
+            .check_handshake_response(b"HTTP/1.1 101 Switching Protocols\r\n\r\n")
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn computes_accept_key() {
+        // example of RFC 6455 §1.3
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn blocking_stream_reads_payload() {
+        use std::io::Cursor;
+
+        struct Duplex {
+            input: Cursor<Vec<u8>>,
+            output: Vec<u8>,
+            frames: Vec<u8>,
+        }
+
+        impl Read for Duplex {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                // answers the upgrade request once it was written
+                if self.input.get_ref().is_empty() {
+                    let request = String::from_utf8_lossy(&self.output);
+                    let key = request
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                        .unwrap();
+                    let mut input = format!(
+                        "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                        accept_key(key)
+                    )
+                    .into_bytes();
+                    input.append(&mut self.frames);
+                    self.input = Cursor::new(input);
+                }
+                self.input.read(buf)
+            }
+        }
+
+        impl Write for Duplex {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.output.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let duplex = Duplex {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+            frames: server_frame(OP_BINARY, b"blynk"),
+        };
+        let mut ws = WebSocket::connect(duplex, "localhost", "/websocket").unwrap();
+        assert!(ws.get_ref().output.starts_with(b"GET /websocket HTTP/1.1"));
+
+        let mut buf = [0; 8];
+        assert_eq!(5, ws.read(&mut buf).unwrap());
+        assert_eq!(b"blynk", &buf[..5]);
+
+        ws.get_mut().output.clear();
+        ws.write_all(b"hi").unwrap();
+        assert_eq!(0x82, ws.get_ref().output[0]);
+    }
+}
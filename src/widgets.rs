@@ -0,0 +1,887 @@
+//! Typed values of app widgets
+//!
+//! Some widgets write several values at once, they arrive together in
+//! `Event::handle_vpin_write` and the types here decode them.
+//! Others, like the terminal, need a bit of state kept on the device.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use log::*;
+
+use crate::proto;
+use crate::{maybe_async, BlynkError, ParamList, Protocol, Result, VirtualPin};
+
+/// Time of day picked in the Time Input widget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeOfDay {
+    /// Seconds since midnight
+    Seconds(u32),
+    Sunrise,
+    Sunset,
+}
+
+impl TimeOfDay {
+    fn parse(value: &str) -> Result<Option<TimeOfDay>> {
+        match value {
+            "" => Ok(None),
+            "sr" => Ok(Some(TimeOfDay::Sunrise)),
+            "ss" => Ok(Some(TimeOfDay::Sunset)),
+            secs => secs
+                .parse()
+                .map(|secs| Some(TimeOfDay::Seconds(secs)))
+                .map_err(|_| BlynkError::InvalidMessageBody),
+        }
+    }
+}
+
+/// Value of the Time Input widget: `start`, `stop`, time zone name,
+/// weekdays and time zone offset
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimeInput {
+    pub start: Option<TimeOfDay>,
+    pub stop: Option<TimeOfDay>,
+    /// Zone name, e.g. `Europe/Warsaw`
+    pub timezone: Option<String>,
+    /// Selected days, 1 is Monday and 7 is Sunday
+    pub weekdays: Vec<u8>,
+    /// Offset of the zone from UTC in seconds
+    pub tz_offset: Option<i32>,
+}
+
+impl TimeInput {
+    /// Decodes the values written by the widget, fields the widget didn't
+    /// send are left empty
+    pub fn parse(values: &[&str]) -> Result<TimeInput> {
+        let field = |i: usize| values.get(i).copied().unwrap_or_default();
+        let weekdays = field(3)
+            .split(',')
+            .filter(|day| !day.is_empty())
+            .map(|day| match day.parse() {
+                Ok(day @ 1..=7) => Ok(day),
+                _ => Err(BlynkError::InvalidMessageBody),
+            })
+            .collect::<Result<_>>()?;
+        let tz_offset = match field(4) {
+            "" => None,
+            offset => Some(offset.parse().map_err(|_| BlynkError::InvalidMessageBody)?),
+        };
+        Ok(TimeInput {
+            start: TimeOfDay::parse(field(0))?,
+            stop: TimeOfDay::parse(field(1))?,
+            timezone: Some(field(2)).filter(|tz| !tz.is_empty()).map(String::from),
+            weekdays,
+            tz_offset,
+        })
+    }
+
+    /// True if the day (1 is Monday) was selected in the widget
+    pub fn is_weekday_selected(&self, day: u8) -> bool {
+        self.weekdays.contains(&day)
+    }
+}
+
+/// LED widget on a virtual pin. The brightness last written is kept, so
+/// setting the same state again doesn't send anything
+#[derive(Debug, Clone)]
+pub struct Led {
+    pin: VirtualPin,
+    brightness: Option<u8>,
+}
+
+impl Led {
+    pub fn new<N: Into<VirtualPin>>(pin: N) -> Led {
+        Led {
+            pin: pin.into(),
+            brightness: None,
+        }
+    }
+
+    pub fn pin(&self) -> VirtualPin {
+        self.pin
+    }
+
+    /// Brightness last written, `None` until the first write
+    pub fn brightness(&self) -> Option<u8> {
+        self.brightness
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.brightness.is_some_and(|b| b > 0)
+    }
+
+    /// Forgets the written state, so the next change is sent even if it
+    /// matches, e.g. after a reconnect
+    pub fn invalidate(&mut self) {
+        self.brightness = None;
+    }
+
+    /// Records the brightness, returns the value to write if it changed
+    fn update(&mut self, brightness: u8) -> Option<String> {
+        if self.brightness == Some(brightness) {
+            return None;
+        }
+        self.brightness = Some(brightness);
+        Some(brightness.to_string())
+    }
+}
+
+#[maybe_async]
+impl Led {
+    pub async fn on<P: Protocol>(&mut self, client: &mut P) -> Result<()> {
+        self.set_brightness(client, u8::MAX).await
+    }
+
+    pub async fn off<P: Protocol>(&mut self, client: &mut P) -> Result<()> {
+        self.set_brightness(client, 0).await
+    }
+
+    pub async fn set_brightness<P: Protocol>(
+        &mut self,
+        client: &mut P,
+        brightness: u8,
+    ) -> Result<()> {
+        match self.update(brightness) {
+            Some(val) => client
+                .virtual_write(self.pin, &val)
+                .await
+                .inspect_err(|_| self.invalidate()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Position as sent by the GPS Stream widget, altitude in meters and
+/// speed in m/s
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Location {
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+    pub speed: f64,
+}
+
+impl Location {
+    pub fn new(lat: f64, lon: f64) -> Location {
+        Location {
+            lat,
+            lon,
+            ..Default::default()
+        }
+    }
+
+    /// Decodes the `lat lon alt speed` values, altitude and speed are
+    /// optional
+    pub fn parse(values: &[&str]) -> Result<Location> {
+        let num = |i: usize| match values.get(i) {
+            Some(val) => val.parse().map_err(|_| BlynkError::InvalidMessageBody),
+            None => Ok(0.0),
+        };
+        if values.len() < 2 {
+            return Err(BlynkError::InvalidMessageBody);
+        }
+        Ok(Location {
+            lat: num(0)?,
+            lon: num(1)?,
+            alt: num(2)?,
+            speed: num(3)?,
+        })
+    }
+}
+
+/// Position of the Joystick widget in merged mode, where both axes arrive
+/// in a single write. In split mode each axis has its own pin and comes
+/// as a regular single value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Joystick {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Joystick {
+    pub fn parse(values: &[&str]) -> Result<Joystick> {
+        match values {
+            [x, y, ..] => Ok(Joystick {
+                x: x.parse().map_err(|_| BlynkError::InvalidMessageBody)?,
+                y: y.parse().map_err(|_| BlynkError::InvalidMessageBody)?,
+            }),
+            _ => Err(BlynkError::InvalidMessageBody),
+        }
+    }
+}
+
+impl From<Joystick> for (i32, i32) {
+    fn from(joystick: Joystick) -> (i32, i32) {
+        (joystick.x, joystick.y)
+    }
+}
+
+/// Written by the GPS Trigger widget when the phone crosses the border of
+/// the selected area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpsTrigger {
+    Entered,
+    Left,
+}
+
+impl GpsTrigger {
+    pub fn parse(value: &str) -> Result<GpsTrigger> {
+        match value {
+            "1" => Ok(GpsTrigger::Entered),
+            "0" => Ok(GpsTrigger::Left),
+            _ => Err(BlynkError::InvalidMessageBody),
+        }
+    }
+}
+
+/// RGB color of widget properties, written as `#RRGGBB`
+///
+/// # Example
+/// ```
+/// use blynk_io::widgets::Color;
+///
+/// let warm = Color::BLYNK_GREEN.mix(Color::BLYNK_RED, 0.5);
+/// assert_eq!("#7B8475", warm.to_string());
+/// assert_eq!(Color::rgb(0x23, 0xC4, 0x8E), "#23C48E".parse().unwrap());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLYNK_GREEN: Color = Color::rgb(0x23, 0xC4, 0x8E);
+    pub const BLYNK_BLUE: Color = Color::rgb(0x04, 0xC0, 0xF8);
+    pub const BLYNK_YELLOW: Color = Color::rgb(0xED, 0x9D, 0x00);
+    pub const BLYNK_RED: Color = Color::rgb(0xD3, 0x43, 0x5C);
+    pub const BLYNK_DARK_BLUE: Color = Color::rgb(0x5F, 0x7C, 0xD8);
+    pub const WHITE: Color = Color::rgb(0xFF, 0xFF, 0xFF);
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b }
+    }
+
+    /// Color `t` (0 to 1) of the way from this one to `other`, for
+    /// gradients over a range of values
+    pub fn mix(self, other: Color, t: f32) -> Color {
+        let t = t.clamp(0.0, 1.0);
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        Color::rgb(
+            mix(self.r, other.r),
+            mix(self.g, other.g),
+            mix(self.b, other.b),
+        )
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.r, self.g, self.b)
+    }
+}
+
+impl FromStr for Color {
+    type Err = BlynkError;
+
+    /// Parses `#RRGGBB`, the `#` is optional
+    fn from_str(hex: &str) -> Result<Color> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if hex.len() != 6 {
+            return Err(BlynkError::InvalidMessageBody);
+        }
+        let byte = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or(BlynkError::InvalidMessageBody)
+        };
+        Ok(Color::rgb(byte(0)?, byte(2)?, byte(4)?))
+    }
+}
+
+/// Location shown on the Map widget
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub lat: f64,
+    pub lon: f64,
+    pub label: String,
+}
+
+/// Map widget on a virtual pin. Markers are written as `index lat lon
+/// label` and the widget can only be cleared as a whole, so the markers
+/// are kept and removing one redraws the rest
+#[derive(Debug, Clone)]
+pub struct Map {
+    pin: VirtualPin,
+    markers: BTreeMap<u32, Marker>,
+}
+
+impl Map {
+    pub fn new<N: Into<VirtualPin>>(pin: N) -> Map {
+        Map {
+            pin: pin.into(),
+            markers: BTreeMap::new(),
+        }
+    }
+
+    pub fn pin(&self) -> VirtualPin {
+        self.pin
+    }
+
+    pub fn markers(&self) -> &BTreeMap<u32, Marker> {
+        &self.markers
+    }
+
+    fn location(&self, id: u16, index: u32, marker: &Marker) -> Vec<u8> {
+        let (index, lat, lon) = (
+            index.to_string(),
+            marker.lat.to_string(),
+            marker.lon.to_string(),
+        );
+        proto::virtual_write_multi(id, self.pin.number(), &[&index, &lat, &lon, &marker.label])
+    }
+
+    /// Clears the widget and draws the markers left
+    fn redraw<P: Protocol>(&self, client: &mut P) -> Vec<Vec<u8>> {
+        let mut msgs = vec![proto::virtual_write(
+            client.msg_id(),
+            self.pin.number(),
+            "clr",
+        )];
+        for (&index, marker) in &self.markers {
+            msgs.push(self.location(client.msg_id(), index, marker));
+        }
+        msgs
+    }
+}
+
+#[maybe_async]
+impl Map {
+    /// Adds the marker, or moves the one with the same index
+    pub async fn add<P: Protocol>(
+        &mut self,
+        client: &mut P,
+        index: u32,
+        lat: f64,
+        lon: f64,
+        label: &str,
+    ) -> Result<()> {
+        let marker = Marker {
+            lat,
+            lon,
+            label: label.to_string(),
+        };
+        let msg = self.location(client.msg_id(), index, &marker);
+        self.markers.insert(index, marker);
+        client.send(msg).await
+    }
+
+    pub async fn remove<P: Protocol>(&mut self, client: &mut P, index: u32) -> Result<()> {
+        if self.markers.remove(&index).is_none() {
+            return Ok(());
+        }
+        let msgs = self.redraw(client);
+        client.send_all(msgs).await
+    }
+
+    pub async fn clear<P: Protocol>(&mut self, client: &mut P) -> Result<()> {
+        self.markers.clear();
+        client.virtual_write(self.pin, "clr").await
+    }
+}
+
+/// Button or switch on a virtual pin. The state last written or received
+/// from the app is kept, so writing the same state again sends nothing
+#[derive(Debug, Clone)]
+pub struct Button {
+    pin: VirtualPin,
+    state: Option<bool>,
+}
+
+impl Button {
+    pub fn new<N: Into<VirtualPin>>(pin: N) -> Button {
+        Button {
+            pin: pin.into(),
+            state: None,
+        }
+    }
+
+    pub fn pin(&self) -> VirtualPin {
+        self.pin
+    }
+
+    /// State last written or received, `None` until the first one
+    pub fn state(&self) -> Option<bool> {
+        self.state
+    }
+
+    /// Forgets the state, so the next write is sent even if it matches
+    pub fn invalidate(&mut self) {
+        self.state = None;
+    }
+
+    /// Records a write from the app, returns the new state if it was for
+    /// this button
+    pub fn handle_write(&mut self, pin: VirtualPin, params: ParamList<'_>) -> Option<bool> {
+        if pin != self.pin {
+            return None;
+        }
+        let on = params.as_str() != "0";
+        self.state = Some(on);
+        Some(on)
+    }
+
+    /// Records the state, returns the value to write if it changed
+    fn update(&mut self, on: bool) -> Option<&'static str> {
+        if self.state == Some(on) {
+            return None;
+        }
+        self.state = Some(on);
+        Some(if on { "1" } else { "0" })
+    }
+}
+
+#[maybe_async]
+impl Button {
+    pub async fn set<P: Protocol>(&mut self, client: &mut P, on: bool) -> Result<()> {
+        match self.update(on) {
+            Some(val) => client
+                .virtual_write(self.pin, val)
+                .await
+                .inspect_err(|_| self.invalidate()),
+            None => Ok(()),
+        }
+    }
+
+    /// Label shown in the on state
+    pub async fn set_on_label<P: Protocol>(&self, client: &mut P, label: &str) -> Result<()> {
+        client.set_property(self.pin, "onLabel", label).await
+    }
+
+    /// Label shown in the off state
+    pub async fn set_off_label<P: Protocol>(&self, client: &mut P, label: &str) -> Result<()> {
+        client.set_property(self.pin, "offLabel", label).await
+    }
+
+    pub async fn set_disabled<P: Protocol>(&self, client: &mut P, disabled: bool) -> Result<()> {
+        client
+            .set_property(self.pin, "isDisabled", bool_str(disabled))
+            .await
+    }
+
+    pub async fn set_hidden<P: Protocol>(&self, client: &mut P, hidden: bool) -> Result<()> {
+        client
+            .set_property(self.pin, "isHidden", bool_str(hidden))
+            .await
+    }
+}
+
+/// Video Streaming widget on a virtual pin, lets the device point it to
+/// another stream and pause or resume playback
+#[derive(Debug, Clone)]
+pub struct Video {
+    pin: VirtualPin,
+}
+
+impl Video {
+    /// Schemes of the streams the widget plays
+    const SCHEMES: [&'static str; 4] = ["http://", "https://", "rtsp://", "rtmp://"];
+
+    pub fn new<N: Into<VirtualPin>>(pin: N) -> Video {
+        Video { pin: pin.into() }
+    }
+
+    pub fn pin(&self) -> VirtualPin {
+        self.pin
+    }
+
+    /// Fails with `BlynkError::InvalidUrl` unless the URL has a host and
+    /// one of the supported schemes
+    pub fn validate_url(url: &str) -> Result<()> {
+        let invalid = || BlynkError::InvalidUrl(url.to_string());
+        let rest = Self::SCHEMES
+            .iter()
+            .find_map(|scheme| url.strip_prefix(scheme))
+            .ok_or_else(invalid)?;
+        let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        if host.is_empty() || url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+}
+
+#[maybe_async]
+impl Video {
+    pub async fn set_url<P: Protocol>(&self, client: &mut P, url: &str) -> Result<()> {
+        Self::validate_url(url)?;
+        client.set_property(self.pin, "url", url).await
+    }
+
+    pub async fn pause<P: Protocol>(&self, client: &mut P) -> Result<()> {
+        client.set_property(self.pin, "isOnPlay", "false").await
+    }
+
+    pub async fn resume<P: Protocol>(&self, client: &mut P) -> Result<()> {
+        client.set_property(self.pin, "isOnPlay", "true").await
+    }
+}
+
+/// Menu or Segmented Switch on a virtual pin. The app writes the 1-based
+/// index of the selected item, `selection` turns it into a 0-based one
+#[derive(Debug, Clone)]
+pub struct Menu {
+    pin: VirtualPin,
+}
+
+pub type SegmentedSwitch = Menu;
+
+impl Menu {
+    pub fn new<N: Into<VirtualPin>>(pin: N) -> Menu {
+        Menu { pin: pin.into() }
+    }
+
+    pub fn pin(&self) -> VirtualPin {
+        self.pin
+    }
+
+    /// 0-based index of the item selected in the app, converted into `T`,
+    /// e.g. an enum implementing `TryFrom<usize>`
+    pub fn selection<T: TryFrom<usize>>(data: &str) -> Result<T> {
+        match data.parse::<usize>() {
+            Ok(index) if index > 0 => {
+                T::try_from(index - 1).map_err(|_| BlynkError::InvalidMessageBody)
+            }
+            _ => Err(BlynkError::InvalidMessageBody),
+        }
+    }
+}
+
+#[maybe_async]
+impl Menu {
+    /// Replaces the items, in order
+    pub async fn set_labels<P: Protocol>(&self, client: &mut P, labels: &[&str]) -> Result<()> {
+        let msg = proto::set_property_multi(client.msg_id(), self.pin.number(), "labels", labels);
+        client.send(msg).await
+    }
+}
+
+/// Slider or Gauge on a virtual pin, lets the device change the range at
+/// runtime, e.g. when switching between °C and °F. Gauges have no step
+#[derive(Debug, Clone)]
+pub struct Slider {
+    pin: VirtualPin,
+}
+
+pub type Gauge = Slider;
+
+impl Slider {
+    pub fn new<N: Into<VirtualPin>>(pin: N) -> Slider {
+        Slider { pin: pin.into() }
+    }
+
+    pub fn pin(&self) -> VirtualPin {
+        self.pin
+    }
+}
+
+#[maybe_async]
+impl Slider {
+    /// Sets `min` and `max` together
+    pub async fn set_range<P: Protocol>(&self, client: &mut P, min: f64, max: f64) -> Result<()> {
+        let (min, max) = (min.to_string(), max.to_string());
+        client
+            .set_properties(self.pin, &[("min", &min), ("max", &max)])
+            .await
+    }
+
+    pub async fn set_step<P: Protocol>(&self, client: &mut P, step: f64) -> Result<()> {
+        client
+            .set_property(self.pin, "step", &step.to_string())
+            .await
+    }
+
+    pub async fn set_label<P: Protocol>(&self, client: &mut P, label: &str) -> Result<()> {
+        client.set_property(self.pin, "label", label).await
+    }
+}
+
+fn bool_str(val: bool) -> &'static str {
+    if val {
+        "true"
+    } else {
+        "false"
+    }
+}
+
+type CommandHandler<C> = Box<dyn FnMut(&mut C, &[&str]) + Send>;
+
+/// Console on a Terminal widget: collects the written fragments into
+/// lines, splits them into a command and whitespace separated arguments
+/// and calls the handler registered for the command. `C` is whatever the
+/// handlers need, usually the client to answer with.
+///
+/// Lines end with a newline, so "Add newline" has to be enabled in the
+/// widget settings.
+///
+/// # Example
+/// ```
+/// use blynk_io::widgets::Terminal;
+/// use blynk_io::{pins, ParamList};
+///
+/// let mut terminal = Terminal::new(10).on("echo", |out: &mut Vec<String>, args| {
+///     out.push(args.join(" "))
+/// });
+/// let mut out = Vec::new();
+/// terminal.feed(&mut out, pins::V10, ParamList::new(&["echo hel"]));
+/// terminal.feed(&mut out, pins::V10, ParamList::new(&["lo world\n"]));
+/// assert_eq!(vec!["hello world"], out);
+/// ```
+pub struct Terminal<C> {
+    pin: VirtualPin,
+    line: String,
+    commands: Vec<(String, CommandHandler<C>)>,
+    unknown: Option<CommandHandler<C>>,
+}
+
+impl<C> Terminal<C> {
+    pub fn new<N: Into<VirtualPin>>(pin: N) -> Terminal<C> {
+        Terminal {
+            pin: pin.into(),
+            line: String::new(),
+            commands: Vec::new(),
+            unknown: None,
+        }
+    }
+
+    /// Registers the handler of `name`, it receives the arguments
+    pub fn on<F>(mut self, name: &str, handler: F) -> Self
+    where
+        F: FnMut(&mut C, &[&str]) + Send + 'static,
+    {
+        self.commands.push((name.to_string(), Box::new(handler)));
+        self
+    }
+
+    /// Handler of commands without one of their own, it receives the
+    /// command name followed by the arguments
+    pub fn on_unknown<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(&mut C, &[&str]) + Send + 'static,
+    {
+        self.unknown = Some(Box::new(handler));
+        self
+    }
+
+    pub fn pin(&self) -> VirtualPin {
+        self.pin
+    }
+
+    /// Handles a write of `pin`, returns false if it's not the terminal
+    /// pin. Values of a write carrying several are separated by a space.
+    /// Handlers of the completed lines are called right away
+    pub fn feed(&mut self, ctx: &mut C, pin: VirtualPin, params: ParamList<'_>) -> bool {
+        if pin != self.pin {
+            return false;
+        }
+        for (n, data) in params.iter().enumerate() {
+            if n > 0 {
+                self.line.push(' ');
+            }
+            self.line.push_str(data);
+        }
+        while let Some(end) = self.line.find(['\n', '\r']) {
+            let line: String = self.line.drain(..=end).collect();
+            self.run(ctx, &line);
+        }
+        if self.line.len() > crate::conf::MAX_BODY_LEN {
+            warn!("Terminal line too long, dropping it");
+            self.line.clear();
+        }
+        true
+    }
+
+    fn run(&mut self, ctx: &mut C, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((name, args)) = words.split_first() else {
+            return;
+        };
+        match self.commands.iter_mut().find(|(cmd, _)| cmd == name) {
+            Some((_, handler)) => handler(ctx, args),
+            None => match &mut self.unknown {
+                Some(handler) => handler(ctx, &words),
+                None => debug!("Unknown terminal command {}", name),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pins;
+
+    #[test]
+    fn parses_time_input() {
+        let input = TimeInput::parse(&["3600", "ss", "Europe/Warsaw", "1,2,7", "7200"]).unwrap();
+        assert_eq!(Some(TimeOfDay::Seconds(3600)), input.start);
+        assert_eq!(Some(TimeOfDay::Sunset), input.stop);
+        assert_eq!(Some("Europe/Warsaw".into()), input.timezone);
+        assert!(input.is_weekday_selected(7) && !input.is_weekday_selected(3));
+        assert_eq!(Some(7200), input.tz_offset);
+
+        let input = TimeInput::parse(&["", "", "UTC"]).unwrap();
+        assert_eq!(None, input.start);
+        assert!(input.weekdays.is_empty());
+        assert!(TimeInput::parse(&["noon"]).is_err());
+        assert!(TimeInput::parse(&["", "", "UTC", "8"]).is_err());
+    }
+
+    #[test]
+    fn runs_terminal_commands() {
+        let mut terminal = Terminal::new(3)
+            .on("set", |log: &mut Vec<String>, args| {
+                log.push(format!("set {}", args.join(",")))
+            })
+            .on_unknown(|log: &mut Vec<String>, words| log.push(format!("unknown {}", words[0])));
+        let mut log = Vec::new();
+
+        let feed = |terminal: &mut Terminal<_>, log: &mut _, pin, values: &[&str]| {
+            terminal.feed(log, pin, ParamList::new(values))
+        };
+        assert!(!feed(&mut terminal, &mut log, pins::V4, &["set a 1\n"]));
+        assert!(feed(&mut terminal, &mut log, pins::V3, &["set  a"]));
+        assert!(log.is_empty());
+        feed(&mut terminal, &mut log, pins::V3, &[" 1\r\n\nreboot\nset"]);
+        assert_eq!(vec!["set a,1", "unknown reboot"], log);
+        feed(&mut terminal, &mut log, pins::V3, &["\n"]);
+        assert_eq!("set ", log[2]);
+        // values of a single write are separate words
+        feed(&mut terminal, &mut log, pins::V3, &["set", "b", "2\n"]);
+        assert_eq!("set b,2", log[3]);
+    }
+
+    #[test]
+    fn led_skips_redundant_writes() {
+        let mut led = Led::new(1);
+        assert!(!led.is_on());
+        assert_eq!(Some("255".into()), led.update(u8::MAX));
+        assert_eq!(None, led.update(u8::MAX));
+        assert!(led.is_on());
+        assert_eq!(Some("0".into()), led.update(0));
+        led.invalidate();
+        assert_eq!(Some("0".into()), led.update(0));
+    }
+
+    #[test]
+    fn map_redraws_remaining_markers() {
+        let mut map = Map::new(2);
+        let marker = |lat, lon| Marker {
+            lat,
+            lon,
+            label: "home".into(),
+        };
+        map.markers.insert(1, marker(52.25, 21.0));
+        map.markers.insert(4, marker(-33.5, 151.25));
+
+        let msg = map.location(7, 4, &map.markers[&4]);
+        assert_eq!(b"vw\x002\x004\x00-33.5\x00151.25\x00home", &msg[5..]);
+
+        map.markers.remove(&1);
+        let msgs = map.redraw(&mut crate::Client::default());
+        assert_eq!(2, msgs.len());
+        assert_eq!(b"vw\x002\x00clr", &msgs[0][5..]);
+    }
+
+    #[test]
+    fn parses_gps_widgets() {
+        let location = Location::parse(&["52.2297", "21.0122", "110.5", "1.2"]).unwrap();
+        assert_eq!(52.2297, location.lat);
+        assert_eq!(1.2, location.speed);
+        assert_eq!(
+            Location::new(1.5, -2.0),
+            Location::parse(&["1.5", "-2"]).unwrap()
+        );
+        assert!(Location::parse(&["1.5"]).is_err());
+        assert!(Location::parse(&["north", "2"]).is_err());
+
+        assert_eq!(GpsTrigger::Entered, GpsTrigger::parse("1").unwrap());
+        assert_eq!(GpsTrigger::Left, GpsTrigger::parse("0").unwrap());
+        assert!(GpsTrigger::parse("2").is_err());
+    }
+
+    #[test]
+    fn formats_and_parses_colors() {
+        assert_eq!("#23C48E", Color::BLYNK_GREEN.to_string());
+        assert_eq!(Ok(Color::rgb(255, 0, 16)), "ff0010".parse().map_err(|_| ()));
+        assert!("#12345".parse::<Color>().is_err());
+        assert!("#12345G".parse::<Color>().is_err());
+        assert_eq!(Color::WHITE, Color::BLACK.mix(Color::WHITE, 2.0));
+        assert_eq!(
+            Color::rgb(128, 128, 128),
+            Color::BLACK.mix(Color::WHITE, 0.5)
+        );
+    }
+
+    #[test]
+    fn button_tracks_state() {
+        let mut button = Button::new(6);
+        assert_eq!(Some("1"), button.update(true));
+        assert_eq!(None, button.update(true));
+        let off = ParamList::new(&["0"]);
+        assert_eq!(None, button.handle_write(pins::V7, off));
+        assert_eq!(Some(false), button.handle_write(pins::V6, off));
+        // app switched it off, switching on is sent again
+        assert_eq!(Some("1"), button.update(true));
+    }
+
+    #[test]
+    fn validates_video_urls() {
+        assert!(Video::validate_url("rtsp://10.0.0.5:554/stream1").is_ok());
+        assert!(Video::validate_url("https://cam.local").is_ok());
+        assert!(matches!(
+            Video::validate_url("ftp://cam.local/a"),
+            Err(BlynkError::InvalidUrl(_))
+        ));
+        assert!(Video::validate_url("http:///stream").is_err());
+        assert!(Video::validate_url("http://cam.local/my stream").is_err());
+    }
+
+    #[test]
+    fn menu_selection_is_zero_based() {
+        #[derive(Debug, PartialEq)]
+        enum Mode {
+            Auto,
+            Manual,
+        }
+        impl TryFrom<usize> for Mode {
+            type Error = ();
+            fn try_from(index: usize) -> std::result::Result<Mode, ()> {
+                match index {
+                    0 => Ok(Mode::Auto),
+                    1 => Ok(Mode::Manual),
+                    _ => Err(()),
+                }
+            }
+        }
+
+        assert_eq!(2, Menu::selection::<usize>("3").unwrap());
+        assert_eq!(Mode::Manual, Menu::selection::<Mode>("2").unwrap());
+        assert!(Menu::selection::<Mode>("3").is_err());
+        assert!(Menu::selection::<usize>("0").is_err());
+
+        let msg = proto::set_property_multi(1, 4, "labels", &["Auto", "Manual"]);
+        assert_eq!(b"4\x00labels\x00Auto\x00Manual", &msg[5..]);
+    }
+
+    #[test]
+    fn parses_joystick() {
+        let joystick = Joystick::parse(&["-128", "255"]).unwrap();
+        assert_eq!((-128, 255), joystick.into());
+        assert!(Joystick::parse(&["12"]).is_err());
+        assert!(Joystick::parse(&["12", "up"]).is_err());
+    }
+}